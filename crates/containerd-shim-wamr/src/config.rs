@@ -0,0 +1,251 @@
+//! Execution mode and memory settings of the WAMR runtime.
+//!
+//! The settings are configured per container through annotations:
+//!
+//! * [`MODE_ANNOTATION`]: how the guest is executed, one of:
+//!   * `interpreter`: the WAMR interpreter. The shim builds WAMR with the fast
+//!     interpreter, the classic interpreter can only be selected when building WAMR.
+//!   * `jit`: the LLVM JIT, only available when the shim is built with the `llvm-jit` feature.
+//!   * `aot`: the entrypoint must be a module compiled ahead of time with `wamrc`.
+//!
+//!   By default AOT compiled modules are run as such, and other modules are interpreted.
+//! * [`HEAP_SIZE_ANNOTATION`]: the size of the app heap of the instance.
+//! * [`STACK_SIZE_ANNOTATION`]: the size of the stack of the instance, 64 KiB by default.
+//! * [`MEMORY_POOL_ANNOTATION`]: the size of a memory pool allocated once by the
+//!   host, from which WAMR allocates all its memory. By default WAMR uses the system allocator.
+//!
+//! AOT compiled modules can be the entrypoint of the container, or be distributed
+//! in OCI layers with the [`AOT_LAYER_MEDIA_TYPE`] media type.
+//!
+//! Sizes are a number of bytes, optionally followed by `Ki`, `Mi` or `Gi`, e.g., `512Ki`,
+//! and must be less than 4 GiB.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, bail, ensure};
+
+/// Annotation to select the execution mode.
+pub const MODE_ANNOTATION: &str = "runwasi.io/wamr.mode";
+/// Annotation to set the app heap size.
+pub const HEAP_SIZE_ANNOTATION: &str = "runwasi.io/wamr.heap-size";
+/// Annotation to set the stack size.
+pub const STACK_SIZE_ANNOTATION: &str = "runwasi.io/wamr.stack-size";
+/// Annotation to use a host managed memory pool of the given size.
+pub const MEMORY_POOL_ANNOTATION: &str = "runwasi.io/wamr.memory-pool-size";
+
+/// Media type of the OCI layers with a module compiled ahead of time by `wamrc`.
+pub const AOT_LAYER_MEDIA_TYPE: &str = "application/vnd.bytecodealliance.wamr.aot";
+
+/// The magic number of modules compiled ahead of time by `wamrc`.
+const AOT_MAGIC: &[u8] = b"\0aot";
+
+const DEFAULT_STACK_SIZE: u32 = 64 * 1024;
+
+/// Returns `true` if `bytes` is a module compiled ahead of time.
+pub fn is_aot(bytes: &[u8]) -> bool {
+    bytes.starts_with(AOT_MAGIC)
+}
+
+/// How the guest is executed, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExecutionMode {
+    /// AOT compiled modules are run as such, other modules are interpreted.
+    #[default]
+    Auto,
+    /// Modules are interpreted.
+    Interpreter,
+    /// Modules are compiled with the LLVM JIT.
+    Jit,
+    /// Only AOT compiled modules are accepted.
+    Aot,
+}
+
+impl FromStr for ExecutionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "interpreter" => Ok(Self::Interpreter),
+            "jit" if cfg!(feature = "llvm-jit") => Ok(Self::Jit),
+            "jit" => {
+                bail!("the jit mode requires the shim to be built with the `llvm-jit` feature")
+            }
+            "aot" => Ok(Self::Aot),
+            _ => bail!("unknown mode {s:?}, expected `interpreter`, `jit` or `aot`"),
+        }
+    }
+}
+
+/// The settings of the WAMR runtime, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WamrConfig {
+    pub mode: ExecutionMode,
+    pub heap_size: Option<u32>,
+    pub stack_size: u32,
+    pub memory_pool_size: Option<u32>,
+}
+
+impl Default for WamrConfig {
+    fn default() -> Self {
+        Self {
+            mode: ExecutionMode::default(),
+            heap_size: None,
+            stack_size: DEFAULT_STACK_SIZE,
+            memory_pool_size: None,
+        }
+    }
+}
+
+impl WamrConfig {
+    /// Reads the settings from the container annotations.
+    pub fn from_annotations(annotations: &HashMap<String, String>) -> Result<Self> {
+        let get = |key: &str| annotations.get(key).map(|value| value.trim());
+        let size = |key: &str| -> Result<Option<u64>> {
+            get(key)
+                .map(|value| {
+                    parse_size(value)
+                        .with_context(|| format!("invalid value {value:?} for annotation {key}"))
+                })
+                .transpose()
+        };
+
+        let default = Self::default();
+        let mode = get(MODE_ANNOTATION)
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or(default.mode);
+        let heap_size = size(HEAP_SIZE_ANNOTATION)?
+            .map(u32::try_from)
+            .transpose()
+            .context("the heap size must be less than 4 GiB")?;
+        let stack_size = size(STACK_SIZE_ANNOTATION)?
+            .map(u32::try_from)
+            .transpose()
+            .context("the stack size must be less than 4 GiB")?
+            .unwrap_or(default.stack_size);
+        let memory_pool_size = size(MEMORY_POOL_ANNOTATION)?
+            .map(u32::try_from)
+            .transpose()
+            .context("the memory pool must be less than 4 GiB")?;
+
+        Ok(Self {
+            mode,
+            heap_size,
+            stack_size,
+            memory_pool_size,
+        })
+    }
+
+    /// Checks that the module can be run in the configured mode.
+    pub fn ensure_mode_supports(&self, bytes: &[u8]) -> Result<()> {
+        match self.mode {
+            ExecutionMode::Aot => {
+                ensure!(
+                    is_aot(bytes),
+                    "the aot mode requires a module compiled with `wamrc`"
+                )
+            }
+            ExecutionMode::Interpreter | ExecutionMode::Jit => ensure!(
+                !is_aot(bytes),
+                "AOT compiled modules can only run in the aot mode"
+            ),
+            ExecutionMode::Auto => {}
+        }
+        Ok(())
+    }
+}
+
+fn parse_size(value: &str) -> Result<u64> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, ""),
+    };
+    let multiplier = match unit {
+        "" => 1,
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        _ => bail!("unknown unit {unit:?}, expected `Ki`, `Mi` or `Gi`"),
+    };
+    let number: u64 = number.parse()?;
+    number.checked_mul(multiplier).context("size is too large")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wamr_config_defaults() -> Result<()> {
+        let config = WamrConfig::from_annotations(&HashMap::new())?;
+        assert_eq!(config, WamrConfig::default());
+        assert_eq!(config.stack_size, 64 * 1024);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wamr_config_from_annotations() -> Result<()> {
+        let annotations = HashMap::from([
+            (MODE_ANNOTATION.to_string(), "interpreter".to_string()),
+            (HEAP_SIZE_ANNOTATION.to_string(), "1Mi".to_string()),
+            (STACK_SIZE_ANNOTATION.to_string(), " 32768 ".to_string()),
+            (MEMORY_POOL_ANNOTATION.to_string(), "16Mi".to_string()),
+        ]);
+
+        let config = WamrConfig::from_annotations(&annotations)?;
+        assert_eq!(
+            config,
+            WamrConfig {
+                mode: ExecutionMode::Interpreter,
+                heap_size: Some(1024 * 1024),
+                stack_size: 32 * 1024,
+                memory_pool_size: Some(16 * 1024 * 1024),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wamr_config_invalid_annotations() {
+        for (key, value) in [
+            (MODE_ANNOTATION, "turbo"),
+            (HEAP_SIZE_ANNOTATION, "1Ti"),
+            (STACK_SIZE_ANNOTATION, "8Gi"),
+            (MEMORY_POOL_ANNOTATION, "-1"),
+            (MEMORY_POOL_ANNOTATION, "4Gi"),
+        ] {
+            let annotations = HashMap::from([(key.to_string(), value.to_string())]);
+            assert!(
+                WamrConfig::from_annotations(&annotations).is_err(),
+                "{key}={value} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_ensure_mode_supports() -> Result<()> {
+        let module = b"\0asm\x01\0\0\0";
+        let aot = b"\0aot\x04\0\0\0";
+
+        let auto = WamrConfig::default();
+        auto.ensure_mode_supports(module)?;
+        auto.ensure_mode_supports(aot)?;
+
+        let interpreter = WamrConfig {
+            mode: ExecutionMode::Interpreter,
+            ..Default::default()
+        };
+        interpreter.ensure_mode_supports(module)?;
+        assert!(interpreter.ensure_mode_supports(aot).is_err());
+
+        let aot_only = WamrConfig {
+            mode: ExecutionMode::Aot,
+            ..Default::default()
+        };
+        aot_only.ensure_mode_supports(aot)?;
+        assert!(aot_only.ensure_mode_supports(module).is_err());
+
+        Ok(())
+    }
+}
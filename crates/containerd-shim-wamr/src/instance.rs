@@ -1,28 +1,32 @@
-use anyhow::{Context, Result};
-use containerd_shim_wasm::sandbox::Sandbox;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, bail, ensure};
+use containerd_shim_wasm::sandbox::config::EngineConfig;
 use containerd_shim_wasm::sandbox::context::{Entrypoint, RuntimeContext};
+use containerd_shim_wasm::sandbox::{Sandbox, ensure_module};
 use containerd_shim_wasm::shim::{Shim, Version, version};
 use wamr_rust_sdk::function::Function;
 use wamr_rust_sdk::instance::Instance as WamrInst;
 use wamr_rust_sdk::module::Module;
 use wamr_rust_sdk::runtime::Runtime;
 use wamr_rust_sdk::wasi_context::WasiCtxBuilder;
+#[cfg(feature = "llvm-jit")]
+use wamr_sys::RunningMode_Mode_LLVM_JIT;
+use wamr_sys::{
+    RunningMode, RunningMode_Mode_Interp, RuntimeInitArgs, mem_alloc_type_t_Alloc_With_Pool,
+    wasm_module_inst_t, wasm_runtime_full_init, wasm_runtime_terminate,
+};
 
-pub struct WamrShim;
-
-pub struct WamrSandbox {
-    runtime: Runtime,
-}
+use crate::config::{
+    AOT_LAYER_MEDIA_TYPE, ExecutionMode, HEAP_SIZE_ANNOTATION, WamrConfig, is_aot,
+};
 
-unsafe impl Send for WamrSandbox {}
-unsafe impl Sync for WamrSandbox {}
+pub struct WamrShim;
 
-impl Default for WamrSandbox {
-    fn default() -> Self {
-        let runtime = Runtime::new().unwrap();
-        Self { runtime }
-    }
-}
+/// The WAMR runtime is created when the container starts,
+/// as its settings depend on the container annotations.
+#[derive(Default)]
+pub struct WamrSandbox;
 
 impl Shim for WamrShim {
     type Sandbox = WamrSandbox;
@@ -34,10 +38,36 @@ impl Shim for WamrShim {
     fn version() -> Version {
         version!()
     }
+
+    fn supported_layers_types() -> &'static [&'static str] {
+        &[
+            "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm",
+            "application/wasm",
+            AOT_LAYER_MEDIA_TYPE,
+        ]
+    }
 }
 
 impl Sandbox for WamrSandbox {
+    fn supports_components() -> bool {
+        false
+    }
+
+    fn supports_cancellation() -> bool {
+        true
+    }
+
+    /// Unlike the default implementation, AOT compiled modules are accepted,
+    /// and text format modules are rejected as WAMR can't run them.
+    async fn can_handle(&self, ctx: &impl RuntimeContext) -> Result<()> {
+        let config = WamrConfig::from_annotations(ctx.annotations())?;
+        check_engine_config(ctx.engine_config())?;
+        let bytes = ctx.entrypoint().source.as_bytes()?;
+        check_binary(&config, &bytes)
+    }
+
     async fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32> {
+        let config = WamrConfig::from_annotations(ctx.annotations())?;
         let args = ctx.args();
         let envs = ctx.envs();
         let Entrypoint {
@@ -47,6 +77,12 @@ impl Sandbox for WamrSandbox {
         let wasm_bytes = source
             .as_bytes()
             .context("Failed to get bytes from source")?;
+        check_binary(&config, &wasm_bytes)?;
+
+        check_engine_config(ctx.engine_config())?;
+
+        log::info!("Create a WAMR runtime: {config:?}");
+        let runtime = build_runtime(&config)?;
 
         log::info!("Create a WAMR module");
 
@@ -54,7 +90,7 @@ impl Sandbox for WamrSandbox {
 
         let mod_name = name.unwrap_or_else(|| "main".to_string());
 
-        let mut module = Module::from_buf(&self.runtime, &wasm_bytes, &mod_name)
+        let mut module = Module::from_buf(&runtime, &wasm_bytes, &mod_name)
             .context("Failed to create module from bytes")?;
 
         log::info!("Create a WASI context");
@@ -71,14 +107,35 @@ impl Sandbox for WamrSandbox {
 
         log::info!("Create a WAMR instance");
 
-        let instance = WamrInst::new(&self.runtime, &module, 1024 * 64)
-            .context("Failed to create instance")?;
+        let instance = match config.heap_size {
+            Some(heap_size) => {
+                WamrInst::new_with_args(&runtime, &module, config.stack_size, heap_size)
+            }
+            None => WamrInst::new(&runtime, &module, config.stack_size),
+        }
+        .context("Failed to create instance")?;
 
         log::info!("Running {func:?}");
         let function =
             Function::find_export_func(&instance, &func).context("Failed to find function")?;
-        let status = function
-            .call(&instance, &vec![])
+
+        // Terminate the instance when the container is cancelled. WAMR raises
+        // the termination as an exception, which unwinds the guest.
+        let terminator = Terminator::new(&instance);
+        let terminate = tokio::spawn({
+            let cancellation = ctx.cancellation().clone();
+            let terminator = terminator.clone();
+            async move {
+                cancellation.cancelled().await;
+                terminator.terminate();
+            }
+        });
+
+        let status = tokio::task::block_in_place(|| function.call(&instance, &vec![]));
+        terminate.abort();
+        terminator.disarm();
+
+        let status = status
             .map(|_| 0)
             .map_err(|err| {
                 log::error!("Error: {err:?}");
@@ -89,3 +146,129 @@ impl Sandbox for WamrSandbox {
         Ok(status)
     }
 }
+
+/// Terminates a running WAMR instance from another thread.
+///
+/// The instance is only referenced until [`Terminator::disarm`] is called,
+/// which must happen before the instance is dropped.
+#[derive(Clone)]
+struct Terminator(Arc<Mutex<Option<ModuleInstance>>>);
+
+struct ModuleInstance(wasm_module_inst_t);
+
+// SAFETY: `wasm_runtime_terminate` can be called from any thread while the
+// instance is alive, which the `Terminator` lock guarantees.
+unsafe impl Send for ModuleInstance {}
+
+impl Terminator {
+    fn new(instance: &WamrInst) -> Self {
+        let instance = ModuleInstance(instance.get_inner_instance());
+        Self(Arc::new(Mutex::new(Some(instance))))
+    }
+
+    fn terminate(&self) {
+        if let Some(instance) = self.0.lock().unwrap().as_ref() {
+            log::info!("Terminating the WAMR instance");
+            // SAFETY: the instance is alive until `disarm` is called
+            unsafe { wasm_runtime_terminate(instance.0) };
+        }
+    }
+
+    fn disarm(&self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
+/// Checks that `bytes` is a core wasm module or an AOT compiled module
+/// that can run with the given `config`.
+pub(crate) fn check_binary(config: &WamrConfig, bytes: &[u8]) -> Result<()> {
+    config.ensure_mode_supports(bytes)?;
+    if !is_aot(bytes) {
+        ensure!(bytes.starts_with(b"\0asm"), "not a wasm module");
+        ensure_module(bytes)?;
+    }
+    Ok(())
+}
+
+/// Checks that the engine config of the image only has settings the shim honors.
+/// WAMR runs with the wasm features it was built with, its memory is configured with
+/// annotations, and guests can't open sockets.
+pub(crate) fn check_engine_config(engine_config: &EngineConfig) -> Result<()> {
+    ensure!(
+        engine_config.features.is_default(),
+        "the wamr shim uses the wasm features it was built with, the engine config can't set them"
+    );
+    ensure!(
+        engine_config.limits.max_memory_bytes.is_none(),
+        "the wamr shim doesn't support max-memory-bytes, use the {HEAP_SIZE_ANNOTATION} annotation"
+    );
+    let wasi = &engine_config.wasi;
+    for (name, enabled) in [("tcp", wasi.tcp), ("udp", wasi.udp), ("dns", wasi.dns)] {
+        ensure!(
+            enabled != Some(true),
+            "the wamr shim doesn't support {name} sockets"
+        );
+    }
+    Ok(())
+}
+
+fn build_runtime(config: &WamrConfig) -> Result<Runtime> {
+    let builder = Runtime::builder().use_system_allocator();
+
+    let (builder, running_mode) = match config.mode {
+        // AOT compiled modules are run by the AOT runtime in any mode
+        ExecutionMode::Auto | ExecutionMode::Interpreter | ExecutionMode::Aot => {
+            (builder.run_as_interpreter(), RunningMode_Mode_Interp)
+        }
+        #[cfg(feature = "llvm-jit")]
+        ExecutionMode::Jit => (builder.run_as_llvm_jit(3, 3), RunningMode_Mode_LLVM_JIT),
+        #[cfg(not(feature = "llvm-jit"))]
+        ExecutionMode::Jit => {
+            bail!("the jit mode requires the shim to be built with the `llvm-jit` feature")
+        }
+    };
+
+    if let Some(size) = config.memory_pool_size {
+        init_with_memory_pool(size, running_mode)?;
+    }
+
+    builder.build().context("Failed to create runtime")
+}
+
+/// Initializes WAMR to allocate all its memory from a pool of `size` bytes.
+///
+/// WAMR only keeps a pointer to the pool, and the WAMR SDK drops the buffer it's given,
+/// so the pool is allocated here and leaked. WAMR counts its initializations: the runtime
+/// built by the SDK afterwards reuses this one, and WAMR stays initialized, with its pool,
+/// for the lifetime of the process.
+fn init_with_memory_pool(size: u32, running_mode: RunningMode) -> Result<()> {
+    static MEMORY_POOL_SIZE: Mutex<Option<u32>> = Mutex::new(None);
+
+    let mut pool_size = MEMORY_POOL_SIZE.lock().unwrap();
+    if let Some(pool_size) = *pool_size {
+        ensure!(
+            pool_size == size,
+            "WAMR is already initialized with a memory pool of {pool_size} bytes"
+        );
+        return Ok(());
+    }
+
+    let pool: &'static mut [u8] = Box::leak(vec![0; size as usize].into_boxed_slice());
+
+    // SAFETY: zeroed arguments are WAMR's defaults
+    let mut args: RuntimeInitArgs = unsafe { std::mem::zeroed() };
+    args.mem_alloc_type = mem_alloc_type_t_Alloc_With_Pool;
+    args.mem_alloc_option.pool.heap_buf = pool.as_mut_ptr().cast();
+    args.mem_alloc_option.pool.heap_size = size;
+    args.running_mode = running_mode;
+    args.llvm_jit_opt_level = 3;
+    args.llvm_jit_size_level = 3;
+
+    // SAFETY: the pool is leaked, so it outlives WAMR
+    ensure!(
+        unsafe { wasm_runtime_full_init(&mut args) },
+        "Failed to initialize WAMR with a memory pool of {size} bytes"
+    );
+    *pool_size = Some(size);
+    Ok(())
+}
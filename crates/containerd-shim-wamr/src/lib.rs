@@ -1,4 +1,6 @@
 #[cfg(unix)]
+pub mod config;
+#[cfg(unix)]
 pub mod instance;
 
 #[cfg(unix)]
@@ -1,10 +1,43 @@
+use std::collections::HashMap;
+use std::process::Command;
 use std::time::Duration;
 
-use containerd_shim_wasm::testing::WasiTest;
+use anyhow::{Context, ensure};
+use containerd_shim_wasm::sandbox::config::EngineConfig;
+use containerd_shim_wasm::shim::Isolation;
 use containerd_shim_wasm::testing::modules::*;
+use containerd_shim_wasm::testing::{
+    WasiTest, assert_component_is_unsupported, assert_stops_on_sigterm,
+};
 use serial_test::serial;
 
 use crate::WamrShim as WasiEngine;
+use crate::config::{
+    ExecutionMode, HEAP_SIZE_ANNOTATION, MEMORY_POOL_ANNOTATION, MODE_ANNOTATION,
+    STACK_SIZE_ANNOTATION, WamrConfig, is_aot,
+};
+use crate::instance::{check_binary, check_engine_config};
+
+/// Compiles `module` ahead of time with `wamrc`, found in `PATH` or at `$WAMRC`.
+fn compile_aot(module: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let dir = tempfile::tempdir()?;
+    let input = dir.path().join("module.wasm");
+    let output = dir.path().join("module.aot");
+    std::fs::write(&input, module)?;
+
+    let wamrc = std::env::var_os("WAMRC").unwrap_or_else(|| "wamrc".into());
+    let status = Command::new(wamrc)
+        .arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .status()
+        .context("failed to run wamrc")?;
+    ensure!(status.success(), "wamrc failed with {status}");
+
+    let aot = std::fs::read(output)?;
+    ensure!(is_aot(&aot), "wamrc didn't output an AOT compiled module");
+    Ok(aot)
+}
 
 #[test]
 #[serial]
@@ -42,6 +75,117 @@ fn test_hello_world_oci() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+#[serial]
+fn test_hello_world_with_memory_settings() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation(MODE_ANNOTATION, "interpreter")
+        .with_annotation(HEAP_SIZE_ANNOTATION, "64Ki")
+        .with_annotation(STACK_SIZE_ANNOTATION, "128Ki")
+        .with_annotation(MEMORY_POOL_ANNOTATION, "16Mi")
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_aot_mode_rejects_wasm_modules() -> anyhow::Result<()> {
+    let config = WamrConfig {
+        mode: ExecutionMode::Aot,
+        ..Default::default()
+    };
+    let err = check_binary(&config, HELLO_WORLD.bytes).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the aot mode requires a module compiled with `wamrc`"
+    );
+
+    // the container is rejected when it's created
+    let result = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation(MODE_ANNOTATION, "aot")
+        .build();
+
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+#[cfg(not(feature = "llvm-jit"))]
+fn test_jit_mode_requires_llvm_jit() -> anyhow::Result<()> {
+    let annotations = HashMap::from([(MODE_ANNOTATION.to_string(), "jit".to_string())]);
+    let err = WamrConfig::from_annotations(&annotations).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the jit mode requires the shim to be built with the `llvm-jit` feature"
+    );
+
+    // the container is rejected when it's created
+    let result = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation(MODE_ANNOTATION, "jit")
+        .build();
+
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+#[ignore = "requires wamrc to compile the module ahead of time"]
+fn test_hello_world_aot() -> anyhow::Result<()> {
+    let aot = compile_aot(HELLO_WORLD.bytes)?;
+
+    let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(aot)?
+        .with_annotation(MODE_ANNOTATION, "aot")
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_engine_config_rejects_unsupported_settings() -> anyhow::Result<()> {
+    check_engine_config(&EngineConfig::default())?;
+    check_engine_config(&EngineConfig::from_toml("[wasi]\ntcp = false")?)?;
+
+    for (toml, error) in [
+        (
+            "[features]\nsimd = true",
+            "the wamr shim uses the wasm features it was built with, the engine config can't set them",
+        ),
+        (
+            "[limits]\nmax-memory-bytes = 1048576",
+            "the wamr shim doesn't support max-memory-bytes, use the runwasi.io/wamr.heap-size annotation",
+        ),
+        (
+            "[wasi]\ntcp = true",
+            "the wamr shim doesn't support tcp sockets",
+        ),
+    ] {
+        let err = check_engine_config(&EngineConfig::from_toml(toml)?).unwrap_err();
+        assert_eq!(err.to_string(), error);
+    }
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_unreachable() -> anyhow::Result<()> {
@@ -116,3 +260,15 @@ fn test_custom_entrypoint() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+#[serial]
+fn test_wasip2_component_is_unsupported() -> anyhow::Result<()> {
+    assert_component_is_unsupported::<WasiEngine>()
+}
+
+#[test]
+#[serial]
+fn test_sleep_stops_on_sigterm() -> anyhow::Result<()> {
+    assert_stops_on_sigterm::<WasiEngine>(Isolation::Container)
+}
@@ -0,0 +1,7 @@
+(module
+    ;; loops forever without calling the host
+    (func $main (export "_start")
+        (loop $forever
+            (br $forever))
+    )
+)
@@ -0,0 +1,5 @@
+(tag $error)
+
+(func $main (export "_start")
+    (throw $error)
+)
@@ -0,0 +1,29 @@
+(module
+    ;; sleeps for a second, so the limits of the container can be updated, then grows both
+    ;; its memories one page at a time until they can't grow, and exits with their total
+    ;; number of pages
+    (import "wasi_snapshot_preview1" "poll_oneoff" (func $poll_oneoff (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+    (memory $a 1)
+    (memory $b 1)
+    (export "memory" (memory $a))
+    (func $main (export "_start")
+        (local $grown i32)
+        ;; subscription at offset 0: userdata = 0, tag = clock (0),
+        ;; clock id = monotonic (1), timeout = 1s, precision = 0, flags = relative (0)
+        (i32.store $a (i32.const 16) (i32.const 1))
+        (i64.store $a (i32.const 24) (i64.const 1000000000))
+        ;; the event is written at offset 64, and the number of events at offset 128
+        (drop (call $poll_oneoff (i32.const 0) (i32.const 64) (i32.const 1) (i32.const 128)))
+
+        (loop $grow
+            (local.set $grown (i32.const 0))
+            (if (i32.ne (memory.grow $a (i32.const 1)) (i32.const -1))
+                (then (local.set $grown (i32.const 1))))
+            (if (i32.ne (memory.grow $b (i32.const 1)) (i32.const -1))
+                (then (local.set $grown (i32.const 1))))
+            (br_if $grow (local.get $grown)))
+
+        (call $proc_exit (i32.add (memory.size $a) (memory.size $b)))
+    )
+)
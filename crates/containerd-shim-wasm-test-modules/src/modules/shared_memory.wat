@@ -0,0 +1,5 @@
+(memory 1 1 shared)
+
+(func $main (export "_start")
+    (i32.atomic.store (i32.const 0) (i32.const 42))
+)
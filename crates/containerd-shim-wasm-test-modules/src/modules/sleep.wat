@@ -0,0 +1,16 @@
+(module
+    ;; Import the poll_oneoff WASI function to wait on a clock subscription.
+    ;; The function signature for poll_oneoff is:
+    ;; (in: *subscription, out: *event, nsubscriptions: i32, nevents: *i32) -> errno
+    (import "wasi_snapshot_preview1" "poll_oneoff" (func $poll_oneoff (param i32 i32 i32 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+    (func $main (export "_start")
+        ;; subscription at offset 0: userdata = 0, tag = clock (0),
+        ;; clock id = monotonic (1), timeout = 60s, precision = 0, flags = relative (0)
+        (i32.store (i32.const 16) (i32.const 1))
+        (i64.store (i32.const 24) (i64.const 60000000000))
+        ;; the event is written at offset 64, and the number of events at offset 128
+        (drop (call $poll_oneoff (i32.const 0) (i32.const 64) (i32.const 1) (i32.const 128)))
+    )
+)
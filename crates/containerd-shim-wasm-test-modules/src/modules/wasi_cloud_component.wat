@@ -0,0 +1,122 @@
+;; `set` sets the `counter` key of the `wasi:keyvalue` store to `1`, and
+;; `check` traps unless the key is set, and the `greeting` config value is `hello`
+(component
+  (import "wasi:config/store@0.2.0-draft" (instance $config
+    (type $error' (variant (case "upstream" string) (case "io" string)))
+    (export "error" (type $error (eq $error')))
+    (export "get" (func (param "key" string) (result (result (option string) (error $error)))))
+  ))
+  (import "wasi:keyvalue/store@0.2.0-draft" (instance $keyvalue
+    (type $error' (variant (case "no-such-store") (case "access-denied") (case "other" string)))
+    (export "error" (type $error (eq $error')))
+    (export "bucket" (type $bucket (sub resource)))
+    (export "open" (func (param "identifier" string) (result (result (own $bucket) (error $error)))))
+    (export "[method]bucket.get" (func (param "self" (borrow $bucket)) (param "key" string) (result (result (option (list u8)) (error $error)))))
+    (export "[method]bucket.set" (func (param "self" (borrow $bucket)) (param "key" string) (param "value" (list u8)) (result (result (error $error)))))
+  ))
+
+  (alias export $config "get" (func $config-get))
+  (alias export $keyvalue "bucket" (type $bucket))
+  (alias export $keyvalue "open" (func $open))
+  (alias export $keyvalue "[method]bucket.get" (func $bucket-get))
+  (alias export $keyvalue "[method]bucket.set" (func $bucket-set))
+
+  ;; the memory of the guest, with a bump allocator for the values returned by the host
+  (core module $allocator
+    (memory (export "memory") 1)
+    (global $next (mut i32) (i32.const 1024))
+    (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+      (local $ptr i32)
+      (local.set $ptr
+        (i32.and
+          (i32.add (global.get $next) (i32.sub (local.get 2) (i32.const 1)))
+          (i32.sub (i32.const 0) (local.get 2))))
+      (global.set $next (i32.add (local.get $ptr) (local.get 3)))
+      (local.get $ptr))
+  )
+  (core instance $memory (instantiate $allocator))
+  (alias core export $memory "memory" (core memory $mem))
+  (alias core export $memory "realloc" (core func $realloc))
+
+  (core func $lowered-config-get (canon lower (func $config-get) (memory $mem) (realloc $realloc)))
+  (core func $lowered-open (canon lower (func $open) (memory $mem) (realloc $realloc)))
+  (core func $lowered-bucket-get (canon lower (func $bucket-get) (memory $mem) (realloc $realloc)))
+  (core func $lowered-bucket-set (canon lower (func $bucket-set) (memory $mem) (realloc $realloc)))
+  (core func $lowered-bucket-drop (canon resource.drop $bucket))
+
+  (core module $guest
+    (import "memory" "memory" (memory 1))
+    (import "host" "config-get" (func $config-get (param i32 i32 i32)))
+    (import "host" "open" (func $open (param i32 i32 i32)))
+    (import "host" "bucket-get" (func $bucket-get (param i32 i32 i32 i32)))
+    (import "host" "bucket-set" (func $bucket-set (param i32 i32 i32 i32 i32 i32)))
+    (import "host" "bucket-drop" (func $bucket-drop (param i32)))
+
+    (data (i32.const 16) "greeting")
+    (data (i32.const 32) "hello")
+    (data (i32.const 48) "counter")
+    (data (i32.const 64) "1")
+
+    ;; traps unless the `len` bytes at `a` and `b` are equal
+    (func $expect-eq (param $a i32) (param $b i32) (param $len i32)
+      (block $done
+        (loop $next
+          (br_if $done (i32.eqz (local.get $len)))
+          (if (i32.ne (i32.load8_u (local.get $a)) (i32.load8_u (local.get $b)))
+            (then unreachable))
+          (local.set $a (i32.add (local.get $a) (i32.const 1)))
+          (local.set $b (i32.add (local.get $b) (i32.const 1)))
+          (local.set $len (i32.sub (local.get $len) (i32.const 1)))
+          (br $next)))
+    )
+
+    ;; traps unless the `result<option<string>, error>` at `ret` is `ok(some(value))`,
+    ;; where `value` is the `len` bytes at `expected`
+    (func $expect-some (param $ret i32) (param $expected i32) (param $len i32)
+      (if (i32.load8_u (local.get $ret)) (then unreachable))
+      (if (i32.eqz (i32.load8_u offset=4 (local.get $ret))) (then unreachable))
+      (if (i32.ne (i32.load offset=12 (local.get $ret)) (local.get $len)) (then unreachable))
+      (call $expect-eq (i32.load offset=8 (local.get $ret)) (local.get $expected) (local.get $len))
+    )
+
+    ;; opens the store, and returns the handle of the bucket
+    (func $open-bucket (result i32)
+      (call $open (i32.const 0) (i32.const 0) (i32.const 128))
+      (if (i32.load8_u (i32.const 128)) (then unreachable))
+      (i32.load (i32.const 132))
+    )
+
+    (func (export "set")
+      (local $bucket i32)
+      (local.set $bucket (call $open-bucket))
+      (call $bucket-set (local.get $bucket) (i32.const 48) (i32.const 7) (i32.const 64) (i32.const 1) (i32.const 144))
+      (if (i32.load8_u (i32.const 144)) (then unreachable))
+      (call $bucket-drop (local.get $bucket))
+    )
+
+    (func (export "check")
+      (local $bucket i32)
+      (call $config-get (i32.const 16) (i32.const 8) (i32.const 160))
+      (call $expect-some (i32.const 160) (i32.const 32) (i32.const 5))
+
+      (local.set $bucket (call $open-bucket))
+      (call $bucket-get (local.get $bucket) (i32.const 48) (i32.const 7) (i32.const 176))
+      (call $expect-some (i32.const 176) (i32.const 64) (i32.const 1))
+      (call $bucket-drop (local.get $bucket))
+    )
+  )
+  (core instance $host
+    (export "config-get" (func $lowered-config-get))
+    (export "open" (func $lowered-open))
+    (export "bucket-get" (func $lowered-bucket-get))
+    (export "bucket-set" (func $lowered-bucket-set))
+    (export "bucket-drop" (func $lowered-bucket-drop))
+  )
+  (core instance $main (instantiate $guest
+    (with "memory" (instance $memory))
+    (with "host" (instance $host))
+  ))
+
+  (func (export "set") (canon lift (core func $main "set")))
+  (func (export "check") (canon lift (core func $main "check")))
+)
@@ -0,0 +1,6 @@
+;; fails to instantiate unless wasi-nn is linked
+(import "wasi_ephemeral_nn" "load" (func $load (param i32 i32 i32 i32 i32) (result i32)))
+
+(memory (export "memory") 1)
+
+(func $main (export "_start"))
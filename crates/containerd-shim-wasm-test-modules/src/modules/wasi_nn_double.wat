@@ -0,0 +1,43 @@
+;; runs the `double` model preloaded by the shim on [1, 2, 3, 4],
+;; and exits with 0 if the output is [2, 4, 6, 8]
+(import "wasi_ephemeral_nn" "load_by_name" (func $load_by_name (param i32 i32 i32) (result i32)))
+(import "wasi_ephemeral_nn" "init_execution_context" (func $init_execution_context (param i32 i32) (result i32)))
+(import "wasi_ephemeral_nn" "set_input" (func $set_input (param i32 i32 i32) (result i32)))
+(import "wasi_ephemeral_nn" "compute" (func $compute (param i32) (result i32)))
+(import "wasi_ephemeral_nn" "get_output" (func $get_output (param i32 i32 i32 i32 i32) (result i32)))
+(import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+
+(memory (export "memory") 1)
+
+;; the name of the graph
+(data (i32.const 16) "double")
+
+;; the input tensor: dimensions [4], type f32, data [1, 2, 3, 4]
+(data (i32.const 64) "\80\00\00\00\01\00\00\00\01\00\00\00\00\01\00\00\10\00\00\00")
+(data (i32.const 128) "\04\00\00\00")
+(data (i32.const 256) "\00\00\80\3f\00\00\00\40\00\00\40\40\00\00\80\40")
+
+(func $check (param $errno i32)
+    (if (local.get $errno) (then (call $proc_exit (i32.const 1))))
+)
+
+(func $expect (param $offset i32) (param $value f32)
+    (if (f32.ne (f32.load (local.get $offset)) (local.get $value))
+        (then (call $proc_exit (i32.const 2))))
+)
+
+(func $main (export "_start")
+    ;; graph at 0, execution context at 4, output size at 8
+    (call $check (call $load_by_name (i32.const 16) (i32.const 6) (i32.const 0)))
+    (call $check (call $init_execution_context (i32.load (i32.const 0)) (i32.const 4)))
+    (call $check (call $set_input (i32.load (i32.const 4)) (i32.const 0) (i32.const 64)))
+    (call $check (call $compute (i32.load (i32.const 4))))
+    (call $check (call $get_output (i32.load (i32.const 4)) (i32.const 0) (i32.const 512) (i32.const 16) (i32.const 8)))
+
+    (if (i32.ne (i32.load (i32.const 8)) (i32.const 16))
+        (then (call $proc_exit (i32.const 2))))
+    (call $expect (i32.const 512) (f32.const 2))
+    (call $expect (i32.const 516) (f32.const 4))
+    (call $expect (i32.const 520) (f32.const 6))
+    (call $expect (i32.const 524) (f32.const 8))
+)
@@ -0,0 +1,17 @@
+(import "wasi" "thread-spawn" (func $thread_spawn (param i32) (result i32)))
+(import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+(import "env" "memory" (memory 1 1 shared))
+
+(func (export "wasi_thread_start") (param $tid i32) (param $arg i32)
+    (i32.atomic.store (i32.const 0) (i32.const 1))
+    (drop (memory.atomic.notify (i32.const 0) (i32.const 1)))
+)
+
+;; exits with 42 once the spawned thread ran, or with 1 if it can't be spawned
+(func $main (export "_start")
+    (if (i32.lt_s (call $thread_spawn (i32.const 0)) (i32.const 0))
+        (then (call $proc_exit (i32.const 1)))
+    )
+    (drop (memory.atomic.wait32 (i32.const 0) (i32.const 0) (i64.const -1)))
+    (call $proc_exit (i32.add (i32.const 41) (i32.atomic.load (i32.const 0))))
+)
@@ -0,0 +1,355 @@
+#![cfg(unix)]
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, SystemTime};
+
+use containerd_shimkit::sandbox::BlobCacheConfig;
+use containerd_shimkit::sandbox::error::{Error as ShimError, Result};
+use oci_spec::image::{Digest, DigestAlgorithm};
+use sha2::{Digest as _, Sha256};
+use tokio::io::{AsyncWrite, AsyncWriteExt as _};
+
+use crate::sandbox::context::LayerContent;
+
+/// Name of the directory, relative to the shim's root directory, where
+/// blobs are cached unless the `BlobCache` runtime option sets another one.
+pub(crate) const BLOB_CACHE_DIR: &str = ".blobs";
+
+/// Age after which a temporary file is considered left behind by a shim that died
+/// while writing it.
+const STALE_TMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A content addressable store for blobs, e.g., Wasm layers.
+///
+/// Blobs are stored as `<root>/sha256/<hex>` and are only ever written
+/// after their content has been verified against their digest.
+/// Blobs are read back as memory mapped [`LayerContent`].
+///
+/// The size of the cache is bounded: when a blob is added, the least recently used blobs
+/// are evicted until the cache fits in `max_bytes`. Evicting a blob doesn't affect the
+/// containers using it, as their mapping, and the file a [`LayerContent`] keeps open until
+/// it's mapped by the container, keep its content alive.
+#[derive(Debug, Clone)]
+pub(crate) struct BlobCache {
+    root: PathBuf,
+    max_bytes: u64,
+}
+
+impl BlobCache {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            max_bytes: BlobCacheConfig::default().max_bytes,
+        }
+    }
+
+    /// Creates the cache set by the `BlobCache` runtime option, under the `rootdir` of the
+    /// runtime unless the option sets a directory.
+    pub fn from_config(config: &BlobCacheConfig, rootdir: &Path) -> Self {
+        let root = match &config.dir {
+            Some(dir) => dir.clone(),
+            None => rootdir.join(BLOB_CACHE_DIR),
+        };
+        Self::new(root).with_max_bytes(config.max_bytes)
+    }
+
+    /// Sets the maximum size of the blobs in the cache.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    fn path(&self, digest: &Digest) -> Result<PathBuf> {
+        let DigestAlgorithm::Sha256 = digest.algorithm() else {
+            return Err(ShimError::InvalidArgument(format!(
+                "unsupported digest algorithm for {digest}"
+            )));
+        };
+        Ok(self.root.join("sha256").join(digest.digest()))
+    }
+
+    /// Returns the content of the blob if it is present in the cache.
+    pub async fn get(&self, digest: &Digest) -> Result<Option<LayerContent>> {
+        let path = self.path(digest)?;
+        match LayerContent::from_file(&path) {
+            Ok(content) => {
+                touch(&path);
+                Ok(Some(content))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Verifies the content against the digest and stores it in the cache.
+    pub async fn put(&self, digest: &Digest, data: &[u8]) -> Result<LayerContent> {
+        let mut writer = self.writer(digest).await?;
+        writer.write(data).await?;
+        writer.commit().await
+    }
+
+    /// Returns a writer to store the blob with `digest` in chunks.
+    /// The blob is only added to the cache once the writer is committed.
+    pub async fn writer(&self, digest: &Digest) -> Result<BlobWriter> {
+        let path = self.path(digest)?;
+        let dir = path.parent().expect("blob path always has a parent");
+        tokio::fs::create_dir_all(dir).await?;
+
+        // write to a temporary file first so that a partially written blob
+        // is never observed by a concurrent reader
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let tmp = dir.join(format!(
+            ".{}-{}-{n}.tmp",
+            digest.digest(),
+            std::process::id()
+        ));
+        let file = tokio::fs::File::create(&tmp).await?;
+
+        Ok(BlobWriter {
+            cache: self.clone(),
+            digest: digest.clone(),
+            path,
+            tmp,
+            file,
+            hasher: Sha256::new(),
+        })
+    }
+
+    /// Evicts the least recently used blobs, other than `keep`, until the cache fits in
+    /// `max_bytes`, and removes the temporary files left behind by dead writers.
+    fn evict(&self, keep: &Path) -> Result<()> {
+        let dir = self.root.join("sha256");
+        let now = SystemTime::now();
+        let mut blobs = vec![];
+        let mut total = 0;
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            // the blob may have been evicted concurrently, e.g., by another shim
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let modified = metadata.modified().unwrap_or(now);
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                if now.duration_since(modified).unwrap_or_default() > STALE_TMP_AGE {
+                    let _ = std::fs::remove_file(&path);
+                }
+                continue;
+            }
+            total += metadata.len();
+            if path != keep {
+                blobs.push((modified, metadata.len(), path));
+            }
+        }
+
+        blobs.sort_by_key(|(modified, _, _)| *modified);
+        for (_, len, path) in blobs {
+            if total <= self.max_bytes {
+                break;
+            }
+            log::debug!("evicting blob {path:?} from the cache");
+            match std::fs::remove_file(&path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(err.into());
+                }
+                _ => total -= len,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Records the use of the blob at `path`, so it's evicted last.
+fn touch(path: &Path) {
+    let touched = File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(err) = touched {
+        log::debug!("failed to record the use of blob {path:?}: {err}");
+    }
+}
+
+/// Writes a blob into a [`BlobCache`], see [`BlobCache::writer`].
+pub(crate) struct BlobWriter {
+    cache: BlobCache,
+    digest: Digest,
+    path: PathBuf,
+    tmp: PathBuf,
+    file: tokio::fs::File,
+    hasher: Sha256,
+}
+
+impl BlobWriter {
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        self.hasher.update(chunk);
+        self.file.write_all(chunk).await?;
+        Ok(())
+    }
+
+    /// Verifies the written content against the digest and adds it to the cache.
+    pub async fn commit(mut self) -> Result<LayerContent> {
+        self.file.flush().await?;
+        let actual = format!("{:x}", self.hasher.finalize_reset());
+        if actual != self.digest.digest() {
+            return Err(ShimError::FailedPrecondition(format!(
+                "digest mismatch: expected {}, got sha256:{actual}",
+                self.digest
+            )));
+        }
+        tokio::fs::rename(&self.tmp, &self.path).await?;
+        let content = LayerContent::from_file(&self.path)?;
+
+        let cache = self.cache.clone();
+        let path = self.path.clone();
+        let evicted = tokio::task::spawn_blocking(move || cache.evict(&path)).await;
+        if let Ok(Err(err)) = evicted {
+            log::warn!("failed to evict blobs from the cache: {err}");
+        }
+        Ok(content)
+    }
+}
+
+// lets blobs be streamed into the cache, e.g., from a registry
+impl AsyncWrite for BlobWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.file).poll_write(cx, buf))?;
+        this.hasher.update(&buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().file).poll_shutdown(cx)
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        // this is a no-op if the blob was committed
+        let _ = std::fs::remove_file(&self.tmp);
+    }
+}
+
+/// Checks that the sha256 of `data` matches `expected`.
+pub(crate) fn verify_digest(expected: &Digest, data: &[u8]) -> Result<()> {
+    let DigestAlgorithm::Sha256 = expected.algorithm() else {
+        return Err(ShimError::InvalidArgument(format!(
+            "unsupported digest algorithm for {expected}"
+        )));
+    };
+    let actual = sha256::digest(data);
+    if actual != expected.digest() {
+        return Err(ShimError::FailedPrecondition(format!(
+            "digest mismatch: expected {expected}, got sha256:{actual}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_put_and_get() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = BlobCache::new(dir.path());
+
+        let data = b"hello world";
+        let digest: Digest = format!("sha256:{}", sha256::digest(data.as_slice())).parse()?;
+
+        assert!(cache.get(&digest).await?.is_none());
+        cache.put(&digest, data).await?;
+
+        let content = cache.get(&digest).await?.unwrap();
+        assert_eq!(content, data);
+        assert!(content.path().is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_in_chunks() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = BlobCache::new(dir.path());
+
+        let digest: Digest = format!("sha256:{}", sha256::digest("hello world")).parse()?;
+
+        let mut writer = cache.writer(&digest).await?;
+        writer.write(b"hello").await?;
+        writer.write(b" world").await?;
+        let content = writer.commit().await?;
+
+        assert_eq!(content, b"hello world");
+        assert_eq!(cache.get(&digest).await?, Some(content));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used_blobs() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = BlobCache::new(dir.path()).with_max_bytes(16);
+
+        let mut digests = vec![];
+        for data in [b"aaaaaaa", b"bbbbbbb"] {
+            let digest: Digest = format!("sha256:{}", sha256::digest(data.as_slice())).parse()?;
+            cache.put(&digest, data).await?;
+            digests.push(digest);
+        }
+        let [a, b] = &digests[..] else { unreachable!() };
+
+        // `a` was added first, but used last
+        let now = SystemTime::now();
+        File::options()
+            .write(true)
+            .open(cache.path(a)?)?
+            .set_modified(now - Duration::from_secs(100))?;
+        File::options()
+            .write(true)
+            .open(cache.path(b)?)?
+            .set_modified(now - Duration::from_secs(50))?;
+        assert!(cache.get(a).await?.is_some());
+
+        let c: Digest = format!("sha256:{}", sha256::digest("ccccccc")).parse()?;
+        cache.put(&c, b"ccccccc").await?;
+
+        assert!(cache.get(a).await?.is_some());
+        assert!(cache.get(b).await?.is_none());
+        assert!(cache.get(&c).await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_rejects_digest_mismatch() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = BlobCache::new(dir.path());
+
+        let digest: Digest = format!("sha256:{}", sha256::digest("something else")).parse()?;
+
+        let err = cache.put(&digest, b"hello world").await.unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)));
+        assert!(cache.get(&digest).await?.is_none());
+
+        // no temporary files are left behind
+        let entries = std::fs::read_dir(dir.path().join("sha256"))?.count();
+        assert_eq!(entries, 0);
+
+        Ok(())
+    }
+}
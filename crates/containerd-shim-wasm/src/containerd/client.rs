@@ -3,7 +3,9 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::hash::{DefaultHasher, Hash, Hasher as _};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
 
 use containerd_client::services::v1::containers_client::ContainersClient;
 use containerd_client::services::v1::content_client::ContentClient;
@@ -18,15 +20,19 @@ use containerd_client::tonic::Streaming;
 use containerd_client::tonic::transport::Channel;
 use containerd_client::{tonic, with_namespace};
 use containerd_shimkit::sandbox::error::{Error as ShimError, Result};
+use containerd_shimkit::sandbox::{ImagePolicy, RetryPolicy};
 use futures::TryStreamExt;
-use oci_spec::image::{Arch, Digest, ImageManifest, MediaType, Platform};
+use oci_spec::image::{Arch, Descriptor, Digest, ImageIndex, ImageManifest, MediaType, Platform};
 use sha256::digest;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Code, Request};
 
 use super::lease::LeaseGuard;
-use crate::sandbox::context::WasmLayer;
+use crate::blobs::BlobCache;
+use crate::policy;
+use crate::sandbox::config::{EngineConfig, is_config_layer};
+use crate::sandbox::context::{LayerContent, WasmLayer};
 use crate::shim::Compiler;
 
 // Adds lease info to grpc header
@@ -43,6 +49,8 @@ macro_rules! with_lease {
 }
 
 static PRECOMPILE_PREFIX: &str = "runwasi.io/precompiled";
+static DOCKER_MANIFEST_LIST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
 // 16MB is the default maximum gRPC message size for gRPC in containerd:
 // https://github.com/containerd/containerd/blob/main/defaults/defaults.go
 // Conservatively set the max to 15MB to leave room for message overhead
@@ -50,8 +58,33 @@ static MAX_WRITE_CHUNK_SIZE_BYTES: i64 = 1024 * 1024 * 15;
 
 #[derive(Debug)]
 pub struct Client {
-    inner: Channel,
+    inner: RwLock<Channel>,
+    address: PathBuf,
     namespace: String,
+    blobs: Option<BlobCache>,
+    retry_policy: RetryPolicy,
+}
+
+/// The `wasm` platforms a shim can run, used to select a manifest from a
+/// multi-platform image index.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WasmPlatforms {
+    /// The supported `os` values, in order of preference, e.g., `wasip2` or `wasip1`.
+    pub os: &'static [&'static str],
+    /// The supported `os.features`.
+    pub features: &'static [&'static str],
+}
+
+impl WasmPlatforms {
+    fn supports(&self, os: &str, platform: &Platform) -> bool {
+        *platform.architecture() == Arch::Wasm
+            && platform.os().to_string() == os
+            && platform
+                .os_features()
+                .iter()
+                .flatten()
+                .all(|feature| self.features.contains(&feature.as_str()))
+    }
 }
 
 #[derive(Debug)]
@@ -81,11 +114,100 @@ impl Client {
             .map_err(|err| ShimError::Containerd(err.to_string()))?;
 
         Ok(Client {
-            inner,
+            inner: RwLock::new(inner),
+            address: address.as_ref().to_path_buf(),
             namespace: namespace.into(),
+            blobs: None,
+            retry_policy: RetryPolicy::default(),
         })
     }
 
+    // retry transient failures of the requests to containerd according to `retry_policy`
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    // stream layers into `blobs` instead of reading them in memory
+    pub fn with_blob_cache(mut self, blobs: BlobCache) -> Self {
+        self.blobs = Some(blobs);
+        self
+    }
+
+    fn channel(&self) -> Channel {
+        self.inner.read().unwrap().clone()
+    }
+
+    // replaces the channel, e.g., after containerd was restarted
+    async fn reconnect(&self) {
+        match containerd_client::connect(&self.address).await {
+            Ok(channel) => *self.inner.write().unwrap() = channel,
+            Err(err) => log::debug!("failed to reconnect to containerd: {err}"),
+        }
+    }
+
+    // runs `op` with a deadline, retrying it with an exponential backoff
+    // for as long as it fails with a transient (`Unavailable`) error
+    async fn retry<T, Fut>(&self, what: &str, op: impl FnMut() -> Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let timeout = self.request_timeout();
+        self.retry_with_deadline(what, Some(timeout), op).await
+    }
+
+    // the timeout of a request, or of each message of a streaming request
+    fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.retry_policy.request_timeout_ms)
+    }
+
+    // like `retry`, but without a deadline for the whole of `op` when `timeout` is `None`,
+    // e.g., for streaming requests that time out when they're idle instead
+    async fn retry_with_deadline<T, Fut>(
+        &self,
+        what: &str,
+        timeout: Option<Duration>,
+        mut op: impl FnMut() -> Fut,
+    ) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let max_backoff = Duration::from_millis(self.retry_policy.max_backoff_ms);
+        let mut backoff =
+            Duration::from_millis(self.retry_policy.initial_backoff_ms).min(max_backoff);
+        let mut attempt = 0;
+        loop {
+            let result = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, op())
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(ShimError::Unavailable(format!(
+                            "{what} timed out after {timeout:?}"
+                        )))
+                    }),
+                None => op().await,
+            };
+            match result {
+                Err(ShimError::Unavailable(err)) if attempt < self.retry_policy.max_retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "{what} failed: {err}, retrying in {backoff:?} ({attempt}/{})",
+                        self.retry_policy.max_retries
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(max_backoff);
+                    self.reconnect().await;
+                }
+                Err(ShimError::Unavailable(err)) => {
+                    return Err(ShimError::Unavailable(format!(
+                        "{what} failed after {attempt} retries: {err}"
+                    )));
+                }
+                result => return result,
+            }
+        }
+    }
+
     // wrapper around read that will read the entire content file
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     async fn read_content(&self, digest: impl ToString + std::fmt::Debug) -> Result<Vec<u8>> {
@@ -93,16 +215,68 @@ impl Client {
             digest: digest.to_string(),
             ..Default::default()
         };
-        let req = with_namespace!(req, self.namespace);
-        ContentClient::new(self.inner.clone())
-            .read(req)
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?
-            .into_inner()
-            .map_ok(|msg| msg.data)
-            .try_concat()
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))
+        self.retry("read content", || {
+            let req = with_namespace!(req.clone(), self.namespace);
+            let mut client = ContentClient::new(self.channel());
+            async move {
+                client
+                    .read(req)
+                    .await
+                    .map_err(status_error)?
+                    .into_inner()
+                    .map_ok(|msg| msg.data)
+                    .try_concat()
+                    .await
+                    .map_err(status_error)
+            }
+        })
+        .await
+    }
+
+    // reads a layer, streaming it into the blob cache when there is one,
+    // so that the layer is never fully loaded in memory
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    async fn read_layer(&self, digest: &Digest) -> Result<LayerContent> {
+        let Some(blobs) = &self.blobs else {
+            return Ok(self.read_content(digest).await?.into());
+        };
+
+        if let Some(content) = blobs.get(digest).await? {
+            log::debug!("using cached layer {digest}");
+            return Ok(content);
+        }
+
+        let req = ReadContentRequest {
+            digest: digest.to_string(),
+            ..Default::default()
+        };
+        // large layers can take longer than the request timeout on slow links, so the
+        // timeout only applies to each message of the stream, and a stalled read is retried.
+        // A failed attempt drops its writer, so every retry starts from an empty blob
+        let timeout = self.request_timeout();
+        let idle =
+            move || ShimError::Unavailable(format!("read layer stalled for more than {timeout:?}"));
+        self.retry_with_deadline("read layer", None, || {
+            let req = with_namespace!(req.clone(), self.namespace);
+            let mut client = ContentClient::new(self.channel());
+            async move {
+                let mut stream = tokio::time::timeout(timeout, client.read(req))
+                    .await
+                    .map_err(|_| idle())?
+                    .map_err(status_error)?
+                    .into_inner();
+                let mut writer = blobs.writer(digest).await?;
+                while let Some(msg) = tokio::time::timeout(timeout, stream.message())
+                    .await
+                    .map_err(|_| idle())?
+                    .map_err(status_error)?
+                {
+                    writer.write(&msg.data).await?;
+                }
+                writer.commit().await
+            }
+        })
+        .await
     }
 
     // used in tests to clean up content
@@ -112,11 +286,12 @@ impl Client {
         let req = DeleteContentRequest {
             digest: digest.to_string(),
         };
-        let req = with_namespace!(req, self.namespace);
-        ContentClient::new(self.inner.clone())
-            .delete(req)
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?;
+        self.retry("delete content", || {
+            let req = with_namespace!(req.clone(), self.namespace);
+            let mut client = ContentClient::new(self.channel());
+            async move { client.delete(req).await.map_err(status_error) }
+        })
+        .await?;
         Ok(())
     }
 
@@ -132,11 +307,15 @@ impl Client {
             labels: lease_labels,
         };
 
-        let mut leases_client = LeasesClient::new(self.inner.clone());
-        let lease = leases_client
-            .create(with_namespace!(lease_request, self.namespace))
-            .await
-            .map_err(|e| ShimError::Containerd(e.to_string()))?
+        // the client is created for each attempt, so a retry uses the channel
+        // re-established after a transient failure
+        let lease = self
+            .retry("create lease", || {
+                let req = with_namespace!(lease_request.clone(), self.namespace);
+                let mut client = LeasesClient::new(self.channel());
+                async move { client.create(req).await.map_err(status_error) }
+            })
+            .await?
             .into_inner()
             .lease
             .ok_or_else(|| {
@@ -144,132 +323,173 @@ impl Client {
             })?;
 
         Ok(LeaseGuard::new(
-            leases_client.clone(),
+            LeasesClient::new(self.channel()),
             lease.id,
             self.namespace.clone(),
         ))
     }
 
-    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(data), level = "Debug"))]
     async fn save_content(
         &self,
-        data: Vec<u8>,
+        data: &[u8],
         unique_id: &str,
         labels: HashMap<String, String>,
     ) -> Result<WriteContent> {
-        let expected = format!("sha256:{}", digest(data.clone()));
+        let expected = format!("sha256:{}", digest(data));
         let reference = format!("precompile-{}", unique_id);
         let lease = self.lease(reference.clone()).await?;
 
-        let digest = 'digest: {
-            // create a channel to feed the stream; only sending one message at a time so we can set this to one
-            let (tx, rx) = mpsc::channel(1);
-
-            let len = data.len() as i64;
-            log::debug!("Writing {} bytes to content store", len);
-            let mut client = ContentClient::new(self.inner.clone());
+        // large layers can take longer than the request timeout on slow links, so the
+        // timeout only applies to each message of the write, like for `read_layer`, and
+        // a retried write resumes from the offset containerd already has for `reference`
+        let digest = self
+            .retry_with_deadline("write content", None, || {
+                self.write_content(data, &reference, &expected, lease.id(), labels.clone())
+            })
+            .await?;
 
-            // Send write request with Stat action to containerd to let it know that we are going to write content
-            // if the content is already there, it will return early with AlreadyExists
-            let req = WriteContentRequest {
-                r#ref: reference.clone(),
-                action: WriteAction::Stat.into(),
-                expected: expected.clone(),
-                ..Default::default()
-            };
-            tx.send(req)
-                .await
-                .map_err(|err| ShimError::Containerd(err.to_string()))?;
-
-            // Create stream for the channel
-            let request_stream = ReceiverStream::new(rx);
-            let request_stream = with_lease!(request_stream, self.namespace, lease.id());
-            let mut response_stream = match client.write(request_stream).await {
-                Ok(response_stream) => response_stream.into_inner(),
-                Err(e) if e.code() == Code::AlreadyExists => {
-                    log::info!("content already exists {expected}");
-                    break 'digest expected;
-                }
-                Err(e) => return Err(ShimError::Containerd(e.to_string())),
-            };
+        Ok(WriteContent { lease, digest })
+    }
 
-            // Get initial Stat response
-            let response = response_stream
-                .message()
-                .await
-                .map_err(|e| ShimError::Containerd(e.to_string()))?
-                .ok_or_else(|| {
-                    ShimError::Containerd(format!(
-                        "no response received after write request for {}",
-                        expected
-                    ))
-                })?;
-            log::debug!(
-                "Starting to write content for layer {} with current status response {:?}",
-                expected,
-                response
-            );
+    // fails with a transient error if `fut`, e.g., a message of a streaming request,
+    // doesn't complete within the request timeout
+    async fn idle_timeout<T>(&self, what: &str, fut: impl Future<Output = Result<T>>) -> Result<T> {
+        let timeout = self.request_timeout();
+        tokio::time::timeout(timeout, fut)
+            .await
+            .unwrap_or_else(|_| {
+                Err(ShimError::Unavailable(format!(
+                    "{what} stalled for more than {timeout:?}"
+                )))
+            })
+    }
 
-            // Separate the content into chunks and send a write request for each chunk.
-            let mut offset = response.offset;
-            while offset < len {
-                let end = (offset + MAX_WRITE_CHUNK_SIZE_BYTES).min(len);
-                let chunk = &data[offset as usize..end as usize];
-
-                let write_request = WriteContentRequest {
-                    action: WriteAction::Write.into(),
-                    // Ignore size verification of each chunk
-                    total: 0,
-                    offset,
-                    data: chunk.to_vec(),
-                    ..Default::default()
-                };
-                let response =
-                    send_message(write_request, &mut response_stream, &tx, &expected).await?;
-                log::debug!(
-                    "Writing content for layer {} at offset {} got response: {:?}",
-                    expected,
-                    offset,
-                    response
-                );
-                offset = end;
+    // writes `data` to the content store in a single write transaction for `reference`,
+    // returning the digest of the content
+    async fn write_content(
+        &self,
+        data: &[u8],
+        reference: &str,
+        expected: &str,
+        lease_id: &str,
+        labels: HashMap<String, String>,
+    ) -> Result<String> {
+        // create a channel to feed the stream; only sending one message at a time so we can set this to one
+        let (tx, rx) = mpsc::channel(1);
+
+        let len = data.len() as i64;
+        log::debug!("Writing {} bytes to content store", len);
+        let mut client = ContentClient::new(self.channel());
+
+        // Send write request with Stat action to containerd to let it know that we are going to write content
+        // if the content is already there, it will return early with AlreadyExists
+        let req = WriteContentRequest {
+            r#ref: reference.to_string(),
+            action: WriteAction::Stat.into(),
+            expected: expected.to_string(),
+            ..Default::default()
+        };
+        tx.send(req)
+            .await
+            .map_err(|err| ShimError::Unavailable(err.to_string()))?;
+
+        // Create stream for the channel
+        let request_stream = ReceiverStream::new(rx);
+        let request_stream = with_lease!(request_stream, self.namespace, lease_id);
+        let write = async { Ok(client.write(request_stream).await) };
+        let mut response_stream = match self.idle_timeout("write content", write).await? {
+            Ok(response_stream) => response_stream.into_inner(),
+            Err(e) if e.code() == Code::AlreadyExists => {
+                log::info!("content already exists {expected}");
+                return Ok(expected.to_string());
             }
+            Err(e) => return Err(status_error(e)),
+        };
 
-            // Send a final empty commit request to end the transaction
-            let commit_request = WriteContentRequest {
-                action: WriteAction::Commit.into(),
-                total: len,
-                offset: len,
-                expected: expected.clone(),
-                labels,
-                data: Vec::new(),
+        // Get initial Stat response
+        let response = self
+            .idle_timeout("write content", async {
+                response_stream.message().await.map_err(status_error)
+            })
+            .await?
+            .ok_or_else(|| {
+                ShimError::Unavailable(format!(
+                    "no response received after write request for {}",
+                    expected
+                ))
+            })?;
+        log::debug!(
+            "Starting to write content for layer {} with current status response {:?}",
+            expected,
+            response
+        );
+
+        // Separate the content into chunks and send a write request for each chunk.
+        let mut offset = response.offset;
+        while offset < len {
+            let end = (offset + MAX_WRITE_CHUNK_SIZE_BYTES).min(len);
+            let chunk = &data[offset as usize..end as usize];
+
+            let write_request = WriteContentRequest {
+                action: WriteAction::Write.into(),
+                // Ignore size verification of each chunk
+                total: 0,
+                offset,
+                data: chunk.to_vec(),
                 ..Default::default()
             };
-            let response =
-                send_message(commit_request, &mut response_stream, &tx, &expected).await?;
-            log::info!(
-                "Validating final response after writing content for layer {}: {:?}",
+            let response = self
+                .idle_timeout(
+                    "write content",
+                    send_message(write_request, &mut response_stream, &tx, expected),
+                )
+                .await?;
+            log::debug!(
+                "Writing content for layer {} at offset {} got response: {:?}",
                 expected,
+                offset,
                 response
             );
+            offset = end;
+        }
 
-            // Client should validate that all bytes were written and that the digest matches
-            if response.offset != len {
-                return Err(ShimError::Containerd(format!(
-                    "failed to write all bytes, expected {} got {}",
-                    len, response.offset
-                )));
-            }
-            if response.digest != expected {
-                return Err(ShimError::Containerd(format!(
-                    "unexpected digest, expected {} got {}",
-                    expected, response.digest
-                )));
-            }
-            response.digest
+        // Send a final empty commit request to end the transaction
+        let commit_request = WriteContentRequest {
+            action: WriteAction::Commit.into(),
+            total: len,
+            offset: len,
+            expected: expected.to_string(),
+            labels,
+            data: Vec::new(),
+            ..Default::default()
         };
+        let response = self
+            .idle_timeout(
+                "write content",
+                send_message(commit_request, &mut response_stream, &tx, expected),
+            )
+            .await?;
+        log::info!(
+            "Validating final response after writing content for layer {}: {:?}",
+            expected,
+            response
+        );
 
-        Ok(WriteContent { lease, digest })
+        // Client should validate that all bytes were written and that the digest matches
+        if response.offset != len {
+            return Err(ShimError::Containerd(format!(
+                "failed to write all bytes, expected {} got {}",
+                len, response.offset
+            )));
+        }
+        if response.digest != expected {
+            return Err(ShimError::Containerd(format!(
+                "unexpected digest, expected {} got {}",
+                expected, response.digest
+            )));
+        }
+        Ok(response.digest)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
@@ -277,11 +497,13 @@ impl Client {
         let req = InfoRequest {
             digest: content_digest.to_string(),
         };
-        let req = with_namespace!(req, self.namespace);
-        let info = ContentClient::new(self.inner.clone())
-            .info(req)
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?
+        let info = self
+            .retry("get content info", || {
+                let req = with_namespace!(req.clone(), self.namespace);
+                let mut client = ContentClient::new(self.channel());
+                async move { client.info(req).await.map_err(status_error) }
+            })
+            .await?
             .into_inner()
             .info
             .ok_or_else(|| {
@@ -300,11 +522,13 @@ impl Client {
         // The type is `prost_types::FieldMask` and not re-exported, naming it would require depending on it.
         // Depending on it would mean keeping it's version in sync with the version in `containerd-client`.
         req.update_mask.as_mut().unwrap().paths = vec!["labels".to_string()];
-        let req = with_namespace!(req, self.namespace);
-        let info = ContentClient::new(self.inner.clone())
-            .update(req)
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?
+        let info = self
+            .retry("update content info", || {
+                let req = with_namespace!(req.clone(), self.namespace);
+                let mut client = ContentClient::new(self.channel());
+                async move { client.update(req).await.map_err(status_error) }
+            })
+            .await?
             .into_inner()
             .info
             .ok_or_else(|| {
@@ -317,11 +541,13 @@ impl Client {
     async fn get_image(&self, image_name: impl ToString + std::fmt::Debug) -> Result<Image> {
         let name = image_name.to_string();
         let req = GetImageRequest { name };
-        let req = with_namespace!(req, self.namespace);
-        let image = ImagesClient::new(self.inner.clone())
-            .get(req)
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?
+        let image = self
+            .retry("get image", || {
+                let req = with_namespace!(req.clone(), self.namespace);
+                let mut client = ImagesClient::new(self.channel());
+                async move { client.get(req).await.map_err(status_error) }
+            })
+            .await?
             .into_inner()
             .image
             .ok_or_else(|| {
@@ -354,11 +580,13 @@ impl Client {
         let container_name = container_name.as_ref();
         let id = container_name.to_string();
         let req = GetContainerRequest { id };
-        let req = with_namespace!(req, self.namespace);
-        let container = ContainersClient::new(self.inner.clone())
-            .get(req)
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?
+        let container = self
+            .retry("get container", || {
+                let req = with_namespace!(req.clone(), self.namespace);
+                let mut client = ContainersClient::new(self.channel());
+                async move { client.get(req).await.map_err(status_error) }
+            })
+            .await?
             .into_inner()
             .container
             .ok_or_else(|| {
@@ -369,16 +597,56 @@ impl Client {
         Ok(container)
     }
 
+    // Returns the manifest of the image, along with the digest of the image's target.
+    // If the target is an image index, the manifest of the preferred `wasm` platform is
+    // returned, or `None` if the index has no manifest for the supported platforms,
+    // e.g., because it's an image for native platforms only.
+    // The digest is always returned, so the image policy can be checked for any image.
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     async fn get_image_manifest_and_digest(
         &self,
         image_name: &str,
-    ) -> Result<(ImageManifest, Digest)> {
+        platforms: &WasmPlatforms,
+    ) -> Result<(Option<ImageManifest>, Digest)> {
         let image = self.get_image(image_name).await?;
-        let image_digest = self.extract_image_content_sha(&image)?.try_into()?;
-        let manifest =
-            ImageManifest::from_reader(self.read_content(&image_digest).await?.as_slice())?;
-        Ok((manifest, image_digest))
+        let image_digest: Digest = self.extract_image_content_sha(&image)?.try_into()?;
+        let content = self.read_content(&image_digest).await?;
+
+        let media_type = image
+            .target
+            .as_ref()
+            .map(|target| target.media_type.as_str());
+        if !media_type.is_some_and(is_image_index) {
+            let manifest = ImageManifest::from_reader(content.as_slice())?;
+            return Ok((Some(manifest), image_digest));
+        }
+
+        let index = ImageIndex::from_reader(content.as_slice())?;
+        for descriptor in select_manifests(&index, platforms) {
+            // an image is usually only pulled for a single platform,
+            // so the content of the other manifests might be missing
+            match self.read_content(descriptor.digest()).await {
+                Ok(content) => {
+                    log::info!(
+                        "using manifest {} for platform {:?} of image index {image_digest}",
+                        descriptor.digest(),
+                        descriptor.platform()
+                    );
+                    let manifest = ImageManifest::from_reader(content.as_slice())?;
+                    return Ok((Some(manifest), image_digest));
+                }
+                Err(ShimError::NotFound(_)) => {
+                    log::debug!(
+                        "manifest {} is not in the content store",
+                        descriptor.digest()
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        log::info!("image index {image_digest} has no manifest for a supported wasm platform");
+        Ok((None, image_digest))
     }
 
     // load module will query the containerd store to find an image that has an OS of type 'wasm'
@@ -393,10 +661,30 @@ impl Client {
         containerd_id: impl AsRef<str> + Debug,
         engine_name: impl AsRef<str> + Debug,
         supported_layer_types: &[&str],
+        platforms: &WasmPlatforms,
+        policy: &ImagePolicy,
         compiler: Option<&impl Compiler>,
     ) -> Result<Vec<WasmLayer>> {
         let container = self.get_container(containerd_id).await?;
-        let (manifest, image_digest) = self.get_image_manifest_and_digest(&container.image).await?;
+        let (manifest, image_digest) = self
+            .get_image_manifest_and_digest(&container.image, platforms)
+            .await?;
+
+        // The policy applies to every image, including images without wasm layers that
+        // run from the files of their rootfs, so it's enforced before looking for them.
+        self.enforce_image_policy(&container.image, &image_digest, policy)
+            .await
+            .map_err(|err| match err {
+                err @ (ShimError::FailedPrecondition(_) | ShimError::Unavailable(_)) => err,
+                err => ShimError::FailedPrecondition(format!(
+                    "failed to verify image {}: {err}",
+                    container.image
+                )),
+            })?;
+
+        let Some(manifest) = manifest else {
+            return Ok(vec![]);
+        };
 
         let image_config_descriptor = manifest.config();
         let image_config = self.read_content(image_config_descriptor.digest()).await?;
@@ -418,14 +706,85 @@ impl Client {
             .iter()
             .filter(|x| is_wasm_layer(x.media_type(), supported_layer_types))
             .collect::<Vec<_>>();
+        let engine_configs = manifest
+            .layers()
+            .iter()
+            .filter(|x| is_config_layer(&x.media_type().to_string()))
+            .collect::<Vec<_>>();
 
         if configs.is_empty() {
             log::info!("no WASM layers found in OCI image");
             return Ok(vec![]);
         }
 
+        // The image is made of Wasm layers, there are no files inside the container image
+        // to fall back to. Any error from here on must fail the task instead of
+        // running it with an empty list of modules.
+        self.load_wasm_layers(
+            &container.image,
+            &image_digest,
+            configs,
+            engine_configs,
+            engine_name.as_ref(),
+            compiler,
+        )
+        .await
+        .map_err(|err| match err {
+            err @ (ShimError::FailedPrecondition(_) | ShimError::Unavailable(_)) => err,
+            err => ShimError::FailedPrecondition(format!(
+                "failed to load wasm layers of image {}: {err}",
+                container.image
+            )),
+        })
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(configs, engine_configs, compiler), level = "Debug")
+    )]
+    async fn load_wasm_layers(
+        &self,
+        image_name: &str,
+        image_digest: &Digest,
+        configs: Vec<&oci_spec::image::Descriptor>,
+        engine_configs: Vec<&oci_spec::image::Descriptor>,
+        engine_name: &str,
+        compiler: Option<&impl Compiler>,
+    ) -> Result<Vec<WasmLayer>> {
         log::info!("using OCI layers");
 
+        // The engine config is surfaced after the Wasm layers. It can change the settings
+        // the modules are compiled with, so it's part of the key of the precompiled layers.
+        let mut engine_config_layers = vec![];
+        for config in engine_configs {
+            engine_config_layers.push(self.read_original_layer(config).await?);
+        }
+        let (engine_config, _) = EngineConfig::from_layers(engine_config_layers.clone())
+            .map_err(|err| ShimError::InvalidArgument(format!("{err:#}")))?;
+
+        let mut layers = self
+            .load_code_layers(
+                image_name,
+                image_digest,
+                configs,
+                engine_name,
+                &engine_config,
+                compiler,
+            )
+            .await?;
+        layers.extend(engine_config_layers);
+        Ok(layers)
+    }
+
+    async fn load_code_layers(
+        &self,
+        image_name: &str,
+        image_digest: &Digest,
+        configs: Vec<&oci_spec::image::Descriptor>,
+        engine_name: &str,
+        engine_config: &EngineConfig,
+        compiler: Option<&impl Compiler>,
+    ) -> Result<Vec<WasmLayer>> {
         let Some(compiler) = compiler else {
             let mut layers = vec![];
             for config in configs {
@@ -435,9 +794,14 @@ impl Client {
             return Ok(layers);
         };
 
-        let precompile_id = precompile_label(engine_name.as_ref(), compiler.cache_key());
+        // images without a config layer keep the key they were precompiled with before
+        let precompile_id = if *engine_config == EngineConfig::default() {
+            precompile_label(engine_name, compiler.cache_key())
+        } else {
+            precompile_label(engine_name, (compiler.cache_key(), engine_config))
+        };
 
-        let image_info = self.get_info(&image_digest).await?;
+        let image_info = self.get_info(image_digest).await?;
         let mut needs_precompile = !image_info.labels.contains_key(&precompile_id);
 
         let mut layers = vec![];
@@ -458,8 +822,8 @@ impl Client {
         }
 
         if needs_precompile {
-            log::info!("precompiling layers for image: {}", container.image);
-            let compiled_layers = match compiler.compile(&layers).await {
+            log::info!("precompiling layers for image: {image_name}");
+            let compiled_layers = match compiler.compile_with_config(&layers, engine_config).await {
                 Ok(compiled_layers) => {
                     if compiled_layers.len() != layers.len() {
                         return Err(ShimError::FailedPrecondition(
@@ -475,21 +839,21 @@ impl Client {
             };
 
             let mut layers_for_runtime = Vec::with_capacity(compiled_layers.len());
-            for (i, compiled_layer) in compiled_layers.iter().enumerate() {
-                if compiled_layer.is_none() {
+            for (i, (compiled_layer, layer)) in compiled_layers.into_iter().zip(layers).enumerate()
+            {
+                let Some(compiled_layer) = compiled_layer else {
                     log::debug!("no compiled layer using original");
-                    layers_for_runtime.push(layers[i].clone());
+                    layers_for_runtime.push(layer);
                     continue;
-                }
+                };
 
-                let compiled_layer = compiled_layer.as_ref().unwrap();
-                let original_config = &layers[i].config;
+                let original_config = &layer.config;
                 let labels = HashMap::from([(
                     format!("{precompile_id}/original"),
                     original_config.digest().to_string(),
                 )]);
                 let precompiled_content = self
-                    .save_content(compiled_layer.clone(), &precompile_id, labels)
+                    .save_content(&compiled_layer, &precompile_id, labels)
                     .await?;
 
                 log::debug!(
@@ -517,7 +881,7 @@ impl Client {
                 log::debug!(
                     "updating image content with precompile digest to avoid garbage collection"
                 );
-                let mut image_content = self.get_info(&image_digest).await?;
+                let mut image_content = self.get_info(image_digest).await?;
                 image_content.labels.insert(
                     format!("containerd.io/gc.ref.content.precompile.{}", i),
                     precompiled_content.digest,
@@ -528,8 +892,8 @@ impl Client {
                 self.update_info(image_content).await?;
 
                 layers_for_runtime.push(WasmLayer {
-                    config: original_config.clone(),
-                    layer: compiled_layer.clone(),
+                    config: layer.config,
+                    layer: compiled_layer.into(),
                 });
 
                 let _ = precompiled_content.lease.release().await;
@@ -541,6 +905,52 @@ impl Client {
         Ok(layers)
     }
 
+    // checks the image against the policy from the runtime options, rejecting
+    // the task with a `FailedPrecondition` error if it's not satisfied
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    async fn enforce_image_policy(
+        &self,
+        image_name: &str,
+        image_digest: &Digest,
+        policy: &ImagePolicy,
+    ) -> Result<()> {
+        if policy.require_digest {
+            policy::check_pinned(image_name, image_digest)?;
+        }
+
+        if policy.require_signature {
+            let keys = policy::trusted_keys(&policy.trusted_keys)?;
+            let signature_image = policy::signature_reference(image_name, image_digest);
+            let manifest = self
+                .get_image_manifest_and_digest(&signature_image, &WasmPlatforms::default())
+                .await
+                .and_then(|(manifest, _)| {
+                    manifest.ok_or_else(|| {
+                        ShimError::NotFound("signature is an image index".to_string())
+                    })
+                })
+                .map_err(|err| {
+                    ShimError::FailedPrecondition(format!(
+                        "failed to find signature {signature_image} for image {image_name}: {err}"
+                    ))
+                })?;
+            policy::check_subject(&signature_image, &manifest, image_digest)?;
+
+            let mut layers = vec![];
+            for descriptor in manifest.layers() {
+                if descriptor.media_type().to_string() != policy::SIMPLE_SIGNING_MEDIA_TYPE {
+                    continue;
+                }
+                let payload = self.read_content(descriptor.digest()).await?;
+                layers.push((descriptor.clone(), payload));
+            }
+            policy::verify_signature(&keys, image_digest, &layers)?;
+            log::info!("verified signature of image {image_name}");
+        }
+
+        Ok(())
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     async fn read_precompiled_layer(
         &self,
@@ -560,7 +970,7 @@ impl Client {
             info.digest,
             &digest
         );
-        self.read_content(digest).await.map(|module| WasmLayer {
+        self.read_layer(&digest).await.map(|module| WasmLayer {
             config: config.clone(),
             layer: module,
         })
@@ -573,13 +983,45 @@ impl Client {
     ) -> Result<WasmLayer, ShimError> {
         let digest = config.digest();
         log::debug!("loading digest: {} ", digest);
-        self.read_content(digest).await.map(|module| WasmLayer {
+        self.read_layer(digest).await.map(|module| WasmLayer {
             config: config.clone(),
             layer: module,
         })
     }
 }
 
+// maps the status of a failed request to an error, reporting transient
+// failures as `Unavailable` so that they are retried. `ResourceExhausted`, e.g.,
+// a full disk or a message that is too large, fails the same way again, so it's not retried
+fn status_error(status: tonic::Status) -> ShimError {
+    match status.code() {
+        Code::Unavailable | Code::DeadlineExceeded | Code::Aborted => {
+            ShimError::Unavailable(status.to_string())
+        }
+        Code::NotFound => ShimError::NotFound(status.message().to_string()),
+        _ => ShimError::Containerd(status.to_string()),
+    }
+}
+
+fn is_image_index(media_type: &str) -> bool {
+    media_type == MediaType::ImageIndex.to_string() || media_type == DOCKER_MANIFEST_LIST_MEDIA_TYPE
+}
+
+// returns the manifests of the index for the supported `wasm` platforms, in order of preference
+fn select_manifests<'a>(
+    index: &'a ImageIndex,
+    platforms: &'a WasmPlatforms,
+) -> impl Iterator<Item = &'a Descriptor> {
+    platforms.os.iter().flat_map(move |os| {
+        index.manifests().iter().filter(move |descriptor| {
+            descriptor
+                .platform()
+                .as_ref()
+                .is_some_and(|platform| platforms.supports(os, platform))
+        })
+    })
+}
+
 fn precompile_label(name: &str, version: impl Hash) -> String {
     let version = {
         let mut hasher = DefaultHasher::new();
@@ -605,15 +1047,17 @@ async fn send_message(
     tx: &mpsc::Sender<WriteContentRequest>,
     digest: &str,
 ) -> Result<WriteContentResponse> {
+    // the request stream is dropped when the connection fails, so both ends are
+    // reported as transient errors, and the write is retried
     tx.send(request)
         .await
-        .map_err(|err| ShimError::Containerd(format!("commit request error: {}", err)))?;
+        .map_err(|err| ShimError::Unavailable(format!("commit request error: {}", err)))?;
     response_stream
         .message()
         .await
-        .map_err(|err| ShimError::Containerd(format!("response stream error: {}", err)))?
+        .map_err(status_error)?
         .ok_or_else(|| {
-            ShimError::Containerd(format!(
+            ShimError::Unavailable(format!(
                 "no response received after write content request for {}",
                 digest
             ))
@@ -624,16 +1068,241 @@ async fn send_message(
 mod tests {
     use std::hash::Hash;
     use std::path::PathBuf;
-    use std::sync::Arc;
     use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::{Arc, Mutex};
 
+    use oci_spec::image::{DescriptorBuilder, ImageIndexBuilder, Os, PlatformBuilder};
     use oci_tar_builder::WASM_LAYER_MEDIA_TYPE;
 
     use super::*;
+    use crate::sandbox::config::CONFIG_LAYER_MEDIA_TYPE;
     use crate::shim::NO_COMPILER;
     use crate::testing::oci_helpers::ImageContent;
     use crate::testing::{TEST_NAMESPACE, oci_helpers};
 
+    const OCI_LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+
+    // a client that never reaches containerd, to test the retry policy
+    fn disconnected_client(retry_policy: RetryPolicy) -> Client {
+        let channel = tonic::transport::Endpoint::from_static("http://[::]:50051").connect_lazy();
+        Client {
+            inner: RwLock::new(channel),
+            address: PathBuf::from("/nonexistent/containerd.sock"),
+            namespace: TEST_NAMESPACE.to_string(),
+            blobs: None,
+            retry_policy,
+        }
+    }
+
+    fn fast_retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 1,
+            request_timeout_ms: 100,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_recovers_from_transient_errors() {
+        let client = disconnected_client(fast_retry_policy());
+        let attempts = &AtomicI32::new(0);
+
+        let result = client
+            .retry("test", || async move {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(ShimError::Unavailable(
+                        "containerd is restarting".to_string(),
+                    )),
+                    n => Ok(n),
+                }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(result, 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up_after_max_retries() {
+        let client = disconnected_client(fast_retry_policy());
+        let attempts = &AtomicI32::new(0);
+
+        let err = client
+            .retry("test", || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<(), _>(ShimError::Unavailable("containerd is down".to_string()))
+            })
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ShimError::Unavailable(_)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_does_not_retry_permanent_errors() {
+        let client = disconnected_client(fast_retry_policy());
+        let attempts = &AtomicI32::new(0);
+
+        let err = client
+            .retry("test", || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<(), _>(status_error(tonic::Status::not_found("no such content")))
+            })
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ShimError::NotFound(_)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        let err = client
+            .retry("test", || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<(), _>(status_error(tonic::Status::resource_exhausted("disk full")))
+            })
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ShimError::Containerd(_)), "{err}");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_times_out_requests() {
+        let client = disconnected_client(fast_retry_policy());
+
+        let err = client
+            .retry("test", std::future::pending::<Result<()>>)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ShimError::Unavailable(_)));
+    }
+
+    fn index_manifest(seed: &str, arch: Arch, os: Os, os_features: &[&str]) -> Descriptor {
+        let platform = PlatformBuilder::default()
+            .architecture(arch)
+            .os(os)
+            .os_features(
+                os_features
+                    .iter()
+                    .map(|f| f.to_string())
+                    .collect::<Vec<_>>(),
+            )
+            .build()
+            .unwrap();
+        DescriptorBuilder::default()
+            .media_type(MediaType::ImageManifest)
+            .digest(Digest::try_from(format!("sha256:{}", digest(seed))).unwrap())
+            .size(0u64)
+            .platform(platform)
+            .build()
+            .unwrap()
+    }
+
+    fn image_index(manifests: Vec<Descriptor>) -> ImageIndex {
+        ImageIndexBuilder::default()
+            .schema_version(2u32)
+            .media_type(MediaType::ImageIndex)
+            .manifests(manifests)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_select_manifests_by_os_preference() {
+        let native = index_manifest("native", Arch::Amd64, Os::Linux, &[]);
+        let wasip1 = index_manifest("wasip1", Arch::Wasm, Os::Other("wasip1".into()), &[]);
+        let wasip2 = index_manifest("wasip2", Arch::Wasm, Os::Other("wasip2".into()), &[]);
+        let index = image_index(vec![native, wasip1.clone(), wasip2.clone()]);
+
+        let platforms = WasmPlatforms {
+            os: &["wasip2", "wasip1"],
+            features: &[],
+        };
+        let selected = select_manifests(&index, &platforms).collect::<Vec<_>>();
+        assert_eq!(selected, vec![&wasip2, &wasip1]);
+
+        let platforms = WasmPlatforms {
+            os: &["wasip1"],
+            features: &[],
+        };
+        let selected = select_manifests(&index, &platforms).collect::<Vec<_>>();
+        assert_eq!(selected, vec![&wasip1]);
+    }
+
+    #[test]
+    fn test_select_manifests_skips_native_platforms() {
+        let index = image_index(vec![
+            index_manifest("amd64", Arch::Amd64, Os::Linux, &[]),
+            index_manifest("arm64", Arch::ARM64, Os::Linux, &[]),
+        ]);
+
+        let platforms = WasmPlatforms {
+            os: &["wasip2", "wasip1"],
+            features: &[],
+        };
+        assert_eq!(select_manifests(&index, &platforms).count(), 0);
+    }
+
+    #[test]
+    fn test_select_manifests_requires_supported_features() {
+        let threads = index_manifest(
+            "threads",
+            Arch::Wasm,
+            Os::Other("wasip1".into()),
+            &["threads"],
+        );
+        let plain = index_manifest("plain", Arch::Wasm, Os::Other("wasip1".into()), &[]);
+        let index = image_index(vec![threads.clone(), plain.clone()]);
+
+        let platforms = WasmPlatforms {
+            os: &["wasip1"],
+            features: &[],
+        };
+        let selected = select_manifests(&index, &platforms).collect::<Vec<_>>();
+        assert_eq!(selected, vec![&plain]);
+
+        let platforms = WasmPlatforms {
+            os: &["wasip1"],
+            features: &["threads"],
+        };
+        let selected = select_manifests(&index, &platforms).collect::<Vec<_>>();
+        assert_eq!(selected, vec![&threads, &plain]);
+    }
+
+    #[test]
+    fn test_is_image_index() {
+        assert!(is_image_index("application/vnd.oci.image.index.v1+json"));
+        assert!(is_image_index(
+            "application/vnd.docker.distribution.manifest.list.v2+json"
+        ));
+        assert!(!is_image_index(
+            "application/vnd.oci.image.manifest.v1+json"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_load_modules_fails_when_containerd_is_unavailable() {
+        let client = disconnected_client(fast_retry_policy());
+
+        let err = client
+            .load_modules(
+                "container",
+                "test",
+                &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
+                NO_COMPILER.as_ref(),
+            )
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ShimError::Unavailable(_)));
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_save_content() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
@@ -646,7 +1315,7 @@ mod tests {
 
         let label = HashMap::from([(precompile_label("test", "hasdfh"), "original".to_string())]);
         let returned = client
-            .save_content(data, "test", label.clone())
+            .save_content(&data, "test", label.clone())
             .await
             .unwrap();
         assert_eq!(expected, returned.digest.clone());
@@ -655,7 +1324,7 @@ mod tests {
         assert_eq!(data, b"hello world");
 
         client
-            .save_content(data.clone(), "test", label.clone())
+            .save_content(&data, "test", label.clone())
             .await
             .expect_err("Should not be able to save when lease is open");
 
@@ -665,7 +1334,7 @@ mod tests {
 
         // a second call should be successful since it already exists
         let returned = client
-            .save_content(data, "test", label.clone())
+            .save_content(&data, "test", label.clone())
             .await
             .unwrap();
         assert_eq!(expected, returned.digest);
@@ -694,6 +1363,8 @@ mod tests {
                 container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 NO_COMPILER.as_ref(),
             )
             .await
@@ -702,6 +1373,176 @@ mod tests {
         assert_eq!(layers[0].layer, fake_bytes.bytes);
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_image_policy_rejects_unpinned_image() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, TEST_NAMESPACE).await.unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (_, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let policy = ImagePolicy {
+            require_digest: true,
+            ..Default::default()
+        };
+        let err = client
+            .load_modules(
+                container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &policy,
+                NO_COMPILER.as_ref(),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)), "{err}");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_image_policy_rejects_unsigned_image() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, TEST_NAMESPACE).await.unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (_, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("cosign.pub");
+        let key = *crate::policy::tests::signing_key(1).verifying_key();
+        std::fs::write(
+            &key_path,
+            p256::pkcs8::EncodePublicKey::to_public_key_pem(&key, Default::default()).unwrap(),
+        )
+        .unwrap();
+
+        let policy = ImagePolicy {
+            require_signature: true,
+            trusted_keys: vec![key_path],
+            ..Default::default()
+        };
+        let err = client
+            .load_modules(
+                container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &policy,
+                NO_COMPILER.as_ref(),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)), "{err}");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_image_policy_rejects_unpinned_image_without_wasm_layers() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, TEST_NAMESPACE).await.unwrap();
+
+        let fake_bytes = generate_content("original", OCI_LAYER_MEDIA_TYPE);
+        let (_, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        // the image runs from its rootfs, so it must be verified too
+        let layers = client
+            .load_modules(
+                container_name.clone(),
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
+                NO_COMPILER.as_ref(),
+            )
+            .await
+            .unwrap();
+        assert!(layers.is_empty());
+
+        let policy = ImagePolicy {
+            require_digest: true,
+            ..Default::default()
+        };
+        let err = client
+            .load_modules(
+                container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &policy,
+                NO_COMPILER.as_ref(),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)), "{err}");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_image_policy_rejects_unsigned_image_without_wasm_layers() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, TEST_NAMESPACE).await.unwrap();
+
+        let fake_bytes = generate_content("original", OCI_LAYER_MEDIA_TYPE);
+        let (_, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("cosign.pub");
+        let key = *crate::policy::tests::signing_key(1).verifying_key();
+        std::fs::write(
+            &key_path,
+            p256::pkcs8::EncodePublicKey::to_public_key_pem(&key, Default::default()).unwrap(),
+        )
+        .unwrap();
+
+        let policy = ImagePolicy {
+            require_signature: true,
+            trusted_keys: vec![key_path],
+            ..Default::default()
+        };
+        let err = client
+            .load_modules(
+                container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &policy,
+                NO_COMPILER.as_ref(),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)), "{err}");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_image_policy_rejects_missing_trusted_keys() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, TEST_NAMESPACE).await.unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (_, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let policy = ImagePolicy {
+            require_signature: true,
+            trusted_keys: vec![PathBuf::from("/nonexistent/cosign.pub")],
+            ..Default::default()
+        };
+        let err = client
+            .load_modules(
+                container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &policy,
+                NO_COMPILER.as_ref(),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)), "{err}");
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_layers_are_precompiled_once() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
@@ -722,6 +1563,8 @@ mod tests {
                 &container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 Some(&engine),
             )
             .await
@@ -734,6 +1577,8 @@ mod tests {
                 &container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 Some(&engine),
             )
             .await
@@ -763,6 +1608,8 @@ mod tests {
                 &container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 Some(&engine),
             )
             .await
@@ -775,6 +1622,8 @@ mod tests {
                 &container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 Some(&engine),
             )
             .await
@@ -803,6 +1652,8 @@ mod tests {
                 container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 Some(&engine),
             )
             .await
@@ -812,8 +1663,9 @@ mod tests {
         assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
 
         let (manifest, _) = client
-            .get_image_manifest_and_digest(&image_name)
+            .get_image_manifest_and_digest(&image_name, &WasmPlatforms::default())
             .await
+            .unwrap()
             .unwrap();
         let original_config = manifest.layers().first().unwrap();
         let info = client.get_info(original_config.digest()).await.unwrap();
@@ -847,6 +1699,8 @@ mod tests {
                 container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE, "textfile"],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 Some(&engine),
             )
             .await
@@ -859,6 +1713,62 @@ mod tests {
         assert_eq!(layers[1].layer, non_wasm_bytes.bytes);
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_layers_are_precompiled_with_the_engine_config() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
+            .await
+            .unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let config_bytes = generate_content("[features]\nthreads = true", CONFIG_LAYER_MEDIA_TYPE);
+        let (image_name, container_name, _cleanup) =
+            generate_test_container(None, &[&config_bytes, &fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomipler::new();
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+        let engine_config = EngineConfig::from_toml("[features]\nthreads = true").unwrap();
+        let expected_id = precompile_label("fake", (engine.cache_key(), &engine_config));
+
+        let layers = client
+            .load_modules(
+                container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
+                Some(&engine),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            *engine.engine_configs.lock().unwrap(),
+            [engine_config.clone()]
+        );
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
+        assert_eq!(layers[1].layer, config_bytes.bytes);
+
+        // the layers precompiled with the engine config aren't used with the default config
+        let (manifest, _) = client
+            .get_image_manifest_and_digest(&image_name, &WasmPlatforms::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let original_config = &manifest.layers()[1];
+        let info = client.get_info(original_config.digest()).await.unwrap();
+        assert!(info.labels.contains_key(&expected_id));
+        assert!(
+            !info
+                .labels
+                .contains_key(&precompile_label("fake", engine.cache_key()))
+        );
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_layers_do_not_need_precompiled_if_new_layers_are_added_to_existing_image() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
@@ -879,6 +1789,8 @@ mod tests {
                 container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 Some(&engine),
             )
             .await
@@ -913,6 +1825,8 @@ mod tests {
                 container_name2,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 Some(&engine),
             )
             .await
@@ -942,6 +1856,8 @@ mod tests {
                 container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 Some(&engine),
             )
             .await
@@ -961,6 +1877,8 @@ mod tests {
                 container_name2,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 Some(&engine),
             )
             .await
@@ -998,6 +1916,8 @@ mod tests {
                 container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
+                &WasmPlatforms::default(),
+                &ImagePolicy::default(),
                 Some(&engine),
             )
             .await
@@ -1010,8 +1930,9 @@ mod tests {
         assert_eq!(layers[1].layer, fake_precompiled_bytes2.bytes);
 
         let (manifest, _) = client
-            .get_image_manifest_and_digest(&image_name)
+            .get_image_manifest_and_digest(&image_name, &WasmPlatforms::default())
             .await
+            .unwrap()
             .unwrap();
 
         let original_config1 = manifest.layers().first().unwrap();
@@ -1073,6 +1994,7 @@ mod tests {
         precompiled_layers: HashMap<String, Vec<u8>>,
         precompile_called: Arc<AtomicI32>,
         layers_compiled_per_call: Arc<AtomicI32>,
+        engine_configs: Arc<Mutex<Vec<EngineConfig>>>,
     }
 
     impl FakePrecomipler {
@@ -1083,6 +2005,7 @@ mod tests {
                 precompiled_layers: HashMap::new(),
                 precompile_called: Arc::new(AtomicI32::new(0)),
                 layers_compiled_per_call: Arc::new(AtomicI32::new(0)),
+                engine_configs: Arc::default(),
             }
         }
         fn add_precompiled_bits(
@@ -1112,7 +2035,7 @@ mod tests {
                     continue;
                 }
 
-                let key = digest(layer.layer.clone());
+                let key = digest(&layer.layer[..]);
                 if self.precompiled_layers.values().any(|l| digest(l) == key) {
                     // simulate scenario were one of the layers is already compiled
                     compiled_layers.push(None);
@@ -1130,5 +2053,14 @@ mod tests {
             }
             Ok(compiled_layers)
         }
+
+        async fn compile_with_config(
+            &self,
+            layers: &[WasmLayer],
+            config: &EngineConfig,
+        ) -> anyhow::Result<Vec<Option<Vec<u8>>>> {
+            self.engine_configs.lock().unwrap().push(config.clone());
+            self.compile(layers).await
+        }
     }
 }
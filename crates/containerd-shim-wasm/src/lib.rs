@@ -20,3 +20,7 @@ pub mod testing;
 mod test;
 
 pub(crate) mod containerd;
+
+pub(crate) mod blobs;
+pub(crate) mod policy;
+pub(crate) mod registry;
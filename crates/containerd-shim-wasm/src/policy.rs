@@ -0,0 +1,318 @@
+#![cfg(unix)]
+
+//! Enforcement of the `ImagePolicy` runtime option.
+//!
+//! Signatures follow the [cosign](https://github.com/sigstore/cosign) conventions:
+//! the signature manifest of an image with digest `sha256:<hex>` is tagged
+//! `sha256-<hex>.sig` in the same repository, and each of its layers is a
+//! "simple signing" payload with the base64 encoded signature in the
+//! `dev.cosignproject.cosign/signature` annotation.
+//!
+//! The signature manifest must also refer to the signed image through its `subject`,
+//! as written by cosign with the OCI 1.1 referrers mode, so a signature can't be moved
+//! to another image.
+
+use std::path::PathBuf;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use containerd_shimkit::sandbox::error::{Error as ShimError, Result};
+use oci_spec::image::{Descriptor, Digest, ImageManifest};
+use p256::ecdsa::signature::Verifier as _;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::pkcs8::DecodePublicKey as _;
+use serde::Deserialize;
+
+pub(crate) const SIMPLE_SIGNING_MEDIA_TYPE: &str =
+    "application/vnd.dev.cosign.simplesigning.v1+json";
+pub(crate) const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// Returns the reference of the cosign signature for the image `reference` with `digest`.
+pub(crate) fn signature_reference(reference: &str, digest: &Digest) -> String {
+    format!(
+        "{}:{}-{}.sig",
+        repository(reference),
+        digest.algorithm(),
+        digest.digest()
+    )
+}
+
+/// Strips the tag and/or digest from an image reference.
+fn repository(reference: &str) -> &str {
+    let reference = reference
+        .split_once('@')
+        .map_or(reference, |(name, _)| name);
+    match reference.rsplit_once(':') {
+        // a `:` after the last `/` separates the tag, otherwise it's a registry port
+        Some((name, tag)) if !tag.contains('/') => name,
+        _ => reference,
+    }
+}
+
+/// Checks that `reference` is pinned to `digest`.
+pub(crate) fn check_pinned(reference: &str, digest: &Digest) -> Result<()> {
+    match reference.rsplit_once('@') {
+        Some((_, pinned)) if pinned == digest.to_string() => Ok(()),
+        Some((_, pinned)) => Err(ShimError::FailedPrecondition(format!(
+            "image {reference} is pinned to {pinned}, but its manifest has digest {digest}"
+        ))),
+        None => Err(ShimError::FailedPrecondition(format!(
+            "image policy requires a digest pinned reference, got {reference}"
+        ))),
+    }
+}
+
+/// Checks that the `signature` manifest at `reference` refers to the image with `digest`
+/// through its subject.
+pub(crate) fn check_subject(
+    reference: &str,
+    signature: &ImageManifest,
+    digest: &Digest,
+) -> Result<()> {
+    match signature.subject() {
+        Some(subject) if subject.digest() == digest => Ok(()),
+        Some(subject) => Err(ShimError::FailedPrecondition(format!(
+            "signature {reference} refers to {}, not {digest}",
+            subject.digest()
+        ))),
+        None => Err(ShimError::FailedPrecondition(format!(
+            "signature {reference} doesn't refer to its image through a subject"
+        ))),
+    }
+}
+
+/// Loads the keys trusted to sign images.
+pub(crate) fn trusted_keys(paths: &[PathBuf]) -> Result<Vec<VerifyingKey>> {
+    if paths.is_empty() {
+        return Err(ShimError::InvalidArgument(
+            "image policy requires signatures, but no trusted keys are configured".to_string(),
+        ));
+    }
+    paths
+        .iter()
+        .map(|path| {
+            let pem = std::fs::read_to_string(path)?;
+            VerifyingKey::from_public_key_pem(&pem).map_err(|err| {
+                ShimError::InvalidArgument(format!("invalid public key {path:?}: {err}"))
+            })
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct SimpleSigning {
+    critical: Critical,
+}
+
+#[derive(Deserialize)]
+struct Critical {
+    image: SignedImage,
+}
+
+#[derive(Deserialize)]
+struct SignedImage {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+/// Checks that at least one of the signature `layers` is a valid signature of
+/// `digest` made by one of the `keys`.
+pub(crate) fn verify_signature(
+    keys: &[VerifyingKey],
+    digest: &Digest,
+    layers: &[(Descriptor, Vec<u8>)],
+) -> Result<()> {
+    let verified = layers.iter().any(|(descriptor, payload)| {
+        match verify_layer(keys, digest, descriptor, payload) {
+            Ok(()) => true,
+            Err(err) => {
+                log::debug!("signature layer {} rejected: {err}", descriptor.digest());
+                false
+            }
+        }
+    });
+    if !verified {
+        return Err(ShimError::FailedPrecondition(format!(
+            "no valid signature found for image {digest}"
+        )));
+    }
+    Ok(())
+}
+
+fn verify_layer(
+    keys: &[VerifyingKey],
+    digest: &Digest,
+    descriptor: &Descriptor,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        descriptor.media_type().to_string() == SIMPLE_SIGNING_MEDIA_TYPE,
+        "unexpected media type {}",
+        descriptor.media_type()
+    );
+    let signature = descriptor
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(SIGNATURE_ANNOTATION))
+        .ok_or_else(|| anyhow::anyhow!("missing {SIGNATURE_ANNOTATION} annotation"))?;
+    let signature = Signature::from_der(&BASE64.decode(signature)?)?;
+    anyhow::ensure!(
+        keys.iter()
+            .any(|key| key.verify(payload, &signature).is_ok()),
+        "signature not made by a trusted key"
+    );
+
+    let payload: SimpleSigning = serde_json::from_slice(payload)?;
+    let signed = payload.critical.image.docker_manifest_digest;
+    anyhow::ensure!(
+        signed == digest.to_string(),
+        "signature is for {signed}, not {digest}"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use oci_spec::image::{DescriptorBuilder, ImageManifestBuilder, MediaType};
+    use p256::ecdsa::SigningKey;
+    use p256::ecdsa::signature::Signer as _;
+
+    use super::*;
+
+    pub(crate) fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    /// Creates a cosign-style signature layer for `digest`.
+    pub(crate) fn sign(key: &SigningKey, digest: &Digest) -> (Descriptor, Vec<u8>) {
+        let payload = serde_json::json!({
+            "critical": {
+                "identity": { "docker-reference": "example.com/app" },
+                "image": { "docker-manifest-digest": digest.to_string() },
+                "type": "cosign container image signature"
+            },
+            "optional": null
+        });
+        let payload = serde_json::to_vec(&payload).unwrap();
+        let signature: Signature = key.sign(&payload);
+        let descriptor = DescriptorBuilder::default()
+            .media_type(SIMPLE_SIGNING_MEDIA_TYPE)
+            .digest(test_digest(&payload))
+            .size(payload.len() as u64)
+            .annotations(HashMap::from([(
+                SIGNATURE_ANNOTATION.to_string(),
+                BASE64.encode(signature.to_der().as_bytes()),
+            )]))
+            .build()
+            .unwrap();
+        (descriptor, payload)
+    }
+
+    /// Creates a signature manifest with the signature `layers` of the image with `digest`.
+    pub(crate) fn signature_manifest(digest: &Digest, layers: Vec<Descriptor>) -> ImageManifest {
+        let config = b"{}";
+        ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(
+                DescriptorBuilder::default()
+                    .media_type(MediaType::ImageConfig)
+                    .digest(test_digest(config))
+                    .size(config.len() as u64)
+                    .build()
+                    .unwrap(),
+            )
+            .layers(layers)
+            .subject(
+                DescriptorBuilder::default()
+                    .media_type(MediaType::ImageManifest)
+                    .digest(digest.clone())
+                    .size(0u64)
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    fn test_digest(data: &[u8]) -> Digest {
+        format!("sha256:{}", sha256::digest(data)).parse().unwrap()
+    }
+
+    #[test]
+    fn test_signature_reference() {
+        let digest = test_digest(b"manifest");
+        let hex = digest.digest();
+        assert_eq!(
+            signature_reference("example.com/app:v1", &digest),
+            format!("example.com/app:sha256-{hex}.sig")
+        );
+        assert_eq!(
+            signature_reference(&format!("localhost:5000/app@{digest}"), &digest),
+            format!("localhost:5000/app:sha256-{hex}.sig")
+        );
+        assert_eq!(
+            signature_reference("localhost:5000/app", &digest),
+            format!("localhost:5000/app:sha256-{hex}.sig")
+        );
+    }
+
+    #[test]
+    fn test_check_pinned() {
+        let digest = test_digest(b"manifest");
+        assert!(check_pinned(&format!("example.com/app@{digest}"), &digest).is_ok());
+
+        let other = test_digest(b"other");
+        let err = check_pinned(&format!("example.com/app@{other}"), &digest).unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)));
+
+        let err = check_pinned("example.com/app:latest", &digest).unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)));
+    }
+
+    #[test]
+    fn test_check_subject() {
+        let digest = test_digest(b"manifest");
+        let signature = signature_manifest(&digest, vec![]);
+        check_subject("example.com/app:sig", &signature, &digest).unwrap();
+
+        let err =
+            check_subject("example.com/app:sig", &signature, &test_digest(b"other")).unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)));
+
+        let mut signature = signature;
+        signature.set_subject(None);
+        let err = check_subject("example.com/app:sig", &signature, &digest).unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)));
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let key = signing_key(1);
+        let digest = test_digest(b"manifest");
+        let layers = [sign(&key, &digest)];
+
+        verify_signature(&[*key.verifying_key()], &digest, &layers).unwrap();
+    }
+
+    #[test]
+    fn test_verify_signature_untrusted_key() {
+        let digest = test_digest(b"manifest");
+        let layers = [sign(&signing_key(1), &digest)];
+
+        let err =
+            verify_signature(&[*signing_key(2).verifying_key()], &digest, &layers).unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)));
+    }
+
+    #[test]
+    fn test_verify_signature_other_image() {
+        let key = signing_key(1);
+        let digest = test_digest(b"manifest");
+        let layers = [sign(&key, &test_digest(b"other manifest"))];
+
+        let err = verify_signature(&[*key.verifying_key()], &digest, &layers).unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)));
+    }
+}
@@ -0,0 +1,626 @@
+#![cfg(unix)]
+
+//! Fetching Wasm OCI artifacts directly from a registry.
+//!
+//! Containers can reference additional Wasm artifacts (sidecar components,
+//! plugins, etc.) through the [`ARTIFACTS_ANNOTATION`] annotation, without
+//! having to add them to the container image. The artifacts must follow the
+//! `oci-wasm` format, and every blob is streamed into the blob cache under
+//! the shim's root directory, where it's verified against its digest.
+//!
+//! Artifacts are only attached to images that already provide wasm layers, so they never
+//! replace the entrypoint of a container running from the files of its rootfs.
+
+use std::time::Duration;
+
+use containerd_shimkit::sandbox::error::{Error as ShimError, Result};
+use containerd_shimkit::sandbox::{ImagePolicy, RetryPolicy};
+use futures::StreamExt as _;
+use oci_client::client::{ClientConfig, ClientProtocol};
+use oci_client::manifest::{IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
+use oci_client::secrets::RegistryAuth;
+use oci_client::{Client, Reference};
+use oci_spec::image::{Digest, ImageManifest};
+use oci_wasm::WASM_MANIFEST_CONFIG_MEDIA_TYPE;
+use tokio::io::{AsyncWrite, AsyncWriteExt as _};
+
+use crate::blobs::{BlobCache, verify_digest};
+use crate::policy;
+use crate::sandbox::context::WasmLayer;
+
+/// Annotation with a comma separated list of OCI artifact references to load
+/// in addition to the layers of the container image.
+///
+/// References should be pinned by digest, e.g., `ghcr.io/org/plugin@sha256:...`.
+pub(crate) const ARTIFACTS_ANNOTATION: &str = "runwasi.io/oci-artifacts";
+
+/// A source of OCI artifacts.
+#[trait_variant::make(Send)]
+pub(crate) trait Registry: Sync {
+    /// Fetches the raw manifest for `reference`.
+    async fn fetch_manifest(&self, reference: &str) -> Result<Vec<u8>>;
+
+    /// Streams the raw content of the blob with `digest` from the repository of `reference`
+    /// into `out`.
+    async fn fetch_blob(
+        &self,
+        reference: &str,
+        digest: &Digest,
+        out: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<()>;
+}
+
+/// A [`Registry`] backed by a remote OCI distribution registry.
+///
+/// Only anonymous access over HTTPS is supported. Requests fail with
+/// [`ShimError::Unavailable`] when the registry doesn't respond within the
+/// `request_timeout_ms` of the `ContainerdRetry` runtime option, and blobs, which
+/// can take longer to download, when no data is received for that long.
+pub(crate) struct OciRegistry {
+    client: Client,
+    timeout: Duration,
+}
+
+impl OciRegistry {
+    pub fn new(retry_policy: &RetryPolicy) -> Self {
+        let client = Client::new(ClientConfig {
+            protocol: ClientProtocol::Https,
+            ..Default::default()
+        });
+        let timeout = Duration::from_millis(retry_policy.request_timeout_ms);
+        Self { client, timeout }
+    }
+}
+
+impl Registry for OciRegistry {
+    async fn fetch_manifest(&self, reference: &str) -> Result<Vec<u8>> {
+        let reference = parse_reference(reference)?;
+        let pull = self.client.pull_manifest_raw(
+            &reference,
+            &RegistryAuth::Anonymous,
+            &[OCI_IMAGE_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE],
+        );
+        let (manifest, _) = tokio::time::timeout(self.timeout, pull)
+            .await
+            .map_err(|_| {
+                ShimError::Unavailable(format!(
+                    "pulling {reference} timed out after {:?}",
+                    self.timeout
+                ))
+            })?
+            .map_err(|err| ShimError::Others(format!("failed to pull {reference}: {err}")))?;
+        Ok(manifest.to_vec())
+    }
+
+    async fn fetch_blob(
+        &self,
+        reference: &str,
+        digest: &Digest,
+        out: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<()> {
+        let reference = parse_reference(reference)?;
+        let failed = |err: &dyn std::fmt::Display| {
+            ShimError::Others(format!("failed to pull {digest} from {reference}: {err}"))
+        };
+        let stalled = || {
+            ShimError::Unavailable(format!(
+                "pulling {digest} from {reference} stalled for more than {:?}",
+                self.timeout
+            ))
+        };
+
+        let pull = self
+            .client
+            .pull_blob_stream(&reference, digest.to_string().as_str());
+        let mut stream = tokio::time::timeout(self.timeout, pull)
+            .await
+            .map_err(|_| stalled())?
+            .map_err(|err| failed(&err))?;
+        while let Some(chunk) = tokio::time::timeout(self.timeout, stream.next())
+            .await
+            .map_err(|_| stalled())?
+        {
+            out.write_all(&chunk.map_err(|err| failed(&err))?).await?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_reference(reference: &str) -> Result<Reference> {
+    reference.parse().map_err(|err| {
+        ShimError::InvalidArgument(format!("invalid OCI reference {reference:?}: {err}"))
+    })
+}
+
+/// Returns the digest a reference is pinned to, if any.
+fn pinned_digest(reference: &str) -> Result<Option<Digest>> {
+    let Some((_, digest)) = reference.rsplit_once('@') else {
+        return Ok(None);
+    };
+    let digest = digest.parse().map_err(|err| {
+        ShimError::InvalidArgument(format!("invalid digest in reference {reference:?}: {err}"))
+    })?;
+    Ok(Some(digest))
+}
+
+/// Returns the artifact references listed in the [`ARTIFACTS_ANNOTATION`] annotation.
+pub(crate) fn artifact_references(spec: &oci_spec::runtime::Spec) -> Vec<String> {
+    spec.annotations()
+        .as_ref()
+        .and_then(|a| a.get(ARTIFACTS_ANNOTATION))
+        .map(|refs| {
+            refs.split(',')
+                .map(str::trim)
+                .filter(|r| !r.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Checks that the artifacts in `references` can be attached to a container with the wasm
+/// `layers` of its image.
+///
+/// Attaching artifacts to an image without wasm layers would replace the entrypoint read from
+/// its rootfs, so the container is rejected instead.
+pub(crate) fn ensure_attachable(references: &[String], layers: &[WasmLayer]) -> Result<()> {
+    if !references.is_empty() && layers.is_empty() {
+        return Err(ShimError::FailedPrecondition(format!(
+            "the artifacts in the {ARTIFACTS_ANNOTATION} annotation can only be attached to images with wasm layers"
+        )));
+    }
+    Ok(())
+}
+
+/// Pulls the Wasm layers of the artifacts in `references`.
+///
+/// Manifests of references pinned by digest are verified against that digest,
+/// and every layer is verified against the digest in the manifest.
+/// Layers already present in `cache` are not fetched again.
+/// Artifacts must also satisfy the image `policy`.
+pub(crate) async fn pull_artifacts(
+    registry: &impl Registry,
+    cache: &BlobCache,
+    references: &[String],
+    supported_layer_types: &[&str],
+    policy: &ImagePolicy,
+) -> Result<Vec<WasmLayer>> {
+    let mut layers = vec![];
+    for reference in references {
+        log::info!("pulling wasm artifact {reference}");
+        let manifest = registry.fetch_manifest(reference).await?;
+        let digest = match pinned_digest(reference)? {
+            Some(digest) => {
+                verify_digest(&digest, &manifest).map_err(|err| {
+                    ShimError::FailedPrecondition(format!("manifest for {reference}: {err}"))
+                })?;
+                digest
+            }
+            None => format!("sha256:{}", sha256::digest(manifest.as_slice())).parse()?,
+        };
+        enforce_policy(registry, reference, &digest, policy).await?;
+
+        let manifest = ImageManifest::from_reader(manifest.as_slice())?;
+        let config_type = manifest.config().media_type();
+        if config_type.to_string() != WASM_MANIFEST_CONFIG_MEDIA_TYPE {
+            return Err(ShimError::InvalidArgument(format!(
+                "{reference} is not a wasm artifact, config media type is {config_type}"
+            )));
+        }
+
+        let descriptors = manifest
+            .layers()
+            .iter()
+            .filter(|d| supported_layer_types.contains(&d.media_type().to_string().as_str()));
+
+        for descriptor in descriptors {
+            let digest = descriptor.digest();
+            let layer = match cache.get(digest).await? {
+                Some(layer) => {
+                    log::debug!("using cached blob {digest} for {reference}");
+                    layer
+                }
+                None => {
+                    let mut writer = cache.writer(digest).await?;
+                    registry.fetch_blob(reference, digest, &mut writer).await?;
+                    writer.commit().await.map_err(|err| match err {
+                        ShimError::FailedPrecondition(err) => {
+                            ShimError::FailedPrecondition(format!("layer of {reference}: {err}"))
+                        }
+                        err => err,
+                    })?
+                }
+            };
+            layers.push(WasmLayer {
+                config: descriptor.clone(),
+                layer,
+            });
+        }
+    }
+    Ok(layers)
+}
+
+async fn enforce_policy(
+    registry: &impl Registry,
+    reference: &str,
+    digest: &Digest,
+    policy: &ImagePolicy,
+) -> Result<()> {
+    if policy.require_digest {
+        policy::check_pinned(reference, digest)?;
+    }
+
+    if policy.require_signature {
+        let keys = policy::trusted_keys(&policy.trusted_keys)?;
+        let signature_reference = policy::signature_reference(reference, digest);
+        let manifest = registry
+            .fetch_manifest(&signature_reference)
+            .await
+            .map_err(|err| {
+                ShimError::FailedPrecondition(format!(
+                    "failed to find signature {signature_reference} for {reference}: {err}"
+                ))
+            })?;
+        let manifest = ImageManifest::from_reader(manifest.as_slice())?;
+        policy::check_subject(&signature_reference, &manifest, digest)?;
+
+        let mut layers = vec![];
+        for descriptor in manifest.layers() {
+            if descriptor.media_type().to_string() != policy::SIMPLE_SIGNING_MEDIA_TYPE {
+                continue;
+            }
+            let mut payload: Vec<u8> = vec![];
+            registry
+                .fetch_blob(&signature_reference, descriptor.digest(), &mut payload)
+                .await?;
+            verify_digest(descriptor.digest(), &payload)?;
+            layers.push((descriptor.clone(), payload));
+        }
+        policy::verify_signature(&keys, digest, &layers)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use oci_spec::image::{DescriptorBuilder, ImageManifestBuilder};
+    use oci_tar_builder::WASM_LAYER_MEDIA_TYPE;
+    use oci_wasm::WASM_LAYER_MEDIA_TYPE as WASI_LAYER_MEDIA_TYPE;
+
+    use super::*;
+
+    /// An in-memory stand-in for a registry.
+    #[derive(Default)]
+    struct MockRegistry {
+        manifests: HashMap<String, Vec<u8>>,
+        blobs: HashMap<String, Vec<u8>>,
+        blob_fetches: AtomicUsize,
+    }
+
+    impl MockRegistry {
+        fn add_blob(&mut self, data: &[u8]) -> Digest {
+            let digest: Digest = format!("sha256:{}", sha256::digest(data)).parse().unwrap();
+            self.blobs.insert(digest.to_string(), data.to_vec());
+            digest
+        }
+
+        /// Adds an artifact and returns its reference pinned by digest
+        fn add_artifact(&mut self, layers: &[(&str, &[u8])]) -> String {
+            let config = self.add_blob(b"{}");
+            let layers = layers
+                .iter()
+                .map(|(media_type, data)| {
+                    DescriptorBuilder::default()
+                        .media_type(*media_type)
+                        .digest(self.add_blob(data))
+                        .size(data.len() as u64)
+                        .build()
+                        .unwrap()
+                })
+                .collect::<Vec<_>>();
+            let manifest = ImageManifestBuilder::default()
+                .schema_version(2u32)
+                .config(
+                    DescriptorBuilder::default()
+                        .media_type(WASM_MANIFEST_CONFIG_MEDIA_TYPE)
+                        .digest(config)
+                        .size(2u64)
+                        .build()
+                        .unwrap(),
+                )
+                .layers(layers)
+                .build()
+                .unwrap();
+            let manifest = serde_json::to_vec(&manifest).unwrap();
+            let reference = format!(
+                "localhost:5000/plugin@sha256:{}",
+                sha256::digest(manifest.as_slice())
+            );
+            self.manifests.insert(reference.clone(), manifest);
+            reference
+        }
+    }
+
+    impl Registry for MockRegistry {
+        async fn fetch_manifest(&self, reference: &str) -> Result<Vec<u8>> {
+            self.manifests
+                .get(reference)
+                .cloned()
+                .ok_or_else(|| ShimError::NotFound(reference.to_string()))
+        }
+
+        async fn fetch_blob(
+            &self,
+            _reference: &str,
+            digest: &Digest,
+            out: &mut (dyn AsyncWrite + Send + Unpin),
+        ) -> Result<()> {
+            self.blob_fetches.fetch_add(1, Ordering::SeqCst);
+            let blob = self
+                .blobs
+                .get(&digest.to_string())
+                .ok_or_else(|| ShimError::NotFound(digest.to_string()))?;
+            out.write_all(blob).await?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_artifact() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = BlobCache::new(dir.path());
+        let mut registry = MockRegistry::default();
+        let reference = registry.add_artifact(&[
+            (WASI_LAYER_MEDIA_TYPE, b"\0asm module"),
+            ("application/vnd.unknown", b"ignored"),
+        ]);
+
+        let layers = pull_artifacts(
+            &registry,
+            &cache,
+            &[reference],
+            &[WASM_LAYER_MEDIA_TYPE, WASI_LAYER_MEDIA_TYPE],
+            &ImagePolicy::default(),
+        )
+        .await?;
+
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].layer, b"\0asm module");
+        assert_eq!(
+            layers[0].config.media_type().to_string(),
+            WASI_LAYER_MEDIA_TYPE
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pull_artifact_uses_cache() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = BlobCache::new(dir.path());
+        let mut registry = MockRegistry::default();
+        let reference = registry.add_artifact(&[(WASI_LAYER_MEDIA_TYPE, b"\0asm module")]);
+        let references = [reference];
+
+        let policy = ImagePolicy::default();
+        pull_artifacts(
+            &registry,
+            &cache,
+            &references,
+            &[WASI_LAYER_MEDIA_TYPE],
+            &policy,
+        )
+        .await?;
+        let layers = pull_artifacts(
+            &registry,
+            &cache,
+            &references,
+            &[WASI_LAYER_MEDIA_TYPE],
+            &policy,
+        )
+        .await?;
+
+        assert_eq!(layers[0].layer, b"\0asm module");
+        assert_eq!(registry.blob_fetches.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pull_artifact_rejects_tampered_layer() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = BlobCache::new(dir.path());
+        let mut registry = MockRegistry::default();
+        let reference = registry.add_artifact(&[(WASI_LAYER_MEDIA_TYPE, b"\0asm module")]);
+        for blob in registry.blobs.values_mut() {
+            *blob = b"\0asm tampered".to_vec();
+        }
+
+        let err = pull_artifacts(
+            &registry,
+            &cache,
+            &[reference],
+            &[WASI_LAYER_MEDIA_TYPE],
+            &ImagePolicy::default(),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(err, ShimError::FailedPrecondition(_)), "{err}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pull_artifact_rejects_tampered_manifest() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = BlobCache::new(dir.path());
+        let mut registry = MockRegistry::default();
+        let reference = registry.add_artifact(&[(WASI_LAYER_MEDIA_TYPE, b"\0asm module")]);
+        registry.manifests.get_mut(&reference).unwrap().push(b' ');
+
+        let err = pull_artifacts(
+            &registry,
+            &cache,
+            &[reference],
+            &[WASI_LAYER_MEDIA_TYPE],
+            &ImagePolicy::default(),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(err, ShimError::FailedPrecondition(_)), "{err}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pull_artifact_with_signature_policy() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = BlobCache::new(dir.path().join("blobs"));
+        let mut registry = MockRegistry::default();
+        let reference = registry.add_artifact(&[(WASI_LAYER_MEDIA_TYPE, b"\0asm module")]);
+
+        let key = policy::tests::signing_key(1);
+        let key_path = dir.path().join("cosign.pub");
+        std::fs::write(
+            &key_path,
+            p256::pkcs8::EncodePublicKey::to_public_key_pem(
+                key.verifying_key(),
+                Default::default(),
+            )?,
+        )?;
+        let policy = ImagePolicy {
+            require_digest: true,
+            require_signature: true,
+            trusted_keys: vec![key_path],
+        };
+
+        // no signature yet
+        let err = pull_artifacts(
+            &registry,
+            &cache,
+            &[reference.clone()],
+            &[WASI_LAYER_MEDIA_TYPE],
+            &policy,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)), "{err}");
+
+        let digest = pinned_digest(&reference)?.unwrap();
+        let (descriptor, payload) = policy::tests::sign(&key, &digest);
+        registry
+            .blobs
+            .insert(descriptor.digest().to_string(), payload);
+        let signature = policy::tests::signature_manifest(&digest, vec![descriptor]);
+        registry.manifests.insert(
+            policy::signature_reference(&reference, &digest),
+            serde_json::to_vec(&signature)?,
+        );
+
+        let layers = pull_artifacts(
+            &registry,
+            &cache,
+            &[reference],
+            &[WASI_LAYER_MEDIA_TYPE],
+            &policy,
+        )
+        .await?;
+        assert_eq!(layers.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pull_artifact_requires_pinned_reference() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = BlobCache::new(dir.path());
+        let mut registry = MockRegistry::default();
+        let pinned = registry.add_artifact(&[(WASI_LAYER_MEDIA_TYPE, b"\0asm module")]);
+        let tagged = "localhost:5000/plugin:latest".to_string();
+        let manifest = registry.manifests[&pinned].clone();
+        registry.manifests.insert(tagged.clone(), manifest);
+
+        let policy = ImagePolicy {
+            require_digest: true,
+            ..Default::default()
+        };
+        let err = pull_artifacts(
+            &registry,
+            &cache,
+            &[tagged],
+            &[WASI_LAYER_MEDIA_TYPE],
+            &policy,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)), "{err}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_registry_times_out() -> anyhow::Result<()> {
+        // connections are accepted by the kernel, but the registry never responds
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let registry = OciRegistry::new(&RetryPolicy {
+            request_timeout_ms: 100,
+            ..Default::default()
+        });
+        let reference = format!("{}/plugin:latest", listener.local_addr()?);
+        let digest = MockRegistry::default().add_blob(b"\0asm");
+
+        let err = registry.fetch_manifest(&reference).await.unwrap_err();
+        assert!(matches!(err, ShimError::Unavailable(_)), "{err}");
+        let err = registry
+            .fetch_blob(&reference, &digest, &mut Vec::<u8>::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ShimError::Unavailable(_)), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_artifacts_need_wasm_layers() -> anyhow::Result<()> {
+        let references = ["example.com/a@sha256:abc".to_string()];
+        let layer = WasmLayer {
+            config: DescriptorBuilder::default()
+                .media_type(WASM_LAYER_MEDIA_TYPE)
+                .digest(MockRegistry::default().add_blob(b"\0asm"))
+                .size(4u64)
+                .build()?,
+            layer: b"\0asm".to_vec().into(),
+        };
+
+        ensure_attachable(&[], &[])?;
+        ensure_attachable(&references, &[layer])?;
+        let err = ensure_attachable(&references, &[]).unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_artifact_references() -> anyhow::Result<()> {
+        use oci_spec::runtime::SpecBuilder;
+
+        let annotations = HashMap::from([(
+            ARTIFACTS_ANNOTATION.to_string(),
+            "example.com/a@sha256:abc, example.com/b:v1,".to_string(),
+        )]);
+        let spec = SpecBuilder::default().annotations(annotations).build()?;
+
+        assert_eq!(
+            artifact_references(&spec),
+            ["example.com/a@sha256:abc", "example.com/b:v1"]
+        );
+
+        Ok(())
+    }
+}
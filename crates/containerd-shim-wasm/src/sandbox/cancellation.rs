@@ -0,0 +1,118 @@
+//! Cancellation of a running Wasm guest.
+//!
+//! When the container receives a termination signal, the shim cancels the
+//! [`Cancellation`] exposed by [`RuntimeContext::cancellation`]. Runtimes map the
+//! cancellation onto their own interruption mechanism, e.g., by dropping the
+//! future running the guest, or by delivering a signal to the guest, and
+//! return from [`Sandbox::run_wasi`].
+//!
+//! The exit code of a container stopped by a signal is `128 + signal` on every
+//! runtime, as it would be for a native process. The only exception is `SIGINT`,
+//! which asks the guest to stop gracefully: the exit code returned by the
+//! runtime is preserved.
+//!
+//! A termination signal only asks the guest to stop: the shim waits for the guest
+//! of a runtime that [supports cancellation](crate::sandbox::Sandbox::supports_cancellation)
+//! for as long as containerd does, i.e., until the stop timeout requested by the
+//! client, e.g., the grace period of the kubelet, expires and containerd sends `SIGKILL`.
+//! Runtimes that can't interrupt a running guest have their containers stopped right away
+//! by a termination signal, as for the default action of the signal on a native process.
+//!
+//! How soon a guest is interrupted depends on the runtime:
+//!
+//! * wasmtime drops the future running the guest, so the guest stops at its next await
+//!   point, e.g., a WASI call, or at the next epoch tick when it runs in the shim process,
+//! * wasmer delivers the signal to the WASIX process, which handles it at its next syscall,
+//! * WAMR terminates the instance, which raises an exception in the guest when it
+//!   returns from its current function call,
+//! * WasmEdge can't interrupt a guest through its Rust SDK, so its containers are
+//!   stopped by the shim.
+//!
+//! [`RuntimeContext::cancellation`]: crate::sandbox::context::RuntimeContext::cancellation
+//! [`Sandbox::run_wasi`]: crate::sandbox::Sandbox::run_wasi
+
+use std::sync::{Arc, OnceLock};
+
+use tokio_util::sync::CancellationToken;
+
+/// A handle to request the cancellation of a running Wasm guest.
+///
+/// Cloning the handle is cheap, and all the clones observe the same cancellation.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    token: CancellationToken,
+    signal: OnceLock<i32>,
+}
+
+impl Cancellation {
+    /// Creates a handle that isn't cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the cancellation of the guest because of `signal`.
+    ///
+    /// Only the first call has an effect, later calls are ignored.
+    pub fn cancel(&self, signal: i32) {
+        if self.0.signal.set(signal).is_ok() {
+            self.0.token.cancel();
+        }
+    }
+
+    /// Whether the cancellation has been requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.token.is_cancelled()
+    }
+
+    /// The signal that caused the cancellation, if any.
+    pub fn signal(&self) -> Option<i32> {
+        self.0.signal.get().copied()
+    }
+
+    /// Waits until the cancellation is requested, and returns the signal that caused it.
+    pub async fn cancelled(&self) -> i32 {
+        self.0.token.cancelled().await;
+        // the signal is always set before the token is cancelled
+        self.signal().unwrap_or_default()
+    }
+
+    /// The exit code of a guest stopped by the cancellation, i.e., `128 + signal`.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.signal().map(|signal| 128 + signal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancellation() {
+        let cancellation = Cancellation::new();
+        assert!(!cancellation.is_cancelled());
+        assert_eq!(cancellation.signal(), None);
+        assert_eq!(cancellation.exit_code(), None);
+
+        let waiter = tokio::spawn({
+            let cancellation = cancellation.clone();
+            async move { cancellation.cancelled().await }
+        });
+
+        cancellation.cancel(libc::SIGTERM);
+        // later cancellations don't override the first one
+        cancellation.cancel(libc::SIGINT);
+
+        let signal = tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("waiter should be woken up")
+            .unwrap();
+        assert_eq!(signal, libc::SIGTERM);
+        assert!(cancellation.is_cancelled());
+        assert_eq!(cancellation.exit_code(), Some(128 + libc::SIGTERM));
+    }
+}
@@ -0,0 +1,207 @@
+//! Engine settings declared by the image author.
+//!
+//! An image can include a TOML layer with the [`CONFIG_LAYER_MEDIA_TYPE`] media type,
+//! next to its Wasm layers, to declare what its modules need from the runtime:
+//!
+//! ```toml
+//! [features]
+//! simd = true
+//! threads = false
+//! exceptions = false
+//! memory64 = false
+//! gc = false
+//!
+//! [limits]
+//! max-memory-bytes = 268435456
+//!
+//! [wasi]
+//! tcp = true
+//! udp = false
+//! dns = false
+//! ```
+//!
+//! Every setting is optional, and the runtime defaults are used for the missing ones.
+//! The layer is read from the image along with the Wasm layers, and exposed to
+//! runtimes with [`RuntimeContext::engine_config`]. Runtimes fail to start a container
+//! with a setting they don't support, e.g., a feature they can't enable or a limit they
+//! can't enforce, instead of ignoring it.
+//!
+//! The `[wasi]` settings are the defaults of the [`NetworkPolicy`], and the network
+//! annotations of the container take precedence over them.
+//!
+//! [`RuntimeContext::engine_config`]: crate::sandbox::context::RuntimeContext::engine_config
+//! [`NetworkPolicy`]: crate::sandbox::network::NetworkPolicy
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::sandbox::context::WasmLayer;
+
+/// Media type of the layer with the engine settings.
+pub const CONFIG_LAYER_MEDIA_TYPE: &str = "application/vnd.runwasi.config.v1+toml";
+
+/// The engine settings of an image, see the [module documentation](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct EngineConfig {
+    pub features: Features,
+    pub limits: Limits,
+    pub wasi: WasiPolicy,
+}
+
+/// The Wasm proposals the modules need enabled, or disabled.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Features {
+    /// The fixed-width SIMD proposal.
+    pub simd: Option<bool>,
+    /// The threads proposal, i.e., shared memories and atomics.
+    pub threads: Option<bool>,
+    /// The exception handling proposal.
+    pub exceptions: Option<bool>,
+    /// The 64-bit memories proposal.
+    pub memory64: Option<bool>,
+    /// The garbage collection proposal.
+    pub gc: Option<bool>,
+}
+
+/// Limits on the resources used by the guest.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Limits {
+    /// The maximum size of each linear memory, in bytes.
+    pub max_memory_bytes: Option<u64>,
+}
+
+/// The WASI capabilities the guest needs.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WasiPolicy {
+    /// Whether the guest can open TCP sockets.
+    pub tcp: Option<bool>,
+    /// Whether the guest can open UDP sockets.
+    pub udp: Option<bool>,
+    /// Whether the guest can resolve host names.
+    pub dns: Option<bool>,
+}
+
+impl EngineConfig {
+    /// Parses the content of a config layer.
+    pub fn from_toml(content: &str) -> Result<Self> {
+        toml::from_str(content).context("invalid engine config")
+    }
+
+    /// Separates the config layer from the Wasm layers.
+    ///
+    /// Images have at most one config layer, and use the default config if they have none.
+    pub(crate) fn from_layers(layers: Vec<WasmLayer>) -> Result<(Self, Vec<WasmLayer>)> {
+        let (configs, layers): (Vec<_>, Vec<_>) = layers
+            .into_iter()
+            .partition(|layer| is_config_layer(&layer.config.media_type().to_string()));
+
+        let config = match configs.as_slice() {
+            [] => Self::default(),
+            [config] => {
+                let content = std::str::from_utf8(&config.layer)
+                    .context("the engine config isn't valid UTF-8")?;
+                Self::from_toml(content)?
+            }
+            _ => bail!("an image can only have one {CONFIG_LAYER_MEDIA_TYPE} layer"),
+        };
+
+        Ok((config, layers))
+    }
+}
+
+impl Features {
+    /// Whether the runtime defaults are used for every feature.
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Returns `true` if the layer with `media_type` holds the engine settings.
+pub(crate) fn is_config_layer(media_type: &str) -> bool {
+    media_type == CONFIG_LAYER_MEDIA_TYPE
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::image::{Descriptor, Digest, MediaType};
+
+    use super::*;
+
+    fn layer(media_type: &str, content: &str) -> Result<WasmLayer> {
+        Ok(WasmLayer {
+            config: Descriptor::new(
+                MediaType::Other(media_type.to_string()),
+                content.len() as u64,
+                Digest::try_from(format!("sha256:{:064?}", 0))?,
+            ),
+            layer: content.as_bytes().to_vec().into(),
+        })
+    }
+
+    #[test]
+    fn test_engine_config_from_toml() -> Result<()> {
+        let config = EngineConfig::from_toml(
+            r#"
+            [features]
+            simd = true
+            gc = false
+
+            [limits]
+            max-memory-bytes = 1048576
+
+            [wasi]
+            udp = false
+            "#,
+        )?;
+
+        assert_eq!(
+            config,
+            EngineConfig {
+                features: Features {
+                    simd: Some(true),
+                    gc: Some(false),
+                    ..Default::default()
+                },
+                limits: Limits {
+                    max_memory_bytes: Some(1024 * 1024),
+                },
+                wasi: WasiPolicy {
+                    tcp: None,
+                    udp: Some(false),
+                    dns: None,
+                },
+            }
+        );
+        assert!(!config.features.is_default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine_config_rejects_unknown_settings() {
+        assert!(EngineConfig::from_toml("[features]\nturbo = true").is_err());
+        assert!(EngineConfig::from_toml("[limits]\nmax-memory-bytes = \"1Gi\"").is_err());
+    }
+
+    #[test]
+    fn test_engine_config_from_layers() -> Result<()> {
+        let module = layer("application/wasm", "\0asm")?;
+        let config = layer(CONFIG_LAYER_MEDIA_TYPE, "[features]\nthreads = true")?;
+
+        let (engine_config, layers) = EngineConfig::from_layers(vec![module.clone()])?;
+        assert_eq!(engine_config, EngineConfig::default());
+        assert_eq!(layers.len(), 1);
+
+        let (engine_config, layers) =
+            EngineConfig::from_layers(vec![config.clone(), module.clone()])?;
+        assert_eq!(engine_config.features.threads, Some(true));
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].layer, module.layer);
+
+        assert!(EngineConfig::from_layers(vec![config.clone(), module, config]).is_err());
+        Ok(())
+    }
+}
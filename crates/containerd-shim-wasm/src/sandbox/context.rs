@@ -1,12 +1,22 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
 
 use anyhow::{Context, bail};
+use containerd_shimkit::sandbox::Isolation;
+use memmap2::Mmap;
 use oci_spec::image::Descriptor;
 use oci_spec::runtime::Spec;
 use serde::{Deserialize, Serialize};
 use wasmparser::Parser;
 
+use crate::sandbox::cancellation::Cancellation;
+use crate::sandbox::config::EngineConfig;
+use crate::sandbox::network::NetworkPolicy;
 use crate::sandbox::path::PathResolve;
 
 /// The `RuntimeContext` trait provides access to the runtime context that includes
@@ -19,6 +29,22 @@ pub trait RuntimeContext: Send + Sync {
     /// Returns environment variables in the format `ENV_VAR_NAME=VALUE` from the runtime spec process field.
     fn envs(&self) -> &[String];
 
+    /// Returns the value of the environment variable `key` from the runtime spec process field.
+    /// If the variable is set more than once, the last value is returned.
+    fn env(&self, key: &str) -> Option<&str> {
+        self.envs()
+            .iter()
+            .rev()
+            .find_map(|env| env.strip_prefix(key)?.strip_prefix('='))
+    }
+
+    /// Returns the working directory of the guest from the runtime spec process field,
+    /// as an absolute path in the guest, so engines can set the initial working directory
+    /// of WASI. Defaults to `/`.
+    fn cwd(&self) -> &Path {
+        Path::new("/")
+    }
+
     /// Returns a `Entrypoint` with the following fields obtained from the first argument in the OCI spec for entrypoint:
     ///   - `arg0` - raw entrypoint from the OCI spec
     ///   - `name` - provided as the file name of the module in the entrypoint without the extension
@@ -32,6 +58,129 @@ pub trait RuntimeContext: Send + Sync {
     ///   "my_module.wat" -> { source: File("my_module.wat"), func: "_start", name: "Some(my_module)", arg0: "my_module.wat" }
     ///   "#init" -> { source: File(""), func: "init", name: None, arg0: "#init" }
     fn entrypoint(&self) -> Entrypoint<'_>;
+
+    /// Returns the annotations from the runtime spec.
+    fn annotations(&self) -> &HashMap<String, String> {
+        &NO_ANNOTATIONS
+    }
+
+    /// Returns the engine settings declared by the image, as described in the
+    /// [`config`](crate::sandbox::config) module.
+    fn engine_config(&self) -> &EngineConfig {
+        &DEFAULT_ENGINE_CONFIG
+    }
+
+    /// Returns the network capabilities granted to the guest, read from the engine config
+    /// and the annotations as described in the [`network`](crate::sandbox::network) module.
+    fn network_policy(&self) -> anyhow::Result<NetworkPolicy> {
+        NetworkPolicy::from_wasi_policy(&self.engine_config().wasi)
+            .with_annotations(self.annotations())
+    }
+
+    /// Returns the handle that is cancelled when the container is asked to stop,
+    /// as described in the [`cancellation`](crate::sandbox::cancellation) module.
+    fn cancellation(&self) -> &Cancellation {
+        &NEVER_CANCELLED
+    }
+
+    /// Returns how the guest is isolated: in a container of its own, or in the shim process
+    /// with the other guests of the shim, where a guest can't be stopped by killing its process.
+    fn isolation(&self) -> Isolation {
+        Isolation::Container
+    }
+
+    /// Returns the host directory to preopen as `/` in the guest.
+    /// It's the root of the container, or the rootfs of the bundle for guests running
+    /// in the shim process.
+    fn rootfs(&self) -> &Path {
+        Path::new("/")
+    }
+
+    /// Returns the files to use as the stdio of the guest, or `None` to inherit the
+    /// stdio of the process, which is already redirected for guests running in a container.
+    fn stdio(&self) -> Option<&Stdio> {
+        None
+    }
+
+    /// Returns the limits of the guest that are updated while it runs, when the resources of
+    /// its container are updated, or `None` if they can't change.
+    /// Only guests running in the shim process have live limits: the guests running in a
+    /// container are limited by its cgroup instead.
+    fn live_limits(&self) -> Option<&Arc<LiveLimits>> {
+        None
+    }
+}
+
+static NO_ANNOTATIONS: LazyLock<HashMap<String, String>> = LazyLock::new(HashMap::new);
+static NEVER_CANCELLED: LazyLock<Cancellation> = LazyLock::new(Cancellation::new);
+static DEFAULT_ENGINE_CONFIG: LazyLock<EngineConfig> = LazyLock::new(EngineConfig::default);
+
+/// The files used as the stdio of a guest running in the shim process.
+///
+/// A missing file means that the stream isn't connected.
+#[derive(Debug, Default)]
+pub struct Stdio {
+    pub stdin: Option<File>,
+    pub stdout: Option<File>,
+    pub stderr: Option<File>,
+}
+
+/// The limits of a guest running in the shim process, updated with the resources of its container.
+///
+/// The memory limit of the container caps the total size of the linear memories of the guest,
+/// across all its stores. The limits only keep the guest from growing further: the memory it
+/// already uses isn't reclaimed when a limit is lowered.
+#[derive(Debug)]
+pub struct LiveLimits {
+    /// The maximum total size of the linear memories, in bytes, `u64::MAX` when unlimited.
+    max_memory_bytes: AtomicU64,
+    /// The total size of the linear memories, in bytes.
+    memory_bytes: AtomicU64,
+}
+
+impl LiveLimits {
+    pub fn new(max_memory_bytes: Option<u64>) -> Self {
+        Self {
+            max_memory_bytes: AtomicU64::new(max_memory_bytes.unwrap_or(u64::MAX)),
+            memory_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the maximum total size of the linear memories, in bytes.
+    pub fn max_memory_bytes(&self) -> Option<u64> {
+        let max = self.max_memory_bytes.load(Ordering::Relaxed);
+        (max != u64::MAX).then_some(max)
+    }
+
+    pub fn set_max_memory_bytes(&self, max: Option<u64>) {
+        self.max_memory_bytes
+            .store(max.unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    /// Returns the total size of the linear memories, in bytes.
+    pub fn memory_bytes(&self) -> u64 {
+        self.memory_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Accounts for `bytes` more of linear memory, unless the total would exceed the maximum.
+    /// Returns whether the memory can grow.
+    pub fn grow_memory(&self, bytes: u64) -> bool {
+        let max = self.max_memory_bytes.load(Ordering::Relaxed);
+        self.memory_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|total| *total <= max)
+            })
+            .is_ok()
+    }
+
+    /// Releases `bytes` of linear memory, when it fails to grow or its store is dropped.
+    pub fn release_memory(&self, bytes: u64) {
+        let _ = self
+            .memory_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used.saturating_sub(bytes))
+            });
+    }
 }
 
 /// The source for a WASI module / components.
@@ -52,8 +201,161 @@ pub enum Source<'a> {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WasmLayer {
     pub config: Descriptor,
-    #[serde(with = "serde_bytes")]
-    pub layer: Vec<u8>,
+    pub layer: LayerContent,
+}
+
+/// The content of a [`WasmLayer`].
+///
+/// The content is either kept in memory or memory mapped from a file.
+/// In both cases it's reference counted, so cloning a `LayerContent` never
+/// duplicates the underlying data.
+/// When sent to another process, file backed content is sent as the path of the
+/// file it keeps open, e.g., `/proc/<pid>/fd/<fd>` on Linux, and mapped again on the
+/// other side. The file is mapped even if it was removed in the meantime, e.g., evicted
+/// from the blob cache, as long as the sender keeps the content alive.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "LayerContentRepr", try_from = "LayerContentRepr")]
+pub struct LayerContent(Arc<LayerContentInner>);
+
+enum LayerContentInner {
+    Bytes(Vec<u8>),
+    File {
+        path: PathBuf,
+        file: File,
+        map: Mmap,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+enum LayerContentRepr {
+    Bytes(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// The path of the file, and the path to open it through, if it differs.
+    File {
+        path: PathBuf,
+        open: Option<PathBuf>,
+    },
+}
+
+impl LayerContent {
+    /// Memory maps the content of the file at `path`.
+    ///
+    /// The file must not be modified while the content is alive.
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path)?;
+        Self::map(path, file)
+    }
+
+    fn map(path: PathBuf, file: File) -> std::io::Result<Self> {
+        // SAFETY: layer files are content addressed and never modified
+        // after being written, which upholds the requirements of `Mmap::map`.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self(Arc::new(LayerContentInner::File { path, file, map })))
+    }
+
+    /// Returns the path of the file backing this content, if any.
+    pub fn path(&self) -> Option<&Path> {
+        match self.0.as_ref() {
+            LayerContentInner::Bytes(_) => None,
+            LayerContentInner::File { path, .. } => Some(path),
+        }
+    }
+}
+
+/// Returns the path the open `file` can be opened again through by another process.
+#[cfg(target_os = "linux")]
+fn open_file_path(file: &File) -> Option<PathBuf> {
+    use std::os::fd::AsRawFd as _;
+    Some(format!("/proc/{}/fd/{}", std::process::id(), file.as_raw_fd()).into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_file_path(_file: &File) -> Option<PathBuf> {
+    None
+}
+
+impl Deref for LayerContent {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.0.as_ref() {
+            LayerContentInner::Bytes(bytes) => &bytes[..],
+            LayerContentInner::File { map, .. } => &map[..],
+        }
+    }
+}
+
+impl AsRef<[u8]> for LayerContent {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Vec<u8>> for LayerContent {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(Arc::new(LayerContentInner::Bytes(bytes)))
+    }
+}
+
+impl From<LayerContent> for LayerContentRepr {
+    fn from(content: LayerContent) -> Self {
+        match content.0.as_ref() {
+            LayerContentInner::Bytes(bytes) => Self::Bytes(bytes.clone()),
+            LayerContentInner::File { path, file, .. } => Self::File {
+                path: path.clone(),
+                open: open_file_path(file),
+            },
+        }
+    }
+}
+
+impl TryFrom<LayerContentRepr> for LayerContent {
+    type Error = std::io::Error;
+
+    fn try_from(repr: LayerContentRepr) -> std::io::Result<Self> {
+        match repr {
+            LayerContentRepr::Bytes(bytes) => Ok(bytes.into()),
+            LayerContentRepr::File { path, open } => {
+                let file = File::open(open.as_ref().unwrap_or(&path))?;
+                Self::map(path, file)
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for LayerContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.as_ref() {
+            LayerContentInner::Bytes(bytes) => write!(f, "LayerContent({} bytes)", bytes.len()),
+            LayerContentInner::File { path, map, .. } => {
+                write!(f, "LayerContent({} bytes at {path:?})", map.len())
+            }
+        }
+    }
+}
+
+impl PartialEq for LayerContent {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl PartialEq<[u8]> for LayerContent {
+    fn eq(&self, other: &[u8]) -> bool {
+        self[..] == *other
+    }
+}
+
+impl PartialEq<Vec<u8>> for LayerContent {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<const N: usize> PartialEq<&[u8; N]> for LayerContent {
+    fn eq(&self, other: &&[u8; N]) -> bool {
+        self[..] == other[..]
+    }
 }
 
 impl<'a> Source<'a> {
@@ -67,7 +369,7 @@ impl<'a> Source<'a> {
                     .context("module not found")?;
                 Ok(Cow::Owned(std::fs::read(path)?))
             }
-            Source::Oci([module]) => Ok(Cow::Borrowed(&module.layer)),
+            Source::Oci([module]) => Ok(Cow::Borrowed(&module.layer[..])),
             Source::Oci(_modules) => {
                 bail!("only a single module is supported when using images with OCI layers")
             }
@@ -90,6 +392,8 @@ pub struct Entrypoint<'a> {
 pub(crate) struct WasiContext<'a> {
     pub spec: &'a Spec,
     pub wasm_layers: &'a [WasmLayer],
+    pub cancellation: &'a Cancellation,
+    pub engine_config: &'a EngineConfig,
 }
 
 impl RuntimeContext for WasiContext<'_> {
@@ -111,6 +415,27 @@ impl RuntimeContext for WasiContext<'_> {
             .unwrap_or_default()
     }
 
+    fn cwd(&self) -> &Path {
+        self.spec
+            .process()
+            .as_ref()
+            .map(|p| p.cwd().as_path())
+            .filter(|cwd| !cwd.as_os_str().is_empty())
+            .unwrap_or(Path::new("/"))
+    }
+
+    fn annotations(&self) -> &HashMap<String, String> {
+        self.spec.annotations().as_ref().unwrap_or(&NO_ANNOTATIONS)
+    }
+
+    fn cancellation(&self) -> &Cancellation {
+        self.cancellation
+    }
+
+    fn engine_config(&self) -> &EngineConfig {
+        self.engine_config
+    }
+
     fn entrypoint(&self) -> Entrypoint<'_> {
         let arg0 = self.args().first();
 
@@ -167,6 +492,45 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_layer_content_from_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("layer");
+        std::fs::write(&path, b"\0asm")?;
+
+        let content = LayerContent::from_file(&path)?;
+        assert_eq!(content, b"\0asm");
+        assert_eq!(content.path(), Some(path.as_path()));
+
+        // file backed content is sent by path, and mapped again when received
+        let json = serde_json::to_string(&content)?;
+        assert!(json.contains("File"));
+        let received: LayerContent = serde_json::from_str(&json)?;
+        assert_eq!(received, content);
+        assert_eq!(received.path(), Some(path.as_path()));
+
+        // the file is opened through the sender, so it can be removed in the meantime
+        if cfg!(target_os = "linux") {
+            std::fs::remove_file(&path)?;
+            let received: LayerContent = serde_json::from_str(&json)?;
+            assert_eq!(received, b"\0asm");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_content_from_bytes() -> Result<()> {
+        let content = LayerContent::from(b"\0asm".to_vec());
+        assert_eq!(content, b"\0asm");
+        assert_eq!(content.path(), None);
+
+        let received: LayerContent = serde_json::from_str(&serde_json::to_string(&content)?)?;
+        assert_eq!(received, content);
+
+        Ok(())
+    }
+
     #[test]
     fn test_get_args() -> Result<()> {
         let spec = SpecBuilder::default()
@@ -182,6 +546,8 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
         };
 
         let args = ctx.args();
@@ -201,6 +567,8 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
         };
 
         let args = ctx.args();
@@ -228,6 +596,8 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
         };
 
         let args = ctx.args();
@@ -249,6 +619,8 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
         };
 
         let path = ctx.entrypoint().source;
@@ -279,6 +651,8 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
         };
 
         let expected_path = PathBuf::from("hello.wat");
@@ -318,6 +692,8 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
         };
 
         let expected_path = PathBuf::from("/root/hello.wat");
@@ -357,6 +733,8 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
         };
 
         let expected_path = PathBuf::from("/root/hello.wat");
@@ -387,13 +765,15 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[WasmLayer {
-                layer: vec![],
+                layer: vec![].into(),
                 config: Descriptor::new(
                     oci_spec::image::MediaType::Other("".to_string()),
                     10,
                     Digest::try_from(format!("sha256:{:064?}", 0))?,
                 ),
             }],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
         };
 
         assert!(matches!(ctx.entrypoint().source, Source::Oci(_)));
@@ -416,6 +796,8 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
         };
 
         let envs = ctx.envs();
@@ -426,6 +808,38 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_get_env() -> Result<()> {
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(
+                ProcessBuilder::default()
+                    .cwd("/")
+                    .env(vec![
+                        "KEY=FIRST".to_string(),
+                        "KEY_LONGER=OTHER".to_string(),
+                        "EMPTY=".to_string(),
+                        "KEY=a=b".to_string(),
+                    ])
+                    .build()?,
+            )
+            .build()?;
+
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
+        };
+
+        assert_eq!(ctx.env("KEY"), Some("a=b"));
+        assert_eq!(ctx.env("EMPTY"), Some(""));
+        assert_eq!(ctx.env("KEY_"), None);
+        assert_eq!(ctx.env("MISSING"), None);
+
+        Ok(())
+    }
+
     #[test]
     fn test_get_envs_return_empty() -> Result<()> {
         let spec = SpecBuilder::default()
@@ -436,6 +850,8 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
         };
 
         let envs = ctx.envs();
@@ -454,6 +870,8 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
         };
 
         let envs = ctx.envs();
@@ -461,4 +879,74 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_get_cwd() -> Result<()> {
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(ProcessBuilder::default().cwd("/app").build()?)
+            .build()?;
+
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
+        };
+        assert_eq!(ctx.cwd(), Path::new("/app"));
+
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .build()?;
+        let ctx = WasiContext { spec: &spec, ..ctx };
+        assert_eq!(ctx.cwd(), Path::new("/"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_network_policy_from_annotations() -> Result<()> {
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(ProcessBuilder::default().cwd("/").build()?)
+            .annotations(HashMap::from([(
+                crate::sandbox::network::UDP_ANNOTATION.to_string(),
+                "false".to_string(),
+            )]))
+            .build()?;
+
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            cancellation: &Cancellation::new(),
+            engine_config: &EngineConfig::default(),
+        };
+
+        assert_eq!(ctx.annotations().len(), 1);
+        let policy = ctx.network_policy()?;
+        assert!(policy.allow_tcp);
+        assert!(!policy.allow_udp);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_live_limits_account_for_all_memories() {
+        let limits = LiveLimits::new(Some(100));
+        assert!(limits.grow_memory(60));
+        assert!(limits.grow_memory(40));
+        assert!(!limits.grow_memory(1));
+        assert_eq!(limits.memory_bytes(), 100);
+
+        limits.release_memory(40);
+        assert!(limits.grow_memory(10));
+
+        // lowering the limit doesn't reclaim the memory, it only prevents growth
+        limits.set_max_memory_bytes(Some(50));
+        assert!(!limits.grow_memory(1));
+        assert_eq!(limits.memory_bytes(), 70);
+
+        limits.set_max_memory_bytes(None);
+        assert!(limits.grow_memory(1 << 40));
+    }
 }
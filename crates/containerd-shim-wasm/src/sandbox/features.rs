@@ -0,0 +1,228 @@
+//! Negotiation of the Wasm proposals used by a container.
+//!
+//! Runtimes that declare their [`FeatureSupport`] with [`Shim::wasm_features`] have the
+//! Wasm binaries of a container validated with [`wasmparser`] before the container is created.
+//! The proposals used by the binaries are compared with those the runtime supports:
+//!
+//! * proposals the runtime enables by default need nothing else,
+//! * proposals the runtime can enable are recorded in the [`Features`] of the
+//!   [`EngineConfig`], so the runtime configures a matching engine,
+//! * and any other proposal fails the creation of the task with an `InvalidArgument`
+//!   error listing the missing proposals.
+//!
+//! Only the proposals that are part of [`Features`] are negotiated, binaries using other
+//! proposals are validated by the runtime when the container starts.
+//!
+//! [`Shim::wasm_features`]: crate::shim::Shim::wasm_features
+//! [`EngineConfig`]: crate::sandbox::config::EngineConfig
+
+use anyhow::{Context, Result, bail};
+use wasmparser::Validator;
+#[doc(inline)]
+pub use wasmparser::WasmFeatures;
+
+use crate::sandbox::config::Features;
+
+/// The Wasm proposals supported by a runtime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeatureSupport {
+    /// The proposals enabled by the default engine of the runtime.
+    pub enabled: WasmFeatures,
+    /// The proposals the runtime can enable when a binary requires them, including `enabled`.
+    pub supported: WasmFeatures,
+}
+
+struct Proposal {
+    name: &'static str,
+    features: WasmFeatures,
+    setting: fn(&mut Features) -> &mut Option<bool>,
+}
+
+const PROPOSALS: [Proposal; 5] = [
+    Proposal {
+        name: "simd",
+        features: WasmFeatures::SIMD.union(WasmFeatures::RELAXED_SIMD),
+        setting: |features| &mut features.simd,
+    },
+    Proposal {
+        name: "threads",
+        features: WasmFeatures::THREADS,
+        setting: |features| &mut features.threads,
+    },
+    Proposal {
+        name: "exceptions",
+        features: WasmFeatures::EXCEPTIONS.union(WasmFeatures::LEGACY_EXCEPTIONS),
+        setting: |features| &mut features.exceptions,
+    },
+    Proposal {
+        name: "memory64",
+        features: WasmFeatures::MEMORY64,
+        setting: |features| &mut features.memory64,
+    },
+    Proposal {
+        name: "gc",
+        features: WasmFeatures::GC.union(WasmFeatures::FUNCTION_REFERENCES),
+        setting: |features| &mut features.gc,
+    },
+];
+
+/// Returns the negotiated proposals used by the wasm binary `bytes`.
+pub fn required_features(bytes: &[u8]) -> Result<WasmFeatures> {
+    let validates = |features| {
+        Validator::new_with_features(features)
+            .validate_all(bytes)
+            .is_ok()
+    };
+
+    let all = WasmFeatures::all();
+    Validator::new_with_features(all)
+        .validate_all(bytes)
+        .context("invalid wasm binary")?;
+
+    let negotiated = PROPOSALS
+        .iter()
+        .fold(WasmFeatures::empty(), |acc, proposal| {
+            acc | proposal.features
+        });
+    if validates(all - negotiated) {
+        return Ok(WasmFeatures::empty());
+    }
+
+    // each flag is removed in turn, the binary doesn't validate without the ones it uses
+    Ok(negotiated
+        .iter()
+        .filter(|feature| !validates(all - *feature))
+        .fold(WasmFeatures::empty(), |acc, feature| acc | feature))
+}
+
+/// Checks that the runtime supports the proposals used by `binaries`, and enables in `features`
+/// the proposals that the default engine of the runtime doesn't enable.
+///
+/// Fails with the list of the proposals the runtime doesn't support, or that `features` disables.
+pub fn negotiate<'a>(
+    binaries: impl IntoIterator<Item = &'a [u8]>,
+    support: &FeatureSupport,
+    features: &mut Features,
+) -> Result<()> {
+    let mut required = WasmFeatures::empty();
+    for bytes in binaries {
+        required |= required_features(bytes)?;
+    }
+
+    let mut missing = vec![];
+    for proposal in &PROPOSALS {
+        let needed = required & proposal.features;
+        let setting = (proposal.setting)(features);
+        match *setting {
+            Some(false) if !needed.is_empty() => {
+                missing.push(format!("{} (disabled by the engine config)", proposal.name));
+            }
+            Some(true) if !support.supported.intersects(proposal.features) => {
+                missing.push(proposal.name.to_string());
+            }
+            _ if !support.supported.contains(needed) => {
+                missing.push(proposal.name.to_string());
+            }
+            None if !support.enabled.contains(needed) => {
+                log::info!(
+                    "enabling the {} proposal required by the container",
+                    proposal.name
+                );
+                *setting = Some(true);
+            }
+            _ => {}
+        }
+    }
+
+    if !missing.is_empty() {
+        bail!(
+            "the runtime doesn't support the wasm features required by the container: {}",
+            missing.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIMD_MODULE: &str = r#"(module
+        (func (result v128) (v128.const i32x4 0 0 0 0))
+    )"#;
+
+    const THREADS_MODULE: &str = r#"(module
+        (memory 1 1 shared)
+    )"#;
+
+    const SUPPORT: FeatureSupport = FeatureSupport {
+        enabled: WasmFeatures::SIMD,
+        supported: WasmFeatures::SIMD.union(WasmFeatures::THREADS),
+    };
+
+    #[test]
+    fn test_required_features() -> Result<()> {
+        let module = wat::parse_str("(module)")?;
+        assert_eq!(required_features(&module)?, WasmFeatures::empty());
+
+        let module = wat::parse_str(SIMD_MODULE)?;
+        assert_eq!(required_features(&module)?, WasmFeatures::SIMD);
+
+        let module = wat::parse_str(THREADS_MODULE)?;
+        assert_eq!(required_features(&module)?, WasmFeatures::THREADS);
+
+        assert!(required_features(b"\0asm\x01\0\0\0\xff").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_negotiate_enables_supported_features() -> Result<()> {
+        let simd = wat::parse_str(SIMD_MODULE)?;
+        let threads = wat::parse_str(THREADS_MODULE)?;
+
+        let mut features = Features::default();
+        negotiate([simd.as_slice()], &SUPPORT, &mut features)?;
+        assert!(features.is_default());
+
+        negotiate(
+            [simd.as_slice(), threads.as_slice()],
+            &SUPPORT,
+            &mut features,
+        )?;
+        assert_eq!(features.threads, Some(true));
+        assert_eq!(features.simd, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_negotiate_lists_missing_features() -> Result<()> {
+        let simd = wat::parse_str(SIMD_MODULE)?;
+        let threads = wat::parse_str(THREADS_MODULE)?;
+        let support = FeatureSupport {
+            enabled: WasmFeatures::empty(),
+            supported: WasmFeatures::empty(),
+        };
+
+        let err = negotiate(
+            [simd.as_slice(), threads.as_slice()],
+            &support,
+            &mut Features::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().ends_with(": simd, threads"), "{err}");
+
+        let mut features = Features {
+            threads: Some(false),
+            gc: Some(true),
+            ..Default::default()
+        };
+        let err = negotiate([threads.as_slice()], &SUPPORT, &mut features).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with(": threads (disabled by the engine config), gc"),
+            "{err}"
+        );
+        Ok(())
+    }
+}
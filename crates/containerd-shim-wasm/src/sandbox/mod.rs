@@ -2,23 +2,69 @@ use std::fs::File;
 use std::io::Read;
 
 use anyhow::{Context, Result};
-use context::{RuntimeContext, Source};
+use context::{RuntimeContext, Source, WasmBinaryType};
 use path::PathResolve as _;
 
+pub mod cancellation;
+pub mod config;
 pub mod context;
+pub mod features;
+pub mod network;
 pub(crate) mod path;
+pub mod process;
+pub mod rootfs;
 
 #[trait_variant::make(Send)]
-pub trait Sandbox: Default + 'static {
+pub trait Sandbox: Default + Send + Sync + 'static {
     /// Run a WebAssembly container
     async fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32>;
 
+    /// Whether the runtime can run wasm components, in addition to core wasm modules.
+    /// Runtimes that return `false` have components rejected by [`Sandbox::can_handle`]
+    /// with an [`UnsupportedError`].
+    fn supports_components() -> bool {
+        true
+    }
+
+    /// Whether the runtime can run guests in the shim process, when the runtime handler
+    /// selects the `in-process` isolation.
+    /// Runtimes that return `true` must preopen [`RuntimeContext::rootfs`] instead of `/`,
+    /// use the [`RuntimeContext::stdio`] files, and run concurrent guests on the same sandbox.
+    fn supports_in_process() -> bool {
+        false
+    }
+
+    /// Whether the runtime enforces [`RuntimeContext::live_limits`] while the guest runs,
+    /// so the resources of containers running in the shim process can be updated.
+    /// Runtimes that return `true` must account for all the linear memories of the guest
+    /// with [`LiveLimits::grow_memory`](context::LiveLimits::grow_memory), and release them
+    /// when they fail to grow or are dropped.
+    fn supports_live_limits() -> bool {
+        false
+    }
+
+    /// Whether the runtime stops the guest when its [`RuntimeContext::cancellation`] is
+    /// cancelled, as described in the [`cancellation`] module.
+    /// Containers of runtimes that return `false` are stopped right away by a termination signal.
+    fn supports_cancellation() -> bool {
+        false
+    }
+
+    /// Whether the runtime starts the guest in [`RuntimeContext::cwd`], instead of `/`.
+    /// Containers with another working directory are reported by
+    /// [`validate_process`](process::validate_process) for runtimes that return `false`.
+    fn supports_cwd() -> bool {
+        false
+    }
+
     /// Check that the runtime can run the container.
     /// This checks runs after the container creation and before the container starts.
     /// By default it checks that the wasi_entrypoint is either:
     /// * a OCI image with wasm layers
     /// * a file with the `wasm` filetype header
     /// * a parsable `wat` file.
+    ///
+    /// and that it isn't a component if the runtime doesn't [support components](Sandbox::supports_components).
     async fn can_handle(&self, ctx: &impl RuntimeContext) -> Result<()> {
         // this async block is required to make the rewrite of trait_variant happy
         async move {
@@ -26,22 +72,66 @@ pub trait Sandbox: Default + 'static {
 
             let path = match source {
                 Source::File(path) => path,
-                Source::Oci(_) => return Ok(()),
+                Source::Oci(layers) => {
+                    if !Self::supports_components() {
+                        for layer in layers {
+                            ensure_module(&layer.layer)?;
+                        }
+                    }
+                    return Ok(());
+                }
             };
 
             path.resolve_in_path_or_cwd()
                 .next()
                 .context("module not found")?;
 
-            let mut buffer = [0; 4];
-            File::open(&path)?.read_exact(&mut buffer)?;
+            // the magic number and the version, which tells modules and components apart
+            let mut buffer = Vec::with_capacity(8);
+            File::open(&path)?.take(8).read_to_end(&mut buffer)?;
 
-            if buffer.as_slice() != b"\0asm" {
+            if !buffer.starts_with(b"\0asm") {
                 // Check if this is a `.wat` file
                 wat::parse_file(&path)?;
+            } else if !Self::supports_components() {
+                ensure_module(&buffer)?;
             }
 
             Ok(())
         }
     }
 }
+
+/// Error returned for a wasm binary that the runtime can't run.
+#[derive(Debug, thiserror::Error)]
+pub enum UnsupportedError {
+    /// The binary is a component, but the runtime can only run core wasm modules.
+    #[error("wasm components are not supported by this runtime, only core wasm modules are")]
+    Component,
+}
+
+/// Returns an [`UnsupportedError`] if `bytes` is a wasm component.
+/// Runtimes that only run core wasm modules can use this before compiling `bytes`.
+pub fn ensure_module(bytes: &[u8]) -> Result<(), UnsupportedError> {
+    match WasmBinaryType::from_bytes(bytes) {
+        Some(WasmBinaryType::Component) => Err(UnsupportedError::Component),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_module() -> Result<()> {
+        let module = wat::parse_str("(module)")?;
+        ensure_module(&module)?;
+
+        let component = wat::parse_str("(component)")?;
+        let err = ensure_module(&component).unwrap_err();
+        assert!(matches!(err, UnsupportedError::Component));
+
+        Ok(())
+    }
+}
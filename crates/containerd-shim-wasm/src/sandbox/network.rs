@@ -0,0 +1,144 @@
+//! Network capabilities granted to a Wasm guest.
+//!
+//! The capabilities are configured per container through annotations, so that
+//! every runtime applies the same policy:
+//!
+//! * [`TCP_ANNOTATION`]: whether the guest can open TCP sockets.
+//! * [`UDP_ANNOTATION`]: whether the guest can open UDP sockets.
+//! * [`DNS_ANNOTATION`]: whether the guest can resolve host names.
+//!
+//! Each annotation accepts `true` or `false`. Everything is allowed by default,
+//! unless the [engine config](crate::sandbox::config) of the image denies it.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use crate::sandbox::config::WasiPolicy;
+
+/// Annotation to allow or deny TCP sockets.
+pub const TCP_ANNOTATION: &str = "runwasi.io/network.tcp";
+/// Annotation to allow or deny UDP sockets.
+pub const UDP_ANNOTATION: &str = "runwasi.io/network.udp";
+/// Annotation to allow or deny host name resolution.
+pub const DNS_ANNOTATION: &str = "runwasi.io/network.dns";
+
+/// The network capabilities of a Wasm guest, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetworkPolicy {
+    /// The guest can connect to and listen on TCP sockets.
+    pub allow_tcp: bool,
+    /// The guest can bind UDP sockets.
+    pub allow_udp: bool,
+    /// The guest can resolve host names.
+    pub allow_ip_name_lookup: bool,
+}
+
+impl Default for NetworkPolicy {
+    fn default() -> Self {
+        Self {
+            allow_tcp: true,
+            allow_udp: true,
+            allow_ip_name_lookup: true,
+        }
+    }
+}
+
+impl NetworkPolicy {
+    /// Reads the policy from the container annotations.
+    pub fn from_annotations(annotations: &HashMap<String, String>) -> Result<Self> {
+        Self::default().with_annotations(annotations)
+    }
+
+    /// Returns the policy declared in the engine config of the image.
+    pub fn from_wasi_policy(wasi: &WasiPolicy) -> Self {
+        let default = Self::default();
+        Self {
+            allow_tcp: wasi.tcp.unwrap_or(default.allow_tcp),
+            allow_udp: wasi.udp.unwrap_or(default.allow_udp),
+            allow_ip_name_lookup: wasi.dns.unwrap_or(default.allow_ip_name_lookup),
+        }
+    }
+
+    /// Overrides the policy with the container annotations.
+    pub fn with_annotations(self, annotations: &HashMap<String, String>) -> Result<Self> {
+        let flag = |key: &str, default: bool| -> Result<bool> {
+            annotations.get(key).map_or(Ok(default), |value| {
+                value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid value {value:?} for annotation {key}"))
+            })
+        };
+
+        Ok(Self {
+            allow_tcp: flag(TCP_ANNOTATION, self.allow_tcp)?,
+            allow_udp: flag(UDP_ANNOTATION, self.allow_udp)?,
+            allow_ip_name_lookup: flag(DNS_ANNOTATION, self.allow_ip_name_lookup)?,
+        })
+    }
+
+    /// Whether the guest can use any kind of socket.
+    pub fn allows_sockets(&self) -> bool {
+        self.allow_tcp || self.allow_udp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_policy_defaults() -> Result<()> {
+        let policy = NetworkPolicy::from_annotations(&HashMap::new())?;
+        assert_eq!(policy, NetworkPolicy::default());
+        assert!(policy.allows_sockets());
+        Ok(())
+    }
+
+    #[test]
+    fn test_network_policy_from_annotations() -> Result<()> {
+        let annotations = HashMap::from([
+            (TCP_ANNOTATION.to_string(), "false".to_string()),
+            (DNS_ANNOTATION.to_string(), " false ".to_string()),
+        ]);
+
+        let policy = NetworkPolicy::from_annotations(&annotations)?;
+        assert_eq!(
+            policy,
+            NetworkPolicy {
+                allow_tcp: false,
+                allow_udp: true,
+                allow_ip_name_lookup: false,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_annotations_override_wasi_policy() -> Result<()> {
+        let wasi = WasiPolicy {
+            tcp: Some(false),
+            udp: Some(false),
+            dns: None,
+        };
+        let annotations = HashMap::from([(TCP_ANNOTATION.to_string(), "true".to_string())]);
+
+        let policy = NetworkPolicy::from_wasi_policy(&wasi).with_annotations(&annotations)?;
+        assert_eq!(
+            policy,
+            NetworkPolicy {
+                allow_tcp: true,
+                allow_udp: false,
+                allow_ip_name_lookup: true,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_network_policy_invalid_annotation() {
+        let annotations = HashMap::from([(UDP_ANNOTATION.to_string(), "nope".to_string())]);
+        assert!(NetworkPolicy::from_annotations(&annotations).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+//! Validation of the process of the runtime spec of a Wasm container.
+//!
+//! Only some of the fields of the process make sense for a Wasm guest. The others are applied
+//! to the Linux container the guest runs in by libcontainer, or ignored when the guest runs in
+//! the shim process. [`validate_process`] reports which of the fields set for a container are
+//! honored, and [`ProcessReport::warn_ignored`] logs the ignored ones, so the containers relying
+//! on them aren't silently run without them:
+//!
+//! | field                          | `container`                     | `in-process`                    |
+//! |--------------------------------|---------------------------------|---------------------------------|
+//! | `args`, `env`, `terminal`      | honored                         | honored                         |
+//! | `cwd`                          | [`Sandbox::supports_cwd`]       | [`Sandbox::supports_cwd`]       |
+//! | `user`, `rlimits`, `noNewPrivileges` | honored                     | if the shim already applies them |
+//! | `capabilities`, `apparmorProfile`, `selinuxLabel`, `oomScoreAdj`, `ioPriority`, `scheduler` | honored | ignored |
+//! | `consoleSize`                  | ignored                         | ignored                         |
+//!
+//! A guest running in the shim process gets the user, the rlimits and the `no_new_privs` flag
+//! of the shim, which are shared by all the guests of the process, so they can't be applied per
+//! container. They're only reported as honored when the shim already runs as the `user`, within
+//! the `rlimits`, or with the flag set. containerd sets `noNewPrivileges` and `RLIMIT_NOFILE` in
+//! the spec of every container, so they're usually reported as ignored for these guests.
+//!
+//! The size of the console is set by containerd with the `resize_pty` request instead.
+
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+use containerd_shimkit::sandbox::Isolation;
+use oci_spec::runtime::{Process, Spec};
+
+use crate::sandbox::Sandbox;
+
+/// A field of the process of the runtime spec.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessField {
+    Args,
+    Env,
+    Cwd,
+    Terminal,
+    ConsoleSize,
+    User,
+    Rlimits,
+    NoNewPrivileges,
+    Capabilities,
+    ApparmorProfile,
+    SelinuxLabel,
+    OomScoreAdj,
+    IoPriority,
+    Scheduler,
+}
+
+impl ProcessField {
+    /// Returns the name of the field in the runtime spec.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Args => "args",
+            Self::Env => "env",
+            Self::Cwd => "cwd",
+            Self::Terminal => "terminal",
+            Self::ConsoleSize => "consoleSize",
+            Self::User => "user",
+            Self::Rlimits => "rlimits",
+            Self::NoNewPrivileges => "noNewPrivileges",
+            Self::Capabilities => "capabilities",
+            Self::ApparmorProfile => "apparmorProfile",
+            Self::SelinuxLabel => "selinuxLabel",
+            Self::OomScoreAdj => "oomScoreAdj",
+            Self::IoPriority => "ioPriority",
+            Self::Scheduler => "scheduler",
+        }
+    }
+
+    /// Whether the field is set in the `process`, to a value other than its default.
+    fn is_set(self, process: &Process) -> bool {
+        match self {
+            Self::Args => process.args().as_ref().is_some_and(|args| !args.is_empty()),
+            Self::Env => process.env().as_ref().is_some_and(|env| !env.is_empty()),
+            Self::Cwd => !is_root(process.cwd()),
+            Self::Terminal => matches!(process.terminal(), Some(true)),
+            Self::ConsoleSize => process.console_size().is_some(),
+            Self::User => {
+                let user = process.user();
+                user.uid() != 0
+                    || user.gid() != 0
+                    || user.umask().is_some()
+                    || user
+                        .additional_gids()
+                        .as_ref()
+                        .is_some_and(|gids| !gids.is_empty())
+            }
+            Self::Rlimits => process
+                .rlimits()
+                .as_ref()
+                .is_some_and(|rlimits| !rlimits.is_empty()),
+            Self::NoNewPrivileges => matches!(process.no_new_privileges(), Some(true)),
+            Self::Capabilities => process.capabilities().is_some(),
+            Self::ApparmorProfile => process.apparmor_profile().is_some(),
+            Self::SelinuxLabel => process.selinux_label().is_some(),
+            Self::OomScoreAdj => process.oom_score_adj().is_some(),
+            Self::IoPriority => process.io_priority().is_some(),
+            Self::Scheduler => process.scheduler().is_some(),
+        }
+    }
+
+    /// Whether the field is honored for a guest of `S` with the `isolation`.
+    fn is_honored<S: Sandbox>(self, process: &Process, isolation: Isolation) -> bool {
+        if isolation == Isolation::InProcess {
+            match self {
+                Self::User => return shim::runs_as(process.user()),
+                Self::Rlimits => return shim::runs_within(process.rlimits()),
+                Self::NoNewPrivileges => return shim::has_no_new_privs(),
+                _ => {}
+            }
+        }
+        match self {
+            Self::Args | Self::Env | Self::Terminal => true,
+            Self::Cwd => S::supports_cwd(),
+            Self::ConsoleSize => false,
+            // applied by libcontainer to the init process of the container
+            Self::User
+            | Self::Rlimits
+            | Self::NoNewPrivileges
+            | Self::Capabilities
+            | Self::ApparmorProfile
+            | Self::SelinuxLabel
+            | Self::OomScoreAdj
+            | Self::IoPriority
+            | Self::Scheduler => isolation == Isolation::Container,
+        }
+    }
+}
+
+impl Display for ProcessField {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+const FIELDS: [ProcessField; 14] = [
+    ProcessField::Args,
+    ProcessField::Env,
+    ProcessField::Cwd,
+    ProcessField::Terminal,
+    ProcessField::ConsoleSize,
+    ProcessField::User,
+    ProcessField::Rlimits,
+    ProcessField::NoNewPrivileges,
+    ProcessField::Capabilities,
+    ProcessField::ApparmorProfile,
+    ProcessField::SelinuxLabel,
+    ProcessField::OomScoreAdj,
+    ProcessField::IoPriority,
+    ProcessField::Scheduler,
+];
+
+/// The fields set in the process of a container, split by whether they're honored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProcessReport {
+    pub honored: Vec<ProcessField>,
+    pub ignored: Vec<ProcessField>,
+}
+
+impl ProcessReport {
+    /// Logs a warning listing the ignored fields of the container `id`, if any.
+    pub fn warn_ignored(&self, id: &str) {
+        if self.ignored.is_empty() {
+            return;
+        }
+        let ignored: Vec<_> = self.ignored.iter().map(|field| field.name()).collect();
+        log::warn!(
+            "the process fields {} of container {id} are ignored for Wasm guests",
+            ignored.join(", ")
+        );
+    }
+}
+
+/// Reports which of the fields set in the process of the `spec` are honored for a guest
+/// of `S`, running with the `isolation`, as described in the [module documentation](self).
+pub fn validate_process<S: Sandbox>(spec: &Spec, isolation: Isolation) -> ProcessReport {
+    let mut report = ProcessReport::default();
+    let Some(process) = spec.process() else {
+        return report;
+    };
+    for field in FIELDS {
+        if !field.is_set(process) {
+            continue;
+        }
+        if field.is_honored::<S>(process, isolation) {
+            report.honored.push(field);
+        } else {
+            report.ignored.push(field);
+        }
+    }
+    report
+}
+
+fn is_root(path: &Path) -> bool {
+    path.as_os_str().is_empty() || path == Path::new("/")
+}
+
+/// The settings of the shim process, which a guest running in it inherits.
+#[cfg(target_os = "linux")]
+mod shim {
+    use oci_spec::runtime::{PosixRlimit, PosixRlimitType, User};
+
+    /// Whether the shim runs as the `user`.
+    pub fn runs_as(user: &User) -> bool {
+        // SAFETY: these calls can't fail
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        user.uid() == uid
+            && user.gid() == gid
+            && user.umask().is_none()
+            && user
+                .additional_gids()
+                .as_ref()
+                .is_none_or(|gids| gids.is_empty())
+    }
+
+    /// Whether the limits of the shim are at most the `rlimits`.
+    pub fn runs_within(rlimits: &Option<Vec<PosixRlimit>>) -> bool {
+        rlimits.iter().flatten().all(|rlimit| {
+            let resource = match rlimit.typ() {
+                PosixRlimitType::RlimitCpu => libc::RLIMIT_CPU,
+                PosixRlimitType::RlimitFsize => libc::RLIMIT_FSIZE,
+                PosixRlimitType::RlimitData => libc::RLIMIT_DATA,
+                PosixRlimitType::RlimitStack => libc::RLIMIT_STACK,
+                PosixRlimitType::RlimitCore => libc::RLIMIT_CORE,
+                PosixRlimitType::RlimitRss => libc::RLIMIT_RSS,
+                PosixRlimitType::RlimitNproc => libc::RLIMIT_NPROC,
+                PosixRlimitType::RlimitNofile => libc::RLIMIT_NOFILE,
+                PosixRlimitType::RlimitMemlock => libc::RLIMIT_MEMLOCK,
+                PosixRlimitType::RlimitAs => libc::RLIMIT_AS,
+                PosixRlimitType::RlimitLocks => libc::RLIMIT_LOCKS,
+                PosixRlimitType::RlimitSigpending => libc::RLIMIT_SIGPENDING,
+                PosixRlimitType::RlimitMsgqueue => libc::RLIMIT_MSGQUEUE,
+                PosixRlimitType::RlimitNice => libc::RLIMIT_NICE,
+                PosixRlimitType::RlimitRtprio => libc::RLIMIT_RTPRIO,
+                PosixRlimitType::RlimitRttime => libc::RLIMIT_RTTIME,
+            };
+            let mut current = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            // SAFETY: `current` is a valid rlimit to write to
+            if unsafe { libc::getrlimit(resource, &mut current) } != 0 {
+                return false;
+            }
+            // RLIM_INFINITY is the largest value of rlim_t
+            current.rlim_cur <= rlimit.soft() && current.rlim_max <= rlimit.hard()
+        })
+    }
+
+    /// Whether the `no_new_privs` flag of the shim is set.
+    pub fn has_no_new_privs() -> bool {
+        // SAFETY: PR_GET_NO_NEW_PRIVS only returns the flag
+        unsafe { libc::prctl(libc::PR_GET_NO_NEW_PRIVS, 0, 0, 0, 0) == 1 }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod shim {
+    use oci_spec::runtime::{PosixRlimit, User};
+
+    pub fn runs_as(_user: &User) -> bool {
+        false
+    }
+
+    pub fn runs_within(_rlimits: &Option<Vec<PosixRlimit>>) -> bool {
+        false
+    }
+
+    pub fn has_no_new_privs() -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use oci_spec::runtime::{
+        PosixRlimitBuilder, PosixRlimitType, ProcessBuilder, SpecBuilder, UserBuilder,
+    };
+
+    use super::*;
+    use crate::sandbox::context::RuntimeContext;
+
+    #[derive(Default)]
+    struct CwdSandbox;
+
+    impl Sandbox for CwdSandbox {
+        async fn run_wasi(&self, _ctx: &impl RuntimeContext) -> Result<i32> {
+            Ok(0)
+        }
+
+        fn supports_cwd() -> bool {
+            true
+        }
+    }
+
+    fn spec() -> Result<Spec> {
+        let rlimit = PosixRlimitBuilder::default()
+            .typ(PosixRlimitType::RlimitNofile)
+            .hard(0u64)
+            .soft(0u64)
+            .build()?;
+        let process = ProcessBuilder::default()
+            .args(vec!["/app.wasm".to_string()])
+            .cwd("/app")
+            .rlimits(vec![rlimit])
+            .no_new_privileges(true)
+            .build()?;
+        Ok(SpecBuilder::default().process(process).build()?)
+    }
+
+    #[test]
+    fn test_validate_process_in_container() -> Result<()> {
+        let report = validate_process::<CwdSandbox>(&spec()?, Isolation::Container);
+        assert!(report.ignored.is_empty(), "{report:?}");
+        assert!(report.honored.contains(&ProcessField::Cwd));
+        assert!(report.honored.contains(&ProcessField::Rlimits));
+        assert!(report.honored.contains(&ProcessField::NoNewPrivileges));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_process_in_process() -> Result<()> {
+        let report = validate_process::<CwdSandbox>(&spec()?, Isolation::InProcess);
+        assert!(report.honored.contains(&ProcessField::Args));
+        assert!(report.honored.contains(&ProcessField::Cwd));
+        assert!(report.ignored.contains(&ProcessField::Rlimits));
+        if !shim::has_no_new_privs() {
+            assert!(report.ignored.contains(&ProcessField::NoNewPrivileges));
+        }
+        assert!(!report.ignored.contains(&ProcessField::User));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_default_process_in_process() -> Result<()> {
+        // the default process has the `noNewPrivileges`, rlimits and capabilities set by containerd
+        let spec = SpecBuilder::default()
+            .process(ProcessBuilder::default().build()?)
+            .build()?;
+        let report = validate_process::<CwdSandbox>(&spec, Isolation::InProcess);
+        assert!(report.ignored.contains(&ProcessField::Capabilities));
+        report.warn_ignored("container");
+
+        let report = validate_process::<CwdSandbox>(&spec, Isolation::Container);
+        assert!(report.ignored.is_empty(), "{report:?}");
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_validate_process_in_process_as_the_shim() -> Result<()> {
+        let rlimit = PosixRlimitBuilder::default()
+            .typ(PosixRlimitType::RlimitNofile)
+            .hard(u64::MAX)
+            .soft(u64::MAX)
+            .build()?;
+        // SAFETY: these calls can't fail
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let process = ProcessBuilder::default()
+            .args(vec!["/app.wasm".to_string()])
+            .user(UserBuilder::default().uid(uid).gid(gid).build()?)
+            .rlimits(vec![rlimit])
+            .no_new_privileges(false)
+            .build()?;
+        let spec = SpecBuilder::default().process(process).build()?;
+        let report = validate_process::<CwdSandbox>(&spec, Isolation::InProcess);
+        assert!(!report.ignored.contains(&ProcessField::User), "{report:?}");
+        assert!(
+            !report.ignored.contains(&ProcessField::Rlimits),
+            "{report:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_process_without_cwd_support() -> Result<()> {
+        #[derive(Default)]
+        struct NoCwdSandbox;
+
+        impl Sandbox for NoCwdSandbox {
+            async fn run_wasi(&self, _ctx: &impl RuntimeContext) -> Result<i32> {
+                Ok(0)
+            }
+        }
+
+        let report = validate_process::<NoCwdSandbox>(&spec()?, Isolation::Container);
+        assert_eq!(report.ignored, [ProcessField::Cwd]);
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+//! Access to the root file system of a container from the shim.
+//!
+//! The rootfs is controlled by the image, so the paths the shim reads in it, e.g., the
+//! entrypoint of a container running in the shim process, are resolved inside it with
+//! [`cap_std`]: paths whose `..` components or symlinks would resolve outside of the rootfs
+//! fail to open instead of referring to a file of the host. Absolute symlinks are resolved
+//! from the root of the host by the kernel, so they are rejected as well.
+//!
+//! Directories of the rootfs can be opened as a [`Rootfs`] of their own, to give their
+//! resolved host path to APIs that only take paths, or to read their files with [`Rootfs::dir`].
+
+use std::io::{self, Read as _};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result, bail, ensure};
+use cap_std::ambient_authority;
+use cap_std::fs::Dir;
+
+/// The root file system of a container, see the [module documentation](self).
+pub struct Rootfs {
+    dir: Dir,
+    path: PathBuf,
+}
+
+impl Rootfs {
+    /// Opens the rootfs at the host `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let dir = Dir::open_ambient_dir(path, ambient_authority())
+            .with_context(|| format!("failed to open rootfs {path:?}"))?;
+        Ok(Self {
+            dir,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// The host path of the rootfs.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The directory of the rootfs, to open its files relative to it.
+    pub fn dir(&self) -> &Dir {
+        &self.dir
+    }
+
+    /// Opens the directory at `path` in the rootfs.
+    ///
+    /// The [`path`](Self::path) of the returned directory is its host path, with the symlinks
+    /// resolved inside the rootfs.
+    pub fn open_dir(&self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let relative = relative(path)?;
+        let dir = self
+            .dir
+            .open_dir(relative)
+            .with_context(|| format!("failed to open {path:?} in the rootfs"))?;
+        let path = resolved_path(&dir).unwrap_or_else(|| self.path.join(relative));
+        Ok(Self { dir, path })
+    }
+
+    /// Reads the regular file at `path` in the rootfs, which must not be larger than `max_len`.
+    ///
+    /// Fails with an [`io::ErrorKind::NotFound`] error if the file doesn't exist.
+    pub fn read_file(&self, path: impl AsRef<Path>, max_len: u64) -> Result<Vec<u8>> {
+        let path = path.as_ref();
+        let file = self
+            .dir
+            .open(relative(path)?)
+            .with_context(|| format!("failed to open {path:?} in the rootfs"))?;
+
+        let metadata = file.metadata()?;
+        ensure!(metadata.is_file(), "{path:?} isn't a regular file");
+        ensure!(
+            metadata.len() <= max_len,
+            "{path:?} is larger than {max_len} bytes"
+        );
+
+        // the file can grow after its metadata is read
+        let mut content = Vec::with_capacity(metadata.len() as usize);
+        file.take(max_len + 1).read_to_end(&mut content)?;
+        ensure!(
+            content.len() as u64 <= max_len,
+            "{path:?} is larger than {max_len} bytes"
+        );
+        Ok(content)
+    }
+}
+
+/// Returns `path`, relative to the root of the rootfs.
+fn relative(path: &Path) -> Result<&Path> {
+    let relative = path.strip_prefix("/").unwrap_or(path);
+    if relative.as_os_str().is_empty() {
+        return Ok(Path::new("."));
+    }
+    if relative
+        .components()
+        .any(|component| matches!(component, Component::Prefix(_) | Component::RootDir))
+    {
+        bail!("invalid path {path:?}");
+    }
+    Ok(relative)
+}
+
+/// Returns the host path of an open `dir`, if the platform can tell it.
+#[cfg(target_os = "linux")]
+fn resolved_path(dir: &Dir) -> Option<PathBuf> {
+    use std::os::fd::AsRawFd as _;
+    std::fs::read_link(format!("/proc/self/fd/{}", dir.as_raw_fd())).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resolved_path(_dir: &Dir) -> Option<PathBuf> {
+    None
+}
+
+/// Returns `true` if the error of a [`Rootfs`] method is caused by a missing file.
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|err| err.downcast_ref::<io::Error>())
+        .any(|err| err.kind() == io::ErrorKind::NotFound)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    fn rootfs() -> Result<(tempfile::TempDir, Rootfs)> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir_all(dir.path().join("rootfs/app"))?;
+        std::fs::write(dir.path().join("rootfs/app/main.wasm"), b"\0asm")?;
+        std::fs::write(dir.path().join("secret"), b"secret")?;
+        let rootfs = Rootfs::open(dir.path().join("rootfs"))?;
+        Ok((dir, rootfs))
+    }
+
+    #[test]
+    fn test_read_file() -> Result<()> {
+        let (_dir, rootfs) = rootfs()?;
+        assert_eq!(rootfs.read_file("/app/main.wasm", 4)?, b"\0asm");
+        assert_eq!(rootfs.read_file("app/main.wasm", 4)?, b"\0asm");
+        assert!(rootfs.read_file("/app/main.wasm", 3).is_err());
+        assert!(rootfs.read_file("/app", 1024).is_err());
+
+        let err = rootfs.read_file("/app/missing.wasm", 1024).unwrap_err();
+        assert!(is_not_found(&err), "{err:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_file_stays_in_the_rootfs() -> Result<()> {
+        let (dir, rootfs) = rootfs()?;
+        symlink("../../secret", rootfs.path().join("app/relative"))?;
+        symlink(
+            dir.path().join("secret"),
+            rootfs.path().join("app/absolute"),
+        )?;
+
+        for path in [
+            "/../secret",
+            "/app/../../secret",
+            "/app/relative",
+            "/app/absolute",
+        ] {
+            assert!(rootfs.read_file(path, 1024).is_err(), "{path} was read");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_dir_stays_in_the_rootfs() -> Result<()> {
+        let (dir, rootfs) = rootfs()?;
+        symlink("../app", rootfs.path().join("app/link"))?;
+        symlink("../..", rootfs.path().join("app/relative"))?;
+        symlink(dir.path(), rootfs.path().join("app/absolute"))?;
+
+        let app = rootfs.open_dir("/app/link")?;
+        if cfg!(target_os = "linux") {
+            assert_eq!(app.path(), rootfs.path().canonicalize()?.join("app"));
+        }
+        assert_eq!(app.read_file("main.wasm", 4)?, b"\0asm");
+
+        for path in ["/..", "/app/../..", "/app/relative", "/app/absolute"] {
+            assert!(rootfs.open_dir(path).is_err(), "{path} was opened");
+        }
+        Ok(())
+    }
+}
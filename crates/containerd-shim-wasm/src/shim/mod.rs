@@ -61,7 +61,7 @@ mod shim;
 pub(crate) use instance::Instance;
 pub use shim::{Compiler, Shim, Version};
 
-use crate::sys::container::instance;
+use crate::sys::instance;
 
 #[cfg(test)]
 mod tests;
@@ -73,6 +73,8 @@ pub(crate) use shim::NO_COMPILER;
 pub(crate) mod cli;
 
 pub use cli::Cli;
+#[doc(inline)]
+pub use containerd_shimkit::sandbox::Isolation;
 pub use containerd_shimkit::shim_version as version;
 
 /// Config of shim binary options provided by shim implementations
@@ -5,7 +5,9 @@ use anyhow::Result;
 pub use containerd_shimkit::sandbox::cli::Version;
 
 use crate::sandbox::Sandbox;
+use crate::sandbox::config::EngineConfig;
 use crate::sandbox::context::WasmLayer;
+use crate::sandbox::features::FeatureSupport;
 
 /// The `Shim` trait provides a simplified API for running WebAssembly containers.
 ///
@@ -36,13 +38,36 @@ pub trait Shim: Sync + 'static {
     /// The default implementation returns the OCI layer type 'application/vnd.bytecodealliance.wasm.component.layer.v0+wasm'
     /// for WASM modules which can be contain with wasip1 or wasip2 components.
     /// Runtimes can override this to support other layer types
-    /// such as lays that contain runtime specific configuration
+    /// such as lays that contain runtime specific configuration.
+    /// The engine config layer, see [`crate::sandbox::config`], is always supported.
     fn supported_layers_types() -> &'static [&'static str] {
         &[
             "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm",
             "application/wasm",
         ]
     }
+
+    /// Return the OS names of the `wasm` platforms supported by the runtime, in order of preference.
+    /// This is used to select a manifest when the image is a multi-platform image index.
+    /// The default implementation only supports WASI preview 1 modules.
+    /// Runtimes that can run components should prepend `wasip2`.
+    fn supported_platform_os() -> &'static [&'static str] {
+        &["wasip1", "wasi"]
+    }
+
+    /// Return the `os.features` supported by the runtime.
+    /// Manifests of an image index that require other features are not selected.
+    fn supported_platform_features() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Return the Wasm proposals supported by the runtime.
+    /// When `Some`, the Wasm binaries of a container are validated before the container is created,
+    /// and the proposals they require are negotiated as described in [`crate::sandbox::features`].
+    /// The default implementation returns `None`, and the runtime validates the binaries when the container starts.
+    fn wasm_features() -> Option<FeatureSupport> {
+        None
+    }
 }
 
 #[trait_variant::make(Send)]
@@ -64,6 +89,25 @@ pub trait Compiler: Sync {
     /// The runtime is expected to return the same number of layers passed in, if the layer cannot be precompiled it should return `None` for that layer.
     /// In some edge cases it is possible that the layers may already be precompiled and None should be returned in this case.
     async fn compile(&self, _layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>>;
+
+    /// `compile_with_config` precompiles the layers of an image with an engine config layer,
+    /// see [`crate::sandbox::config`], so they match the engine the runtime creates for the image.
+    /// The precompiled layers are cached with a key that includes the engine config.
+    /// The default implementation only precompiles the layers of images using the default config,
+    /// and returns `None` for every layer otherwise.
+    async fn compile_with_config(
+        &self,
+        layers: &[WasmLayer],
+        config: &EngineConfig,
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        async move {
+            if *config == EngineConfig::default() {
+                self.compile(layers).await
+            } else {
+                Ok(vec![None; layers.len()])
+            }
+        }
+    }
 }
 
 /// Like the unstable never type, this type can never be constructed.
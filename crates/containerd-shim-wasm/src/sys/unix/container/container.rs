@@ -3,9 +3,12 @@ use std::io::Error as IoError;
 use std::mem::transmute;
 
 use anyhow::{Context, anyhow};
+use chrono::{DateTime, Utc};
 use containerd_shimkit::zygote::{WireError, Zygote};
-use libcontainer::container::Container as YoukiContainer;
+use libcgroups::common::{CgroupConfig, CgroupManager as _, ControllerOpt, create_cgroup_manager};
+use libcontainer::container::{Container as YoukiContainer, ContainerStatus};
 use libcontainer::signal::Signal;
+use oci_spec::runtime::LinuxResources;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 
@@ -39,6 +42,18 @@ impl Container {
             .context("Failed to obtain PID")
     }
 
+    /// Returns the status of the container, the pid of its init process and the time it was
+    /// created, refreshed from the state of the process.
+    pub fn state(&self) -> anyhow::Result<(ContainerStatus, Option<i32>, Option<DateTime<Utc>>)> {
+        self.run(
+            |c, _| {
+                c.refresh_status()?;
+                Ok((c.status(), c.pid().map(|pid| pid.as_raw()), c.created()))
+            },
+            (),
+        )
+    }
+
     pub fn start(&self) -> anyhow::Result<()> {
         self.run(|c, _| Ok(c.start()?), ())
     }
@@ -54,6 +69,25 @@ impl Container {
     pub fn delete(&self) -> anyhow::Result<()> {
         self.run(|c, _| Ok(c.delete(true)?), ())
     }
+    pub fn update(&self, resources: LinuxResources) -> anyhow::Result<()> {
+        self.run(
+            |c, resources| {
+                let manager = create_cgroup_manager(CgroupConfig {
+                    cgroup_path: c.spec()?.cgroup_path,
+                    systemd_cgroup: c.systemd(),
+                    container_name: c.id().to_string(),
+                })?;
+                manager.apply(&ControllerOpt {
+                    resources: &resources,
+                    disable_oom_killer: false,
+                    oom_score_adj: None,
+                    freezer_state: None,
+                })?;
+                Ok(())
+            },
+            resources,
+        )
+    }
 }
 
 impl Container {
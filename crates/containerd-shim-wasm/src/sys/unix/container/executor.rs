@@ -2,6 +2,7 @@ use std::cell::OnceCell;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
+use std::os::unix::fs::FileExt as _;
 use std::os::unix::prelude::PermissionsExt;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -14,8 +15,11 @@ use libcontainer::workload::{
     ExecutorSetEnvsError, ExecutorValidationError,
 };
 use oci_spec::runtime::Spec;
+use tokio::signal::unix::{SignalKind, signal};
 
 use crate::sandbox::Sandbox;
+use crate::sandbox::cancellation::Cancellation;
+use crate::sandbox::config::EngineConfig;
 use crate::sandbox::context::{RuntimeContext, Source, WasiContext, WasmLayer};
 use crate::sandbox::path::PathResolve;
 use crate::shim::Shim;
@@ -38,6 +42,9 @@ impl<S: Shim> Clone for Executor<S> {
 pub(crate) struct InnerExecutor<S: Shim> {
     ty: OnceCell<ExecutorType<S>>,
     wasm_layers: Vec<WasmLayer>,
+    engine_config: EngineConfig,
+    cancellation: Cancellation,
+    exit_status: Option<File>,
 }
 
 impl<S: Shim> LibcontainerExecutor for Executor<S> {
@@ -62,14 +69,17 @@ impl<S: Shim> LibcontainerExecutor for Executor<S> {
             }
             ExecutorType::Wasm(container) => {
                 let ctx = self.ctx(spec);
+                watch_signals(
+                    self.0.cancellation.clone(),
+                    S::Sandbox::supports_cancellation(),
+                    self.0.exit_status.as_ref().and_then(|f| f.try_clone().ok()),
+                );
                 log::info!("calling start function");
-                match container.run_wasi(&ctx).block_on() {
-                    Ok(code) => std::process::exit(code),
-                    Err(err) => {
-                        log::info!("error running start function: {err}");
-                        std::process::exit(137)
-                    }
-                };
+                let result = container.run_wasi(&ctx).block_on();
+                exit(
+                    self.0.exit_status.as_ref(),
+                    exit_code(result, &self.0.cancellation),
+                )
             }
         }
     }
@@ -91,16 +101,36 @@ impl<S: Shim> LibcontainerExecutor for Executor<S> {
 }
 
 impl<S: Shim> Executor<S> {
-    pub fn new(wasm_layers: Vec<WasmLayer>) -> Self {
+    /// Creates the executor of a container.
+    ///
+    /// The exit code of a wasm container is written to `exit_status` before it exits, so a
+    /// shim that can't wait for the container, as it isn't its parent, can still report it.
+    /// The file is inherited by the init process of the container, and it's closed when a
+    /// Linux container execs its entrypoint.
+    pub fn new(
+        wasm_layers: Vec<WasmLayer>,
+        engine_config: EngineConfig,
+        exit_status: Option<File>,
+    ) -> Self {
         Self(Arc::new(InnerExecutor {
             ty: Default::default(),
             wasm_layers,
+            engine_config,
+            cancellation: Cancellation::new(),
+            exit_status,
         }))
     }
 
     fn ctx<'a>(&'a self, spec: &'a Spec) -> WasiContext<'a> {
         let wasm_layers = &self.0.wasm_layers;
-        WasiContext { spec, wasm_layers }
+        let cancellation = &self.0.cancellation;
+        let engine_config = &self.0.engine_config;
+        WasiContext {
+            spec,
+            wasm_layers,
+            cancellation,
+            engine_config,
+        }
     }
 
     fn ty(&self, spec: &Spec) -> &ExecutorType<S> {
@@ -125,6 +155,91 @@ impl<S: Shim> Executor<S> {
     }
 }
 
+/// Cancels the guest when the container receives a signal.
+///
+/// The signals are handled in a dedicated thread, so that they are handled
+/// even when the runtime blocks the thread running the guest.
+fn watch_signals(cancellation: Cancellation, cancellable: bool, exit_status: Option<File>) {
+    std::thread::spawn(move || {
+        let result = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(Into::into)
+            .and_then(|rt| {
+                rt.block_on(handle_signals(
+                    cancellation,
+                    cancellable,
+                    exit_status.as_ref(),
+                ))
+            });
+        if let Err(err) = result {
+            log::error!("error handling signals: {err}");
+        }
+    });
+}
+
+/// Handles the signals received by the container.
+///
+/// A signal asks the guest to stop, and a second signal stops the container right away.
+/// The guest isn't given a deadline: containerd sends `SIGKILL` once the stop timeout
+/// requested by its client, e.g., the grace period of the kubelet, expires.
+/// When the runtime can't interrupt the guest, i.e., it isn't `cancellable`, the container
+/// is stopped right away instead, as for the default action of the signal.
+async fn handle_signals(
+    cancellation: Cancellation,
+    cancellable: bool,
+    exit_status: Option<&File>,
+) -> Result<()> {
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigquit = signal(SignalKind::quit())?;
+
+    loop {
+        let signum = tokio::select! {
+            _ = sigint.recv() => libc::SIGINT,
+            _ = sigterm.recv() => libc::SIGTERM,
+            _ = sigquit.recv() => libc::SIGQUIT,
+        };
+
+        if !cancellable {
+            log::info!("received signal {signum}, the runtime can't stop the guest, exiting");
+            exit(exit_status, 128 + signum);
+        }
+
+        if cancellation.is_cancelled() {
+            log::info!("received signal {signum} while stopping, exiting");
+            exit(exit_status, 128 + signum);
+        }
+
+        log::info!("received signal {signum}, stopping the guest");
+        cancellation.cancel(signum);
+    }
+}
+
+/// Exits the container with `code`, after writing it to the `exit_status` file.
+/// The code is padded and written at the start of the file, so the last of the threads that
+/// exit the container concurrently overwrites the code of the others.
+fn exit(exit_status: Option<&File>, code: i32) -> ! {
+    if let Some(file) = exit_status {
+        if let Err(err) = file.write_all_at(format!("{code:>11}").as_bytes(), 0) {
+            log::warn!("failed to record the exit code {code}: {err}");
+        }
+    }
+    std::process::exit(code)
+}
+
+/// The exit code of the container, given the result of [`Sandbox::run_wasi`].
+pub(crate) fn exit_code(result: Result<i32>, cancellation: &Cancellation) -> i32 {
+    match (result, cancellation.signal()) {
+        (Ok(code), None | Some(libc::SIGINT)) => code,
+        (Ok(_), Some(signal)) => 128 + signal,
+        (Err(err), signal) => {
+            log::info!("error running start function: {err}");
+            signal.map_or(137, |signal| 128 + signal)
+        }
+    }
+}
+
 fn is_linux_container(ctx: &impl RuntimeContext) -> Result<()> {
     if let Source::Oci(_) = ctx.entrypoint().source {
         bail!("the entry point contains wasm layers")
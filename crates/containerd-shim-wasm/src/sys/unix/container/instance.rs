@@ -1,90 +1,120 @@
+use std::collections::HashMap;
+use std::fs::File;
 use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
 
-use chrono::{DateTime, Utc};
+use anyhow::Context as _;
+use chrono::{DateTime, TimeDelta, Utc};
 use containerd_client::tonic::async_trait;
 use containerd_shimkit::sandbox::sync::WaitableCell;
 use containerd_shimkit::sandbox::{
-    Error as SandboxError, Instance as SandboxInstance, InstanceConfig,
+    BlobCacheConfig, Error as SandboxError, ImagePolicy, Instance as SandboxInstance,
+    InstanceConfig, Isolation, RetryPolicy,
 };
 use containerd_shimkit::set_logger_kv;
 use libcontainer::container::builder::ContainerBuilder;
+use libcontainer::container::{Container as YoukiContainer, ContainerStatus};
 use libcontainer::syscall::syscall::SyscallType;
 use nix::sys::wait::WaitStatus;
-use oci_spec::runtime::Spec;
-use tokio::sync::OnceCell;
+use oci_spec::runtime::{LinuxResources, Spec};
+use tokio::sync::Mutex;
 
 use super::container::Container;
+use crate::blobs::BlobCache;
 use crate::containerd;
-use crate::sandbox::context::WasmLayer;
+use crate::registry::{self, OciRegistry};
+use crate::sandbox::config::EngineConfig;
+use crate::sandbox::context::{WasmBinaryType, WasmLayer};
+use crate::sandbox::features;
+use crate::sandbox::process::validate_process;
+use crate::sandbox::rootfs::{Rootfs, is_not_found};
 use crate::shim::{Compiler, Shim};
 use crate::sys::container::executor::Executor;
 use crate::sys::pid_fd::PidFd;
 
+/// The maximum size of an entrypoint read from the rootfs, see [`read_entrypoint`].
+pub(crate) const MAX_ENTRYPOINT_LEN: u64 = 256 << 20;
+
+/// The directory, under the root dir, of the files the containers write their exit code to.
+const EXIT_STATUS_DIR: &str = ".exit";
+
 pub struct Instance<S: Shim> {
     exit_code: WaitableCell<(u32, DateTime<Utc>)>,
     container: Container,
     id: String,
+    /// The file the container writes its exit code to, see [`Executor::new`].
+    exit_status: PathBuf,
+    /// Whether the container was created by a previous shim process, so its init process
+    /// isn't a child of the shim.
+    recovered: bool,
     _phantom: PhantomData<S>,
 }
 
 #[async_trait]
 trait OciClient {
-    async fn load_modules(&self, id: &str) -> Result<Vec<WasmLayer>, SandboxError>;
+    async fn load_modules(
+        &self,
+        id: &str,
+        policy: &ImagePolicy,
+    ) -> Result<Vec<WasmLayer>, SandboxError>;
 }
 
 struct EngineOciClient<P: Compiler> {
     client: containerd::Client,
     precompiler: Option<P>,
     supported_layer_types: &'static [&'static str],
+    platforms: containerd::WasmPlatforms,
     name: &'static str,
 }
 
 #[async_trait]
 impl<P: Compiler> OciClient for EngineOciClient<P> {
-    async fn load_modules(&self, id: &str) -> Result<Vec<WasmLayer>, SandboxError> {
+    async fn load_modules(
+        &self,
+        id: &str,
+        policy: &ImagePolicy,
+    ) -> Result<Vec<WasmLayer>, SandboxError> {
         self.client
             .load_modules(
                 id,
                 self.name,
                 self.supported_layer_types,
+                &self.platforms,
+                policy,
                 self.precompiler.as_ref(),
             )
             .await
     }
 }
 
-static OCI_CLIENT: OnceCell<Box<dyn OciClient + Send + Sync + 'static>> = OnceCell::const_new();
+/// The options a containerd client is created with.
+#[derive(PartialEq, Eq, Hash)]
+struct OciClientKey {
+    address: String,
+    namespace: String,
+    rootdir: PathBuf,
+    retry_policy: RetryPolicy,
+    blob_cache: BlobCacheConfig,
+}
+
+/// The containerd clients of the shim, shared by the containers created with the same
+/// options, so each container uses the retry policy and the blob cache of its own runtime
+/// options.
+static OCI_CLIENTS: LazyLock<Mutex<HashMap<OciClientKey, Arc<dyn OciClient + Send + Sync>>>> =
+    LazyLock::new(Default::default);
 
 impl<S: Shim> SandboxInstance for Instance<S> {
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
     async fn new(id: String, cfg: &InstanceConfig) -> Result<Self, SandboxError> {
-        let oci_client = OCI_CLIENT
-            .get_or_try_init(|| async {
-                let client =
-                    containerd::Client::connect(&cfg.containerd_address, &cfg.namespace).await?;
-                let precompiler = S::compiler().await;
-                let supported_layer_types = S::supported_layers_types();
-                let name = S::name();
-                Result::<_, SandboxError>::Ok(Box::new(EngineOciClient {
-                    client,
-                    precompiler,
-                    supported_layer_types,
-                    name,
-                }) as _)
-            })
-            .await?;
+        let spec = Spec::load(cfg.bundle.join("config.json"))?;
+        validate_process::<S::Sandbox>(&spec, Isolation::Container).warn_ignored(&id);
 
-        // check if container is OCI image with wasm layers and attempt to read the module
-        let modules = oci_client
-            .load_modules(&id)
-            .await
-            .unwrap_or_else(|e| {
-                log::warn!("Error obtaining wasm layers for container {id}.  Will attempt to use files inside container image. Error: {e}");
-                vec![]
-            });
+        let (modules, engine_config) = load_wasm::<S>(&id, cfg, &spec).await?;
+        let exit_status = exit_status_path(&cfg.determine_rootdir(S::name())?, &id);
 
         let container = Container::build(
-            |(id, cfg, modules)| {
+            |(id, cfg, modules, engine_config, exit_status)| {
                 let source_spec_path = cfg.bundle.join("config.json");
                 let spec = Spec::load(source_spec_path)?;
                 let pod_id = pod_id(&spec);
@@ -96,9 +126,19 @@ impl<S: Shim> SandboxInstance for Instance<S> {
 
                 let rootdir = cfg.determine_rootdir(S::name())?;
 
+                if let Some(dir) = exit_status.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let exit_status = File::create(exit_status)?;
+
                 let mut builder = ContainerBuilder::new(id, SyscallType::Linux)
-                    .with_executor(Executor::<S>::new(modules))
-                    .with_root_path(rootdir.clone())?;
+                    .with_executor(Executor::<S>::new(
+                        modules,
+                        engine_config,
+                        Some(exit_status),
+                    ))
+                    .with_root_path(rootdir.clone())?
+                    .with_console_socket(cfg.console_socket.as_ref());
 
                 if let Ok(f) = cfg.open_stdin() {
                     builder = builder.with_stdin(f);
@@ -118,13 +158,89 @@ impl<S: Shim> SandboxInstance for Instance<S> {
 
                 Ok(container)
             },
-            (id.clone(), cfg.clone(), modules),
+            (
+                id.clone(),
+                cfg.clone(),
+                // the zygote maps the layers through the files kept open by the shim,
+                // so they're kept alive until the container is built
+                modules.clone(),
+                engine_config,
+                exit_status.clone(),
+            ),
         )?;
+        drop(modules);
 
         Ok(Self {
             id,
             exit_code: WaitableCell::new(),
             container,
+            exit_status,
+            recovered: false,
+            _phantom: Default::default(),
+        })
+    }
+
+    /// Recover the instance after a restart of the shim
+    /// The container is loaded from its state under the root dir, and its init process is
+    /// only watched if it's still the process `pid` of the task. Otherwise the container
+    /// exited while the shim was down, with the exit code it wrote before exiting.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
+    async fn recover(
+        id: String,
+        cfg: &InstanceConfig,
+        pid: Option<u32>,
+    ) -> Result<Self, SandboxError> {
+        let rootdir = cfg.determine_rootdir(S::name())?;
+        let exit_status = exit_status_path(&rootdir, &id);
+        let container = Container::build(
+            |(id, rootdir): (String, PathBuf)| Ok(YoukiContainer::load(rootdir.join(id))?),
+            (id.clone(), rootdir),
+        )?;
+
+        let exit_code = WaitableCell::new();
+        if let Some(pid) = pid {
+            // open the pidfd before checking the process, so it refers to the checked process
+            let pidfd = PidFd::new(pid as i32);
+            let (status, init_pid, created) = container.state()?;
+            if init_pid != Some(pid as i32) {
+                return Err(SandboxError::Unimplemented(format!(
+                    "the init process of container {id} isn't the process {pid} of the task"
+                )));
+            }
+            let pidfd = match pidfd {
+                Ok(pidfd) if status != ContainerStatus::Stopped && started_before(pid, created) => {
+                    Some(pidfd)
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    log::debug!("pidfd_open failed: {e}");
+                    None
+                }
+            };
+            match pidfd {
+                Some(pidfd) => {
+                    let guard = exit_code.clone().set_guard_with(|| (137, Utc::now()));
+                    let exit_code = exit_code.clone();
+                    let exit_status = exit_status.clone();
+                    tokio::spawn(async move {
+                        let _guard = guard;
+                        wait_recovered(pidfd).await;
+                        let _ = exit_code.set((read_exit_status(&exit_status), Utc::now()));
+                    });
+                }
+                // the process has already exited
+                None => {
+                    let _ = exit_code.set((read_exit_status(&exit_status), Utc::now()));
+                }
+            }
+        }
+
+        Ok(Self {
+            id,
+            exit_code,
+            container,
+            exit_status,
+            recovered: true,
             _phantom: Default::default(),
         })
     }
@@ -135,6 +251,14 @@ impl<S: Shim> SandboxInstance for Instance<S> {
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
     async fn start(&self) -> Result<u32, SandboxError> {
         log::info!("starting instance: {}", self.id);
+        // the init process waits to be started by the shim that created it
+        if self.recovered {
+            return Err(SandboxError::FailedPrecondition(format!(
+                "container {} was created by a previous shim process and can't be started",
+                self.id
+            )));
+        }
+
         // make sure we have an exit code by the time we finish (even if there's a panic)
         let guard = self.exit_code.clone().set_guard_with(|| (137, Utc::now()));
 
@@ -184,9 +308,30 @@ impl<S: Shim> SandboxInstance for Instance<S> {
     async fn delete(&self) -> Result<(), SandboxError> {
         log::info!("deleting instance: {}", self.id);
         self.container.delete()?;
+        match std::fs::remove_file(&self.exit_status) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("failed to remove the exit status of {}: {err}", self.id);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Update the resources of the instance
+    /// The new limits are applied to the cgroup of the container.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn update(&self, resources: &LinuxResources) -> Result<(), SandboxError> {
+        log::info!("updating resources of instance: {}", self.id);
+        self.container.update(resources.clone())?;
         Ok(())
     }
 
+    /// The hooks are run by libcontainer, in the namespaces of the container when
+    /// the spec requires it.
+    fn handles_hooks(&self) -> bool {
+        true
+    }
+
     /// Waits for the instance to finish and returns its exit code
     /// Returns None if the timeout is reached before the instance has finished.
     /// This is an async call.
@@ -196,13 +341,226 @@ impl<S: Shim> SandboxInstance for Instance<S> {
     }
 }
 
-fn pod_id(spec: &Spec) -> Option<&str> {
+/// Waits for the init process of a recovered container to exit.
+/// The process isn't a child of the shim, so its exit code is read from the file it writes
+/// before exiting, see [`read_exit_status`].
+async fn wait_recovered(pidfd: PidFd) {
+    if let Err(e) = pidfd.exited().await {
+        log::error!("waiting for the recovered container failed: {e}");
+    }
+}
+
+/// Returns the path of the file the container `id` writes its exit code to.
+fn exit_status_path(rootdir: &Path, id: &str) -> PathBuf {
+    rootdir.join(EXIT_STATUS_DIR).join(id)
+}
+
+/// Reads the exit code the container wrote to `path` before it exited, truncated as the
+/// exit status of a process is.
+/// A container that didn't write it, e.g., a Linux container or a container killed with
+/// `SIGKILL`, is reported as killed.
+fn read_exit_status(path: &Path) -> u32 {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|code| code.trim().parse::<i32>().ok())
+        .map_or(137, |code| (code & 0xff) as u32)
+}
+
+/// Returns whether the process `pid` started before the container was `created`, i.e., it's
+/// the init process of the container, rather than a process that reused its pid.
+/// The start time of a process is only known to the second, as the boot time of the host.
+fn started_before(pid: u32, created: Option<DateTime<Utc>>) -> bool {
+    let Some(created) = created else {
+        log::warn!("the creation time of the container of process {pid} is unknown");
+        return false;
+    };
+    match process_start_time(pid) {
+        Ok(start) => start <= created + TimeDelta::seconds(1),
+        Err(err) => {
+            log::debug!("failed to read the start time of process {pid}: {err:#}");
+            false
+        }
+    }
+}
+
+/// Returns the time the process `pid` started, from its stat in procfs.
+fn process_start_time(pid: u32) -> anyhow::Result<DateTime<Utc>> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat"))?;
+    // the fields follow the command of the process, in parentheses, which may contain spaces
+    let (_, fields) = stat.rsplit_once(')').context("malformed process stat")?;
+    // the start time is the 22nd field, in clock ticks since boot
+    let ticks: i64 = fields
+        .split_whitespace()
+        .nth(19)
+        .context("missing start time")?
+        .parse()?;
+    let boot_time: i64 = std::fs::read_to_string("/proc/stat")?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .context("missing boot time")?
+        .trim()
+        .parse()?;
+    let ticks_per_second = match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as i64,
+        _ => 100,
+    };
+    DateTime::from_timestamp_millis(boot_time * 1000 + ticks * 1000 / ticks_per_second)
+        .context("invalid start time")
+}
+
+/// Loads the wasm layers of the container `id`, from its image and from the artifacts
+/// referenced by its annotations, and the engine config they declare.
+pub(crate) async fn load_wasm<S: Shim>(
+    id: &str,
+    cfg: &InstanceConfig,
+    spec: &Spec,
+) -> Result<(Vec<WasmLayer>, EngineConfig), SandboxError> {
+    let rootdir = cfg.determine_rootdir(S::name())?;
+    let key = OciClientKey {
+        address: cfg.containerd_address.clone(),
+        namespace: cfg.namespace.clone(),
+        rootdir: rootdir.clone(),
+        retry_policy: cfg.config.containerd_retry.clone(),
+        blob_cache: cfg.config.blob_cache.clone(),
+    };
+    let oci_client = {
+        let mut clients = OCI_CLIENTS.lock().await;
+        match clients.get(&key) {
+            Some(client) => client.clone(),
+            None => {
+                let blobs = BlobCache::from_config(&key.blob_cache, &rootdir);
+                let client = containerd::Client::connect(&key.address, &key.namespace)
+                    .await?
+                    .with_blob_cache(blobs)
+                    .with_retry_policy(key.retry_policy.clone());
+                let precompiler = S::compiler().await;
+                let supported_layer_types = S::supported_layers_types();
+                let platforms = containerd::WasmPlatforms {
+                    os: S::supported_platform_os(),
+                    features: S::supported_platform_features(),
+                };
+                let name = S::name();
+                let client: Arc<dyn OciClient + Send + Sync> = Arc::new(EngineOciClient {
+                    client,
+                    precompiler,
+                    supported_layer_types,
+                    platforms,
+                    name,
+                });
+                clients.insert(key, client.clone());
+                client
+            }
+        }
+    };
+
+    // check if container is OCI image with wasm layers and attempt to read the module
+    let policy = &cfg.config.image_policy;
+    let mut modules = match oci_client.load_modules(id, policy).await {
+        Ok(modules) => modules,
+        // the image was rejected, or its wasm layers couldn't be loaded, it must not run
+        // using the files inside the container image
+        Err(e @ SandboxError::FailedPrecondition(_)) => return Err(e),
+        // containerd couldn't be reached, so it's unknown whether the image has wasm layers
+        Err(e @ SandboxError::Unavailable(_)) => return Err(e),
+        // the image couldn't be verified, so it must not run at all
+        Err(e) if policy.is_enforced() => {
+            return Err(SandboxError::FailedPrecondition(format!(
+                "failed to verify the image of container {id}: {e}"
+            )));
+        }
+        Err(e) => {
+            log::warn!(
+                "Error obtaining wasm layers for container {id}.  Will attempt to use files inside container image. Error: {e}"
+            );
+            vec![]
+        }
+    };
+
+    // append any wasm artifacts referenced through annotations
+    let references = registry::artifact_references(spec);
+    registry::ensure_attachable(&references, &modules)?;
+    if !references.is_empty() {
+        let cache = BlobCache::from_config(&cfg.config.blob_cache, &rootdir);
+        let artifacts = registry::pull_artifacts(
+            &OciRegistry::new(&cfg.config.containerd_retry),
+            &cache,
+            &references,
+            S::supported_layers_types(),
+            policy,
+        )
+        .await?;
+        modules.extend(artifacts);
+    }
+
+    let (mut engine_config, modules) = EngineConfig::from_layers(modules)
+        .map_err(|err| SandboxError::InvalidArgument(format!("{err:#}")))?;
+
+    if let Some(support) = S::wasm_features() {
+        let entrypoint = if modules.is_empty() {
+            read_entrypoint(spec, &cfg.bundle)
+                .map_err(|err| SandboxError::InvalidArgument(format!("{err:#}")))?
+        } else {
+            None
+        };
+        let binaries = modules
+            .iter()
+            .map(|module| &module.layer[..])
+            .chain(entrypoint.as_deref())
+            .filter(|bytes| WasmBinaryType::from_bytes(bytes).is_some());
+        features::negotiate(binaries, &support, &mut engine_config.features)
+            .map_err(|err| SandboxError::InvalidArgument(format!("{err:#}")))?;
+    }
+
+    Ok((modules, engine_config))
+}
+
+pub(crate) fn pod_id(spec: &Spec) -> Option<&str> {
     spec.annotations()
         .as_ref()
         .and_then(|a| a.get("io.kubernetes.cri.sandbox-id"))
         .map(|s| s.as_str())
 }
 
+/// Reads the entrypoint of a container without wasm layers, to check the wasm features it requires,
+/// or to run it in the shim process.
+/// Only entrypoints with an absolute path are read, others are resolved when the container starts.
+/// Entrypoints that aren't in the rootfs, e.g., in a mount of the container, are resolved when it
+/// starts too.
+///
+/// The entrypoint is resolved inside the rootfs, see [`Rootfs`], and must be a regular file
+/// of at most [`MAX_ENTRYPOINT_LEN`] bytes.
+pub(crate) fn read_entrypoint(spec: &Spec, bundle: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(arg0) = spec
+        .process()
+        .as_ref()
+        .and_then(|process| process.args().as_ref())
+        .and_then(|args| args.first())
+    else {
+        return Ok(None);
+    };
+    let path = arg0.split_once('#').map_or(arg0.as_str(), |(path, _)| path);
+    if !Path::new(path).is_absolute() {
+        return Ok(None);
+    }
+
+    let entrypoint = Rootfs::open(rootfs(spec, bundle))
+        .and_then(|rootfs| rootfs.read_file(path, MAX_ENTRYPOINT_LEN));
+    match entrypoint {
+        Ok(entrypoint) => Ok(Some(entrypoint)),
+        Err(err) if is_not_found(&err) => Ok(None),
+        Err(err) => Err(err.context("failed to read the entrypoint")),
+    }
+}
+
+/// Returns the path of the root file system of the container in the `bundle`.
+pub(crate) fn rootfs(spec: &Spec, bundle: &Path) -> PathBuf {
+    let root = spec
+        .root()
+        .as_ref()
+        .map_or(Path::new("rootfs"), |root| root.path());
+    bundle.join(root)
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
@@ -242,4 +600,93 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_process_start_time() -> Result<()> {
+        let start = process_start_time(std::process::id())?;
+        assert!(start <= Utc::now());
+        assert!(started_before(std::process::id(), Some(Utc::now())));
+        assert!(!started_before(
+            std::process::id(),
+            Some(start - TimeDelta::minutes(1))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_exit_status() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = exit_status_path(dir.path(), "test");
+        assert_eq!(read_exit_status(&path), 137);
+
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, "143")?;
+        assert_eq!(read_exit_status(&path), 143);
+        std::fs::write(&path, "-1")?;
+        assert_eq!(read_exit_status(&path), 255);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_entrypoint() -> Result<()> {
+        let bundle = tempfile::tempdir()?;
+        std::fs::create_dir_all(bundle.path().join("rootfs/app"))?;
+        std::fs::write(bundle.path().join("rootfs/app/main.wasm"), b"\0asm")?;
+
+        let spec_with_args = |args: &[&str]| -> Result<Spec> {
+            Ok(SpecBuilder::default()
+                .root(RootBuilder::default().path("rootfs").build()?)
+                .process(
+                    ProcessBuilder::default()
+                        .cwd("/")
+                        .args(args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+                        .build()?,
+                )
+                .build()?)
+        };
+
+        let spec = spec_with_args(&["/app/main.wasm#run"])?;
+        assert_eq!(
+            read_entrypoint(&spec, bundle.path())?.as_deref(),
+            Some(&b"\0asm"[..])
+        );
+
+        let spec = spec_with_args(&["main.wasm"])?;
+        assert_eq!(read_entrypoint(&spec, bundle.path())?, None);
+
+        let spec = spec_with_args(&["/app/missing.wasm"])?;
+        assert_eq!(read_entrypoint(&spec, bundle.path())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_entrypoint_stays_in_the_rootfs() -> Result<()> {
+        let bundle = tempfile::tempdir()?;
+        std::fs::create_dir_all(bundle.path().join("rootfs/app"))?;
+        std::fs::write(bundle.path().join("host.wasm"), b"\0asm")?;
+        std::os::unix::fs::symlink(
+            "../../host.wasm",
+            bundle.path().join("rootfs/app/link.wasm"),
+        )?;
+        std::os::unix::fs::symlink("/dev/zero", bundle.path().join("rootfs/app/zero.wasm"))?;
+
+        for arg0 in ["/../host.wasm", "/app/link.wasm", "/app/zero.wasm", "/app"] {
+            let spec = SpecBuilder::default()
+                .root(RootBuilder::default().path("rootfs").build()?)
+                .process(
+                    ProcessBuilder::default()
+                        .cwd("/")
+                        .args(vec![arg0.to_string()])
+                        .build()?,
+                )
+                .build()?;
+            assert!(
+                read_entrypoint(&spec, bundle.path()).is_err(),
+                "{arg0} was read"
+            );
+        }
+
+        Ok(())
+    }
 }
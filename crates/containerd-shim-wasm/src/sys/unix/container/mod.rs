@@ -1,5 +1,5 @@
 #[allow(clippy::module_inception)]
 mod container;
 
-mod executor;
+pub(crate) mod executor;
 pub mod instance;
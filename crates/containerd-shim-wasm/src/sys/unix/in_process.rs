@@ -0,0 +1,379 @@
+//! Containers running as tasks of the shim process, for runtime handlers selecting the
+//! `in-process` [`Isolation`](containerd_shimkit::sandbox::Isolation).
+//!
+//! All the guests of the shim share a single [`Sandbox`], e.g., a single engine, and run
+//! on the async runtime of the shim. They don't run in a Linux container, so they are only
+//! isolated by the sandbox of the runtime and the WASI capabilities granted to them:
+//!
+//! * the rootfs of the bundle is preopened as `/`, and the mounts of the spec aren't applied,
+//! * the network policy is enforced in the network namespace of the shim,
+//! * the cgroup limits of the spec aren't enforced, only the limits of the engine config, and
+//!   the memory limit of the container, which caps the total size of the linear memories of
+//!   the guest for runtimes that [support it](Sandbox::supports_live_limits),
+//! * the pid of the task is the pid of the shim,
+//! * the user, rlimits and security settings of the process aren't applied, as reported by
+//!   [`validate_process`](crate::sandbox::process::validate_process),
+//! * the guest doesn't outlive the shim process, so the task isn't recovered when the shim restarts.
+//!
+//! Only runtimes that [support it](Sandbox::supports_in_process) can run containers in the shim
+//! process. A guest that never yields to the async runtime can't be stopped: the task is reported
+//! as exited when it's killed, but the guest keeps running until the shim exits.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::os::fd::AsFd as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use chrono::{DateTime, Utc};
+use containerd_shimkit::sandbox::sync::WaitableCell;
+use containerd_shimkit::sandbox::{
+    Error as SandboxError, Instance as SandboxInstance, InstanceConfig, Isolation,
+};
+use oci_spec::image::{Descriptor, Digest, MediaType};
+use oci_spec::runtime::{LinuxResources, Spec};
+use tokio::task::AbortHandle;
+
+use crate::sandbox::Sandbox;
+use crate::sandbox::cancellation::Cancellation;
+use crate::sandbox::config::EngineConfig;
+use crate::sandbox::context::{
+    Entrypoint, LiveLimits, RuntimeContext, Stdio, WasiContext, WasmLayer,
+};
+use crate::sandbox::process::validate_process;
+use crate::shim::Shim;
+use crate::sys::container::executor::exit_code;
+use crate::sys::container::instance::{load_wasm, read_entrypoint, rootfs};
+
+/// The media type of the layer holding the entrypoint read from the rootfs.
+const ENTRYPOINT_MEDIA_TYPE: &str = "application/wasm";
+
+/// The sandbox shared by all the containers of the shim.
+/// A shim process only runs a single [`Shim`], so there's a single sandbox type.
+static SANDBOX: OnceLock<Box<dyn std::any::Any + Send + Sync>> = OnceLock::new();
+
+fn shared_sandbox<S: Shim>() -> &'static S::Sandbox {
+    SANDBOX
+        .get_or_init(|| Box::new(S::Sandbox::default()))
+        .downcast_ref()
+        .expect("the shim process runs a single shim")
+}
+
+pub struct Instance<S: Shim> {
+    id: String,
+    task: Arc<Task>,
+    _phantom: PhantomData<S>,
+}
+
+/// The state of a container, shared with the async task running its guest.
+struct Task {
+    spec: Spec,
+    wasm_layers: Vec<WasmLayer>,
+    engine_config: EngineConfig,
+    cancellation: Cancellation,
+    rootfs: PathBuf,
+    /// The stdio of the guest, taken by the guest when it starts, and closed when it exits,
+    /// so the shim reads the end of its terminal.
+    stdio: Mutex<Option<Stdio>>,
+    limits: Arc<LiveLimits>,
+    exit_code: WaitableCell<(u32, DateTime<Utc>)>,
+    guest: Mutex<Option<AbortHandle>>,
+}
+
+/// The context of a guest running in the shim process.
+struct InProcessContext<'a> {
+    inner: WasiContext<'a>,
+    rootfs: &'a Path,
+    stdio: &'a Stdio,
+    limits: &'a Arc<LiveLimits>,
+}
+
+impl RuntimeContext for InProcessContext<'_> {
+    fn args(&self) -> &[String] {
+        self.inner.args()
+    }
+
+    fn envs(&self) -> &[String] {
+        self.inner.envs()
+    }
+
+    fn cwd(&self) -> &Path {
+        self.inner.cwd()
+    }
+
+    fn entrypoint(&self) -> Entrypoint<'_> {
+        self.inner.entrypoint()
+    }
+
+    fn annotations(&self) -> &HashMap<String, String> {
+        self.inner.annotations()
+    }
+
+    fn engine_config(&self) -> &EngineConfig {
+        self.inner.engine_config()
+    }
+
+    fn cancellation(&self) -> &Cancellation {
+        self.inner.cancellation()
+    }
+
+    fn isolation(&self) -> Isolation {
+        Isolation::InProcess
+    }
+
+    fn rootfs(&self) -> &Path {
+        self.rootfs
+    }
+
+    fn stdio(&self) -> Option<&Stdio> {
+        Some(self.stdio)
+    }
+
+    fn live_limits(&self) -> Option<&Arc<LiveLimits>> {
+        Some(self.limits)
+    }
+}
+
+impl Task {
+    fn ctx<'a>(&'a self, stdio: &'a Stdio) -> InProcessContext<'a> {
+        InProcessContext {
+            inner: WasiContext {
+                spec: &self.spec,
+                wasm_layers: &self.wasm_layers,
+                cancellation: &self.cancellation,
+                engine_config: &self.engine_config,
+            },
+            rootfs: &self.rootfs,
+            stdio,
+            limits: &self.limits,
+        }
+    }
+
+    /// Reports the container as stopped by `signal`, and drops its guest.
+    fn stop(&self, signal: i32) {
+        let _ = self.exit_code.set(((128 + signal) as u32, Utc::now()));
+        if let Some(guest) = self.guest.lock().unwrap().as_ref() {
+            guest.abort();
+        }
+    }
+}
+
+impl<S: Shim> SandboxInstance for Instance<S> {
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
+    async fn new(id: String, cfg: &InstanceConfig) -> Result<Self, SandboxError> {
+        if !S::Sandbox::supports_in_process() {
+            return Err(SandboxError::InvalidArgument(format!(
+                "the {} runtime can't run containers in the shim process",
+                S::name()
+            )));
+        }
+
+        let spec = Spec::load(cfg.bundle.join("config.json"))?;
+        validate_process::<S::Sandbox>(&spec, Isolation::InProcess).warn_ignored(&id);
+
+        let (mut wasm_layers, engine_config) = load_wasm::<S>(&id, cfg, &spec).await?;
+
+        // the entrypoint can't be resolved in the file system of the shim when the guest starts,
+        // so it's read from the rootfs now
+        if wasm_layers.is_empty() {
+            let entrypoint = read_entrypoint(&spec, &cfg.bundle)
+                .map_err(|err| SandboxError::InvalidArgument(format!("{err:#}")))?;
+            let Some(entrypoint) = entrypoint else {
+                return Err(SandboxError::InvalidArgument(
+                    "containers running in the shim process need wasm layers, or the absolute path of their entrypoint".to_string(),
+                ));
+            };
+            wasm_layers.push(entrypoint_layer(entrypoint)?);
+        }
+
+        let resources = spec.linux().as_ref().and_then(|l| l.resources().as_ref());
+        let task = Task {
+            rootfs: rootfs(&spec, &cfg.bundle),
+            limits: Arc::new(LiveLimits::new(resources.and_then(memory_limit))),
+            spec,
+            wasm_layers,
+            engine_config,
+            cancellation: Cancellation::new(),
+            stdio: Mutex::new(Some(stdio(cfg)?)),
+            exit_code: WaitableCell::new(),
+            guest: Mutex::new(None),
+        };
+
+        shared_sandbox::<S>()
+            .can_handle(&task.ctx(&Stdio::default()))
+            .await
+            .map_err(|err| SandboxError::InvalidArgument(format!("{err:#}")))?;
+
+        Ok(Self {
+            id,
+            task: Arc::new(task),
+            _phantom: Default::default(),
+        })
+    }
+
+    /// Start the instance
+    /// The returned value is the pid of the shim, as the guest runs in the shim process.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn start(&self) -> Result<u32, SandboxError> {
+        log::info!("starting instance in the shim process: {}", self.id);
+        // make sure we have an exit code by the time we finish (even if there's a panic)
+        let guard = self
+            .task
+            .exit_code
+            .clone()
+            .set_guard_with(|| (137, Utc::now()));
+
+        let task = self.task.clone();
+        let guest = tokio::spawn(async move {
+            // move the exit code guard into this task
+            let _guard = guard;
+
+            let stdio = task.stdio.lock().unwrap().take().unwrap_or_default();
+            let ctx = task.ctx(&stdio);
+            log::info!("calling start function");
+            let result = shared_sandbox::<S>().run_wasi(&ctx).await;
+            let status = exit_code(result, &task.cancellation) as u32;
+            let _ = task.exit_code.set((status, Utc::now()));
+        });
+        *self.task.guest.lock().unwrap() = Some(guest.abort_handle());
+
+        Ok(std::process::id())
+    }
+
+    /// Send a signal to the instance
+    ///
+    /// Signals are delivered to the guest through its [`Cancellation`], with the same
+    /// semantics as for a guest running in a container: the guest isn't given a deadline,
+    /// containerd sends `SIGKILL` once its stop timeout expires.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn kill(&self, signal: u32) -> Result<(), SandboxError> {
+        log::info!("sending signal {signal} to instance: {}", self.id);
+        let signal = signal as i32;
+
+        if signal == libc::SIGKILL
+            || self.task.cancellation.is_cancelled()
+            || !S::Sandbox::supports_cancellation()
+        {
+            self.task.stop(signal);
+            return Ok(());
+        }
+
+        self.task.cancellation.cancel(signal);
+        Ok(())
+    }
+
+    /// Delete any reference to the instance
+    /// This is called after the instance has exited.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn delete(&self) -> Result<(), SandboxError> {
+        log::info!("deleting instance: {}", self.id);
+        if let Some(guest) = self.task.guest.lock().unwrap().take() {
+            guest.abort();
+        }
+        Ok(())
+    }
+
+    /// Update the resources of the instance
+    /// The memory limit of the container caps the total size of the linear memories of the
+    /// guest, in addition to the limits of the engine config. Other resources aren't enforced
+    /// in the shim process.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn update(&self, resources: &LinuxResources) -> Result<(), SandboxError> {
+        if !S::Sandbox::supports_live_limits() {
+            return Err(SandboxError::Unimplemented(format!(
+                "the {} runtime can't update the limits of a running guest",
+                S::name()
+            )));
+        }
+        let max = memory_limit(resources);
+        log::info!("updating max memory of instance {} to {max:?}", self.id);
+        self.task.limits.set_max_memory_bytes(max);
+        Ok(())
+    }
+
+    /// Waits for the instance to finish and returns its exit code
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn wait(&self) -> (u32, DateTime<Utc>) {
+        *self.task.exit_code.wait().await
+    }
+}
+
+/// Returns the memory limit of the `resources` of a container, or `None` when it's unlimited.
+fn memory_limit(resources: &LinuxResources) -> Option<u64> {
+    resources
+        .memory()
+        .as_ref()
+        .and_then(|memory| memory.limit())
+        .and_then(|limit| u64::try_from(limit).ok())
+}
+
+/// Opens the stdio of the guest: the slave of a terminal sent to the console socket when the
+/// container has a terminal, or the stdio paths otherwise.
+fn stdio(cfg: &InstanceConfig) -> Result<Stdio, SandboxError> {
+    if cfg.console_socket.is_none() {
+        return Ok(Stdio {
+            stdin: cfg.open_stdin().ok(),
+            stdout: cfg.open_stdout().ok(),
+            stderr: cfg.open_stderr().ok(),
+        });
+    }
+    let pty = nix::pty::openpty(None, None)?;
+    cfg.send_console(pty.master.as_fd())?;
+    let slave = File::from(pty.slave);
+    Ok(Stdio {
+        stdin: Some(slave.try_clone()?),
+        stdout: Some(slave.try_clone()?),
+        stderr: Some(slave),
+    })
+}
+
+/// Returns a layer with the `entrypoint` read from the rootfs, converted to a wasm binary
+/// if it's a `wat` file.
+fn entrypoint_layer(entrypoint: Vec<u8>) -> Result<WasmLayer, SandboxError> {
+    let bytes = wat::parse_bytes(&entrypoint)
+        .map_err(|err| SandboxError::InvalidArgument(format!("invalid entrypoint: {err}")))?
+        .into_owned();
+    let digest: Digest = format!("sha256:{}", sha256::digest(&bytes[..])).parse()?;
+    Ok(WasmLayer {
+        config: Descriptor::new(
+            MediaType::Other(ENTRYPOINT_MEDIA_TYPE.to_string()),
+            bytes.len() as u64,
+            digest,
+        ),
+        layer: bytes.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn test_entrypoint_layer() -> Result<()> {
+        let module = wat::parse_str("(module)")?;
+
+        let layer = entrypoint_layer(module.clone())?;
+        assert_eq!(layer.layer, module);
+        assert_eq!(layer.config.size(), module.len() as u64);
+
+        let layer = entrypoint_layer(b"(module)".to_vec())?;
+        assert_eq!(layer.layer, module);
+
+        assert!(entrypoint_layer(b"not wasm".to_vec()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_limit() -> Result<()> {
+        let resources = |json: &str| serde_json::from_str::<LinuxResources>(json);
+        let limited = resources(r#"{"memory":{"limit":4194304}}"#)?;
+        assert_eq!(memory_limit(&limited), Some(4 << 20));
+        for json in ["{}", r#"{"memory":{"limit":-1}}"#] {
+            assert_eq!(memory_limit(&resources(json)?), None);
+        }
+        Ok(())
+    }
+}
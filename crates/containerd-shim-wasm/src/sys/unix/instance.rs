@@ -0,0 +1,82 @@
+use chrono::{DateTime, Utc};
+use containerd_shimkit::sandbox::{
+    Error as SandboxError, Instance as SandboxInstance, InstanceConfig, Isolation,
+};
+use oci_spec::runtime::LinuxResources;
+
+use crate::shim::Shim;
+use crate::sys::container::instance::Instance as ContainerInstance;
+use crate::sys::in_process::Instance as InProcessInstance;
+
+/// Runs a container in a Linux container, or in the shim process,
+/// following the [`Isolation`] selected by the runtime handler.
+pub enum Instance<S: Shim> {
+    Container(ContainerInstance<S>),
+    InProcess(InProcessInstance<S>),
+}
+
+impl<S: Shim> SandboxInstance for Instance<S> {
+    async fn new(id: String, cfg: &InstanceConfig) -> Result<Self, SandboxError> {
+        match cfg.config.isolation {
+            Isolation::Container => Ok(Self::Container(ContainerInstance::new(id, cfg).await?)),
+            Isolation::InProcess => Ok(Self::InProcess(InProcessInstance::new(id, cfg).await?)),
+        }
+    }
+
+    async fn recover(
+        id: String,
+        cfg: &InstanceConfig,
+        pid: Option<u32>,
+    ) -> Result<Self, SandboxError> {
+        match cfg.config.isolation {
+            Isolation::Container => Ok(Self::Container(
+                ContainerInstance::recover(id, cfg, pid).await?,
+            )),
+            Isolation::InProcess => Ok(Self::InProcess(
+                InProcessInstance::recover(id, cfg, pid).await?,
+            )),
+        }
+    }
+
+    async fn start(&self) -> Result<u32, SandboxError> {
+        match self {
+            Self::Container(instance) => instance.start().await,
+            Self::InProcess(instance) => instance.start().await,
+        }
+    }
+
+    async fn kill(&self, signal: u32) -> Result<(), SandboxError> {
+        match self {
+            Self::Container(instance) => instance.kill(signal).await,
+            Self::InProcess(instance) => instance.kill(signal).await,
+        }
+    }
+
+    async fn delete(&self) -> Result<(), SandboxError> {
+        match self {
+            Self::Container(instance) => instance.delete().await,
+            Self::InProcess(instance) => instance.delete().await,
+        }
+    }
+
+    async fn update(&self, resources: &LinuxResources) -> Result<(), SandboxError> {
+        match self {
+            Self::Container(instance) => instance.update(resources).await,
+            Self::InProcess(instance) => instance.update(resources).await,
+        }
+    }
+
+    fn handles_hooks(&self) -> bool {
+        match self {
+            Self::Container(instance) => instance.handles_hooks(),
+            Self::InProcess(instance) => instance.handles_hooks(),
+        }
+    }
+
+    async fn wait(&self) -> (u32, DateTime<Utc>) {
+        match self {
+            Self::Container(instance) => instance.wait().await,
+            Self::InProcess(instance) => instance.wait().await,
+        }
+    }
+}
@@ -1,3 +1,5 @@
 pub mod container;
+pub mod in_process;
+pub mod instance;
 
 mod pid_fd;
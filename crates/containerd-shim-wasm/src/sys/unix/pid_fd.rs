@@ -56,6 +56,15 @@ impl PidFd {
             }
         }
     }
+
+    /// Waits for a process that isn't a child of the shim to exit, e.g., the init process of
+    /// a container created by a previous shim process, which can't be waited for.
+    pub(super) async fn exited(self) -> std::io::Result<()> {
+        // the pidfd becomes readable once the process exits
+        let fd = AsyncFd::new(self.fd)?;
+        let _ = fd.readable().await?;
+        Ok(())
+    }
 }
 
 pub async fn try_wait_pid(pid: i32, s: Subscription) -> Result<i32, Errno> {
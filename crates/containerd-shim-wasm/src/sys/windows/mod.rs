@@ -1 +1,3 @@
 pub mod container;
+
+pub use container::instance;
@@ -1,7 +1,7 @@
 //! Testing utilities used across different modules
 
 use std::collections::HashMap;
-use std::fs::{self, File, create_dir, read, read_to_string, write};
+use std::fs::{self, File, create_dir, create_dir_all, read, read_to_string, write};
 use std::marker::PhantomData;
 use std::ops::Add;
 #[cfg(unix)]
@@ -9,19 +9,29 @@ use std::os::unix::fs::symlink;
 #[cfg(windows)]
 use std::os::windows::fs::symlink_file as symlink;
 use std::path::Path;
+#[cfg(unix)]
+use std::sync::Mutex;
+#[cfg(unix)]
+use std::thread::JoinHandle;
 use std::time::Duration;
 
-use anyhow::{Result, bail};
+use anyhow::{Result, bail, ensure};
 pub use containerd_shim_wasm_test_modules as modules;
 use containerd_shimkit::AmbientRuntime as _;
-use containerd_shimkit::sandbox::{Instance as _, InstanceConfig};
+#[cfg(unix)]
+use containerd_shimkit::sandbox::ConsoleSocket;
+use containerd_shimkit::sandbox::{Config, Instance as _, InstanceConfig};
 use libc::{SIGINT, SIGTERM};
 use oci_spec::runtime::{
-    LinuxBuilder, LinuxNamespace, LinuxNamespaceType, ProcessBuilder, RootBuilder, SpecBuilder,
-    get_default_namespaces,
+    LinuxBuilder, LinuxMemoryBuilder, LinuxNamespace, LinuxNamespaceType, LinuxResources,
+    LinuxResourcesBuilder, ProcessBuilder, RootBuilder, SpecBuilder, get_default_namespaces,
 };
 
-use crate::shim::{Instance, Shim};
+use crate::sandbox::cancellation::Cancellation;
+use crate::sandbox::config::EngineConfig;
+use crate::sandbox::context::WasiContext;
+use crate::sandbox::{Sandbox as _, UnsupportedError};
+use crate::shim::{Instance, Isolation, Shim};
 
 pub const TEST_NAMESPACE: &str = "runwasi-test";
 pub const SIGKILL: u32 = 9;
@@ -30,12 +40,18 @@ pub struct WasiTestBuilder<WasiEngine: Shim> {
     container_name: String,
     start_fn: String,
     namespaces: Vec<LinuxNamespace>,
+    annotations: HashMap<String, String>,
+    isolation: Isolation,
+    terminal: bool,
     tempdir: tempfile::TempDir,
     _phantom: PhantomData<WasiEngine>,
 }
 
 pub struct WasiTest<WasiEngine: Shim> {
     instance: Instance<WasiEngine>,
+    /// The thread reading the output of the terminal of the guest, if it has one.
+    #[cfg(unix)]
+    console: Mutex<Option<JoinHandle<Vec<u8>>>>,
     tempdir: tempfile::TempDir,
 }
 
@@ -68,6 +84,9 @@ impl<WasiEngine: Shim> WasiTestBuilder<WasiEngine> {
             container_name: "test".to_string(),
             start_fn: "".to_string(),
             namespaces: get_default_namespaces(),
+            annotations: HashMap::new(),
+            isolation: Isolation::default(),
+            terminal: false,
             _phantom: Default::default(),
         }
         .with_wasm([0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00])?
@@ -90,6 +109,23 @@ impl<WasiEngine: Shim> WasiTestBuilder<WasiEngine> {
         self
     }
 
+    pub fn with_annotation(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.annotations.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_isolation(mut self, isolation: Isolation) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    /// Runs the guest on a terminal, whose output is read with [`WasiTest::read_console`].
+    #[cfg(unix)]
+    pub fn with_terminal(mut self) -> Self {
+        self.terminal = true;
+        self
+    }
+
     pub fn with_start_fn(mut self, start_fn: impl AsRef<str>) -> Self {
         start_fn.as_ref().clone_into(&mut self.start_fn);
         self
@@ -109,6 +145,22 @@ impl<WasiEngine: Shim> WasiTestBuilder<WasiEngine> {
         Ok(self)
     }
 
+    /// Writes `contents` to the file at the absolute `path` in the rootfs of the container.
+    pub fn with_file(self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<Self> {
+        let path = path.as_ref();
+        let relative = path.strip_prefix("/").unwrap_or(path);
+        let host_path = self.tempdir.path().join("rootfs").join(relative);
+
+        log::info!("setting wasi test file {path:?}");
+
+        if let Some(parent) = host_path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(host_path, contents)?;
+
+        Ok(self)
+    }
+
     pub fn with_stdin(self, stdin: impl AsRef<[u8]>) -> Result<Self> {
         let dir = self.tempdir.path();
 
@@ -200,26 +252,69 @@ impl<WasiEngine: Shim> WasiTestBuilder<WasiEngine> {
                 ProcessBuilder::default()
                     .cwd("/")
                     .args([entrypoint])
+                    .terminal(self.terminal)
                     .build()?,
             )
+            .annotations(self.annotations)
             .build()?;
 
         spec.save(dir.join("config.json"))?;
 
         log::info!("building wasi test: {}", dir.display());
 
+        // the guest gets the slave of a terminal instead of the stdio files,
+        // as it does when the task is created with a terminal
+        #[cfg(unix)]
+        let socket = match self.terminal {
+            true => Some(ConsoleSocket::new()?),
+            false => None,
+        };
+        #[cfg(unix)]
+        let console_socket = socket.as_ref().map(|s| s.path().to_path_buf());
+        #[cfg(windows)]
+        let console_socket = None;
+        let (stdin, stdout, stderr) = match console_socket {
+            Some(_) => Default::default(),
+            None => (dir.join("stdin"), dir.join("stdout"), dir.join("stderr")),
+        };
+
         let cfg = InstanceConfig {
             namespace: TEST_NAMESPACE.to_string(),
             containerd_address: "/run/containerd/containerd.sock".to_string(),
             bundle: dir.to_path_buf(),
-            stdout: dir.join("stdout"),
-            stderr: dir.join("stderr"),
-            stdin: dir.join("stdin"),
-            ..Default::default()
+            stdout,
+            stderr,
+            stdin,
+            config: Config {
+                isolation: self.isolation,
+                ..Default::default()
+            },
+            console_socket,
         };
 
         let instance = Instance::<WasiEngine>::new(self.container_name, &cfg).block_on()?;
-        Ok(WasiTest { instance, tempdir })
+
+        #[cfg(unix)]
+        let console = match socket {
+            Some(socket) => {
+                let mut master = socket.receive()?;
+                Some(std::thread::spawn(move || {
+                    // reading the master fails with EIO once the guest closes the terminal,
+                    // after all its output is read
+                    let mut output = vec![];
+                    let _ = std::io::Read::read_to_end(&mut master, &mut output);
+                    output
+                }))
+            }
+            None => None,
+        };
+
+        Ok(WasiTest {
+            instance,
+            #[cfg(unix)]
+            console: Mutex::new(console),
+            tempdir,
+        })
     }
 }
 
@@ -264,6 +359,12 @@ impl<WasiEngine: Shim> WasiTest<WasiEngine> {
         Ok(self)
     }
 
+    pub fn update(&self, resources: &LinuxResources) -> Result<&Self> {
+        log::info!("updating wasi test");
+        self.instance.update(resources).block_on()?;
+        Ok(self)
+    }
+
     pub fn wait(&self, t: Duration) -> Result<(u32, String, String)> {
         log::info!("waiting wasi test");
         let (status, _) = match self.instance.wait().with_timeout(t).block_on() {
@@ -284,6 +385,18 @@ impl<WasiEngine: Shim> WasiTest<WasiEngine> {
         Ok((status, stdout, stderr))
     }
 
+    /// Returns the output of the terminal of the guest, once the guest closes it.
+    #[cfg(unix)]
+    pub fn read_console(&self) -> Result<String> {
+        let Some(console) = self.console.lock().unwrap().take() else {
+            bail!("the guest has no terminal");
+        };
+        let output = console
+            .join()
+            .map_err(|_| anyhow::anyhow!("failed to read the terminal"))?;
+        Ok(String::from_utf8(output)?)
+    }
+
     pub fn root(&self) -> &Path {
         self.tempdir.path()
     }
@@ -305,6 +418,234 @@ impl<WasiEngine: Shim> WasiTest<WasiEngine> {
     }
 }
 
+/// Checks that `WasiEngine` rejects wasm components with [`UnsupportedError::Component`],
+/// for runtimes that only [support modules](crate::sandbox::Sandbox::supports_components).
+///
+/// The component is also rejected when the container is created, as opposed to failing
+/// obscurely when it starts.
+pub fn assert_component_is_unsupported<WasiEngine: Shim>() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("component.wasm");
+    write(&path, modules::COMPONENT_HELLO_WORLD)?;
+
+    let spec = SpecBuilder::default()
+        .process(
+            ProcessBuilder::default()
+                .cwd("/")
+                .args([path.to_string_lossy().into_owned()])
+                .build()?,
+        )
+        .build()?;
+    let ctx = WasiContext {
+        spec: &spec,
+        wasm_layers: &[],
+        cancellation: &Cancellation::new(),
+        engine_config: &EngineConfig::default(),
+    };
+
+    let Err(err) = WasiEngine::Sandbox::default().can_handle(&ctx).block_on() else {
+        bail!("the component was accepted");
+    };
+    ensure!(
+        matches!(
+            err.downcast_ref::<UnsupportedError>(),
+            Some(UnsupportedError::Component)
+        ),
+        "unexpected error: {err:#}"
+    );
+
+    let result = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(modules::COMPONENT_HELLO_WORLD)?
+        .build();
+    ensure!(
+        result.is_err(),
+        "the container was created with a component"
+    );
+
+    Ok(())
+}
+
+/// Checks that `WasiEngine` stops a guest blocked in a WASI call when the container is
+/// terminated, as described in the [`cancellation`](crate::sandbox::cancellation) module.
+///
+/// The guest sleeps for a minute, so it's only stopped in time if the signal is handled,
+/// either by [cancelling it](crate::sandbox::Sandbox::supports_cancellation) or by stopping
+/// the container right away.
+pub fn assert_stops_on_sigterm<WasiEngine: Shim>(isolation: Isolation) -> Result<()> {
+    let test = WasiTest::<WasiEngine>::builder()?
+        .with_isolation(isolation)
+        .with_wasm(modules::SLEEP)?
+        .build()?;
+
+    test.start()?;
+    std::thread::sleep(Duration::from_millis(500));
+    test.terminate()?;
+
+    let (exit_code, _, _) = test.wait(Duration::from_secs(10))?;
+    ensure!(
+        exit_code == 128 + SIGTERM as u32,
+        "unexpected exit code {exit_code}"
+    );
+
+    Ok(())
+}
+
+/// Asserts that updating the memory limit of a running container applies it to the guest.
+///
+/// The memory limit is applied to the cgroup of a Linux container. For a guest running in
+/// the shim process, it caps the total size of its two linear memories, so the guest exits
+/// with the number of pages that fit in the limit.
+pub fn assert_updates_memory_limit<WasiEngine: Shim>(isolation: Isolation) -> Result<()> {
+    const LIMIT: i64 = 2 << 20;
+    const PAGE_SIZE: i64 = 64 << 10;
+
+    let test = WasiTest::<WasiEngine>::builder()?
+        .with_isolation(isolation)
+        .with_wasm(modules::GROW_MEMORY)?
+        .build()?;
+
+    let pid = test.instance().start().block_on()?;
+    let resources = LinuxResourcesBuilder::default()
+        .memory(LinuxMemoryBuilder::default().limit(LIMIT).build()?)
+        .build()?;
+    test.update(&resources)?;
+
+    if isolation == Isolation::InProcess {
+        let (exit_code, _, _) = test.wait(Duration::from_secs(10))?;
+        ensure!(
+            i64::from(exit_code) == LIMIT / PAGE_SIZE,
+            "unexpected exit code {exit_code}"
+        );
+    } else {
+        let limit = cgroup_memory_limit(pid)?;
+        test.kill()?.wait(Duration::from_secs(10))?;
+        ensure!(
+            limit == LIMIT.to_string(),
+            "unexpected cgroup memory limit {limit}"
+        );
+    }
+
+    Ok(())
+}
+
+/// The variable set in the environment of the helper of [`assert_recovers_after_restart`].
+#[cfg(unix)]
+const RECOVERY_HELPER_VAR: &str = "RUNWASI_TEST_RECOVERY_HELPER";
+
+/// Starts a container that sleeps, and blocks until the process is killed, for
+/// [`assert_recovers_after_restart`]. It does nothing when it isn't run by
+/// [`assert_recovers_after_restart`].
+#[cfg(unix)]
+pub fn run_recovery_helper<WasiEngine: Shim>() -> Result<()> {
+    if std::env::var_os(RECOVERY_HELPER_VAR).is_none() {
+        return Ok(());
+    }
+
+    let test = WasiTest::<WasiEngine>::builder()?
+        .with_name("recovered")
+        .with_wasm(modules::SLEEP)?
+        .build()?;
+    let pid = test.instance().start().block_on()?;
+
+    // the bundle isn't removed, as the process is killed
+    println!("{RECOVERY_HELPER_VAR}={} {pid}", test.root().display());
+    loop {
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
+
+/// Asserts that a Linux container outlives the shim process that started it, and that a
+/// restarted shim recovers it, and reports its exit code.
+///
+/// The container is started by the `helper` test, which calls [`run_recovery_helper`], in a
+/// child process that is killed once the container is running. The recovered container is
+/// then terminated, and its guest exits as it's stopped by `SIGTERM`.
+#[cfg(unix)]
+pub fn assert_recovers_after_restart<WasiEngine: Shim>(helper: &str) -> Result<()> {
+    use std::io::{BufRead as _, BufReader};
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+
+    containerd_shimkit::zygote::Zygote::init();
+
+    let mut shim = Command::new(std::env::current_exe()?)
+        .args([helper, "--exact", "--ignored", "--nocapture"])
+        .env(RECOVERY_HELPER_VAR, "1")
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let prefix = format!("{RECOVERY_HELPER_VAR}=");
+    let started = shim.stdout.take().and_then(|stdout| {
+        BufReader::new(stdout)
+            .lines()
+            .map_while(Result::ok)
+            .find_map(|line| {
+                let (bundle, pid) = line.strip_prefix(&prefix)?.rsplit_once(' ')?;
+                Some((PathBuf::from(bundle), pid.parse::<u32>().ok()?))
+            })
+    });
+
+    // the shim is killed without deleting its container
+    shim.kill()?;
+    shim.wait()?;
+    let Some((bundle, pid)) = started else {
+        bail!("the helper {helper} didn't start the container");
+    };
+
+    let cfg = InstanceConfig {
+        namespace: TEST_NAMESPACE.to_string(),
+        bundle: bundle.clone(),
+        ..Default::default()
+    };
+    let result = (|| {
+        let instance =
+            Instance::<WasiEngine>::recover("recovered".to_string(), &cfg, Some(pid)).block_on()?;
+        ensure!(
+            instance.start().block_on().is_err(),
+            "the recovered container was started again"
+        );
+
+        instance.kill(SIGTERM as u32).block_on()?;
+        let exit = instance
+            .wait()
+            .with_timeout(Duration::from_secs(10))
+            .block_on();
+        if exit.is_none() {
+            instance.kill(SIGKILL).block_on()?;
+            instance.wait().block_on();
+        }
+        instance.delete().block_on()?;
+
+        let Some((exit_code, _)) = exit else {
+            bail!("timeout while waiting for the recovered container to exit");
+        };
+        ensure!(
+            exit_code == 128 + SIGTERM as u32,
+            "unexpected exit code {exit_code}"
+        );
+        Ok(())
+    })();
+
+    let _ = fs::remove_dir_all(bundle);
+    result
+}
+
+/// Returns the memory limit of the cgroup of the process `pid`, with cgroup v1 or v2.
+fn cgroup_memory_limit(pid: u32) -> Result<String> {
+    let cgroups = read_to_string(format!("/proc/{pid}/cgroup"))?;
+    let v1 = cgroups
+        .lines()
+        .find_map(|line| line.split_once(":memory:"))
+        .map(|(_, path)| format!("/sys/fs/cgroup/memory{path}/memory.limit_in_bytes"));
+    let v2 = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| format!("/sys/fs/cgroup{path}/memory.max"));
+    let Some(file) = v1.or(v2) else {
+        bail!("the memory cgroup of process {pid} isn't mounted");
+    };
+    Ok(read_to_string(file)?.trim().to_string())
+}
+
 pub mod oci_helpers {
     use std::fs::{File, write};
     use std::process::{Command, Stdio};
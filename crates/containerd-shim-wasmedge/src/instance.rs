@@ -1,16 +1,19 @@
 use std::collections::HashMap;
-use std::env;
 #[cfg(all(feature = "plugin", not(target_env = "musl")))]
 use std::str::FromStr;
 
-use anyhow::{Context, Result};
+#[cfg(all(feature = "plugin", not(target_env = "musl")))]
+use anyhow::bail;
+use anyhow::{Context, Result, ensure};
 use cfg_if::cfg_if;
-use containerd_shim_wasm::sandbox::Sandbox;
+use containerd_shim_wasm::sandbox::config::EngineConfig;
 use containerd_shim_wasm::sandbox::context::{Entrypoint, RuntimeContext};
+use containerd_shim_wasm::sandbox::features::{FeatureSupport, WasmFeatures};
+use containerd_shim_wasm::sandbox::{Sandbox, ensure_module};
 use containerd_shim_wasm::shim::{Shim, Version, version};
 #[cfg(all(feature = "plugin", not(target_env = "musl")))]
 use wasmedge_sdk::AsInstance;
-use wasmedge_sdk::config::{CommonConfigOptions, Config, ConfigBuilder};
+use wasmedge_sdk::config::{CommonConfigOptions, Config, ConfigBuilder, RuntimeConfigOptions};
 #[cfg(all(feature = "plugin", not(target_env = "musl")))]
 use wasmedge_sdk::plugin::NNPreload;
 #[cfg(all(feature = "plugin", not(target_env = "musl")))]
@@ -18,19 +21,48 @@ use wasmedge_sdk::plugin::PluginManager;
 use wasmedge_sdk::wasi::WasiModule;
 use wasmedge_sdk::{Module, Store, Vm};
 
+use crate::plugin::PluginConfig;
+#[cfg(all(feature = "plugin", not(target_env = "musl")))]
+use crate::plugin::WASI_NN_PLUGIN;
+
 pub struct WasmEdgeShim;
 
-pub struct WasmEdgeSandbox {
-    config: Config,
-}
+#[derive(Default)]
+pub struct WasmEdgeSandbox;
+
+/// Wasm pages are 64 KiB.
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// Builds the WasmEdge config with the settings required by the image.
+pub(crate) fn wasmedge_config(engine_config: &EngineConfig) -> Result<Config> {
+    let features = &engine_config.features;
+    for (name, enabled) in [
+        ("exceptions", features.exceptions),
+        ("memory64", features.memory64),
+        ("gc", features.gc),
+    ] {
+        ensure!(
+            enabled != Some(true),
+            "the wasmedge shim doesn't support the {name} proposal"
+        );
+    }
 
-impl Default for WasmEdgeSandbox {
-    fn default() -> Self {
-        let config = ConfigBuilder::new(CommonConfigOptions::default())
-            .build()
-            .expect("failed to create config");
-        Self { config }
+    let mut options = CommonConfigOptions::default();
+    if let Some(simd) = features.simd {
+        options = options.simd(simd);
     }
+    if let Some(threads) = features.threads {
+        options = options.threads(threads);
+    }
+
+    let mut builder = ConfigBuilder::new(options);
+    if let Some(max) = engine_config.limits.max_memory_bytes {
+        let pages = u32::try_from(max / WASM_PAGE_SIZE).unwrap_or(u32::MAX);
+        builder =
+            builder.with_runtime_config(RuntimeConfigOptions::default().max_memory_pages(pages));
+    }
+
+    builder.build().context("failed to create config")
 }
 
 impl Shim for WasmEdgeShim {
@@ -43,9 +75,21 @@ impl Shim for WasmEdgeShim {
     }
 
     type Sandbox = WasmEdgeSandbox;
+
+    fn wasm_features() -> Option<FeatureSupport> {
+        Some(FeatureSupport {
+            enabled: WasmFeatures::SIMD,
+            supported: WasmFeatures::SIMD | WasmFeatures::THREADS,
+        })
+    }
 }
 
 impl Sandbox for WasmEdgeSandbox {
+    // the WasmEdge SDK can't run WASI preview 2 components yet
+    fn supports_components() -> bool {
+        false
+    }
+
     async fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32> {
         let args = ctx.args();
         let envs = ctx.envs();
@@ -57,34 +101,42 @@ impl Sandbox for WasmEdgeSandbox {
         } = ctx.entrypoint();
 
         log::debug!("initializing WasmEdge runtime");
-
-        let prefix = "WASMEDGE_";
-        for env in envs.iter().filter(|env| env.starts_with(prefix)) {
-            if let Some((key, value)) = env.split_once('=') {
-                unsafe {
-                    env::set_var(key, value);
-                }
-            }
-        }
+        let config = wasmedge_config(ctx.engine_config())?;
 
         let mut instances = HashMap::new();
         cfg_if! {
             if #[cfg(all(feature = "plugin", not(target_env = "musl")))] {
-                PluginManager::load(None)?;
-                match env::var("WASMEDGE_WASINN_PRELOAD") {
-                    Ok(value) => PluginManager::nn_preload(vec![NNPreload::from_str(value.as_str())?]),
-                    Err(_) => log::debug!("No specific nn_preload parameter for wasi_nn plugin"),
+                let plugins = PluginConfig::from_context(ctx)?;
+                log::debug!("plugin configuration: {plugins:?}");
+
+                PluginManager::load(plugins.path.as_deref())?;
+                if plugins.wasinn_preload.is_empty() {
+                    log::debug!("No specific nn_preload parameter for wasi_nn plugin");
+                } else {
+                    let preload = plugins
+                        .wasinn_preload
+                        .iter()
+                        .map(|value| NNPreload::from_str(value))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    PluginManager::nn_preload(preload);
+                }
+
+                let has_wasi_nn = PluginManager::names().iter().any(|name| name == WASI_NN_PLUGIN);
+                if plugins.requires(WASI_NN_PLUGIN) && !has_wasi_nn {
+                    bail!("plugin {WASI_NN_PLUGIN:?} not found");
                 }
 
                 // Load the wasi_nn plugin manually as a workaround.
                 // It should call auto_detect_plugins after the issue is fixed.
-                let mut wasi_nn = PluginManager::names()
-                    .contains(&"wasi_nn".to_string())
+                let mut wasi_nn = (has_wasi_nn && plugins.enables(WASI_NN_PLUGIN))
                     .then(PluginManager::load_plugin_wasi_nn)
                     .transpose()?;
                 if let Some(ref mut nn) = wasi_nn {
                     instances.insert(nn.name().unwrap().to_string(), nn);
                 }
+            } else {
+                // rejects containers that enable plugins
+                PluginConfig::from_context(ctx)?;
             }
         }
 
@@ -96,8 +148,9 @@ impl Sandbox for WasmEdgeSandbox {
         instances.insert(wasi_module.name().to_string(), wasi_module.as_mut());
 
         let wasm_bytes = source.as_bytes()?;
-        let module = Module::from_bytes(Some(&self.config), &wasm_bytes)?;
-        let mut vm = Vm::new(Store::new(Some(&self.config), instances).unwrap());
+        ensure_module(&wasm_bytes)?;
+        let module = Module::from_bytes(Some(&config), &wasm_bytes)?;
+        let mut vm = Vm::new(Store::new(Some(&config), instances).unwrap());
         let mod_name = name.unwrap_or_else(|| "main".to_string());
 
         let vm = vm
@@ -1,4 +1,5 @@
 pub mod instance;
+pub mod plugin;
 
 pub use instance::WasmEdgeShim;
 
@@ -0,0 +1,221 @@
+//! Per container selection and configuration of WasmEdge plugins.
+//!
+//! The plugins are configured through annotations, or through the
+//! environment variables of the container:
+//!
+//! * [`PLUGINS_ANNOTATION`]: comma separated names of the plugins to enable.
+//!   All the plugins found are enabled by default. Only [`WASI_NN_PLUGIN`] can be enabled for now.
+//! * [`PLUGIN_PATH_ANNOTATION`] or `WASMEDGE_PLUGIN_PATH`: the directory to load the
+//!   plugins from, instead of the default plugin directories.
+//! * [`WASINN_PRELOAD_ANNOTATION`] or `WASMEDGE_WASINN_PRELOAD`: whitespace separated
+//!   models to preload for `wasi_nn`, as `alias:backend:target:path`.
+//!
+//! The configuration is read from the container's [`RuntimeContext`] and passed to the plugin
+//! manager. The environment variables of the container are only visible to the guest, and
+//! don't configure WasmEdge or its plugins otherwise.
+//!
+//! The plugins are loaded in the plugin manager of WasmEdge, which is global to the process.
+//! Containers managed by the same shim can only use different plugins and preload settings
+//! because each of them runs in its own process: the WasmEdge shim doesn't run containers in
+//! the shim process, as they would share the plugins loaded by the first container.
+
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
+use containerd_shim_wasm::sandbox::context::RuntimeContext;
+
+/// Annotation to select the plugins to enable.
+pub const PLUGINS_ANNOTATION: &str = "runwasi.io/wasmedge.plugins";
+/// Annotation to set the directory to load the plugins from.
+pub const PLUGIN_PATH_ANNOTATION: &str = "runwasi.io/wasmedge.plugin-path";
+/// Annotation to set the models to preload for `wasi_nn`.
+pub const WASINN_PRELOAD_ANNOTATION: &str = "runwasi.io/wasmedge.wasinn-preload";
+
+/// The name of the `wasi_nn` plugin.
+pub const WASI_NN_PLUGIN: &str = "wasi_nn";
+
+const PLUGIN_PATH_ENV: &str = "WASMEDGE_PLUGIN_PATH";
+const WASINN_PRELOAD_ENV: &str = "WASMEDGE_WASINN_PRELOAD";
+
+/// The plugin configuration of a container, see the [module documentation](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PluginConfig {
+    /// The directory to load the plugins from, or `None` for the default plugin directories.
+    pub path: Option<PathBuf>,
+    /// The plugins to enable, or `None` to enable all the plugins found.
+    pub plugins: Option<Vec<String>>,
+    /// The models to preload for `wasi_nn`.
+    pub wasinn_preload: Vec<String>,
+}
+
+impl PluginConfig {
+    /// Reads the plugin configuration of the container.
+    /// Annotations take precedence over environment variables.
+    pub fn from_context(ctx: &impl RuntimeContext) -> Result<Self> {
+        let annotations = ctx.annotations();
+        let get = |annotation: &str, env: &str| {
+            annotations
+                .get(annotation)
+                .map(|value| value.trim())
+                .or_else(|| ctx.env(env))
+                .filter(|value| !value.is_empty())
+        };
+
+        let path = get(PLUGIN_PATH_ANNOTATION, PLUGIN_PATH_ENV).map(PathBuf::from);
+        let wasinn_preload = get(WASINN_PRELOAD_ANNOTATION, WASINN_PRELOAD_ENV)
+            .map(|value| value.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+        let plugins = annotations.get(PLUGINS_ANNOTATION).map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        });
+
+        for name in plugins.iter().flatten() {
+            if !cfg!(all(feature = "plugin", not(target_env = "musl"))) {
+                bail!("can't enable plugin {name:?}, the shim is built without plugin support");
+            }
+            if name != WASI_NN_PLUGIN {
+                bail!("unsupported plugin {name:?}, only {WASI_NN_PLUGIN:?} can be enabled");
+            }
+        }
+
+        Ok(Self {
+            path,
+            plugins,
+            wasinn_preload,
+        })
+    }
+
+    /// Whether the plugin `name` is enabled.
+    pub fn enables(&self, name: &str) -> bool {
+        self.plugins
+            .as_ref()
+            .is_none_or(|plugins| plugins.iter().any(|plugin| plugin == name))
+    }
+
+    /// Whether the plugin `name` was explicitly enabled, and must be found.
+    pub fn requires(&self, name: &str) -> bool {
+        self.plugins.is_some() && self.enables(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use containerd_shim_wasm::sandbox::context::{Entrypoint, Source};
+
+    use super::*;
+
+    struct TestContext {
+        envs: Vec<String>,
+        annotations: HashMap<String, String>,
+    }
+
+    impl TestContext {
+        fn new(envs: &[&str], annotations: &[(&str, &str)]) -> Self {
+            Self {
+                envs: envs.iter().map(|env| env.to_string()).collect(),
+                annotations: annotations
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            }
+        }
+    }
+
+    impl RuntimeContext for TestContext {
+        fn args(&self) -> &[String] {
+            &[]
+        }
+
+        fn envs(&self) -> &[String] {
+            &self.envs
+        }
+
+        fn entrypoint(&self) -> Entrypoint<'_> {
+            Entrypoint {
+                func: "_start".to_string(),
+                name: None,
+                arg0: None,
+                source: Source::File(PathBuf::new()),
+            }
+        }
+
+        fn annotations(&self) -> &HashMap<String, String> {
+            &self.annotations
+        }
+    }
+
+    #[test]
+    fn test_plugin_config_defaults() -> Result<()> {
+        let config = PluginConfig::from_context(&TestContext::new(&[], &[]))?;
+        assert_eq!(config, PluginConfig::default());
+        assert!(config.enables(WASI_NN_PLUGIN));
+        assert!(!config.requires(WASI_NN_PLUGIN));
+        Ok(())
+    }
+
+    #[test]
+    fn test_plugin_config_from_envs() -> Result<()> {
+        let ctx = TestContext::new(
+            &[
+                "WASMEDGE_PLUGIN_PATH=/plugins",
+                "WASMEDGE_WASINN_PRELOAD=default:GGML:AUTO:llama.gguf",
+            ],
+            &[],
+        );
+
+        let config = PluginConfig::from_context(&ctx)?;
+        assert_eq!(config.path, Some(PathBuf::from("/plugins")));
+        assert_eq!(config.wasinn_preload, ["default:GGML:AUTO:llama.gguf"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plugin_config_annotations_take_precedence() -> Result<()> {
+        let ctx = TestContext::new(
+            &["WASMEDGE_WASINN_PRELOAD=default:GGML:AUTO:llama.gguf"],
+            &[
+                (
+                    WASINN_PRELOAD_ANNOTATION,
+                    "a:GGML:CPU:a.gguf b:GGML:GPU:b.gguf",
+                ),
+                (PLUGIN_PATH_ANNOTATION, "/opt/plugins"),
+            ],
+        );
+
+        let config = PluginConfig::from_context(&ctx)?;
+        assert_eq!(config.path, Some(PathBuf::from("/opt/plugins")));
+        assert_eq!(
+            config.wasinn_preload,
+            ["a:GGML:CPU:a.gguf", "b:GGML:GPU:b.gguf"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_plugin_config_selects_plugins() -> Result<()> {
+        let ctx = TestContext::new(&[], &[(PLUGINS_ANNOTATION, "")]);
+        let config = PluginConfig::from_context(&ctx)?;
+        assert!(!config.enables(WASI_NN_PLUGIN));
+
+        let ctx = TestContext::new(&[], &[(PLUGINS_ANNOTATION, "wasi_crypto")]);
+        assert!(PluginConfig::from_context(&ctx).is_err());
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "plugin", not(target_env = "musl")))]
+    #[test]
+    fn test_plugin_config_requires_plugins() -> Result<()> {
+        let ctx = TestContext::new(&[], &[(PLUGINS_ANNOTATION, " wasi_nn ")]);
+        let config = PluginConfig::from_context(&ctx)?;
+        assert!(config.requires(WASI_NN_PLUGIN));
+        Ok(())
+    }
+}
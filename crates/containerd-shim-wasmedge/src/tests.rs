@@ -1,11 +1,17 @@
 use std::time::Duration;
 
-use containerd_shim_wasm::testing::WasiTest;
+use containerd_shim_wasm::sandbox::config::EngineConfig;
+
+use containerd_shim_wasm::shim::Isolation;
+use containerd_shim_wasm::testing::{
+    WasiTest, assert_component_is_unsupported, assert_stops_on_sigterm,
+};
 //use containerd_shim_wasm::sandbox::Instance;
 use containerd_shim_wasm::testing::modules::*;
 use serial_test::serial;
 
 use crate::WasmEdgeShim as WasiEngine;
+use crate::instance::wasmedge_config;
 
 #[test]
 #[serial]
@@ -153,3 +159,40 @@ fn check_dynamic_linking() {
     let current_exe = std::env::current_exe().unwrap().canonicalize().unwrap();
     assert!(wasmedge_path != current_exe);
 }
+
+#[test]
+#[serial]
+fn test_wasip2_component_is_unsupported() -> anyhow::Result<()> {
+    assert_component_is_unsupported::<WasiEngine>()
+}
+
+#[test]
+#[serial]
+fn test_sleep_stops_on_sigterm() -> anyhow::Result<()> {
+    assert_stops_on_sigterm::<WasiEngine>(Isolation::Container)
+}
+
+#[test]
+fn test_engine_config_rejects_unsupported_settings() -> anyhow::Result<()> {
+    for (toml, error) in [
+        (
+            "[features]\nexceptions = true",
+            "the wasmedge shim doesn't support the exceptions proposal",
+        ),
+        (
+            "[features]\nmemory64 = true",
+            "the wasmedge shim doesn't support the memory64 proposal",
+        ),
+        (
+            "[features]\ngc = true",
+            "the wasmedge shim doesn't support the gc proposal",
+        ),
+    ] {
+        let Err(err) = wasmedge_config(&EngineConfig::from_toml(toml)?) else {
+            panic!("{toml:?} was accepted");
+        };
+        assert_eq!(err.to_string(), error);
+    }
+
+    Ok(())
+}
@@ -1,11 +1,21 @@
-use anyhow::Result;
-use containerd_shim_wasm::sandbox::Sandbox;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+use containerd_shim_wasm::sandbox::config::EngineConfig;
 use containerd_shim_wasm::sandbox::context::{Entrypoint, RuntimeContext};
+use containerd_shim_wasm::sandbox::features::{FeatureSupport, WasmFeatures};
+use containerd_shim_wasm::sandbox::{Sandbox, ensure_module};
 use containerd_shim_wasm::shim::{Shim, Version, version};
 use tokio::runtime::Handle;
-use wasmer::{Module, Store};
+use wasmer::sys::{EngineBuilder, Features};
+use wasmer::{Engine, Module, Store};
+use wasmer_wasix::runtime::task_manager::tokio::TokioTaskManager;
+use wasmer_wasix::types::wasi::Signal;
 use wasmer_wasix::virtual_fs::host_fs::FileSystem;
-use wasmer_wasix::{WasiEnv, WasiError};
+use wasmer_wasix::{PluggableRuntime, WasiEnv, WasiError};
+
+use crate::network::{HTTP_SERVER_SOCKET_ADDR_ENV, PolicyNetworking};
 
 pub struct WasmerShim;
 
@@ -24,12 +34,27 @@ impl Shim for WasmerShim {
     }
 
     type Sandbox = WasmerSandbox;
+
+    fn wasm_features() -> Option<FeatureSupport> {
+        Some(FeatureSupport {
+            enabled: WasmFeatures::SIMD | WasmFeatures::THREADS,
+            supported: WasmFeatures::SIMD | WasmFeatures::THREADS,
+        })
+    }
 }
 
 impl Sandbox for WasmerSandbox {
+    fn supports_components() -> bool {
+        false
+    }
+
+    fn supports_cancellation() -> bool {
+        true
+    }
+
     async fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32> {
         let args = ctx.args();
-        let envs = ctx
+        let mut envs = ctx
             .envs()
             .iter()
             .map(|v| match v.split_once('=') {
@@ -37,6 +62,21 @@ impl Sandbox for WasmerSandbox {
                 Some((key, value)) => (key.to_string(), value.to_string()),
             })
             .collect::<Vec<_>>();
+
+        // Consume the HTTP server settings before passing the envs to the guest
+        let server_addr = match envs
+            .iter()
+            .position(|(key, _)| key == HTTP_SERVER_SOCKET_ADDR_ENV)
+        {
+            Some(i) => Some(
+                envs.remove(i)
+                    .1
+                    .parse::<SocketAddr>()
+                    .with_context(|| format!("invalid {HTTP_SERVER_SOCKET_ADDR_ENV}"))?,
+            ),
+            None => None,
+        };
+        let network = ctx.network_policy()?;
         let Entrypoint {
             source,
             func,
@@ -47,23 +87,41 @@ impl Sandbox for WasmerSandbox {
         let mod_name = name.unwrap_or_else(|| "main".to_string());
 
         log::info!("Create a Store");
-        let mut store = Store::new(self.engine.clone());
+        let mut store = Store::new(self.engine_for(ctx.engine_config())?);
 
         let wasm_bytes = source.as_bytes()?;
+        ensure_module(&wasm_bytes)?;
         let module = Module::from_binary(&store, &wasm_bytes)?;
 
-        log::info!("Creating `WasiEnv`...: args {args:?}, envs: {envs:?}");
+        log::info!("Creating `WasiEnv`...: args {args:?}, envs: {envs:?}, network: {network:?}");
         let fs = FileSystem::new(Handle::current(), "/")?;
+        let mut runtime = PluggableRuntime::new(Arc::new(TokioTaskManager::new(Handle::current())));
+        runtime.set_networking_implementation(PolicyNetworking::new(network, server_addr));
+
         let (instance, wasi_env) = WasiEnv::builder(mod_name)
             .args(&args[1..])
             .envs(envs)
             .fs(Box::new(fs))
+            .runtime(Arc::new(runtime))
             .preopen_dir("/")?
             .instantiate(module, &mut store)?;
 
         log::info!("Running {func:?}");
         let start = instance.exports.get_function(&func)?;
         wasi_env.data(&store).thread.set_status_running();
+
+        // Forward the termination signals to the guest, which handles them on its next syscall
+        let process = wasi_env.data(&store).process.clone();
+        let cancellation = ctx.cancellation().clone();
+        let forward_signal = tokio::spawn(async move {
+            let signal = match cancellation.cancelled().await {
+                libc::SIGINT => Signal::Sigint,
+                libc::SIGQUIT => Signal::Sigquit,
+                _ => Signal::Sigterm,
+            };
+            process.signal_process(signal);
+        });
+
         let status = tokio::task::block_in_place(|| {
             start.call(&mut store, &[]).map(|_| 0).or_else(|err| {
                 match err.downcast_ref::<WasiError>() {
@@ -71,8 +129,44 @@ impl Sandbox for WasmerSandbox {
                     _ => Err(err),
                 }
             })
-        })?;
+        });
+        forward_signal.abort();
+
+        Ok(status?)
+    }
+}
+
+impl WasmerSandbox {
+    /// Returns an engine with the wasm features required by the image.
+    pub(crate) fn engine_for(&self, config: &EngineConfig) -> Result<Engine> {
+        let features = &config.features;
+        for (name, enabled) in [
+            ("exceptions", features.exceptions),
+            ("memory64", features.memory64),
+            ("gc", features.gc),
+        ] {
+            if enabled == Some(true) {
+                bail!("the wasmer shim doesn't support the {name} proposal");
+            }
+        }
+        if config.limits.max_memory_bytes.is_some() {
+            bail!("the wasmer shim doesn't support memory limits, remove max-memory-bytes");
+        }
+
+        if config.features.is_default() {
+            return Ok(self.engine.clone().into());
+        }
+
+        let mut features = Features::default();
+        if let Some(simd) = config.features.simd {
+            features.simd(simd);
+        }
+        if let Some(threads) = config.features.threads {
+            features.threads(threads);
+        }
 
-        Ok(status)
+        Ok(EngineBuilder::new(self.engine.clone())
+            .set_features(Some(features))
+            .into())
     }
 }
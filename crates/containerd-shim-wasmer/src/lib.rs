@@ -1,4 +1,5 @@
 pub mod instance;
+mod network;
 
 pub use instance::WasmerShim;
 
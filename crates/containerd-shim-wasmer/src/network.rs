@@ -0,0 +1,192 @@
+//! WASIX networking restricted by the container's [`NetworkPolicy`].
+//!
+//! In HTTP server mode, enabled by setting [`HTTP_SERVER_SOCKET_ADDR_ENV`] in the
+//! container's environment, the HTTP listener of the guest is bound to the
+//! configured address instead of the one requested by the guest. This lets a
+//! WASIX server be exposed on the same address as a `wasi:http/proxy` component
+//! running in the wasmtime shim.
+//!
+//! Only a single listener is redirected: the first one requesting the port of the
+//! configured address, or else the first listener of the guest. The other listeners
+//! are bound to the address requested by the guest.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use containerd_shim_wasm::sandbox::network::NetworkPolicy;
+use wasmer_wasix::virtual_net::host::LocalNetworking;
+use wasmer_wasix::virtual_net::{
+    NetworkError, VirtualNetworking, VirtualTcpListener, VirtualTcpSocket, VirtualUdpSocket,
+};
+
+/// Environment variable with the address to serve HTTP on, e.g., `0.0.0.0:8080`.
+pub(crate) const HTTP_SERVER_SOCKET_ADDR_ENV: &str = "WASMER_HTTP_SERVER_SOCKET_ADDR";
+
+type Result<T> = std::result::Result<T, NetworkError>;
+
+/// Host networking that only allows what the [`NetworkPolicy`] grants.
+#[derive(Debug)]
+pub(crate) struct PolicyNetworking {
+    inner: LocalNetworking,
+    policy: NetworkPolicy,
+    server_addr: Option<SocketAddr>,
+    /// Whether a listener was already bound to `server_addr`.
+    redirected: AtomicBool,
+}
+
+impl PolicyNetworking {
+    pub fn new(policy: NetworkPolicy, server_addr: Option<SocketAddr>) -> Self {
+        Self {
+            inner: LocalNetworking::default(),
+            policy,
+            server_addr,
+            redirected: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the address to bind a listener requested on `addr` to, in HTTP server mode.
+    fn redirect(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        let server_addr = self.server_addr?;
+        let matching = server_addr.port() != 0 && addr.port() == server_addr.port();
+        let first = !self.redirected.load(Ordering::SeqCst);
+        if !matching && !first {
+            return None;
+        }
+        // only a single listener can be bound to the server address
+        self.redirected
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| server_addr)
+    }
+
+    fn check(allowed: bool, what: &str) -> Result<()> {
+        if !allowed {
+            log::debug!("{what} denied by the network policy");
+            return Err(NetworkError::PermissionDenied);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualNetworking for PolicyNetworking {
+    async fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        only_v6: bool,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualTcpListener + Sync>> {
+        Self::check(self.policy.allow_tcp, "TCP listener")?;
+        let Some(server_addr) = self.redirect(addr) else {
+            return self
+                .inner
+                .listen_tcp(addr, only_v6, reuse_port, reuse_addr)
+                .await;
+        };
+
+        let listener = self
+            .inner
+            .listen_tcp(server_addr, false, reuse_port, reuse_addr)
+            .await;
+        if listener.is_err() {
+            // let another listener be redirected
+            self.redirected.store(false, Ordering::SeqCst);
+        }
+        let listener = listener?;
+        log::info!("Serving HTTP on http://{server_addr}/ (guest requested {addr})");
+        Ok(listener)
+    }
+
+    async fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>> {
+        Self::check(self.policy.allow_tcp, "TCP connection")?;
+        self.inner.connect_tcp(addr, peer).await
+    }
+
+    async fn bind_udp(
+        &self,
+        addr: SocketAddr,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualUdpSocket + Sync>> {
+        Self::check(self.policy.allow_udp, "UDP socket")?;
+        self.inner.bind_udp(addr, reuse_port, reuse_addr).await
+    }
+
+    async fn resolve(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        dns_server: Option<IpAddr>,
+    ) -> Result<Vec<IpAddr>> {
+        Self::check(self.policy.allow_ip_name_lookup, "name lookup")?;
+        self.inner.resolve(host, port, dns_server).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const ANY: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+    #[tokio::test]
+    async fn test_denied_by_policy() {
+        let policy = NetworkPolicy {
+            allow_tcp: false,
+            allow_udp: false,
+            allow_ip_name_lookup: false,
+        };
+        let net = PolicyNetworking::new(policy, None);
+
+        let err = net.listen_tcp(ANY, false, false, false).await.unwrap_err();
+        assert!(matches!(err, NetworkError::PermissionDenied));
+
+        let err = net.bind_udp(ANY, false, false).await.unwrap_err();
+        assert!(matches!(err, NetworkError::PermissionDenied));
+
+        let err = net.resolve("localhost", None, None).await.unwrap_err();
+        assert!(matches!(err, NetworkError::PermissionDenied));
+    }
+
+    #[tokio::test]
+    async fn test_http_server_mode_rebinds_listeners() {
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let net = PolicyNetworking::new(NetworkPolicy::default(), Some(server_addr));
+
+        let listener = net.listen_tcp(ANY, false, false, false).await.unwrap();
+        assert_eq!(listener.addr_local().unwrap().ip(), server_addr.ip());
+
+        // other listeners are bound to the requested address
+        let requested = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let other = net
+            .listen_tcp(requested, false, false, false)
+            .await
+            .unwrap();
+        assert_ne!(
+            other.addr_local().unwrap().port(),
+            listener.addr_local().unwrap().port()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_server_mode_redirects_a_single_listener() {
+        let probe = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let server_addr = probe.local_addr().unwrap();
+        drop(probe);
+        let net = PolicyNetworking::new(NetworkPolicy::default(), Some(server_addr));
+
+        let port = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), server_addr.port());
+        let listener = net.listen_tcp(port, false, false, false).await.unwrap();
+        assert_eq!(listener.addr_local().unwrap(), server_addr);
+
+        let other = net.listen_tcp(ANY, false, false, false).await.unwrap();
+        assert_ne!(other.addr_local().unwrap(), server_addr);
+    }
+}
@@ -1,11 +1,17 @@
 use std::time::Duration;
 
-use containerd_shim_wasm::testing::WasiTest;
+use containerd_shim_wasm::sandbox::config::EngineConfig;
+
+use containerd_shim_wasm::shim::Isolation;
+use containerd_shim_wasm::testing::{
+    WasiTest, assert_component_is_unsupported, assert_stops_on_sigterm,
+};
 //use containerd_shim_wasm::sandbox::Instance;
 use containerd_shim_wasm::testing::modules::*;
 use serial_test::serial;
 
 use crate::WasmerShim as WasiEngine;
+use crate::instance::WasmerSandbox;
 
 #[test]
 #[serial]
@@ -116,3 +122,44 @@ fn test_has_default_devices() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+#[serial]
+fn test_wasip2_component_is_unsupported() -> anyhow::Result<()> {
+    assert_component_is_unsupported::<WasiEngine>()
+}
+
+#[test]
+#[serial]
+fn test_sleep_stops_on_sigterm() -> anyhow::Result<()> {
+    assert_stops_on_sigterm::<WasiEngine>(Isolation::Container)
+}
+
+#[test]
+fn test_engine_config_rejects_unsupported_settings() -> anyhow::Result<()> {
+    for (toml, error) in [
+        (
+            "[features]\nexceptions = true",
+            "the wasmer shim doesn't support the exceptions proposal",
+        ),
+        (
+            "[features]\nmemory64 = true",
+            "the wasmer shim doesn't support the memory64 proposal",
+        ),
+        (
+            "[features]\ngc = true",
+            "the wasmer shim doesn't support the gc proposal",
+        ),
+        (
+            "[limits]\nmax-memory-bytes = 1048576",
+            "the wasmer shim doesn't support memory limits, remove max-memory-bytes",
+        ),
+    ] {
+        let Err(err) = WasmerSandbox::default().engine_for(&EngineConfig::from_toml(toml)?) else {
+            panic!("{toml:?} was accepted");
+        };
+        assert_eq!(err.to_string(), error);
+    }
+
+    Ok(())
+}
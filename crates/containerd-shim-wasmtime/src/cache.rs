@@ -0,0 +1,299 @@
+//! Cache of the engines, and of the modules and components they compile, shared by the
+//! containers of the shim process.
+//!
+//! A wasm layer is compiled, or deserialized when it's precompiled, the first time it runs.
+//! Modules are keyed by the digest of their layer, by the compatibility hash of their engine,
+//! as a module can only run on an engine with the same config as the one that compiled it,
+//! and by whether the layer is precompiled, as a precompiled layer keeps the digest of the
+//! original binary. Modules read from a file of the rootfs aren't cached.
+//!
+//! The cache keeps the [`MAX_CACHED_MODULES`] most recently used modules, and evicts the
+//! least recently used one when a new module doesn't fit. Engines are few, one per set of
+//! wasm features, and are kept for the lifetime of the shim process.
+//!
+//! Every container running in the shim process, with the `in-process` isolation, uses the
+//! cache. A container running in a Linux container has a process of its own, forked from the
+//! zygote before any module is compiled, so it only shares the cache with itself.
+//!
+//! The activity of the cache is reported by [`cache_stats`], and logged when a container exits.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+use anyhow::Result;
+use containerd_shim_wasm::sandbox::config::Features;
+use wasmtime::component::Component;
+use wasmtime::{Engine, Module};
+
+/// The maximum number of compiled modules and components kept by the cache.
+pub const MAX_CACHED_MODULES: usize = 64;
+
+/// The cache of the shim process.
+pub(crate) static MODULE_CACHE: LazyLock<ModuleCache> =
+    LazyLock::new(|| ModuleCache::new(MAX_CACHED_MODULES));
+
+/// Identifies an engine of the cache: the wasm [`Features`] it enables, and whether it's the
+/// synchronous engine of the modules using wasi-threads, see [`crate::threads`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct EngineKey {
+    pub features: Features,
+    pub wasi_threads: bool,
+}
+
+impl EngineKey {
+    /// The key of the async engine enabling `features`.
+    pub(crate) fn new(features: &Features) -> Self {
+        Self {
+            features: features.clone(),
+            wasi_threads: false,
+        }
+    }
+
+    /// The key of the wasi-threads engine enabling `features`.
+    pub(crate) fn wasi_threads(features: &Features) -> Self {
+        Self {
+            features: features.clone(),
+            wasi_threads: true,
+        }
+    }
+}
+
+/// A compiled wasm binary.
+#[derive(Clone)]
+pub(crate) enum Compiled {
+    Module(Module),
+    Component(Component),
+}
+
+/// Identifies a compiled module, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ModuleKey {
+    digest: String,
+    engine: u64,
+    precompiled: bool,
+}
+
+/// A compiled module, and when it was last used.
+struct Entry {
+    compiled: Compiled,
+    last_used: u64,
+}
+
+pub(crate) struct ModuleCache {
+    capacity: usize,
+    engines: Mutex<HashMap<EngineKey, Engine>>,
+    compiled: Mutex<HashMap<ModuleKey, Entry>>,
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// The activity of the module cache of the shim process.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The lookups of compiled modules that hit the cache.
+    pub hits: u64,
+    /// The lookups of compiled modules that missed the cache, and compiled the module.
+    pub misses: u64,
+    /// The modules evicted from the cache to make room for new ones.
+    pub evictions: u64,
+    /// The modules in the cache.
+    pub modules: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            hits,
+            misses,
+            evictions,
+            modules,
+        } = self;
+        write!(
+            f,
+            "{hits} hits, {misses} misses, {evictions} evictions, {modules} modules"
+        )
+    }
+}
+
+/// Returns the statistics of the module cache of the shim process.
+pub fn cache_stats() -> CacheStats {
+    MODULE_CACHE.stats()
+}
+
+impl ModuleCache {
+    /// Creates a cache keeping at most `capacity` compiled modules.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            engines: Mutex::default(),
+            compiled: Mutex::default(),
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the engine with the `key`, created with `create` the first time.
+    pub(crate) fn engine(
+        &self,
+        key: &EngineKey,
+        create: impl FnOnce() -> Result<Engine>,
+    ) -> Result<Engine> {
+        let mut engines = self.engines.lock().unwrap();
+        if let Some(engine) = engines.get(key) {
+            return Ok(engine.clone());
+        }
+        let engine = create()?;
+        engines.insert(key.clone(), engine.clone());
+        Ok(engine)
+    }
+
+    /// Returns the `wasm_bytes` of the layer with `digest`, compiled for `engine` with
+    /// `compile` on a miss.
+    ///
+    /// Binaries without a digest are always compiled, and aren't counted.
+    pub(crate) fn get_or_compile(
+        &self,
+        digest: Option<&str>,
+        engine: &Engine,
+        wasm_bytes: &[u8],
+        compile: impl FnOnce() -> Result<Compiled>,
+    ) -> Result<Compiled> {
+        let Some(digest) = digest else {
+            return compile();
+        };
+        let key = ModuleKey {
+            digest: digest.to_string(),
+            engine: engine_hash(engine),
+            precompiled: Engine::detect_precompiled(wasm_bytes).is_some(),
+        };
+
+        if let Some(entry) = self.compiled.lock().unwrap().get_mut(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            entry.last_used = self.tick();
+            log::debug!("module cache hit for {digest}");
+            return Ok(entry.compiled.clone());
+        }
+
+        // compile outside of the lock, so that other layers can be looked up meanwhile
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        log::debug!("module cache miss for {digest}");
+        let compiled = compile()?;
+
+        let mut cache = self.compiled.lock().unwrap();
+        if !cache.contains_key(&key) && cache.len() >= self.capacity {
+            let lru = cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(lru) = lru {
+                log::debug!("evicting {} from the module cache", lru.digest);
+                cache.remove(&lru);
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let last_used = self.tick();
+        let entry = cache.entry(key).or_insert(Entry {
+            compiled,
+            last_used,
+        });
+        Ok(entry.compiled.clone())
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            modules: self.compiled.lock().unwrap().len(),
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Hashes the compatibility hash of `engine`, which is the same for engines whose
+/// compiled modules are interchangeable.
+fn engine_hash(engine: &Engine) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    engine.precompile_compatibility_hash().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    #[test]
+    fn test_get_or_compile() -> Result<()> {
+        let cache = ModuleCache::new(MAX_CACHED_MODULES);
+        let key = EngineKey::default();
+        let engine = cache.engine(&key, || Engine::new(&Default::default()))?;
+        let same = cache.engine(&key, || unreachable!())?;
+        assert!(Engine::same(&engine, &same));
+
+        let compile = |engine: &Engine| Ok(Compiled::Module(Module::new(engine, MODULE)?));
+
+        cache.get_or_compile(Some("sha256:a"), &engine, MODULE, || compile(&engine))?;
+        cache.get_or_compile(Some("sha256:a"), &engine, MODULE, || unreachable!())?;
+        cache.get_or_compile(Some("sha256:b"), &engine, MODULE, || compile(&engine))?;
+        cache.get_or_compile(None, &engine, MODULE, || compile(&engine))?;
+
+        // a precompiled layer keeps the digest of the original binary
+        let precompiled = engine.precompile_module(MODULE)?;
+        cache.get_or_compile(Some("sha256:a"), &engine, &precompiled, || compile(&engine))?;
+
+        let mut config = wasmtime::Config::new();
+        config.wasm_simd(false).wasm_relaxed_simd(false);
+        let simd = Engine::new(&config)?;
+        cache.get_or_compile(Some("sha256:a"), &simd, MODULE, || compile(&simd))?;
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 4,
+                evictions: 0,
+                modules: 4,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() -> Result<()> {
+        let cache = ModuleCache::new(2);
+        let engine = Engine::default();
+        let compile = || Ok(Compiled::Module(Module::new(&engine, MODULE)?));
+
+        cache.get_or_compile(Some("sha256:a"), &engine, MODULE, compile)?;
+        cache.get_or_compile(Some("sha256:b"), &engine, MODULE, compile)?;
+        cache.get_or_compile(Some("sha256:a"), &engine, MODULE, || unreachable!())?;
+
+        // `b` is the least recently used module
+        cache.get_or_compile(Some("sha256:c"), &engine, MODULE, compile)?;
+        cache.get_or_compile(Some("sha256:a"), &engine, MODULE, || unreachable!())?;
+        cache.get_or_compile(Some("sha256:b"), &engine, MODULE, compile)?;
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 4,
+                evictions: 2,
+                modules: 2,
+            }
+        );
+        Ok(())
+    }
+}
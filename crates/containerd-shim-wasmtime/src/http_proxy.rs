@@ -11,7 +11,6 @@ use anyhow::{Result, bail};
 use containerd_shim_wasm::sandbox::context::RuntimeContext;
 use hyper::server::conn::http1;
 use tokio::net::{TcpListener, TcpStream};
-use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 use wasmtime::Store;
 use wasmtime::component::ResourceTable;
@@ -21,7 +20,8 @@ use wasmtime_wasi_http::body::HyperOutgoingBody;
 use wasmtime_wasi_http::io::TokioIo;
 use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
 
-use crate::instance::{WasiPreview2Ctx, envs_from_ctx};
+use crate::instance::{Limiter, WasiPreview2Ctx, envs_from_ctx, store_limits, yield_on_epochs};
+use crate::wasi_cloud::WasiCloudCtx;
 
 const DEFAULT_ADDR: SocketAddr =
     SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)), 8080);
@@ -67,7 +67,6 @@ async fn tcp_accept(listener: &TcpListener) -> Option<TcpStream> {
 pub(crate) async fn serve_conn(
     ctx: &impl RuntimeContext,
     instance: ProxyPre<WasiPreview2Ctx>,
-    cancel: CancellationToken,
 ) -> Result<()> {
     let mut env = envs_from_ctx(ctx).into_iter().collect::<HashMap<_, _>>();
 
@@ -103,7 +102,15 @@ pub(crate) async fn serve_conn(
     log::info!("Serving HTTP on http://{}/", listener.local_addr()?);
 
     let env = env.into_iter().collect();
-    let handler = Arc::new(ProxyHandler::new(instance, env, tracker.clone()));
+    let limits = store_limits(ctx);
+    let cloud = WasiCloudCtx::from_context(ctx)?;
+    let handler = Arc::new(ProxyHandler::new(
+        instance,
+        env,
+        limits,
+        cloud,
+        tracker.clone(),
+    ));
 
     loop {
         let stream = tokio::select! {
@@ -113,7 +120,7 @@ pub(crate) async fn serve_conn(
                     None => continue,
                 }
             }
-            _ = cancel.cancelled() => {
+            _ = ctx.cancellation().cancelled() => {
                 break;
             }
         };
@@ -145,6 +152,8 @@ struct ProxyHandler {
     instance_pre: ProxyPre<WasiPreview2Ctx>,
     next_id: AtomicU64,
     env: Vec<(String, String)>,
+    limits: Limiter,
+    cloud: WasiCloudCtx,
     tracker: TaskTracker,
 }
 
@@ -152,11 +161,15 @@ impl ProxyHandler {
     fn new(
         instance_pre: ProxyPre<WasiPreview2Ctx>,
         env: Vec<(String, String)>,
+        limits: Limiter,
+        cloud: WasiCloudCtx,
         tracker: TaskTracker,
     ) -> Self {
         ProxyHandler {
             instance_pre,
             env,
+            limits,
+            cloud,
             tracker,
             next_id: AtomicU64::from(0),
         }
@@ -173,9 +186,14 @@ impl ProxyHandler {
             wasi_ctx: builder.build(),
             wasi_http: WasiHttpCtx::new(),
             resource_table: ResourceTable::default(),
+            limits: self.limits.clone(),
+            cloud: self.cloud.clone(),
         };
 
-        Store::new(engine, ctx)
+        let mut store = Store::new(engine, ctx);
+        store.limiter(|ctx| &mut ctx.limits);
+        yield_on_epochs(&mut store);
+        store
     }
 
     async fn handle_request(
@@ -1,23 +1,33 @@
 use std::hash::Hash;
-use std::sync::LazyLock;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 
 use anyhow::{Context, Result, bail};
 use containerd_shim_wasm::sandbox::Sandbox;
+use containerd_shim_wasm::sandbox::config::{EngineConfig, Features};
 use containerd_shim_wasm::sandbox::context::{
-    Entrypoint, RuntimeContext, WasmBinaryType, WasmLayer,
+    Entrypoint, LiveLimits, RuntimeContext, Source, Stdio, WasmBinaryType, WasmLayer,
 };
+use containerd_shim_wasm::sandbox::features::{FeatureSupport, WasmFeatures};
+use containerd_shim_wasm::sandbox::rootfs::Rootfs;
 use containerd_shim_wasm::shim::{Compiler, Shim, Version, version};
-use tokio_util::sync::CancellationToken;
 use wasmtime::component::types::ComponentItem;
 use wasmtime::component::{self, Component, ResourceTable};
-use wasmtime::{Config, Module, Precompiled, Store};
+use wasmtime::{
+    Config, Engine, Module, Precompiled, ResourceLimiter, Store, StoreLimits, StoreLimitsBuilder,
+};
+use wasmtime_wasi::cli::{InputFile, OutputFile};
 use wasmtime_wasi::p2::bindings::Command;
 use wasmtime_wasi::preview1::{self as wasi_preview1, WasiP1Ctx};
 use wasmtime_wasi::{WasiCtx, WasiCtxBuilder, WasiCtxView, WasiView};
 use wasmtime_wasi_http::bindings::ProxyPre;
 use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
 
+use crate::cache::{Compiled, EngineKey, MODULE_CACHE};
 use crate::http_proxy::serve_conn;
+use crate::threads;
+use crate::wasi_cloud::{self, WasiCloudCtx};
 
 /// Represents the WASI API that the component is targeting.
 enum ComponentTarget<'a> {
@@ -56,37 +66,72 @@ pub struct WasmtimeCompiler(wasmtime::Engine);
 
 pub struct WasmtimeSandbox {
     engine: wasmtime::Engine,
-    cancel: CancellationToken,
 }
 
 impl Default for WasmtimeSandbox {
     fn default() -> Self {
-        let mut config = wasmtime::Config::new();
+        let engine = MODULE_CACHE
+            .engine(&EngineKey::default(), || {
+                new_engine(&engine_config()).context("failed to create wasmtime engine")
+            })
+            .unwrap();
+        Self { engine }
+    }
+}
 
-        // Disable Wasmtime parallel compilation for the tests
-        // see https://github.com/containerd/runwasi/pull/405#issuecomment-1928468714 for details
-        config.parallel_compilation(!cfg!(test));
-        config.wasm_component_model(true); // enable component linking
-        config.async_support(true); // must be on
+/// How often the running guests yield to the async runtime.
+///
+/// The engines interrupt the guests at every epoch, so that a guest that doesn't call the host,
+/// e.g., looping forever, still gives the runtime a chance to drop it when its container is
+/// cancelled. This is how guests running in the shim process are stopped.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Creates an engine, and the thread incrementing its epoch every [`EPOCH_TICK`].
+/// The engines are cached for the lifetime of the shim, like the thread.
+fn new_engine(config: &Config) -> Result<Engine> {
+    let engine = Engine::new(config)?;
+    let ticker = engine.weak();
+    std::thread::Builder::new()
+        .name("wasmtime-epoch".to_string())
+        .spawn(move || {
+            while let Some(engine) = ticker.upgrade() {
+                engine.increment_epoch();
+                drop(engine);
+                std::thread::sleep(EPOCH_TICK);
+            }
+        })?;
+    Ok(engine)
+}
 
-        if use_pooling_allocator_by_default() {
-            let cfg = wasmtime::PoolingAllocationConfig::default();
-            config.allocation_strategy(wasmtime::InstanceAllocationStrategy::Pooling(cfg));
-        }
+/// Makes the guest of `store` yield at every epoch, see [`EPOCH_TICK`].
+pub(crate) fn yield_on_epochs<T>(store: &mut Store<T>) {
+    store.epoch_deadline_async_yield_and_update(1);
+}
 
-        Self {
-            engine: wasmtime::Engine::new(&config)
-                .context("failed to create wasmtime engine")
-                .unwrap(),
-            cancel: CancellationToken::new(),
-        }
+fn engine_config() -> Config {
+    let mut config = wasmtime::Config::new();
+
+    // Disable Wasmtime parallel compilation for the tests
+    // see https://github.com/containerd/runwasi/pull/405#issuecomment-1928468714 for details
+    config.parallel_compilation(!cfg!(test));
+    config.wasm_component_model(true); // enable component linking
+    config.async_support(true); // must be on
+    config.epoch_interruption(true); // see `EPOCH_TICK`
+
+    if use_pooling_allocator_by_default() {
+        let cfg = wasmtime::PoolingAllocationConfig::default();
+        config.allocation_strategy(wasmtime::InstanceAllocationStrategy::Pooling(cfg));
     }
+
+    config
 }
 
 pub struct WasiPreview2Ctx {
     pub(crate) wasi_ctx: WasiCtx,
     pub(crate) wasi_http: WasiHttpCtx,
     pub(crate) resource_table: ResourceTable,
+    pub(crate) limits: Limiter,
+    pub(crate) cloud: WasiCloudCtx,
 }
 
 impl WasiPreview2Ctx {
@@ -96,10 +141,20 @@ impl WasiPreview2Ctx {
             wasi_ctx: wasi_builder(ctx)?.build(),
             wasi_http: WasiHttpCtx::new(),
             resource_table: ResourceTable::default(),
+            limits: store_limits(ctx),
+            cloud: WasiCloudCtx::from_context(ctx)?,
         })
     }
 }
 
+/// The store data of a wasm module.
+struct WasiPreview1Ctx {
+    wasi_ctx: WasiP1Ctx,
+    limits: Limiter,
+    #[cfg(feature = "wasi-nn")]
+    wasi_nn: wasmtime_wasi_nn::witx::WasiNnCtx,
+}
+
 /// This impl is required to use wasmtime_wasi::WasiView trait.
 impl WasiView for WasiPreview2Ctx {
     fn ctx(&mut self) -> WasiCtxView<'_> {
@@ -133,19 +188,27 @@ impl Shim for WasmtimeShim {
 
     #[allow(refining_impl_trait)]
     async fn compiler() -> Option<WasmtimeCompiler> {
-        let mut config = wasmtime::Config::new();
-
-        // Disable Wasmtime parallel compilation for the tests
-        // see https://github.com/containerd/runwasi/pull/405#issuecomment-1928468714 for details
-        config.parallel_compilation(!cfg!(test));
-        config.wasm_component_model(true); // enable component linking
-        config.async_support(true); // must be on
-
-        let engine = wasmtime::Engine::new(&config)
+        let engine = wasmtime::Engine::new(&compiler_config())
             .expect("failed to create wasmtime precompilation engine");
 
         Some(WasmtimeCompiler(engine))
     }
+
+    fn supported_platform_os() -> &'static [&'static str] {
+        &["wasip2", "wasip1", "wasi"]
+    }
+
+    fn wasm_features() -> Option<FeatureSupport> {
+        Some(FeatureSupport {
+            enabled: WasmFeatures::SIMD | WasmFeatures::RELAXED_SIMD,
+            supported: WasmFeatures::SIMD
+                | WasmFeatures::RELAXED_SIMD
+                | WasmFeatures::THREADS
+                | WasmFeatures::MEMORY64
+                | WasmFeatures::GC
+                | WasmFeatures::FUNCTION_REFERENCES,
+        })
+    }
 }
 
 impl Sandbox for WasmtimeSandbox {
@@ -159,12 +222,62 @@ impl Sandbox for WasmtimeSandbox {
             name: _,
         } = ctx.entrypoint();
 
+        let digest = match &source {
+            Source::Oci([layer]) => Some(layer.config.digest().to_string()),
+            _ => None,
+        };
         let wasm_bytes = &source.as_bytes()?;
+        let features = &ctx.engine_config().features;
+        let sandbox = self.with_features(features)?;
+
+        let compiled =
+            MODULE_CACHE.get_or_compile(digest.as_deref(), &sandbox.engine, wasm_bytes, || {
+                sandbox.compile(wasm_bytes)
+            })?;
 
-        self.execute(ctx, wasm_bytes, func).await.into_error_code()
+        let status = tokio::select! {
+            status = sandbox.execute(ctx, compiled, wasm_bytes, digest.as_deref(), func) => {
+                status.into_error_code()
+            }
+            status = wait_for_termination(ctx) => {
+                status
+            }
+        };
+
+        log::info!("module cache: {}", MODULE_CACHE.stats());
+        status
+    }
+
+    fn supports_in_process() -> bool {
+        true
+    }
+
+    fn supports_live_limits() -> bool {
+        true
+    }
+
+    fn supports_cancellation() -> bool {
+        true
+    }
+
+    fn supports_cwd() -> bool {
+        true
     }
 }
 
+/// The config of the precompilation engines.
+fn compiler_config() -> Config {
+    let mut config = wasmtime::Config::new();
+
+    // Disable Wasmtime parallel compilation for the tests
+    // see https://github.com/containerd/runwasi/pull/405#issuecomment-1928468714 for details
+    config.parallel_compilation(!cfg!(test));
+    config.wasm_component_model(true); // enable component linking
+    config.async_support(true); // must be on
+    config.epoch_interruption(true); // like the engines running the modules
+    config
+}
+
 impl Compiler for WasmtimeCompiler {
     fn cache_key(&self) -> impl Hash {
         self.0.precompile_compatibility_hash()
@@ -181,7 +294,15 @@ impl Compiler for WasmtimeCompiler {
             }
 
             let compiled_layer = match WasmBinaryType::from_bytes(&layer.layer) {
-                Some(WasmBinaryType::Module) => self.0.precompile_module(&layer.layer)?,
+                Some(WasmBinaryType::Module) => {
+                    let module = Module::from_binary(&self.0, &layer.layer)?;
+                    if threads::uses_wasi_threads(&module) {
+                        log::info!("not precompiling module using wasi-threads");
+                        compiled_layers.push(None);
+                        continue;
+                    }
+                    module.serialize()?
+                }
                 Some(WasmBinaryType::Component) => self.0.precompile_component(&layer.layer)?,
                 None => {
                     log::warn!("Unknown WASM binary type");
@@ -194,9 +315,50 @@ impl Compiler for WasmtimeCompiler {
 
         Ok(compiled_layers)
     }
+
+    /// The layers are compiled with the wasm features of the engine config, like the engine
+    /// of the sandbox running them.
+    async fn compile_with_config(
+        &self,
+        layers: &[WasmLayer],
+        config: &EngineConfig,
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        if config.features.is_default() {
+            return self.compile(layers).await;
+        }
+
+        let mut engine_config = compiler_config();
+        apply_features(&mut engine_config, &config.features);
+        let engine = wasmtime::Engine::new(&engine_config)
+            .context("the wasm features required by the image aren't supported")?;
+        WasmtimeCompiler(engine).compile(layers).await
+    }
 }
 
 impl WasmtimeSandbox {
+    /// Returns a sandbox whose engine enables the wasm `features` required by the image.
+    /// The engine is created once per set of features, and shared through the [`MODULE_CACHE`].
+    fn with_features(&self, features: &Features) -> Result<Self> {
+        if features.is_default() {
+            return Ok(Self {
+                engine: self.engine.clone(),
+            });
+        }
+
+        let engine = MODULE_CACHE.engine(&EngineKey::new(features), || {
+            log::info!("creating engine with features {features:?}");
+            let mut config = engine_config();
+            apply_features(&mut config, features);
+            if features.threads == Some(true) {
+                // the pooling allocator can't allocate shared memories
+                config.allocation_strategy(wasmtime::InstanceAllocationStrategy::OnDemand);
+            }
+
+            new_engine(&config).context("the wasm features required by the image aren't supported")
+        })?;
+        Ok(Self { engine })
+    }
+
     /// Execute a wasm module.
     ///
     /// This function adds wasi_preview1 to the linker and can be utilized
@@ -209,13 +371,24 @@ impl WasmtimeSandbox {
     ) -> Result<i32> {
         log::debug!("execute module");
 
-        let ctx_p1 = wasi_builder(ctx)?.build_p1();
+        let ctx_p1 = WasiPreview1Ctx {
+            wasi_ctx: wasi_builder(ctx)?.build_p1(),
+            limits: store_limits(ctx),
+            #[cfg(feature = "wasi-nn")]
+            wasi_nn: crate::nn::wasi_nn_ctx(ctx)?,
+        };
         let mut store = Store::new(&self.engine, ctx_p1);
+        store.limiter(|ctx| &mut ctx.limits);
+        yield_on_epochs(&mut store);
         let mut module_linker = wasmtime::Linker::new(&self.engine);
 
         log::debug!("init linker");
-        wasi_preview1::add_to_linker_async(&mut module_linker, |wasi_ctx: &mut WasiP1Ctx| {
-            wasi_ctx
+        wasi_preview1::add_to_linker_async(&mut module_linker, |ctx: &mut WasiPreview1Ctx| {
+            &mut ctx.wasi_ctx
+        })?;
+        #[cfg(feature = "wasi-nn")]
+        wasmtime_wasi_nn::witx::add_to_linker(&mut module_linker, |ctx: &mut WasiPreview1Ctx| {
+            &mut ctx.wasi_nn
         })?;
 
         log::info!("instantiating instance");
@@ -235,7 +408,11 @@ impl WasmtimeSandbox {
             .into_error_code()
     }
 
-    async fn execute_component_async(
+    /// Execute a wasm component.
+    ///
+    /// This function adds wasi_preview2 to the linker and can be utilized
+    /// to execute a wasm component that uses wasi_preview2.
+    async fn execute_component(
         &self,
         ctx: &impl RuntimeContext,
         component: Component,
@@ -255,19 +432,18 @@ impl WasmtimeSandbox {
                 let mut linker = component::Linker::new(&self.engine);
                 wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
                 wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker)?;
+                wasi_cloud::add_to_linker(&mut linker, ctx.annotations())?;
 
                 let pre = linker.instantiate_pre(&component)?;
                 log::info!("pre-instantiate_pre");
                 let instance = ProxyPre::new(pre)?;
 
                 log::info!("starting HTTP server");
-                let cancel = self.cancel.clone();
-                serve_conn(ctx, instance, cancel).await
+                serve_conn(ctx, instance).await
             }
             ComponentTarget::Command => {
                 log::info!("Found command target");
-                let wasi_ctx = WasiPreview2Ctx::new(ctx)?;
-                let (mut store, linker) = store_for_context(&self.engine, wasi_ctx)?;
+                let (mut store, linker) = store_for_context(&self.engine, ctx)?;
 
                 let command = Command::instantiate_async(&mut store, &component, &linker).await?;
 
@@ -283,8 +459,7 @@ impl WasmtimeSandbox {
             }
             ComponentTarget::Core(func) => {
                 log::info!("Found Core target");
-                let wasi_ctx = WasiPreview2Ctx::new(ctx)?;
-                let (mut store, linker) = store_for_context(&self.engine, wasi_ctx)?;
+                let (mut store, linker) = store_for_context(&self.engine, ctx)?;
 
                 let pre = linker.instantiate_pre(&component)?;
                 let instance = pre.instantiate_async(&mut store).await?;
@@ -302,69 +477,33 @@ impl WasmtimeSandbox {
         status.into_error_code()
     }
 
-    /// Execute a wasm component.
-    ///
-    /// This function adds wasi_preview2 to the linker and can be utilized
-    /// to execute a wasm component that uses wasi_preview2.
-    async fn execute_component(
-        &self,
-        ctx: &impl RuntimeContext,
-        component: Component,
-        func: String,
-    ) -> Result<i32> {
-        log::debug!("loading wasm component");
-        tokio::select! {
-            status = self.execute_component_async(ctx, component, func) => {
-                status
-            }
-            status = self.handle_signals() => {
-                status
-            }
-        }
-    }
-
-    async fn handle_signals(&self) -> Result<i32> {
-        match wait_for_signal().await? {
-            libc::SIGINT => {
-                // Request graceful shutdown;
-                self.cancel.cancel();
-            }
-            sig => {
-                // On other signal, terminate the process without waiting for spawned tasks to finish.
-                return Ok(128 + sig);
-            }
-        }
-
-        // On a second SIGINT, terminate the process as well
-        wait_for_signal().await
-    }
-
-    async fn execute(
-        &self,
-        ctx: &impl RuntimeContext,
-        wasm_binary: &[u8],
-        func: String,
-    ) -> Result<i32> {
+    /// Compiles a wasm binary, or deserializes a precompiled one.
+    fn compile(&self, wasm_binary: &[u8]) -> Result<Compiled> {
         match WasmBinaryType::from_bytes(wasm_binary) {
             Some(WasmBinaryType::Module) => {
                 log::debug!("loading wasm module");
-                let module = Module::from_binary(&self.engine, wasm_binary)?;
-                self.execute_module(ctx, module, &func).await
+                Ok(Compiled::Module(Module::from_binary(
+                    &self.engine,
+                    wasm_binary,
+                )?))
             }
             Some(WasmBinaryType::Component) => {
-                let component = Component::from_binary(&self.engine, wasm_binary)?;
-                self.execute_component(ctx, component, func).await
+                log::debug!("loading wasm component");
+                Ok(Compiled::Component(Component::from_binary(
+                    &self.engine,
+                    wasm_binary,
+                )?))
             }
             None => match wasmtime::Engine::detect_precompiled(wasm_binary) {
                 Some(Precompiled::Module) => {
                     log::info!("using precompiled module");
                     let module = unsafe { Module::deserialize(&self.engine, wasm_binary) }?;
-                    self.execute_module(ctx, module, &func).await
+                    Ok(Compiled::Module(module))
                 }
                 Some(Precompiled::Component) => {
                     log::info!("using precompiled component");
                     let component = unsafe { Component::deserialize(&self.engine, wasm_binary) }?;
-                    self.execute_component(ctx, component, func).await
+                    Ok(Compiled::Component(component))
                 }
                 None => {
                     bail!("invalid precompiled module")
@@ -372,6 +511,25 @@ impl WasmtimeSandbox {
             },
         }
     }
+
+    async fn execute(
+        &self,
+        ctx: &impl RuntimeContext,
+        compiled: Compiled,
+        wasm_binary: &[u8],
+        digest: Option<&str>,
+        func: String,
+    ) -> Result<i32> {
+        match compiled {
+            Compiled::Module(module) => {
+                if threads::uses_wasi_threads(&module) {
+                    return threads::execute_module(ctx, wasm_binary, digest, &func).await;
+                }
+                self.execute_module(ctx, module, &func).await
+            }
+            Compiled::Component(component) => self.execute_component(ctx, component, func).await,
+        }
+    }
 }
 
 pub(crate) fn envs_from_ctx(ctx: &impl RuntimeContext) -> Vec<(String, String)> {
@@ -384,15 +542,157 @@ pub(crate) fn envs_from_ctx(ctx: &impl RuntimeContext) -> Vec<(String, String)>
         .collect()
 }
 
-fn store_for_context<T: WasiView + 'static>(
+/// Returns the host directory of the working directory of the guest, to preopen as `.`,
+/// or `None` if the guest runs in `/`.
+/// WASI has no current directory, so guests resolve relative paths in the `.` preopen.
+/// The directory is resolved inside the rootfs with [`guest_dir`], so that a working directory
+/// with `..` components or symlinks can't preopen a directory of the host.
+pub(crate) fn host_cwd(ctx: &impl RuntimeContext) -> Result<Option<PathBuf>> {
+    let cwd = ctx.cwd().strip_prefix("/").unwrap_or(ctx.cwd());
+    if cwd.as_os_str().is_empty() {
+        return Ok(None);
+    }
+    let dir = guest_dir(ctx, &cwd.to_string_lossy())
+        .with_context(|| format!("invalid working directory {:?}", ctx.cwd()))?;
+    Ok(Some(dir.path().to_path_buf()))
+}
+
+/// Enables or disables the wasm `features` in `config`.
+pub(crate) fn apply_features(config: &mut Config, features: &Features) {
+    if let Some(simd) = features.simd {
+        config.wasm_simd(simd);
+        config.wasm_relaxed_simd(simd);
+    }
+    if let Some(threads) = features.threads {
+        config.wasm_threads(threads);
+    }
+    if let Some(memory64) = features.memory64 {
+        config.wasm_memory64(memory64);
+    }
+    if let Some(gc) = features.gc {
+        config.wasm_gc(gc);
+        if gc {
+            config.wasm_function_references(true);
+        }
+    }
+}
+
+/// The limiter of the stores, enforcing the limits of the engine config of the image,
+/// and the live limits of the container, when they're updated.
+///
+/// The live limits are shared by all the stores of the container, so each limiter accounts
+/// for the memories of its store, and releases them when the store is dropped.
+pub(crate) struct Limiter {
+    limits: StoreLimits,
+    live: Option<Arc<LiveLimits>>,
+    /// The bytes of the memories of the store accounted in the live limits.
+    memory_bytes: u64,
+    /// The bytes of the last growth of a memory, released if the growth fails.
+    growing_bytes: u64,
+}
+
+impl Clone for Limiter {
+    /// Returns the limiter of a new store, which has no memories yet.
+    fn clone(&self) -> Self {
+        Self {
+            limits: self.limits.clone(),
+            live: self.live.clone(),
+            memory_bytes: 0,
+            growing_bytes: 0,
+        }
+    }
+}
+
+impl Drop for Limiter {
+    fn drop(&mut self) {
+        if let Some(live) = &self.live {
+            live.release_memory(self.memory_bytes);
+        }
+    }
+}
+
+impl ResourceLimiter for Limiter {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        if !self.limits.memory_growing(current, desired, maximum)? {
+            return Ok(false);
+        }
+        let growth = u64::try_from(desired.saturating_sub(current)).unwrap_or(u64::MAX);
+        if let Some(live) = &self.live {
+            if !live.grow_memory(growth) {
+                return Ok(false);
+            }
+        }
+        self.memory_bytes += growth;
+        self.growing_bytes = growth;
+        Ok(true)
+    }
+
+    fn memory_grow_failed(&mut self, error: anyhow::Error) -> Result<()> {
+        if let Some(live) = &self.live {
+            live.release_memory(self.growing_bytes);
+        }
+        self.memory_bytes -= self.growing_bytes;
+        self.growing_bytes = 0;
+        self.limits.memory_grow_failed(error)
+    }
+
+    fn table_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        self.limits.table_growing(current, desired, maximum)
+    }
+
+    fn table_grow_failed(&mut self, error: anyhow::Error) -> Result<()> {
+        self.limits.table_grow_failed(error)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances()
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables()
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories()
+    }
+}
+
+/// Returns the limiter of the stores of the container.
+pub(crate) fn store_limits(ctx: &impl RuntimeContext) -> Limiter {
+    let mut limits = StoreLimitsBuilder::new();
+    if let Some(max) = ctx.engine_config().limits.max_memory_bytes {
+        limits = limits.memory_size(usize::try_from(max).unwrap_or(usize::MAX));
+    }
+    Limiter {
+        limits: limits.build(),
+        live: ctx.live_limits().cloned(),
+        memory_bytes: 0,
+        growing_bytes: 0,
+    }
+}
+
+fn store_for_context(
     engine: &wasmtime::Engine,
-    ctx: T,
-) -> Result<(Store<T>, component::Linker<T>)> {
-    let store = Store::new(engine, ctx);
+    ctx: &impl RuntimeContext,
+) -> Result<(Store<WasiPreview2Ctx>, component::Linker<WasiPreview2Ctx>)> {
+    let mut store = Store::new(engine, WasiPreview2Ctx::new(ctx)?);
+    store.limiter(|ctx| &mut ctx.limits);
+    yield_on_epochs(&mut store);
 
     log::debug!("init linker");
     let mut linker = component::Linker::new(engine);
     wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
+    wasi_cloud::add_to_linker(&mut linker, ctx.annotations())?;
 
     Ok((store, linker))
 }
@@ -406,40 +706,68 @@ fn wasi_builder(ctx: &impl RuntimeContext) -> Result<WasiCtxBuilder, anyhow::Err
     let file_perms = wasmtime_wasi::FilePerms::all();
     let dir_perms = wasmtime_wasi::DirPerms::all();
     let envs = envs_from_ctx(ctx);
+    let network = ctx.network_policy()?;
 
     let mut builder = WasiCtxBuilder::new();
     builder
         .args(ctx.args())
         .envs(&envs)
-        .inherit_stdio()
-        .inherit_network()
-        .allow_tcp(true)
-        .allow_udp(true)
-        .allow_ip_name_lookup(true)
-        .preopened_dir("/", "/", dir_perms, file_perms)?;
+        .allow_tcp(network.allow_tcp)
+        .allow_udp(network.allow_udp)
+        .allow_ip_name_lookup(network.allow_ip_name_lookup)
+        .preopened_dir(ctx.rootfs(), "/", dir_perms, file_perms)?;
+
+    if let Some(cwd) = host_cwd(ctx)? {
+        builder.preopened_dir(cwd, ".", dir_perms, file_perms)?;
+    }
+
+    match ctx.stdio() {
+        Some(stdio) => set_stdio(&mut builder, stdio)?,
+        None => {
+            builder.inherit_stdio();
+        }
+    }
+
+    if network.allows_sockets() {
+        builder.inherit_network();
+    }
 
     log::debug!("WASI context built successfully");
     Ok(builder)
 }
 
-async fn wait_for_signal() -> Result<i32> {
-    #[cfg(unix)]
-    {
-        use tokio::signal::unix::{SignalKind, signal};
-        let mut sigquit = signal(SignalKind::quit())?;
-        let mut sigterm = signal(SignalKind::terminate())?;
-
-        tokio::select! {
-            _ = sigquit.recv() => { Ok(libc::SIGQUIT) }
-            _ = sigterm.recv() => { Ok(libc::SIGTERM) }
-            _ = tokio::signal::ctrl_c() => { Ok(libc::SIGINT) }
-        }
+/// Connects the guest to the `stdio` files of a container running in the shim process.
+fn set_stdio(builder: &mut WasiCtxBuilder, stdio: &Stdio) -> Result<()> {
+    if let Some(stdin) = &stdio.stdin {
+        builder.stdin(InputFile::new(stdin.try_clone()?));
     }
-    #[cfg(not(unix))]
-    {
-        tokio::signal::ctrl_c().await;
-        Ok(1)
+    if let Some(stdout) = &stdio.stdout {
+        builder.stdout(OutputFile::new(stdout.try_clone()?));
+    }
+    if let Some(stderr) = &stdio.stderr {
+        builder.stderr(OutputFile::new(stderr.try_clone()?));
+    }
+    Ok(())
+}
+
+/// Opens the directory at `path` in the file system of the guest, relative to its root.
+/// The path is resolved inside the rootfs, so that `..` components and symlinks can't
+/// refer to a directory of the host, see [`Rootfs`].
+pub(crate) fn guest_dir(ctx: &impl RuntimeContext, path: &str) -> Result<Rootfs> {
+    Rootfs::open(ctx.rootfs())?.open_dir(path)
+}
+
+/// Stops the guest when the container is cancelled by a termination signal.
+///
+/// `SIGINT` requests a graceful shutdown instead, which HTTP servers observe
+/// to stop accepting connections, and the guest is left running.
+async fn wait_for_termination(ctx: &impl RuntimeContext) -> Result<i32> {
+    let signal = ctx.cancellation().cancelled().await;
+    if signal == libc::SIGINT {
+        std::future::pending::<()>().await;
     }
+    // terminate without waiting for spawned tasks to finish
+    Ok(128 + signal)
 }
 
 /// The pooling allocator is tailor made for the `wasi/http` use case. Check if we can use it.
@@ -467,9 +795,15 @@ pub trait IntoErrorCode {
 
 impl IntoErrorCode for Result<i32> {
     fn into_error_code(self) -> Result<i32> {
-        self.or_else(|err| match err.downcast_ref::<wasmtime_wasi::I32Exit>() {
-            Some(exit) => Ok(exit.0),
-            _ => Err(err),
+        self.or_else(|err| {
+            if let Some(exit) = err.downcast_ref::<wasmtime_wasi::I32Exit>() {
+                return Ok(exit.0);
+            }
+            // modules using wasi-threads exit through wasi-common
+            match err.downcast_ref::<wasi_common::I32Exit>() {
+                Some(exit) => Ok(exit.0),
+                _ => Err(err),
+            }
         })
     }
 }
@@ -1,5 +1,10 @@
+pub mod cache;
 mod http_proxy;
 pub mod instance;
+#[cfg(feature = "wasi-nn")]
+pub mod nn;
+pub mod threads;
+pub mod wasi_cloud;
 
 pub use instance::WasmtimeShim;
 
@@ -0,0 +1,96 @@
+//! Support for [wasi-nn] in wasm modules, with the ONNX backend running on the CPU.
+//!
+//! The shim links the `wasi_ephemeral_nn` functions when it's built with the `wasi-nn` feature.
+//! Models can be loaded by the guest from bytes, or preloaded by the shim from directories
+//! listed in the [`GRAPHS_ANNOTATION`] annotation, e.g., `onnx::/models/squeezenet`.
+//! The directories are paths in the container, so they can be part of the image or mounted
+//! in the container, and are resolved inside its rootfs. Preloaded models are loaded by the
+//! guest with `load_by_name`, using the name of their directory once its symlinks are resolved.
+//!
+//! Only wasm modules can use wasi-nn: components can't import `wasi:nn` yet, and modules
+//! using [wasi-threads](crate::threads) fail to start, as their `wasi-common` context
+//! doesn't link wasi-nn.
+//!
+//! [wasi-nn]: https://github.com/WebAssembly/wasi-nn
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, ensure};
+use containerd_shim_wasm::sandbox::context::RuntimeContext;
+use wasmtime_wasi_nn::witx::WasiNnCtx;
+
+use crate::instance::guest_dir;
+
+/// Annotation listing the model directories to preload,
+/// as comma separated `encoding::directory` pairs.
+pub const GRAPHS_ANNOTATION: &str = "runwasi.io/wasmtime.nn-graphs";
+
+/// The only encoding supported by the shim.
+const ONNX_ENCODING: &str = "onnx";
+
+/// Reads the model directories to preload from the container annotations.
+fn graphs_from_annotations(annotations: &HashMap<String, String>) -> Result<Vec<(String, String)>> {
+    let Some(value) = annotations.get(GRAPHS_ANNOTATION) else {
+        return Ok(vec![]);
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|graph| !graph.is_empty())
+        .map(|graph| {
+            let (encoding, dir) = graph.split_once("::").with_context(|| {
+                format!("invalid graph {graph:?} in {GRAPHS_ANNOTATION}, expected `encoding::directory`")
+            })?;
+            ensure!(
+                encoding == ONNX_ENCODING,
+                "unsupported encoding {encoding:?} in {GRAPHS_ANNOTATION}, only {ONNX_ENCODING:?} is supported"
+            );
+            Ok((encoding.to_string(), dir.to_string()))
+        })
+        .collect()
+}
+
+/// Creates the wasi-nn context of a container, preloading the models listed in its annotations.
+pub(crate) fn wasi_nn_ctx(ctx: &impl RuntimeContext) -> Result<WasiNnCtx> {
+    let graphs = graphs_from_annotations(ctx.annotations())?
+        .into_iter()
+        .map(|(encoding, dir)| {
+            let dir = guest_dir(ctx, &dir)?;
+            Ok((encoding, dir.path().to_string_lossy().into_owned()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    log::info!("preloading wasi-nn graphs {graphs:?}");
+    let (backends, registry) =
+        wasmtime_wasi_nn::preload(&graphs).context("failed to preload the wasi-nn graphs")?;
+    Ok(WasiNnCtx::new(backends, registry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graphs_from_annotations() -> Result<()> {
+        assert!(graphs_from_annotations(&HashMap::new())?.is_empty());
+
+        let annotations = HashMap::from([(
+            GRAPHS_ANNOTATION.to_string(),
+            "onnx::/models/a, onnx::/models/b".to_string(),
+        )]);
+        assert_eq!(
+            graphs_from_annotations(&annotations)?,
+            [
+                ("onnx".to_string(), "/models/a".to_string()),
+                ("onnx".to_string(), "/models/b".to_string()),
+            ]
+        );
+
+        for value in ["/models/a", "openvino::/models/a"] {
+            let annotations = HashMap::from([(GRAPHS_ANNOTATION.to_string(), value.to_string())]);
+            assert!(graphs_from_annotations(&annotations).is_err(), "{value}");
+        }
+
+        Ok(())
+    }
+}
@@ -1,10 +1,17 @@
 use std::time::Duration;
 
+use containerd_shim_wasm::shim::Isolation;
 use containerd_shim_wasm::testing::modules::*;
-use containerd_shim_wasm::testing::{WasiTest, oci_helpers};
+use containerd_shim_wasm::testing::{
+    WasiTest, assert_recovers_after_restart, assert_stops_on_sigterm, assert_updates_memory_limit,
+    oci_helpers, run_recovery_helper,
+};
 use serial_test::serial;
 
 use crate::WasmtimeShim as WasiEngine;
+use crate::cache::cache_stats;
+use crate::threads::MAX_THREADS_ANNOTATION;
+use crate::wasi_cloud::{CONFIG_ANNOTATION, KEYVALUE_ANNOTATION};
 
 #[test]
 #[serial]
@@ -28,6 +35,38 @@ fn test_hello_world() -> anyhow::Result<()> {
     Ok(())
 }
 
+// Test that a guest running on a terminal writes its output to it.
+#[test]
+#[serial]
+fn test_hello_world_on_terminal() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_terminal()
+        .build()?;
+
+    let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    assert_eq!(test.read_console()?, "hello world\r\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_hello_world_on_terminal_in_process() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_isolation(Isolation::InProcess)
+        .with_terminal()
+        .build()?;
+
+    let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    assert_eq!(test.read_console()?, "hello world\r\n");
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_hello_world_oci() -> anyhow::Result<()> {
@@ -165,6 +204,227 @@ fn test_exit_code() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_hello_world_in_process() -> anyhow::Result<()> {
+    // the spec has the default process of containerd, whose `noNewPrivileges` and rlimits
+    // can't be applied to a guest running in the shim process, and are only reported
+    let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_isolation(Isolation::InProcess)
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_sleep_stops_on_sigterm() -> anyhow::Result<()> {
+    assert_stops_on_sigterm::<WasiEngine>(Isolation::Container)
+}
+
+#[test]
+#[serial]
+fn test_sleep_stops_on_sigterm_in_process() -> anyhow::Result<()> {
+    assert_stops_on_sigterm::<WasiEngine>(Isolation::InProcess)
+}
+
+// Test that a guest running in the shim process is stopped while it doesn't call the host.
+#[test]
+#[serial]
+fn test_busy_loop_stops_on_sigterm_in_process() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiEngine>::builder()?
+        .with_isolation(Isolation::InProcess)
+        .with_wasm(BUSY_LOOP)?
+        .build()?;
+
+    test.start()?;
+    std::thread::sleep(Duration::from_millis(500));
+    test.terminate()?;
+
+    let (exit_code, _, _) = test.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 128 + libc::SIGTERM as u32);
+
+    Ok(())
+}
+
+// Test that the memory limit of a running container is applied to its cgroup.
+#[test]
+#[serial]
+fn test_update_memory_limit() -> anyhow::Result<()> {
+    assert_updates_memory_limit::<WasiEngine>(Isolation::Container)
+}
+
+// Test that the memory limit of a guest running in the shim process caps all its memories.
+#[test]
+#[serial]
+fn test_update_memory_limit_in_process() -> anyhow::Result<()> {
+    assert_updates_memory_limit::<WasiEngine>(Isolation::InProcess)
+}
+
+// Test that a container started by a shim that is killed is recovered by a restarted shim,
+// with the exit code of its guest.
+#[test]
+#[serial]
+fn test_recovery_after_restart() -> anyhow::Result<()> {
+    assert_recovers_after_restart::<WasiEngine>("wasmtime_tests::recovery_helper")
+}
+
+// The shim killed by `test_recovery_after_restart`.
+#[test]
+#[ignore = "run by test_recovery_after_restart"]
+fn recovery_helper() -> anyhow::Result<()> {
+    run_recovery_helper::<WasiEngine>()
+}
+
+#[test]
+#[serial]
+fn test_concurrent_containers_in_process() -> anyhow::Result<()> {
+    let hello = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_isolation(Isolation::InProcess)
+        .build()?;
+    let exit = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(EXIT_CODE)?
+        .with_isolation(Isolation::InProcess)
+        .build()?;
+
+    hello.start()?;
+    exit.start()?;
+
+    let (exit_code, _, _) = exit.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 42);
+
+    let (exit_code, stdout, _) = hello.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_module_cache_in_process() -> anyhow::Result<()> {
+    let run = || -> anyhow::Result<u32> {
+        let (exit_code, _, _) = WasiTest::<WasiEngine>::builder()?
+            .with_wasm(EXIT_CODE)?
+            .with_isolation(Isolation::InProcess)
+            .build()?
+            .start()?
+            .wait(Duration::from_secs(10))?;
+        Ok(exit_code)
+    };
+
+    assert_eq!(run()?, 42);
+    let stats = cache_stats();
+
+    // the module of the second container is the one compiled for the first one
+    assert_eq!(run()?, 42);
+    assert_eq!(cache_stats().hits, stats.hits + 1);
+    assert_eq!(cache_stats().misses, stats.misses);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_enables_required_features() -> anyhow::Result<()> {
+    let (exit_code, _, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(SHARED_MEMORY)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_rejects_unsupported_features() -> anyhow::Result<()> {
+    let err = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(EXCEPTIONS)?
+        .build()
+        .err()
+        .expect("the exceptions proposal isn't supported");
+
+    assert!(err.to_string().contains("exceptions"), "{err}");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_wasi_threads() -> anyhow::Result<()> {
+    let (exit_code, _, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(WASI_THREADS)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 42);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_wasi_threads_limit() -> anyhow::Result<()> {
+    let (exit_code, _, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(WASI_THREADS)?
+        .with_annotation(MAX_THREADS_ANNOTATION, "0")
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 1);
+
+    Ok(())
+}
+
+#[cfg(feature = "wasi-nn")]
+#[test]
+#[serial]
+fn test_wasi_nn_is_linked() -> anyhow::Result<()> {
+    let (exit_code, _, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(WASI_NN)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+
+    Ok(())
+}
+
+#[cfg(feature = "wasi-nn")]
+#[test]
+#[serial]
+fn test_wasi_nn_inference() -> anyhow::Result<()> {
+    use crate::nn::GRAPHS_ANNOTATION;
+
+    // a single `Add` node computing `y = x + x` for a `float[4]` input `x`
+    const MODEL: &[u8] = include_bytes!("testdata/double.onnx");
+
+    let (exit_code, _, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(WASI_NN_DOUBLE)?
+        .with_file("/models/double/model.onnx", MODEL)?
+        .with_annotation(GRAPHS_ANNOTATION, "onnx::/models/double")
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_seccomp() -> anyhow::Result<()> {
@@ -214,6 +474,35 @@ fn test_simple_component() -> anyhow::Result<()> {
     Ok(())
 }
 
+// Test that the components of a pod running in the shim process share
+// the `wasi:keyvalue` store, and read the `wasi:config` values of their container.
+#[test]
+#[serial]
+fn test_wasi_cloud_component_in_process() -> anyhow::Result<()> {
+    let run = |start_fn: &str| -> anyhow::Result<u32> {
+        let (exit_code, _, _) = WasiTest::<WasiEngine>::builder()?
+            .with_wasm(WASI_CLOUD_COMPONENT)?
+            .with_start_fn(start_fn)
+            .with_isolation(Isolation::InProcess)
+            .with_file("/config/greeting", "hello")?
+            .with_annotation(CONFIG_ANNOTATION, "/config")
+            .with_annotation(KEYVALUE_ANNOTATION, "memory")
+            .with_annotation("io.kubernetes.cri.sandbox-id", "wasi-cloud-pod")
+            .build()?
+            .start()?
+            .wait(Duration::from_secs(10))?;
+        Ok(exit_code)
+    };
+
+    // the key isn't set yet
+    assert_ne!(run("check")?, 0);
+
+    assert_eq!(run("set")?, 0);
+    assert_eq!(run("check")?, 0);
+
+    Ok(())
+}
+
 // Test that the shim can execute a wasm component that is
 // compiled with wasip2.
 //
@@ -0,0 +1,306 @@
+//! Support for modules using [wasi-threads], e.g., built for the `wasm32-wasip1-threads` target.
+//!
+//! Modules importing `wasi:thread-spawn` are run with the wasi-threads implementation of
+//! wasmtime, which starts each thread of the guest on a new host thread. As these threads
+//! share the WASI context of the guest, the module is linked with the `wasi-common`
+//! implementation of WASI preview 1, and runs on a synchronous engine. The engine and the
+//! compiled module are shared through the [`MODULE_CACHE`], like those of other modules.
+//!
+//! The guest gets the same arguments, environment, preopened directories and stdio as a
+//! module without threads. `wasi-common` can't open sockets though, so the guest has no
+//! network access whatever the [network policy](containerd_shim_wasm::sandbox::network), and
+//! modules whose engine config or annotations explicitly require sockets fail to start.
+//!
+//! These modules can't use [wasi-nn](crate::nn), and fail to start if they import it.
+//!
+//! The guest runs on a blocking thread of the async runtime, and its threads on threads of
+//! their own, which can't be interrupted: dropping the task running the guest doesn't stop it.
+//! So these modules only run in a container of their own, whose processes are killed to stop
+//! them, and fail to start in the shim process.
+//!
+//! The number of threads a guest can spawn is capped with the [`MAX_THREADS_ANNOTATION`]
+//! annotation, and is unlimited by default. `thread-spawn` fails once the guest
+//! runs as many threads as the cap, in addition to its main thread.
+//!
+//! [wasi-threads]: https://github.com/WebAssembly/wasi-threads
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{Context, Result, bail, ensure};
+use containerd_shim_wasm::sandbox::context::{RuntimeContext, Stdio};
+use containerd_shim_wasm::sandbox::network::{TCP_ANNOTATION, UDP_ANNOTATION};
+use containerd_shim_wasm::shim::Isolation;
+use wasi_common::WasiFile;
+use wasi_common::sync::{Dir, WasiCtxBuilder, ambient_authority};
+use wasmtime::{Caller, Config, Engine, Linker, Module, Store};
+use wasmtime_wasi_threads::WasiThreadsCtx;
+
+use crate::cache::{Compiled, EngineKey, MODULE_CACHE};
+use crate::instance::{
+    IntoErrorCode, Limiter, apply_features, envs_from_ctx, host_cwd, store_limits,
+};
+
+/// Annotation to set the maximum number of threads a guest can spawn.
+pub const MAX_THREADS_ANNOTATION: &str = "runwasi.io/wasmtime.max-threads";
+
+/// Returns `true` if `module` spawns threads with wasi-threads.
+pub(crate) fn uses_wasi_threads(module: &Module) -> bool {
+    module
+        .imports()
+        .any(|import| import.module() == "wasi" && import.name() == "thread-spawn")
+}
+
+/// The store data of a module using wasi-threads, cloned for every thread of the guest.
+#[derive(Clone)]
+struct WasiThreadsHost {
+    wasi_ctx: wasi_common::WasiCtx,
+    wasi_threads: Option<Arc<WasiThreadsCtx<WasiThreadsHost>>>,
+    limits: Limiter,
+    thread_limit: ThreadLimit,
+    _thread: Option<Arc<ThreadGuard>>,
+}
+
+/// Counts the threads spawned by the guest.
+#[derive(Clone, Debug)]
+struct ThreadLimit {
+    max: Option<usize>,
+    active: Arc<AtomicUsize>,
+}
+
+/// Releases a thread of the [`ThreadLimit`] when the thread's store is dropped.
+struct ThreadGuard(Arc<AtomicUsize>);
+
+impl ThreadLimit {
+    fn from_annotations(annotations: &HashMap<String, String>) -> Result<Self> {
+        let max = annotations
+            .get(MAX_THREADS_ANNOTATION)
+            .map(|value| {
+                value.trim().parse().with_context(|| {
+                    format!("invalid value {value:?} for annotation {MAX_THREADS_ANNOTATION}")
+                })
+            })
+            .transpose()?;
+        Ok(Self {
+            max,
+            active: Arc::default(),
+        })
+    }
+
+    fn try_acquire(&self) -> Option<ThreadGuard> {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                self.max
+                    .is_none_or(|max| active < max)
+                    .then_some(active + 1)
+            })
+            .ok()?;
+        Some(ThreadGuard(self.active.clone()))
+    }
+}
+
+impl Drop for ThreadGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Runs the function `func` of a module using wasi-threads.
+///
+/// The module is compiled from `wasm_binary` for a synchronous engine enabling
+/// the features of the engine config, unless the module with the `digest` is cached.
+pub(crate) async fn execute_module(
+    ctx: &impl RuntimeContext,
+    wasm_binary: &[u8],
+    digest: Option<&str>,
+    func: &str,
+) -> Result<i32> {
+    ensure!(
+        ctx.isolation() == Isolation::Container,
+        "modules using wasi-threads can't run in the shim process"
+    );
+
+    let features = &ctx.engine_config().features;
+    let key = EngineKey::wasi_threads(features);
+    let engine = MODULE_CACHE.engine(&key, || {
+        let mut config = Config::new();
+        config.parallel_compilation(!cfg!(test));
+        apply_features(&mut config, features);
+        config.wasm_threads(true);
+        Engine::new(&config).context("failed to create wasi-threads engine")
+    })?;
+
+    let compiled = MODULE_CACHE.get_or_compile(digest, &engine, wasm_binary, || {
+        log::info!("compiling module using wasi-threads");
+        Ok(Compiled::Module(Module::from_binary(&engine, wasm_binary)?))
+    })?;
+    let Compiled::Module(module) = compiled else {
+        bail!("expected a module using wasi-threads");
+    };
+    ensure!(
+        !module
+            .imports()
+            .any(|import| import.module() == "wasi_ephemeral_nn"),
+        "modules using wasi-threads can't use wasi-nn"
+    );
+
+    let host = WasiThreadsHost {
+        wasi_ctx: wasi_ctx(ctx)?,
+        wasi_threads: None,
+        limits: store_limits(ctx),
+        thread_limit: ThreadLimit::from_annotations(ctx.annotations())?,
+        _thread: None,
+    };
+    log::info!("thread limit: {:?}", host.thread_limit.max);
+
+    let mut store = Store::new(&engine, host);
+    store.limiter(|host| &mut host.limits);
+
+    log::debug!("init linker");
+    let mut linker = Linker::new(&engine);
+    wasi_common::sync::add_to_linker(&mut linker, |host: &mut WasiThreadsHost| &mut host.wasi_ctx)?;
+    wasmtime_wasi_threads::add_to_linker(&mut linker, &store, &module, |host| {
+        host.wasi_threads.as_ref().unwrap()
+    })?;
+
+    // spawn the threads within the thread limit
+    linker.allow_shadowing(true);
+    linker.func_wrap(
+        "wasi",
+        "thread-spawn",
+        |caller: Caller<'_, WasiThreadsHost>, start_arg: i32| -> i32 {
+            let mut host = caller.data().clone();
+            let Some(guard) = host.thread_limit.try_acquire() else {
+                log::warn!("failed to spawn thread: the thread limit is reached");
+                return -1;
+            };
+            host._thread = Some(Arc::new(guard));
+
+            let wasi_threads = host.wasi_threads.clone().unwrap();
+            match wasi_threads.spawn(host, start_arg) {
+                Ok(thread_id) => thread_id,
+                Err(err) => {
+                    log::error!("failed to spawn thread: {err:#}");
+                    -1
+                }
+            }
+        },
+    )?;
+
+    store.data_mut().wasi_threads = Some(Arc::new(WasiThreadsCtx::new(
+        module.clone(),
+        Arc::new(linker.clone()),
+    )?));
+
+    // the blocking task can't be aborted, see the module documentation
+    let func = func.to_string();
+    tokio::task::spawn_blocking(move || {
+        log::info!("instantiating instance");
+        let instance = linker.instantiate(&mut store, &module)?;
+
+        log::debug!("getting start function");
+        let start_func = instance
+            .get_func(&mut store, &func)
+            .context("module does not have a WASI start function")?;
+
+        log::info!("running start function {func:?}");
+        start_func.call(&mut store, &[], &mut [])
+    })
+    .await?
+    .into_error_code()
+}
+
+fn wasi_ctx(ctx: &impl RuntimeContext) -> Result<wasi_common::WasiCtx> {
+    log::debug!("building wasi-common context");
+
+    let envs = envs_from_ctx(ctx);
+    check_network(ctx)?;
+    let root = Dir::open_ambient_dir(ctx.rootfs(), ambient_authority())?;
+
+    let mut builder = WasiCtxBuilder::new();
+    builder
+        .args(ctx.args())?
+        .envs(&envs)?
+        .preopened_dir(root, "/")?;
+
+    if let Some(cwd) = host_cwd(ctx)? {
+        let cwd = Dir::open_ambient_dir(cwd, ambient_authority())?;
+        builder.preopened_dir(cwd, ".")?;
+    }
+
+    match ctx.stdio() {
+        Some(stdio) => set_stdio(&mut builder, stdio)?,
+        None => {
+            builder.inherit_stdio();
+        }
+    }
+
+    Ok(builder.build())
+}
+
+/// Fails if the container explicitly requires sockets, which `wasi-common` can't open.
+fn check_network(ctx: &impl RuntimeContext) -> Result<()> {
+    let network = ctx.network_policy()?;
+    let wasi = &ctx.engine_config().wasi;
+    let annotations = ctx.annotations();
+    let required = |config: Option<bool>, annotation: &str| {
+        config == Some(true) || annotations.get(annotation).map(|v| v.trim()) == Some("true")
+    };
+
+    if network.allow_tcp && required(wasi.tcp, TCP_ANNOTATION) {
+        bail!("modules using wasi-threads can't open TCP sockets");
+    }
+    if network.allow_udp && required(wasi.udp, UDP_ANNOTATION) {
+        bail!("modules using wasi-threads can't open UDP sockets");
+    }
+    if network.allows_sockets() {
+        log::info!("modules using wasi-threads have no network access");
+    }
+    Ok(())
+}
+
+/// Connects the guest to the `stdio` files of a container running in the shim process.
+fn set_stdio(builder: &mut WasiCtxBuilder, stdio: &Stdio) -> Result<()> {
+    let file = |file: &std::fs::File| -> Result<Box<dyn WasiFile>> {
+        let file = cap_std::fs::File::from_std(file.try_clone()?);
+        Ok(Box::new(wasi_common::sync::file::File::from_cap_std(file)))
+    };
+    if let Some(stdin) = &stdio.stdin {
+        builder.stdin(file(stdin)?);
+    }
+    if let Some(stdout) = &stdio.stdout {
+        builder.stdout(file(stdout)?);
+    }
+    if let Some(stderr) = &stdio.stderr {
+        builder.stderr(file(stderr)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_limit() -> Result<()> {
+        let annotations = HashMap::from([(MAX_THREADS_ANNOTATION.to_string(), "2".to_string())]);
+        let limit = ThreadLimit::from_annotations(&annotations)?;
+
+        let first = limit.try_acquire().expect("first thread");
+        let _second = limit.try_acquire().expect("second thread");
+        assert!(limit.try_acquire().is_none());
+
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+
+        let unlimited = ThreadLimit::from_annotations(&HashMap::new())?;
+        let guards: Vec<_> = (0..64).map_while(|_| unlimited.try_acquire()).collect();
+        assert_eq!(guards.len(), 64);
+
+        let annotations = HashMap::from([(MAX_THREADS_ANNOTATION.to_string(), "-1".to_string())]);
+        assert!(ThreadLimit::from_annotations(&annotations).is_err());
+
+        Ok(())
+    }
+}
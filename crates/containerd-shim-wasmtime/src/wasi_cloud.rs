@@ -0,0 +1,460 @@
+//! Host implementations of `wasi:config` and `wasi:keyvalue` for components, backed by local sources.
+//!
+//! The interfaces are linked only for the containers enabling them with annotations:
+//!
+//! * [`CONFIG_ANNOTATION`]: comma separated sources of the values of `wasi:config/store`.
+//!   A source is either `env`, for the environment variables of the container, or the path of a
+//!   directory in the container, where each file is a value named after the file, e.g.,
+//!   a mounted `ConfigMap`. Later sources take precedence over earlier ones.
+//! * [`KEYVALUE_ANNOTATION`]: `memory` for an empty in-memory `wasi:keyvalue` store, or the path
+//!   of a directory in the container whose files are the initial entries of the store.
+//!   Changes to the store aren't written back to the directory.
+//!
+//! The key-value store belongs to the pod of the container, identified by the
+//! `io.kubernetes.cri.sandbox-id` annotation: the containers of a pod enabling the same store
+//! share it, and the first one of them reads its initial entries. As the store lives in the
+//! memory of the shim process, only the containers of the pod running in the shim process share
+//! it, while a container running in a process of its own, or outside of a pod, has its own store.
+//! The store is shared by all the requests served by an HTTP proxy component as well.
+//! It's dropped with the last running container using it, so the entries don't survive
+//! the restart of all of them, and it holds at most [`MAX_STORE_BYTES`] of keys and values.
+
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, Weak};
+
+use anyhow::{Context, Result, ensure};
+use containerd_shim_wasm::sandbox::context::RuntimeContext;
+use containerd_shim_wasm::sandbox::rootfs::Rootfs;
+use wasmtime::component::{HasSelf, Linker, Resource};
+use wasmtime_wasi_config::{WasiConfig, WasiConfigVariables};
+
+use crate::instance::{WasiPreview2Ctx, envs_from_ctx, guest_dir};
+
+mod bindings {
+    wasmtime::component::bindgen!({
+        path: "wit/keyvalue",
+        world: "wasi:keyvalue/imports",
+        with: {
+            "wasi:keyvalue/store/bucket": super::Bucket,
+        },
+    });
+}
+
+use bindings::wasi::keyvalue::store::{Error as KeyValueError, KeyResponse};
+use bindings::wasi::keyvalue::{atomics, batch, store};
+
+/// Annotation to enable `wasi:config`, and set the sources of its values.
+pub const CONFIG_ANNOTATION: &str = "runwasi.io/wasmtime.wasi-config";
+/// Annotation to enable `wasi:keyvalue`, and set the initial entries of its store.
+pub const KEYVALUE_ANNOTATION: &str = "runwasi.io/wasmtime.wasi-keyvalue";
+
+/// Annotation set by containerd on the containers of a pod.
+const SANDBOX_ID_ANNOTATION: &str = "io.kubernetes.cri.sandbox-id";
+
+const ENV_SOURCE: &str = "env";
+const MEMORY_STORE: &str = "memory";
+
+/// The maximum size of a file read as a config value or as an entry of a store.
+const MAX_FILE_LEN: u64 = 1 << 20;
+
+/// The maximum total size of the keys and values of a key-value store.
+pub const MAX_STORE_BYTES: usize = 64 << 20;
+
+/// The key-value stores of the pods, by sandbox id and source of their initial entries.
+/// The stores are owned by the containers using them.
+static POD_STORES: LazyLock<Mutex<HashMap<(String, String), Weak<KeyValueStore>>>> =
+    LazyLock::new(Default::default);
+
+/// The config values of the containers linking `wasi:config` without enabling it, which can't happen.
+static NO_CONFIG: LazyLock<WasiConfigVariables> = LazyLock::new(WasiConfigVariables::new);
+
+/// The entries of a `wasi:keyvalue` store.
+#[derive(Debug, Default)]
+pub(crate) struct KeyValueStore(Mutex<Entries>);
+
+/// The entries of a store, and their total size.
+#[derive(Debug, Default)]
+struct Entries {
+    values: HashMap<String, Vec<u8>>,
+    bytes: usize,
+}
+
+impl Entries {
+    fn new(values: HashMap<String, Vec<u8>>) -> Result<Self> {
+        let bytes = values
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        ensure!(
+            bytes <= MAX_STORE_BYTES,
+            "the entries of the store exceed {MAX_STORE_BYTES} bytes"
+        );
+        Ok(Self { values, bytes })
+    }
+
+    fn insert(&mut self, key: String, value: Vec<u8>) -> Result<(), KeyValueError> {
+        let replaced = self.values.get(&key).map_or(0, |old| key.len() + old.len());
+        let bytes = self.bytes - replaced + key.len() + value.len();
+        if bytes > MAX_STORE_BYTES {
+            return Err(KeyValueError::Other(format!(
+                "the store can't hold more than {MAX_STORE_BYTES} bytes"
+            )));
+        }
+        self.values.insert(key, value);
+        self.bytes = bytes;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(value) = self.values.remove(key) {
+            self.bytes -= key.len() + value.len();
+        }
+    }
+}
+
+/// A `wasi:keyvalue` bucket opened by the guest, giving access to the store of its container.
+pub struct Bucket(Arc<KeyValueStore>);
+
+/// The `wasi:config` values and `wasi:keyvalue` store of a container, if they are enabled.
+#[derive(Clone, Default)]
+pub(crate) struct WasiCloudCtx {
+    config: Option<Arc<WasiConfigVariables>>,
+    keyvalue: Option<Arc<KeyValueStore>>,
+}
+
+impl WasiCloudCtx {
+    /// Reads the config values, and opens the key-value store, enabled by the container annotations.
+    pub(crate) fn from_context(ctx: &impl RuntimeContext) -> Result<Self> {
+        let annotations = ctx.annotations();
+
+        let config = annotations
+            .get(CONFIG_ANNOTATION)
+            .map(|sources| config_variables(sources, ctx))
+            .transpose()?
+            .map(|vars| Arc::new(WasiConfigVariables::from_iter(vars)));
+
+        let keyvalue = annotations
+            .get(KEYVALUE_ANNOTATION)
+            .map(|source| keyvalue_store(source.trim(), ctx))
+            .transpose()?;
+
+        Ok(Self { config, keyvalue })
+    }
+}
+
+/// Links the interfaces enabled by the container `annotations`.
+pub(crate) fn add_to_linker(
+    linker: &mut Linker<WasiPreview2Ctx>,
+    annotations: &HashMap<String, String>,
+) -> Result<()> {
+    if annotations.contains_key(CONFIG_ANNOTATION) {
+        log::info!("linking wasi:config");
+        wasmtime_wasi_config::add_to_linker(linker, |ctx: &mut WasiPreview2Ctx| {
+            WasiConfig::from(ctx.cloud.config.as_deref().unwrap_or(&NO_CONFIG))
+        })?;
+    }
+    if annotations.contains_key(KEYVALUE_ANNOTATION) {
+        log::info!("linking wasi:keyvalue");
+        store::add_to_linker::<_, HasSelf<WasiPreview2Ctx>>(linker, |ctx| ctx)?;
+        atomics::add_to_linker::<_, HasSelf<WasiPreview2Ctx>>(linker, |ctx| ctx)?;
+        batch::add_to_linker::<_, HasSelf<WasiPreview2Ctx>>(linker, |ctx| ctx)?;
+    }
+    Ok(())
+}
+
+/// Returns the key-value store of the pod of the container, created from the `source` the first time.
+fn keyvalue_store(source: &str, ctx: &impl RuntimeContext) -> Result<Arc<KeyValueStore>> {
+    let create = || -> Result<_> {
+        let entries: HashMap<_, _> = match source {
+            MEMORY_STORE => HashMap::new(),
+            dir => read_files(&guest_dir(ctx, dir)?)?.into_iter().collect(),
+        };
+        log::info!("wasi:keyvalue store with {} entries", entries.len());
+        Ok(Arc::new(KeyValueStore(Mutex::new(Entries::new(entries)?))))
+    };
+
+    let Some(sandbox_id) = ctx.annotations().get(SANDBOX_ID_ANNOTATION) else {
+        return create();
+    };
+
+    pod_store((sandbox_id.clone(), source.to_string()), create)
+}
+
+/// Returns the store of a pod still used by one of its containers, or the store returned by `create`.
+fn pod_store(
+    key: (String, String),
+    create: impl FnOnce() -> Result<Arc<KeyValueStore>>,
+) -> Result<Arc<KeyValueStore>> {
+    let mut stores = POD_STORES.lock().unwrap();
+    if let Some(store) = stores.get(&key).and_then(Weak::upgrade) {
+        log::info!("using the wasi:keyvalue store of pod {}", key.0);
+        return Ok(store);
+    }
+    // forget the stores of the pods without running containers
+    stores.retain(|_, store| store.strong_count() > 0);
+    let store = create()?;
+    stores.insert(key, Arc::downgrade(&store));
+    Ok(store)
+}
+
+impl WasiPreview2Ctx {
+    /// Returns the entries of the store of an open `bucket`.
+    fn entries(&self, bucket: &Resource<Bucket>) -> Result<MutexGuard<'_, Entries>, KeyValueError> {
+        let bucket = self
+            .resource_table
+            .get(bucket)
+            .map_err(|err| KeyValueError::Other(err.to_string()))?;
+        Ok(bucket.0.0.lock().unwrap())
+    }
+}
+
+impl store::Host for WasiPreview2Ctx {
+    fn open(&mut self, identifier: String) -> Result<Resource<Bucket>, KeyValueError> {
+        let Some(store) = &self.cloud.keyvalue else {
+            return Err(KeyValueError::NoSuchStore);
+        };
+        // the container has a single store, with the empty identifier
+        if !identifier.is_empty() {
+            return Err(KeyValueError::NoSuchStore);
+        }
+        self.resource_table
+            .push(Bucket(store.clone()))
+            .map_err(|err| KeyValueError::Other(err.to_string()))
+    }
+}
+
+impl store::HostBucket for WasiPreview2Ctx {
+    fn get(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+    ) -> Result<Option<Vec<u8>>, KeyValueError> {
+        Ok(self.entries(&bucket)?.values.get(&key).cloned())
+    }
+
+    fn set(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+        value: Vec<u8>,
+    ) -> Result<(), KeyValueError> {
+        self.entries(&bucket)?.insert(key, value)
+    }
+
+    fn delete(&mut self, bucket: Resource<Bucket>, key: String) -> Result<(), KeyValueError> {
+        self.entries(&bucket)?.remove(&key);
+        Ok(())
+    }
+
+    fn exists(&mut self, bucket: Resource<Bucket>, key: String) -> Result<bool, KeyValueError> {
+        Ok(self.entries(&bucket)?.values.contains_key(&key))
+    }
+
+    fn list_keys(
+        &mut self,
+        bucket: Resource<Bucket>,
+        _cursor: Option<u64>,
+    ) -> Result<KeyResponse, KeyValueError> {
+        // all the keys fit in a single page
+        let keys = self.entries(&bucket)?.values.keys().cloned().collect();
+        Ok(KeyResponse { keys, cursor: None })
+    }
+
+    fn drop(&mut self, bucket: Resource<Bucket>) -> wasmtime::Result<()> {
+        self.resource_table.delete(bucket)?;
+        Ok(())
+    }
+}
+
+impl atomics::Host for WasiPreview2Ctx {
+    fn increment(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+        delta: u64,
+    ) -> Result<u64, KeyValueError> {
+        let mut entries = self.entries(&bucket)?;
+        let value = match entries.values.get(&key) {
+            Some(value) => {
+                let value = value.as_slice().try_into().map_err(|_| {
+                    KeyValueError::Other(format!("the value of {key:?} isn't a u64"))
+                })?;
+                u64::from_le_bytes(value)
+            }
+            None => 0,
+        };
+        let value = value
+            .checked_add(delta)
+            .ok_or_else(|| KeyValueError::Other(format!("the value of {key:?} overflows")))?;
+        entries.insert(key, value.to_le_bytes().to_vec())?;
+        Ok(value)
+    }
+}
+
+impl batch::Host for WasiPreview2Ctx {
+    fn get_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        keys: Vec<String>,
+    ) -> Result<Vec<Option<(String, Vec<u8>)>>, KeyValueError> {
+        let entries = self.entries(&bucket)?;
+        Ok(keys
+            .into_iter()
+            .map(|key| entries.values.get(&key).cloned().map(|value| (key, value)))
+            .collect())
+    }
+
+    fn set_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key_values: Vec<(String, Vec<u8>)>,
+    ) -> Result<(), KeyValueError> {
+        let mut entries = self.entries(&bucket)?;
+        for (key, value) in key_values {
+            entries.insert(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn delete_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        keys: Vec<String>,
+    ) -> Result<(), KeyValueError> {
+        let mut entries = self.entries(&bucket)?;
+        for key in keys {
+            entries.remove(&key);
+        }
+        Ok(())
+    }
+}
+
+fn config_variables(sources: &str, ctx: &impl RuntimeContext) -> Result<HashMap<String, String>> {
+    let mut vars = HashMap::new();
+    for source in sources.split(',').map(str::trim) {
+        match source {
+            "" => {}
+            ENV_SOURCE => vars.extend(envs_from_ctx(ctx)),
+            dir => {
+                for (name, value) in read_files(&guest_dir(ctx, dir)?)? {
+                    let value = String::from_utf8(value)
+                        .with_context(|| format!("config value {name:?} isn't valid UTF-8"))?;
+                    vars.insert(name, value);
+                }
+            }
+        }
+    }
+    Ok(vars)
+}
+
+/// Reads the files of `dir`, skipping hidden entries such as the `..data` link of a mounted `ConfigMap`.
+/// The symlinks of the files are resolved inside the rootfs.
+fn read_files(dir: &Rootfs) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut files = vec![];
+    let entries = dir
+        .dir()
+        .entries()
+        .with_context(|| format!("failed to read {:?}", dir.path()))?;
+    for entry in entries {
+        let name = entry?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let metadata = dir
+            .dir()
+            .metadata(name)
+            .with_context(|| format!("failed to read {name:?} in {:?}", dir.path()))?;
+        if !metadata.is_file() {
+            continue;
+        }
+        files.push((name.to_string(), dir.read_file(name, MAX_FILE_LEN)?));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir_all(dir.path().join("config/..data"))?;
+        std::fs::write(dir.path().join("config/..data/greeting"), "hello")?;
+        std::os::unix::fs::symlink("..data/greeting", dir.path().join("config/greeting"))?;
+        std::fs::write(dir.path().join("config/.hidden"), "ignored")?;
+
+        let rootfs = Rootfs::open(dir.path())?;
+        let files = read_files(&rootfs.open_dir("/config")?)?;
+        assert_eq!(files, [("greeting".to_string(), b"hello".to_vec())]);
+
+        assert!(rootfs.open_dir("/missing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_files_stays_in_the_dir() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("config"))?;
+        std::fs::write(dir.path().join("secret"), "secret")?;
+        std::os::unix::fs::symlink("../secret", dir.path().join("config/relative"))?;
+
+        let config = Rootfs::open(dir.path())?.open_dir("/config")?;
+        assert!(read_files(&config).is_err());
+
+        std::fs::remove_file(dir.path().join("config/relative"))?;
+        std::os::unix::fs::symlink(
+            dir.path().join("secret"),
+            dir.path().join("config/absolute"),
+        )?;
+        assert!(read_files(&config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_size_is_capped() -> Result<()> {
+        let mut entries = Entries::new(HashMap::from([("key".to_string(), vec![0; 5])]))?;
+        assert_eq!(entries.bytes, 8);
+
+        entries.insert("key".to_string(), vec![0; 10]).unwrap();
+        assert_eq!(entries.bytes, 13);
+        assert!(
+            entries
+                .insert("big".to_string(), vec![0; MAX_STORE_BYTES])
+                .is_err()
+        );
+        assert_eq!(entries.bytes, 13);
+
+        entries.remove("key");
+        assert_eq!(entries.bytes, 0);
+
+        let too_big = HashMap::from([("big".to_string(), vec![0; MAX_STORE_BYTES])]);
+        assert!(Entries::new(too_big).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pod_store_is_dropped_with_its_last_container() -> Result<()> {
+        let key = ("test-pod-store".to_string(), MEMORY_STORE.to_string());
+        let create = || Ok(Arc::default());
+
+        let first = pod_store(key.clone(), create)?;
+        let second = pod_store(key.clone(), || panic!("the store of the pod exists"))?;
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let weak = Arc::downgrade(&first);
+        drop((first, second));
+        assert!(weak.upgrade().is_none());
+
+        let mut created = false;
+        pod_store(key, || {
+            created = true;
+            create()
+        })?;
+        assert!(created);
+        Ok(())
+    }
+}
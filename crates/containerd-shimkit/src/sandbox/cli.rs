@@ -98,8 +98,10 @@
 //!
 
 use std::path::PathBuf;
+#[cfg(unix)]
+use std::sync::Arc;
 
-use containerd_shim::{Config, parse, run};
+use containerd_shim::{Config, parse};
 
 #[cfg(feature = "opentelemetry")]
 use crate::sandbox::async_utils::AmbientRuntime as _;
@@ -292,5 +294,14 @@ where
         }
     }
 
-    run::<Shim<I>>(name, config);
+    // the sandbox service is served next to the task service, for the pods the shim is started for
+    #[cfg(unix)]
+    crate::vendor::containerd_shim::serve::run::<Shim<I>>(name, config, |shim| {
+        containerd_shim_protos::sandbox::sandbox_ttrpc::create_sandbox(Arc::new(Box::new(
+            shim.create_sandbox_service(),
+        )))
+    });
+
+    #[cfg(windows)]
+    containerd_shim::run::<Shim<I>>(name, config);
 }
@@ -48,6 +48,12 @@ pub enum Error {
     Libcontainer(#[from] libcontainer::error::LibcontainerError),
     #[error("{0}")]
     Containerd(String),
+    /// A service the operation depends on, e.g., containerd, is currently unavailable
+    #[error("unavailable: {0}")]
+    Unavailable(String),
+    /// The operation isn't supported by the runtime
+    #[error("unimplemented: {0}")]
+    Unimplemented(String),
 }
 
 pub type Result<T, E = Error> = ::std::result::Result<T, E>;
@@ -76,6 +82,12 @@ impl From<Error> for ttrpc::Error {
             Error::FailedPrecondition(ref s) => {
                 ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::FAILED_PRECONDITION, s))
             }
+            Error::Unavailable(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::UNAVAILABLE, s))
+            }
+            Error::Unimplemented(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::UNIMPLEMENTED, s))
+            }
             Error::Oci(ref _s) => {
                 ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::UNKNOWN, e.to_string()))
             }
@@ -141,6 +153,26 @@ mod tests {
             _ => panic!("unexpected error"),
         }
 
+        let e = Error::Unavailable("unavailable".to_string());
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::UNAVAILABLE);
+                assert_eq!(s.message, "unavailable");
+            }
+            _ => panic!("unexpected error"),
+        }
+
+        let e = Error::Unimplemented("unimplemented".to_string());
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::UNIMPLEMENTED);
+                assert_eq!(s.message, "unimplemented");
+            }
+            _ => panic!("unexpected error"),
+        }
+
         let e = Error::Shim(ShimError::InvalidArgument("invalid argument".to_string()));
         let t: ttrpc::Error = e.into();
         match t {
@@ -3,6 +3,7 @@
 use std::path::PathBuf;
 
 use chrono::{DateTime, Utc};
+use oci_spec::runtime::LinuxResources;
 use serde::{Deserialize, Serialize};
 
 use super::error::Error;
@@ -26,6 +27,10 @@ pub struct InstanceConfig {
     pub containerd_address: String,
     /// containerd runtime options config
     pub config: Config,
+    /// Unix socket to send the master of the terminal allocated for the task to, when the task
+    /// has a terminal. The stdio paths are empty then, as the shim copies the stdio of the task
+    /// from and to the terminal.
+    pub console_socket: Option<PathBuf>,
 }
 
 /// Represents a WASI module(s).
@@ -39,6 +44,20 @@ pub trait Instance: 'static {
     where
         Self: Sized;
 
+    /// Recover an instance created by a previous shim process, after the shim restarted
+    /// `pid` is the value returned by [`Instance::start`] if the instance was started.
+    /// The default implementation returns [`Error::Unimplemented`], for instances that
+    /// don't outlive the shim process.
+    async fn recover(id: String, cfg: &InstanceConfig, pid: Option<u32>) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let _ = (id, cfg, pid);
+        Err(Error::Unimplemented(
+            "recovering the instance after a restart of the shim".to_string(),
+        ))
+    }
+
     /// Start the instance
     /// The returned value should be a unique ID (such as a PID) for the instance.
     /// Nothing internally should be using this ID, but it is returned to containerd where a user may want to use it.
@@ -51,6 +70,23 @@ pub trait Instance: 'static {
     /// This is called after the instance has exited.
     async fn delete(&self) -> Result<(), Error>;
 
+    /// Update the resources of the instance, e.g., its cgroup limits
+    /// This is called for a created or running instance.
+    /// The default implementation returns [`Error::Unimplemented`].
+    async fn update(&self, resources: &LinuxResources) -> Result<(), Error> {
+        let _ = resources;
+        Err(Error::Unimplemented(
+            "updating the resources of the instance".to_string(),
+        ))
+    }
+
+    /// Whether the instance runs the OCI hooks of its spec itself, e.g., because its
+    /// container is created by a runtime that runs them.
+    /// The shim runs the hooks of the instances that don't, the default.
+    fn handles_hooks(&self) -> bool {
+        false
+    }
+
     /// Waits for the instance to finish and returns its exit code
     /// This is an async call.
     async fn wait(&self) -> (u32, DateTime<Utc>);
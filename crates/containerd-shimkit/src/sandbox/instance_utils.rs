@@ -2,13 +2,17 @@
 
 use std::fs::File;
 use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+#[cfg(unix)]
+use std::os::fd::BorrowedFd;
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
 use super::{Error, InstanceConfig};
 use crate::sys::DEFAULT_CONTAINER_ROOT_DIR;
-use crate::sys::stdio::open;
+#[cfg(unix)]
+use crate::sys::console::send_console;
+use crate::sys::stdio::{open, open_stdin};
 
 #[derive(Serialize, Deserialize)]
 struct Options {
@@ -45,7 +49,7 @@ impl InstanceConfig {
         if self.stdin.as_os_str().is_empty() {
             return Err(IoError::new(ErrorKind::NotFound, "File not found"));
         }
-        open(&self.stdin)
+        open_stdin(&self.stdin)
     }
 
     pub fn open_stdout(&self) -> IoResult<File> {
@@ -61,6 +65,15 @@ impl InstanceConfig {
         }
         open(&self.stderr)
     }
+
+    /// Sends the `master` of the terminal allocated for the task to the console socket.
+    #[cfg(unix)]
+    pub fn send_console(&self, master: BorrowedFd<'_>) -> IoResult<()> {
+        let Some(socket) = &self.console_socket else {
+            return Err(IoError::new(ErrorKind::NotFound, "task has no terminal"));
+        };
+        send_console(socket, master)
+    }
 }
 
 #[cfg(unix)]
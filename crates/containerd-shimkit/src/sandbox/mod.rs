@@ -58,10 +58,12 @@ pub mod instance;
 pub mod shim;
 pub mod sync;
 
+#[cfg(unix)]
+pub use crate::sys::console::ConsoleSocket;
 pub use error::{Error, Result};
 pub use instance::{Instance, InstanceConfig};
-pub use shim::Config;
 pub(crate) use shim::Shim;
+pub use shim::{BlobCacheConfig, Config, ImagePolicy, Isolation, RetryPolicy};
 
 pub(crate) mod instance_utils;
 pub(crate) mod oci;
@@ -1,15 +1,22 @@
 //! Generic helpers for working with OCI specs that can be consumed by any runtime.
 
 use std::collections::HashMap;
-use std::io::{ErrorKind, Write};
-#[cfg(unix)]
-use std::os::unix::process::CommandExt;
-use std::process;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
 
-use anyhow::Context;
+use anyhow::{Context, bail, ensure};
+use oci_spec::runtime::{Hook, Hooks, Spec};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt as _;
+use tokio::process::Command;
 
 use super::error::Result;
 
+/// The timeout of the hooks that don't set one, so a hook that hangs doesn't block the task.
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(120);
+
 fn parse_env(envs: &[String]) -> HashMap<String, String> {
     // make NAME=VALUE to HashMap<NAME, VALUE>.
     envs.iter()
@@ -24,73 +31,273 @@ fn parse_env(envs: &[String]) -> HashMap<String, String> {
         .collect()
 }
 
-pub(crate) fn setup_prestart_hooks(hooks: &Option<oci_spec::runtime::Hooks>) -> Result<()> {
-    if let Some(hooks) = hooks {
-        let prestart_hooks = hooks.prestart().as_ref().unwrap();
-
-        for hook in prestart_hooks {
-            let mut hook_command = process::Command::new(hook.path());
-            // Based on OCI spec, the first argument of the args vector is the
-            // arg0, which can be different from the path.  For example, path
-            // may be "/usr/bin/true" and arg0 is set to "true". However, rust
-            // command differentiates arg0 from args, where rust command arg
-            // doesn't include arg0. So we have to make the split arg0 from the
-            // rest of args.
-            if let Some((arg0, args)) = hook.args().as_ref().and_then(|a| a.split_first()) {
-                log::debug!("run_hooks arg0: {:?}, args: {:?}", arg0, args);
-
-                #[cfg(unix)]
-                {
-                    hook_command.arg0(arg0).args(args);
-                }
+/// The points of the lifecycle of a container where its hooks run.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum HookStage {
+    /// Deprecated in favor of `CreateRuntime`, `CreateContainer` and `StartContainer`.
+    Prestart,
+    CreateRuntime,
+    CreateContainer,
+    StartContainer,
+    Poststart,
+    Poststop,
+}
 
-                #[cfg(windows)]
-                {
-                    if !&hook.path().ends_with(arg0) {
-                        return Err(crate::sandbox::Error::InvalidArgument("Running with arg0 as different name than executable is not supported on Windows due to rust std library process implementation.".to_string()));
-                    }
+impl HookStage {
+    fn hooks(self, hooks: &Hooks) -> &[Hook] {
+        let hooks = match self {
+            Self::Prestart => hooks.prestart(),
+            Self::CreateRuntime => hooks.create_runtime(),
+            Self::CreateContainer => hooks.create_container(),
+            Self::StartContainer => hooks.start_container(),
+            Self::Poststart => hooks.poststart(),
+            Self::Poststop => hooks.poststop(),
+        };
+        hooks.as_deref().unwrap_or_default()
+    }
+}
 
-                    hook_command.args(args);
-                }
-            } else {
-                #[cfg(unix)]
-                hook_command.arg0(hook.path());
-            };
-
-            let envs: HashMap<String, String> = if let Some(env) = hook.env() {
-                parse_env(env)
-            } else {
-                HashMap::new()
-            };
-            log::debug!("run_hooks envs: {:?}", envs);
-
-            let mut hook_process = hook_command
-                .env_clear()
-                .envs(envs)
-                .stdin(process::Stdio::piped())
-                .spawn()
-                .with_context(|| "Failed to execute hook")?;
-
-            if let Some(stdin) = &mut hook_process.stdin {
-                // We want to ignore BrokenPipe here. A BrokenPipe indicates
-                // either the hook is crashed/errored or it ran successfully.
-                // Either way, this is an indication that the hook command
-                // finished execution.  If the hook command was successful,
-                // which we will check later in this function, we should not
-                // fail this step here. We still want to check for all the other
-                // error, in the case that the hook command is waiting for us to
-                // write to stdin.
-                let state = format!("{{ \"pid\": {} }}", std::process::id());
-                if let Err(e) = stdin.write_all(state.as_bytes()) {
-                    if e.kind() != ErrorKind::BrokenPipe {
-                        // Not a broken pipe. The hook command may be waiting
-                        // for us.
-                        let _ = hook_process.kill();
-                    }
+/// The status of a container, as reported to its hooks.
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ContainerStatus {
+    Created,
+    Running,
+    Stopped,
+}
+
+/// The state of a container, written as JSON to the stdin of its hooks, as defined by the
+/// runtime spec.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct State {
+    oci_version: String,
+    id: String,
+    status: ContainerStatus,
+    pid: u32,
+    bundle: PathBuf,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    annotations: HashMap<String, String>,
+}
+
+impl State {
+    pub(crate) fn new(
+        id: impl Into<String>,
+        status: ContainerStatus,
+        pid: u32,
+        bundle: impl Into<PathBuf>,
+        spec: &Spec,
+    ) -> Self {
+        Self {
+            oci_version: spec.version().clone(),
+            id: id.into(),
+            status,
+            pid,
+            bundle: bundle.into(),
+            annotations: spec.annotations().clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Runs the hooks of the `stages`, in order, with the `state` of the container on their stdin.
+/// It fails as soon as a hook fails, exits with a non-zero status, or outlives its timeout,
+/// or [`DEFAULT_HOOK_TIMEOUT`] if it doesn't set one.
+pub(crate) async fn run_hooks(
+    hooks: &Option<Hooks>,
+    stages: &[HookStage],
+    state: &State,
+) -> Result<()> {
+    let Some(hooks) = hooks else {
+        return Ok(());
+    };
+    let state = serde_json::to_vec(state)?;
+    for stage in stages {
+        for hook in stage.hooks(hooks) {
+            log::debug!("running {stage:?} hook: {:?}", hook.path());
+            run_hook(hook, &state)
+                .await
+                .with_context(|| format!("{stage:?} hook {:?} failed", hook.path()))?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_hook(hook: &Hook, state: &[u8]) -> anyhow::Result<()> {
+    let mut hook_command = Command::new(hook.path());
+    // Based on OCI spec, the first argument of the args vector is the
+    // arg0, which can be different from the path.  For example, path
+    // may be "/usr/bin/true" and arg0 is set to "true". However, rust
+    // command differentiates arg0 from args, where rust command arg
+    // doesn't include arg0. So we have to make the split arg0 from the
+    // rest of args.
+    if let Some((arg0, args)) = hook.args().as_ref().and_then(|a| a.split_first()) {
+        log::debug!("run_hooks arg0: {:?}, args: {:?}", arg0, args);
+
+        #[cfg(unix)]
+        {
+            hook_command.arg0(arg0).args(args);
+        }
+
+        #[cfg(windows)]
+        {
+            if !&hook.path().ends_with(arg0) {
+                return Err(crate::sandbox::Error::InvalidArgument("Running with arg0 as different name than executable is not supported on Windows due to rust std library process implementation.".to_string()).into());
+            }
+
+            hook_command.args(args);
+        }
+    } else {
+        #[cfg(unix)]
+        hook_command.arg0(hook.path());
+    };
+
+    let envs: HashMap<String, String> = if let Some(env) = hook.env() {
+        parse_env(env)
+    } else {
+        HashMap::new()
+    };
+    log::debug!("run_hooks envs: {:?}", envs);
+
+    let mut hook_process = hook_command
+        .env_clear()
+        .envs(envs)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| "Failed to execute hook")?;
+
+    // the timeout of a hook is in seconds, and must be greater than zero
+    let timeout = hook
+        .timeout()
+        .filter(|timeout| *timeout > 0)
+        .map_or(DEFAULT_HOOK_TIMEOUT, |timeout| {
+            Duration::from_secs(timeout as u64)
+        });
+
+    // the state is written within the timeout too, as a hook may not read its stdin
+    let stdin = hook_process.stdin.take();
+    let run = async {
+        // the hook reads EOF once the state is written, as stdin is dropped
+        if let Some(mut stdin) = stdin {
+            // A BrokenPipe indicates that the hook exited without reading the state,
+            // which only fails the hook if it exits with a non-zero status.
+            if let Err(e) = stdin.write_all(state).await {
+                if e.kind() != ErrorKind::BrokenPipe {
+                    return Err(e).context("failed to write the state");
                 }
             }
-            hook_process.wait()?;
         }
-    }
+        Ok(hook_process.wait().await?)
+    };
+    let status = match tokio::time::timeout(timeout, run).await {
+        Ok(status) => status?,
+        Err(_) => {
+            let _ = hook_process.kill().await;
+            bail!("timed out after {}s", timeout.as_secs());
+        }
+    };
+    ensure!(status.success(), "exited with {status}");
     Ok(())
 }
+
+#[cfg(unix)]
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use oci_spec::runtime::{HookBuilder, HooksBuilder, SpecBuilder};
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::sandbox::async_utils::AmbientRuntime as _;
+
+    fn hook(script: &str, timeout: Option<i64>) -> Hook {
+        let mut hook = HookBuilder::default().path("/bin/sh").args([
+            "sh".to_string(),
+            "-c".to_string(),
+            script.to_string(),
+        ]);
+        if let Some(timeout) = timeout {
+            hook = hook.timeout(timeout);
+        }
+        hook.build().unwrap()
+    }
+
+    fn state(spec: &Spec) -> State {
+        State::new("test", ContainerStatus::Created, 42, "/run/bundle", spec)
+    }
+
+    #[test]
+    fn test_run_hooks_in_order_with_state() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let log = dir.path().join("log");
+        let log = log.display();
+        let hooks = HooksBuilder::default()
+            .create_runtime([hook(
+                &format!("echo create_runtime >> {log}; cat >> {log}; echo >> {log}"),
+                None,
+            )])
+            .create_container([hook(&format!("echo create_container >> {log}"), Some(5))])
+            .poststop([hook(&format!("echo poststop >> {log}"), None)])
+            .build()?;
+        let spec = SpecBuilder::default().hooks(hooks).build()?;
+
+        run_hooks(
+            spec.hooks(),
+            &[
+                HookStage::Prestart,
+                HookStage::CreateRuntime,
+                HookStage::CreateContainer,
+            ],
+            &state(&spec),
+        )
+        .block_on()?;
+
+        let log = std::fs::read_to_string(dir.path().join("log"))?;
+        let mut lines = log.lines();
+        assert_eq!(lines.next(), Some("create_runtime"));
+        let state: serde_json::Value = serde_json::from_str(lines.next().unwrap())?;
+        assert_eq!(state["id"], "test");
+        assert_eq!(state["status"], "created");
+        assert_eq!(state["pid"], 42);
+        assert_eq!(state["bundle"], "/run/bundle");
+        assert_eq!(state["ociVersion"], spec.version().as_str());
+        assert_eq!(lines.next(), Some("create_container"));
+        assert_eq!(lines.next(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_hooks_failures() -> anyhow::Result<()> {
+        let spec = Spec::default();
+        run_hooks(&None, &[HookStage::Prestart], &state(&spec)).block_on()?;
+
+        let hooks = HooksBuilder::default()
+            .poststart([hook("exit 3", None)])
+            .build()?;
+        let res = run_hooks(&Some(hooks), &[HookStage::Poststart], &state(&spec)).block_on();
+        assert!(res.is_err());
+
+        let hooks = HooksBuilder::default()
+            .start_container([hook("sleep 10", Some(1))])
+            .build()?;
+        let start = Instant::now();
+        let res = run_hooks(&Some(hooks), &[HookStage::StartContainer], &state(&spec)).block_on();
+        assert!(res.unwrap_err().to_string().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // the state is written within the timeout, even if the hook doesn't read it
+        let large_state = State {
+            annotations: HashMap::from([("large".to_string(), "x".repeat(1 << 20))]),
+            ..state(&spec)
+        };
+        let hooks = HooksBuilder::default()
+            .start_container([hook("sleep 10", Some(1))])
+            .build()?;
+        let start = Instant::now();
+        let res = run_hooks(&Some(hooks), &[HookStage::StartContainer], &large_state).block_on();
+        assert!(res.unwrap_err().to_string().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+}
@@ -1,47 +1,291 @@
-use std::sync::Arc;
+//! Events published to containerd by the task service.
+//!
+//! Events are queued and published by a thread of their own, so the requests of the task
+//! service don't wait for containerd. An event that can't be published, e.g., while containerd
+//! restarts, is retried with an exponential backoff before the next events of its container are
+//! published, so the events of a task are always received in order, while the events of the
+//! other tasks are still published.
+//!
+//! The exit and delete events of the tasks are never dropped, as containerd relies on them to
+//! reap the tasks. The exits that aren't published before the shim process dies are replayed
+//! by the restarted shim, from the task store.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use chrono::{DateTime, TimeZone};
 use containerd_shim::event::Event;
 use containerd_shim::publisher::RemotePublisher;
 use log::warn;
+use protobuf::MessageDyn;
 use protobuf::well_known_types::timestamp::Timestamp;
 
+/// Maximum number of events waiting to be published. Events sent while the queue is full are
+/// dropped, unless they are [critical](CRITICAL_TOPICS).
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Topics of the events that are never dropped, even once they exceed their retries.
+const CRITICAL_TOPICS: &[&str] = &["/tasks/exit", "/tasks/delete"];
+
 pub trait EventSender: Clone + Send + Sync + 'static {
     fn send(&self, event: impl Event);
+
+    /// Sends the `event`, and calls `published` once it's published.
+    fn send_and_then(&self, event: impl Event, published: impl FnOnce() + Send + 'static) {
+        self.send(event);
+        published();
+    }
 }
 
-#[derive(Clone)]
-pub struct RemoteEventSender {
-    inner: Arc<Inner>,
+/// Publishes events to containerd.
+pub(super) trait Publish: Send + 'static {
+    fn publish(
+        &self,
+        topic: &str,
+        namespace: &str,
+        event: Box<dyn MessageDyn>,
+    ) -> containerd_shim::Result<()>;
 }
 
-struct Inner {
-    namespace: String,
-    publisher: RemotePublisher,
+impl Publish for RemotePublisher {
+    fn publish(
+        &self,
+        topic: &str,
+        namespace: &str,
+        event: Box<dyn MessageDyn>,
+    ) -> containerd_shim::Result<()> {
+        RemotePublisher::publish(self, Default::default(), topic, namespace, event)
+    }
+}
+
+/// Backoff between the attempts to publish an event.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// Maximum number of retries of an event before it's dropped, unless it's critical.
+    pub max_retries: u32,
+}
+
+impl Default for Backoff {
+    // retries for about two minutes, e.g., while containerd restarts
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+            max_retries: 30,
+        }
+    }
+}
+
+struct QueuedEvent {
+    topic: String,
+    /// The container the event refers to, whose events are published in order.
+    container_id: String,
+    event: Box<dyn MessageDyn>,
+    published: Option<Box<dyn FnOnce() + Send>>,
+}
+
+/// The events sent to the publisher thread.
+#[derive(Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// The events that the publisher hasn't taken yet.
+    sent: VecDeque<QueuedEvent>,
+    /// The number of events that are neither published nor dropped yet.
+    pending: usize,
+    /// Whether all the senders are dropped.
+    closed: bool,
+}
+
+impl Queue {
+    /// Records that an event is published or dropped.
+    fn done(&self) {
+        self.state.lock().unwrap().pending -= 1;
+        self.changed.notify_all();
+    }
+}
+
+/// Closes the queue once all the senders are dropped, so the publisher thread exits once the
+/// queued events are published.
+struct QueueHandle(Arc<Queue>);
+
+impl Drop for QueueHandle {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().closed = true;
+        self.0.changed.notify_all();
+    }
+}
+
+#[derive(Clone)]
+pub struct RemoteEventSender {
+    queue: Arc<QueueHandle>,
 }
 
 impl RemoteEventSender {
     pub fn new(namespace: impl AsRef<str>, publisher: RemotePublisher) -> RemoteEventSender {
+        Self::with_publisher(namespace, publisher, Backoff::default())
+    }
+
+    pub(super) fn with_publisher(
+        namespace: impl AsRef<str>,
+        publisher: impl Publish,
+        backoff: Backoff,
+    ) -> RemoteEventSender {
         let namespace = namespace.as_ref().to_string();
+        let queue = Arc::new(Queue::default());
+        let events = queue.clone();
+        std::thread::spawn(move || publish_events(&events, publisher, &namespace, backoff));
         RemoteEventSender {
-            inner: Arc::new(Inner {
-                namespace,
-                publisher,
-            }),
+            queue: Arc::new(QueueHandle(queue)),
+        }
+    }
+
+    /// Waits for the sent events to be published, or dropped, for at most `timeout`.
+    /// Returns whether they were all published or dropped in time.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let queue = &self.queue.0;
+        let state = queue.state.lock().unwrap();
+        let (_, result) = queue
+            .changed
+            .wait_timeout_while(state, timeout, |state| state.pending > 0)
+            .unwrap();
+        !result.timed_out()
+    }
+
+    fn enqueue(&self, event: impl Event, published: Option<Box<dyn FnOnce() + Send>>) {
+        let topic = event.topic();
+        let queue = &self.queue.0;
+        let mut state = queue.state.lock().unwrap();
+        if state.pending >= QUEUE_CAPACITY && !CRITICAL_TOPICS.contains(&topic.as_str()) {
+            warn!("event queue is full, dropping event, topic: {topic}");
+            return;
         }
+        state.sent.push_back(QueuedEvent {
+            container_id: container_id(&event),
+            topic,
+            event: Box::new(event),
+            published,
+        });
+        state.pending += 1;
+        queue.changed.notify_all();
     }
 }
 
 impl EventSender for RemoteEventSender {
     fn send(&self, event: impl Event) {
-        let topic = event.topic();
-        let event = Box::new(event);
-        let publisher = &self.inner.publisher;
-        if let Err(err) =
-            publisher.publish(Default::default(), &topic, &self.inner.namespace, event)
-        {
-            warn!("failed to publish event, topic: {}: {}", &topic, err)
+        self.enqueue(event, None);
+    }
+
+    fn send_and_then(&self, event: impl Event, published: impl FnOnce() + Send + 'static) {
+        self.enqueue(event, Some(Box::new(published)));
+    }
+}
+
+/// Returns the id of the container the `event` refers to, if it has one.
+fn container_id(event: &dyn MessageDyn) -> String {
+    event
+        .descriptor_dyn()
+        .field_by_name("container_id")
+        .and_then(|field| {
+            field
+                .get_singular_field_or_default(event)
+                .to_str()
+                .map(str::to_string)
+        })
+        .unwrap_or_default()
+}
+
+/// An event being published, with its next attempt.
+struct Attempt {
+    event: QueuedEvent,
+    retries: u32,
+    delay: Duration,
+    next: Instant,
+}
+
+/// Publishes the queued events, until all the senders are dropped and the events are published.
+///
+/// The events of a container are published in order: an event that fails to be published
+/// holds back the next events of its container until it's published, or dropped once it
+/// exceeds its retries, while the events of the other containers are published.
+fn publish_events(queue: &Queue, publisher: impl Publish, namespace: &str, backoff: Backoff) {
+    let mut attempts = VecDeque::<Attempt>::new();
+    loop {
+        let now = Instant::now();
+        let mut held = HashSet::new();
+        let mut i = 0;
+        while let Some(attempt) = attempts.get_mut(i) {
+            let QueuedEvent {
+                topic,
+                container_id,
+                event,
+                ..
+            } = &attempt.event;
+            if attempt.next > now || held.contains(container_id) {
+                held.insert(container_id.clone());
+                i += 1;
+                continue;
+            }
+            let err = match publisher.publish(topic, namespace, event.clone_box()) {
+                Ok(()) => {
+                    let attempt = attempts.remove(i).unwrap();
+                    if let Some(published) = attempt.event.published {
+                        published();
+                    }
+                    queue.done();
+                    continue;
+                }
+                Err(err) => err,
+            };
+            if attempt.retries >= backoff.max_retries && !CRITICAL_TOPICS.contains(&topic.as_str())
+            {
+                warn!("failed to publish event, topic: {topic}: {err}, dropping it");
+                attempts.remove(i);
+                queue.done();
+                continue;
+            }
+            attempt.retries += 1;
+            warn!(
+                "failed to publish event, topic: {topic}: {err}, retrying in {:?} ({}/{})",
+                attempt.delay, attempt.retries, backoff.max_retries
+            );
+            attempt.next = now + attempt.delay;
+            attempt.delay = (attempt.delay * 2).min(backoff.max);
+            held.insert(container_id.clone());
+            i += 1;
         }
+
+        // wait for new events, or for the next retry
+        let next = attempts.iter().map(|attempt| attempt.next).min();
+        let mut state = queue.state.lock().unwrap();
+        while state.sent.is_empty() {
+            let Some(next) = next else {
+                if state.closed {
+                    return;
+                }
+                state = queue.changed.wait(state).unwrap();
+                continue;
+            };
+            let timeout = next.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                break;
+            }
+            state = queue.changed.wait_timeout(state, timeout).unwrap().0;
+        }
+        let initial = backoff.initial.min(backoff.max);
+        attempts.extend(state.sent.drain(..).map(|event| Attempt {
+            event,
+            retries: 0,
+            delay: initial,
+            next: Instant::now(),
+        }));
     }
 }
 
@@ -58,3 +302,184 @@ impl<Tz: TimeZone> ToTimestamp for DateTime<Tz> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use containerd_shim::error::Error as ShimError;
+    use containerd_shim::protos::events::task::{TaskCreate, TaskExit, TaskStart};
+
+    use super::*;
+
+    /// Publisher that fails the attempts listed in `failures`, and the events of the
+    /// containers that are `down`, and records the published events.
+    #[derive(Clone, Default)]
+    struct FlakyPublisher {
+        attempts: Arc<Mutex<u32>>,
+        failures: Arc<Vec<u32>>,
+        down: Arc<Mutex<HashSet<String>>>,
+        published: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    impl Publish for FlakyPublisher {
+        fn publish(
+            &self,
+            topic: &str,
+            namespace: &str,
+            event: Box<dyn MessageDyn>,
+        ) -> containerd_shim::Result<()> {
+            let mut attempts = self.attempts.lock().unwrap();
+            *attempts += 1;
+            if self.failures.contains(&*attempts) {
+                return Err(ShimError::Other("injected failure".to_string()));
+            }
+            assert_eq!(namespace, "test_namespace");
+            let id = container_id(&*event);
+            if self.down.lock().unwrap().contains(&id) {
+                return Err(ShimError::Other("container is down".to_string()));
+            }
+            self.published.lock().unwrap().push((topic.to_string(), id));
+            Ok(())
+        }
+    }
+
+    fn published(publisher: &FlakyPublisher, count: usize) -> Vec<(String, String)> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let published = publisher.published.lock().unwrap().clone();
+            if published.len() >= count || Instant::now() > deadline {
+                return published;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn backoff(max_retries: u32) -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(2),
+            max_retries,
+        }
+    }
+
+    fn create(id: &str) -> TaskCreate {
+        TaskCreate {
+            container_id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn start(id: &str) -> TaskStart {
+        TaskStart {
+            container_id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn exit(id: &str) -> TaskExit {
+        TaskExit {
+            container_id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_events_are_retried_in_order() {
+        let publisher = FlakyPublisher {
+            failures: Arc::new(vec![1, 2, 4, 6, 7, 8]),
+            ..Default::default()
+        };
+        let sender =
+            RemoteEventSender::with_publisher("test_namespace", publisher.clone(), backoff(5));
+
+        sender.send(create("a"));
+        sender.send(create("b"));
+        sender.send(start("a"));
+        sender.send(start("b"));
+        sender.send(exit("b"));
+        sender.send(exit("a"));
+
+        // the events of each container are published in order
+        let published = published(&publisher, 6);
+        let topics = |id: &str| {
+            published
+                .iter()
+                .filter(|(_, container_id)| container_id == id)
+                .map(|(topic, _)| topic.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            topics("a"),
+            ["/tasks/create", "/tasks/start", "/tasks/exit"]
+        );
+        assert_eq!(
+            topics("b"),
+            ["/tasks/create", "/tasks/start", "/tasks/exit"]
+        );
+    }
+
+    #[test]
+    fn test_events_are_dropped_after_max_retries() {
+        let publisher = FlakyPublisher {
+            failures: Arc::new(vec![1, 2, 3]),
+            ..Default::default()
+        };
+        let sender =
+            RemoteEventSender::with_publisher("test_namespace", publisher.clone(), backoff(2));
+
+        sender.send(start("a"));
+        sender.send(start("a"));
+
+        assert!(sender.flush(Duration::from_secs(5)));
+        assert_eq!(
+            published(&publisher, 1),
+            [("/tasks/start".to_string(), "a".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_exits_are_never_dropped() {
+        let publisher = FlakyPublisher::default();
+        publisher.down.lock().unwrap().insert("a".to_string());
+        let sender =
+            RemoteEventSender::with_publisher("test_namespace", publisher.clone(), backoff(2));
+
+        sender.send(exit("a"));
+        sender.send(start("b"));
+
+        // the events of the other containers aren't held back
+        assert_eq!(
+            published(&publisher, 1),
+            [("/tasks/start".to_string(), "b".to_string())]
+        );
+        assert!(!sender.flush(Duration::from_millis(50)));
+
+        publisher.down.lock().unwrap().clear();
+        assert!(sender.flush(Duration::from_secs(5)));
+        assert_eq!(
+            published(&publisher, 2),
+            [
+                ("/tasks/start".to_string(), "b".to_string()),
+                ("/tasks/exit".to_string(), "a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_published_callback() {
+        let publisher = FlakyPublisher {
+            failures: Arc::new(vec![1]),
+            ..Default::default()
+        };
+        let sender =
+            RemoteEventSender::with_publisher("test_namespace", publisher.clone(), backoff(2));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        sender.send_and_then(exit("a"), move || tx.send(()).unwrap());
+
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            published(&publisher, 1),
+            [("/tasks/exit".to_string(), "a".to_string())]
+        );
+    }
+}
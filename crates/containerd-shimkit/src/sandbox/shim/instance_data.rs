@@ -1,14 +1,39 @@
+use std::fs::File;
+use std::sync::Mutex;
+#[cfg(unix)]
+use std::thread::JoinHandle;
+#[cfg(unix)]
+use std::time::Duration;
+
 use chrono::{DateTime, Utc};
+use oci_spec::runtime::LinuxResources;
 use tokio::sync::{OnceCell, RwLock};
 
+#[cfg(unix)]
+use crate::sandbox::async_utils::AmbientRuntime as _;
 use crate::sandbox::shim::task_state::TaskState;
-use crate::sandbox::{Instance, InstanceConfig, Result};
+use crate::sandbox::shim::task_store::TaskRecord;
+use crate::sandbox::{Error, Instance, InstanceConfig, Result};
+#[cfg(unix)]
+use crate::sys::console::{ConsoleSocket, copy_io, resize};
+use crate::sys::stdio::hold_stdin;
 
 pub(super) struct InstanceData<T: Instance> {
     pub instance: T,
     pub config: InstanceConfig,
     pid: OnceCell<u32>,
     state: RwLock<TaskState>,
+    stdin: Mutex<Option<File>>,
+    #[cfg(unix)]
+    console: Option<File>,
+    /// The thread copying the output of the terminal, joined before the exit is reported.
+    #[cfg(unix)]
+    console_output: Mutex<Option<JoinHandle<()>>>,
+    #[cfg(unix)]
+    console_drained: OnceCell<()>,
+    /// The exit status persisted by a previous shim process, for a recovered instance
+    /// that exited before the shim restarted.
+    recovered_exit: Option<(u32, DateTime<Utc>)>,
 }
 
 impl<T: Instance> InstanceData<T> {
@@ -16,17 +41,124 @@ impl<T: Instance> InstanceData<T> {
     pub async fn new(
         id: impl AsRef<str> + std::fmt::Debug,
         config: InstanceConfig,
+        terminal: bool,
+    ) -> Result<Self> {
+        let id = id.as_ref().to_string();
+        // hold the stdin fifo before the instance opens it, so it only reads EOF once the IO is closed
+        let stdin = if config.stdin.as_os_str().is_empty() {
+            None
+        } else {
+            // the instance handles a stdin it can't open, as it did before stdin was held
+            hold_stdin(&config.stdin).unwrap_or_else(|err| {
+                log::warn!("failed to hold stdin {}: {err}", config.stdin.display());
+                None
+            })
+        };
+
+        if !terminal {
+            let instance = T::new(id, &config).await?;
+            return Ok(Self {
+                instance,
+                config,
+                pid: OnceCell::default(),
+                state: RwLock::new(TaskState::Created),
+                stdin: Mutex::new(stdin),
+                #[cfg(unix)]
+                console: None,
+                #[cfg(unix)]
+                console_output: Mutex::new(None),
+                #[cfg(unix)]
+                console_drained: OnceCell::new(),
+                recovered_exit: None,
+            });
+        }
+
+        #[cfg(unix)]
+        {
+            // the instance sends the master of the terminal it allocates to the console socket,
+            // and the shim copies the stdio of the task from and to the terminal
+            let socket = ConsoleSocket::new()?;
+            let instance_config = InstanceConfig {
+                stdin: Default::default(),
+                stdout: Default::default(),
+                stderr: Default::default(),
+                console_socket: Some(socket.path().to_path_buf()),
+                ..config.clone()
+            };
+            let instance = T::new(id, &instance_config).await?;
+            let console = match socket.receive() {
+                Ok(console) => console,
+                Err(err) => {
+                    let _ = instance.delete().await;
+                    return Err(Error::InvalidArgument(format!(
+                        "terminal is not supported by the instance: {err}"
+                    )));
+                }
+            };
+            let output = copy_io(
+                &console,
+                config.open_stdin().ok(),
+                config.open_stdout().ok(),
+            )?;
+            Ok(Self {
+                instance,
+                config,
+                pid: OnceCell::default(),
+                state: RwLock::new(TaskState::Created),
+                stdin: Mutex::new(stdin),
+                console: Some(console),
+                console_output: Mutex::new(output),
+                console_drained: OnceCell::new(),
+                recovered_exit: None,
+            })
+        }
+
+        #[cfg(windows)]
+        Err(Error::InvalidArgument(
+            "terminal is not supported".to_string(),
+        ))
+    }
+
+    /// Recovers the instance `id` from the state persisted by a previous shim process.
+    /// The stdin and terminal of the task aren't recovered, they were held by the previous shim.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    pub async fn recover(
+        id: impl AsRef<str> + std::fmt::Debug,
+        record: TaskRecord,
     ) -> Result<Self> {
         let id = id.as_ref().to_string();
-        let instance = T::new(id, &config).await?;
+        let instance = T::recover(id, &record.config, record.pid).await?;
+        let state = match (record.pid, record.exit) {
+            (_, Some(_)) => TaskState::Exited,
+            (Some(_), None) => TaskState::Started,
+            (None, None) => TaskState::Created,
+        };
         Ok(Self {
             instance,
-            config,
-            pid: OnceCell::default(),
-            state: RwLock::new(TaskState::Created),
+            config: record.config,
+            pid: OnceCell::new_with(record.pid),
+            state: RwLock::new(state),
+            stdin: Mutex::new(None),
+            #[cfg(unix)]
+            console: None,
+            #[cfg(unix)]
+            console_output: Mutex::new(None),
+            #[cfg(unix)]
+            console_drained: OnceCell::new(),
+            recovered_exit: record.exit,
         })
     }
 
+    /// Returns the state of the instance to persist, with its `exit` status if it has exited.
+    pub fn record(&self, exit: Option<(u32, DateTime<Utc>)>) -> TaskRecord {
+        TaskRecord {
+            config: self.config.clone(),
+            pid: self.pid(),
+            exit: exit.or(self.recovered_exit),
+            exit_published: false,
+        }
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     pub fn pid(&self) -> Option<u32> {
         self.pid.get().copied()
@@ -60,6 +192,35 @@ impl<T: Instance> InstanceData<T> {
         self.instance.kill(signal).await
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn update(&self, resources: &LinuxResources) -> Result<()> {
+        let s = self.state.read().await;
+        s.update()?;
+
+        self.instance.update(resources).await
+    }
+
+    /// Closes the stdin of the instance, which reads EOF once containerd closes its end too.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub fn close_stdin(&self) {
+        self.stdin.lock().unwrap().take();
+    }
+
+    /// Resizes the terminal of the instance.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub fn resize_pty(&self, width: u32, height: u32) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(console) = &self.console {
+            resize(console, width, height)?;
+            return Ok(());
+        }
+        #[cfg(windows)]
+        let _ = (width, height);
+        Err(Error::FailedPrecondition(
+            "task has no terminal".to_string(),
+        ))
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     pub async fn delete(&self) -> Result<()> {
         let mut s = self.state.write().await;
@@ -77,9 +238,33 @@ impl<T: Instance> InstanceData<T> {
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     pub async fn wait(&self) -> (u32, DateTime<Utc>) {
+        if let Some(exit) = self.recovered_exit {
+            return exit;
+        }
         let res = self.instance.wait().await;
+        #[cfg(unix)]
+        self.drain_console().await;
         let mut s = self.state.write().await;
         *s = TaskState::Exited;
         res
     }
+
+    /// Waits for the output of the terminal to be copied, so it's complete once the exit
+    /// of the instance is reported. The wait is bounded, as a guest that is killed while it
+    /// doesn't yield may keep its terminal open.
+    #[cfg(unix)]
+    async fn drain_console(&self) {
+        const CONSOLE_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+        self.console_drained
+            .get_or_init(|| async {
+                let Some(output) = self.console_output.lock().unwrap().take() else {
+                    return;
+                };
+                let join = tokio::task::spawn_blocking(move || output.join());
+                if join.with_timeout(CONSOLE_DRAIN_TIMEOUT).await.is_none() {
+                    log::warn!("the terminal is still open after the instance exited");
+                }
+            })
+            .await;
+    }
 }
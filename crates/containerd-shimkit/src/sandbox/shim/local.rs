@@ -1,24 +1,26 @@
 use std::collections::HashMap;
 use std::fs::create_dir_all;
 use std::ops::Not;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::ensure;
+use chrono::{DateTime, Utc};
 use containerd_shim::api::{
-    ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteRequest, Empty,
-    KillRequest, ShutdownRequest, StartRequest, StartResponse, StateRequest, StateResponse,
-    StatsRequest, StatsResponse, WaitRequest, WaitResponse,
+    CloseIORequest, ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse,
+    DeleteRequest, Empty, KillRequest, PidsRequest, PidsResponse, ResizePtyRequest,
+    ShutdownRequest, StartRequest, StartResponse, StateRequest, StateResponse, StatsRequest,
+    StatsResponse, UpdateTaskRequest, WaitRequest, WaitResponse,
 };
 use containerd_shim::error::Error as ShimError;
 use containerd_shim::protos::events::task::{TaskCreate, TaskDelete, TaskExit, TaskIO, TaskStart};
 use containerd_shim::protos::shim::shim_ttrpc::Task;
-use containerd_shim::protos::types::task::Status;
+use containerd_shim::protos::types::task::{ProcessInfo, Status};
 use containerd_shim::util::IntoOption;
 use containerd_shim::{DeleteResponse, TtrpcContext, TtrpcResult};
 use futures::FutureExt as _;
 use log::debug;
-use oci_spec::runtime::Spec;
+use oci_spec::runtime::{LinuxResources, Spec};
 use prost::Message;
 use protobuf::well_known_types::any::Any;
 use serde::{Deserialize, Serialize};
@@ -30,11 +32,15 @@ use tracing_opentelemetry::OpenTelemetrySpanExt as _;
 use super::otel::extract_context;
 use crate::sandbox::async_utils::AmbientRuntime as _;
 use crate::sandbox::instance::{Instance, InstanceConfig};
+use crate::sandbox::oci::{ContainerStatus, HookStage};
 use crate::sandbox::shim::events::{EventSender, RemoteEventSender, ToTimestamp};
 use crate::sandbox::shim::instance_data::InstanceData;
+use crate::sandbox::shim::pod::Pods;
+use crate::sandbox::shim::task_store::TaskStore;
 use crate::sandbox::sync::WaitableCell;
 use crate::sandbox::{Error, Result, oci};
 use crate::sys::metrics::get_metrics;
+use crate::sys::pids::get_pids;
 
 #[cfg(test)]
 mod tests;
@@ -58,6 +64,120 @@ pub struct Config {
     /// Enables systemd cgroup.
     #[serde(alias = "SystemdCgroup")]
     pub systemd_cgroup: bool,
+    /// Requirements an image must satisfy before its layers are executed.
+    #[serde(default, alias = "ImagePolicy")]
+    pub image_policy: ImagePolicy,
+    /// Retries and deadlines for the requests made to containerd.
+    #[serde(default, alias = "ContainerdRetry")]
+    pub containerd_retry: RetryPolicy,
+    /// How the containers of the runtime handler are isolated from each other.
+    #[serde(default, alias = "Isolation")]
+    pub isolation: Isolation,
+    /// Where the layers read by the shim are cached, and how much of them.
+    #[serde(default, alias = "BlobCache")]
+    pub blob_cache: BlobCacheConfig,
+}
+
+/// Cache of the layers and artifacts the shim reads, shared by its containers.
+///
+/// The layers are memory mapped from the cache, so a cache on a tmpfs, e.g., under `/run`,
+/// keeps them in memory. Large layers should be cached on persistent storage instead.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(default)]
+pub struct BlobCacheConfig {
+    /// Directory of the cache, `.blobs` under the root dir of the runtime by default.
+    #[serde(alias = "Dir")]
+    pub dir: Option<PathBuf>,
+    /// Maximum size of the cache, in bytes. The least recently used layers are evicted
+    /// when it's exceeded.
+    #[serde(alias = "MaxBytes")]
+    pub max_bytes: u64,
+}
+
+impl Default for BlobCacheConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_bytes: 256 << 20,
+        }
+    }
+}
+
+/// Isolation of the containers run by a shim.
+///
+/// It's up to the [`Instance`] to honor it, instances that don't support a mode
+/// reject the containers with [`Error::InvalidArgument`].
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Isolation {
+    /// Each container runs in its own Linux container, with its own processes,
+    /// namespaces and cgroup.
+    #[default]
+    #[serde(rename = "container", alias = "Container")]
+    Container,
+    /// The containers run as tasks of the shim process, and are only isolated from
+    /// each other by the sandbox of the runtime.
+    #[serde(rename = "in-process", alias = "InProcess")]
+    InProcess,
+}
+
+/// Policy enforced on container images before their Wasm layers are executed.
+///
+/// Images that don't satisfy the policy are rejected with [`Error::FailedPrecondition`].
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct ImagePolicy {
+    /// Requires the image to be referenced by a digest that matches its manifest.
+    #[serde(default, alias = "RequireDigest")]
+    pub require_digest: bool,
+    /// Requires the image to have a cosign-style signature, made by one of the
+    /// `trusted_keys`, available in the content store.
+    #[serde(default, alias = "RequireSignature")]
+    pub require_signature: bool,
+    /// Paths to PEM encoded ECDSA P-256 public keys trusted to sign images.
+    #[serde(default, alias = "TrustedKeys")]
+    pub trusted_keys: Vec<PathBuf>,
+}
+
+impl ImagePolicy {
+    /// Whether the policy requires anything from the images, so images that can't be
+    /// verified must be rejected.
+    pub fn is_enforced(&self) -> bool {
+        self.require_digest || self.require_signature
+    }
+}
+
+/// Retry policy for the requests the shim makes to containerd, e.g., to read
+/// the Wasm layers of an image from the content store.
+///
+/// Requests failing with a transient error are retried with an exponential
+/// backoff, starting at `initial_backoff_ms` and capped at `max_backoff_ms`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the first attempt.
+    #[serde(alias = "MaxRetries")]
+    pub max_retries: u32,
+    /// Delay before the first retry, in milliseconds.
+    #[serde(alias = "InitialBackoffMs")]
+    pub initial_backoff_ms: u64,
+    /// Maximum delay between retries, in milliseconds.
+    #[serde(alias = "MaxBackoffMs")]
+    pub max_backoff_ms: u64,
+    /// Deadline for each attempt, in milliseconds.
+    /// Layers are streamed without a deadline, and their reads and writes are retried when
+    /// no data is transferred for that long instead.
+    #[serde(alias = "RequestTimeoutMs")]
+    pub request_timeout_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 2_000,
+            request_timeout_ms: 60_000,
+        }
+    }
 }
 
 impl Config {
@@ -89,10 +209,40 @@ pub struct Local<T: Instance + Send + Sync, E: EventSender = RemoteEventSender>
     pub(super) instances: LocalInstances<T>,
     events: E,
     exit: WaitableCell<()>,
+    pub(super) pods: Arc<Pods>,
+    pub(super) store: TaskStore,
     namespace: String,
     containerd_address: String,
 }
 
+/// Sends the `TaskExit` event of the task `id`, and records in the `store` once it's published.
+fn send_exit(
+    events: &impl EventSender,
+    store: &TaskStore,
+    id: String,
+    pid: u32,
+    (exit_code, timestamp): (u32, DateTime<Utc>),
+) {
+    let published = {
+        let store = store.clone();
+        let id = id.clone();
+        move || {
+            if let Err(err) = store.update(&id, |record| record.exit_published = true) {
+                log::warn!("failed to persist the published exit of task {id}: {err}");
+            }
+        }
+    };
+    let event = TaskExit {
+        container_id: id.clone(),
+        exit_status: exit_code,
+        exited_at: Some(timestamp.to_timestamp()).into(),
+        pid,
+        id,
+        ..Default::default()
+    };
+    events.send_and_then(event, published);
+}
+
 impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
     /// Creates a new local task service.
     #[cfg_attr(
@@ -112,6 +262,8 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             instances,
             events,
             exit,
+            pods: Arc::default(),
+            store: TaskStore::default(),
             namespace,
             containerd_address,
         }
@@ -120,7 +272,108 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     pub(super) async fn get_instance(&self, id: &str) -> Result<Arc<InstanceData<T>>> {
         let instance = self.instances.read().await.get(id).cloned();
-        instance.ok_or_else(|| Error::NotFound(id.to_string()))
+        match instance {
+            Some(instance) => Ok(instance),
+            None => self.recover_instance(id).await,
+        }
+    }
+
+    /// Recovers the task `id` from the state persisted by a previous shim process, if any.
+    ///
+    /// The instances aren't locked while the task is recovered, so the other tasks are still
+    /// served. If the task is recovered concurrently, the first instance is kept.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn recover_instance(&self, id: &str) -> Result<Arc<InstanceData<T>>> {
+        let Some(record) = self.store.load(id)? else {
+            return Err(Error::NotFound(id.to_string()));
+        };
+
+        let running = record.pid.is_some() && record.exit.is_none();
+        let instance = match InstanceData::recover(id, record).await {
+            Ok(instance) => Arc::new(instance),
+            // the instance didn't outlive the previous shim process
+            Err(Error::Unimplemented(err)) => {
+                log::warn!("task {id} can't be recovered: {err}");
+                self.store.remove(id)?;
+                return Err(Error::NotFound(id.to_string()));
+            }
+            Err(err) => return Err(err),
+        };
+
+        let mut instances = self.instances.write().await;
+        if let Some(instance) = instances.get(id) {
+            return Ok(instance.clone());
+        }
+        // the task was recovered and deleted concurrently
+        if self.store.load(id)?.is_none() {
+            return Err(Error::NotFound(id.to_string()));
+        }
+        log::info!("recovered task {id}");
+        instances.insert(id.to_string(), instance.clone());
+        drop(instances);
+        if running {
+            self.watch_exit(id, instance.clone());
+        }
+        Ok(instance)
+    }
+
+    /// Runs the OCI hooks of the `stages` of the task `id`, unless its instance runs them itself.
+    async fn run_hooks(
+        &self,
+        id: &str,
+        i: &InstanceData<T>,
+        stages: &[HookStage],
+        status: ContainerStatus,
+    ) -> Result<()> {
+        if i.instance.handles_hooks() {
+            return Ok(());
+        }
+        let spec = Spec::load(i.config.bundle.join("config.json"))
+            .map_err(|err| Error::InvalidArgument(format!("could not load runtime spec: {err}")))?;
+        let pid = i.pid().unwrap_or_else(std::process::id);
+        let state = oci::State::new(id, status, pid, &i.config.bundle, &spec);
+        oci::run_hooks(spec.hooks(), stages, &state).await
+    }
+
+    /// Waits for the task `id` to exit in the background, to persist its exit status
+    /// and send the `TaskExit` event.
+    fn watch_exit(&self, id: &str, instance: Arc<InstanceData<T>>) {
+        let events = self.events.clone();
+        let store = self.store.clone();
+        let id = id.to_string();
+        async move {
+            let exit = instance.wait().await;
+            let pid = instance.pid().unwrap_or_default();
+            let persisted = store.update(&id, |record| {
+                record.exit = Some(exit);
+                record.exit_published = false;
+            });
+            if let Err(err) = persisted {
+                log::warn!("failed to persist the exit of task {id}: {err}");
+            }
+            send_exit(&events, &store, id, pid, exit);
+        }
+        .spawn();
+    }
+
+    /// Sends the exits persisted by a previous shim process that weren't published, e.g., when
+    /// the shim was killed before containerd received them.
+    pub(super) fn replay_exits(&self) {
+        let tasks = match self.store.list() {
+            Ok(tasks) => tasks,
+            Err(err) => {
+                log::warn!("failed to list the persisted tasks: {err}");
+                return;
+            }
+        };
+        for (id, record) in tasks {
+            let (Some(exit), false) = (record.exit, record.exit_published) else {
+                continue;
+            };
+            log::info!("replaying the exit of task {id}");
+            let pid = record.pid.unwrap_or_default();
+            send_exit(&self.events, &self.store, id, pid, exit);
+        }
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
@@ -145,12 +398,6 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             return Err(ShimError::Unimplemented("checkpoint is not supported".to_string()).into());
         }
 
-        if req.terminal {
-            return Err(Error::InvalidArgument(
-                "terminal is not supported".to_string(),
-            ));
-        }
-
         if self.has_instance(&req.id).await {
             return Err(Error::AlreadyExists(req.id));
         }
@@ -193,15 +440,45 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             stderr: req.stderr.as_str().into(),
             stdin: req.stdin.as_str().into(),
             config,
+            console_socket: None,
         };
 
         // Check if this is a cri container
-        let instance = InstanceData::new(req.id(), cfg).await?;
+        let instance = InstanceData::new(req.id(), cfg, req.terminal).await?;
+
+        // Per the spec, the createRuntime and createContainer hooks, and the deprecated
+        // prestart hooks, must be called as part of the create operation
+        let stages = [
+            HookStage::Prestart,
+            HookStage::CreateRuntime,
+            HookStage::CreateContainer,
+        ];
+        if let Err(err) = self
+            .run_hooks(req.id(), &instance, &stages, ContainerStatus::Created)
+            .await
+        {
+            let _ = instance.delete().await;
+            return Err(err);
+        }
+
+        // the task belongs to the pod it's created for, if the shim manages that pod
+        let instance = Arc::new(instance);
+        let pod_id = spec
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get("io.kubernetes.cri.sandbox-id"));
+        if let Some(pod_id) = pod_id {
+            if let Err(err) = self.pods.add_task(pod_id, req.id(), instance.clone()) {
+                let _ = instance.delete().await;
+                return Err(err);
+            }
+        }
+        self.store.persist(req.id(), &instance.record(None));
 
         self.instances
             .write()
             .await
-            .insert(req.id().to_string(), Arc::new(instance));
+            .insert(req.id().to_string(), instance);
 
         self.events.send(TaskCreate {
             container_id: req.id,
@@ -211,6 +488,7 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
                 stdin: req.stdin,
                 stdout: req.stdout,
                 stderr: req.stderr,
+                terminal: req.terminal,
                 ..Default::default()
             })
             .into(),
@@ -219,10 +497,6 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 
         debug!("create done");
 
-        // Per the spec, the prestart hook must be called as part of the create operation
-        debug!("call prehook before the start");
-        oci::setup_prestart_hooks(spec.hooks())?;
-
         Ok(CreateTaskResponse {
             pid: std::process::id(),
             ..Default::default()
@@ -236,7 +510,28 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         }
 
         let i = self.get_instance(req.id()).await?;
+        self.run_hooks(
+            req.id(),
+            &i,
+            &[HookStage::StartContainer],
+            ContainerStatus::Created,
+        )
+        .await?;
         let pid = i.start().await?;
+        self.store.persist(req.id(), &i.record(None));
+
+        // the task is started already, so the failures of the poststart hooks are only logged
+        if let Err(err) = self
+            .run_hooks(
+                req.id(),
+                &i,
+                &[HookStage::Poststart],
+                ContainerStatus::Running,
+            )
+            .await
+        {
+            log::warn!("{err}");
+        }
 
         self.events.send(TaskStart {
             container_id: req.id().into(),
@@ -244,22 +539,7 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             ..Default::default()
         });
 
-        let events = self.events.clone();
-
-        let id = req.id().to_string();
-
-        async move {
-            let (exit_code, timestamp) = i.wait().await;
-            events.send(TaskExit {
-                container_id: id.clone(),
-                exit_status: exit_code,
-                exited_at: Some(timestamp.to_timestamp()).into(),
-                pid,
-                id,
-                ..Default::default()
-            });
-        }
-        .spawn();
+        self.watch_exit(req.id(), i);
 
         debug!("started: {:?}", req);
 
@@ -291,11 +571,28 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 
         i.delete().await?;
 
+        // the task is deleted already, so the failures of the poststop hooks are only logged
+        if let Err(err) = self
+            .run_hooks(
+                req.id(),
+                &i,
+                &[HookStage::Poststop],
+                ContainerStatus::Stopped,
+            )
+            .await
+        {
+            log::warn!("{err}");
+        }
+
         let pid = i.pid().unwrap_or_default();
         let (exit_code, timestamp) = i.wait().now_or_never().unzip();
         let timestamp = timestamp.map(ToTimestamp::to_timestamp);
 
         self.instances.write().await.remove(req.id());
+        self.pods.remove_task(req.id());
+        if let Err(err) = self.store.remove(req.id()) {
+            log::warn!("failed to remove the state of task {}: {err}", req.id());
+        }
 
         self.events.send(TaskDelete {
             container_id: req.id().into(),
@@ -376,6 +673,66 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             ..Default::default()
         })
     }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_pids(&self, req: PidsRequest) -> Result<PidsResponse> {
+        let i = self.get_instance(req.id()).await?;
+        let pid = i
+            .pid()
+            .ok_or_else(|| Error::FailedPrecondition("task is not running".to_string()))?;
+
+        let processes = get_pids(pid)?
+            .into_iter()
+            .map(|pid| ProcessInfo {
+                pid,
+                ..Default::default()
+            })
+            .collect();
+
+        Ok(PidsResponse {
+            processes,
+            ..Default::default()
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_close_io(&self, req: CloseIORequest) -> Result<Empty> {
+        if !req.exec_id().is_empty() {
+            return Err(Error::InvalidArgument("exec is not supported".to_string()));
+        }
+        let i = self.get_instance(req.id()).await?;
+        if req.stdin() {
+            i.close_stdin();
+        }
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_resize_pty(&self, req: ResizePtyRequest) -> Result<Empty> {
+        if !req.exec_id().is_empty() {
+            return Err(Error::InvalidArgument("exec is not supported".to_string()));
+        }
+        let i = self.get_instance(req.id()).await?;
+        i.resize_pty(req.width, req.height)?;
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_update(&self, req: UpdateTaskRequest) -> Result<Empty> {
+        let resources = req
+            .resources
+            .as_ref()
+            .ok_or_else(|| Error::InvalidArgument("missing resources".to_string()))?;
+        // containerd encodes the resources of the runtime spec as JSON
+        let resources: LinuxResources = serde_json::from_slice(&resources.value)
+            .map_err(|err| Error::InvalidArgument(format!("invalid resources: {err}")))?;
+
+        self.get_instance(req.id())
+            .await?
+            .update(&resources)
+            .await?;
+        Ok(Empty::new())
+    }
 }
 
 impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
@@ -497,7 +854,8 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         #[cfg(feature = "opentelemetry")]
         tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
 
-        if self.is_empty().block_on() {
+        // the shim is kept alive by the pods it manages
+        if self.is_empty().block_on() && self.pods.is_empty() {
             let _ = self.exit.set(());
         }
         Ok(Empty::new())
@@ -512,4 +870,44 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
 
         Ok(self.task_stats(req).block_on()?)
     }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn pids(&self, _ctx: &TtrpcContext, req: PidsRequest) -> TtrpcResult<PidsResponse> {
+        debug!("pids: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(self.task_pids(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn close_io(&self, _ctx: &TtrpcContext, req: CloseIORequest) -> TtrpcResult<Empty> {
+        debug!("close_io: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(self.task_close_io(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn resize_pty(&self, _ctx: &TtrpcContext, req: ResizePtyRequest) -> TtrpcResult<Empty> {
+        debug!("resize_pty: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(self.task_resize_pty(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn update(&self, _ctx: &TtrpcContext, req: UpdateTaskRequest) -> TtrpcResult<Empty> {
+        debug!("update: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(self.task_update(req).block_on()?)
+    }
 }
@@ -5,6 +5,7 @@ use anyhow::Context;
 use chrono::{DateTime, Utc};
 use containerd_shim::api::Status;
 use containerd_shim::event::Event;
+use containerd_shim_protos::sandbox::sandbox::{CreateSandboxRequest, StopSandboxRequest};
 use protobuf::{MessageDyn, SpecialFields};
 use serde_json as json;
 use tempfile::tempdir;
@@ -13,6 +14,8 @@ use tokio_async_drop::tokio_async_drop;
 
 use super::*;
 use crate::sandbox::shim::events::EventSender;
+use crate::sandbox::shim::pod::PodSandbox;
+use crate::sandbox::shim::task_store::{TaskRecord, TaskStore};
 use crate::sandbox::sync::WaitableCell;
 
 /// This is used for the tests and is a no-op instance implementation.
@@ -24,6 +27,12 @@ pub struct InstanceStub {
 
 impl Instance for InstanceStub {
     async fn new(_id: String, _cfg: &InstanceConfig) -> Result<Self, Error> {
+        #[cfg(unix)]
+        if _cfg.console_socket.is_some() {
+            use std::os::fd::AsFd as _;
+            let pty = nix::pty::openpty(None, None)?;
+            _cfg.send_console(pty.master.as_fd())?;
+        }
         Ok(InstanceStub {
             exit_code: WaitableCell::new(),
         })
@@ -130,6 +139,42 @@ async fn test_delete_after_create() -> anyhow::Result<()> {
     Ok(())
 }
 
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_create_with_missing_stdin() -> anyhow::Result<()> {
+    let dir = tempdir().unwrap();
+    let id = "test-create-with-missing-stdin";
+    create_bundle(dir.path(), None).unwrap();
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    local
+        .task_create(CreateTaskRequest {
+            id: id.to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
+            stdin: dir.path().join("missing").to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    local
+        .task_delete(DeleteRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    Ok(())
+}
+
 // Use a multi threaded runtime because LocalWithDestructor needs
 // it to run its async drop.
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
@@ -351,6 +396,82 @@ async fn test_cri_task() -> Result<()> {
     Ok(())
 }
 
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_pod_stop_kills_its_tasks() -> Result<()> {
+    let (etx, _erx) = channel();
+    let mut local =
+        Local::<InstanceStub, _>::new(etx, WaitableCell::new(), "test_namespace", "/test/address");
+    let pods = Arc::<Pods>::default();
+    local.pods = pods.clone();
+    let local = Arc::new(local);
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let service = Arc::new(PodSandbox::new(pods, WaitableCell::new()));
+    service.create(CreateSandboxRequest {
+        sandbox_id: "test-pod".to_string(),
+        ..Default::default()
+    })?;
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, Some(with_cri_sandbox(None, "test-pod".to_string())))?;
+    let create = CreateTaskRequest {
+        id: "test-pod-task".to_string(),
+        bundle: dir.to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    local.task_create(create.clone()).await?;
+    local
+        .task_start(StartRequest {
+            id: "test-pod-task".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let s = service.clone();
+    let stop = tokio::spawn(async move {
+        s.stop(StopSandboxRequest {
+            sandbox_id: "test-pod".to_string(),
+            timeout_secs: 10,
+            ..Default::default()
+        })
+        .await
+    });
+
+    local
+        .task_wait(WaitRequest {
+            id: "test-pod-task".to_string(),
+            ..Default::default()
+        })
+        .with_timeout(Duration::from_secs(5))
+        .await
+        .unwrap()?;
+
+    // the pod is stopped only once its tasks are deleted
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!stop.is_finished());
+
+    local
+        .task_delete(DeleteRequest {
+            id: "test-pod-task".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    stop.with_timeout(Duration::from_secs(5))
+        .await
+        .unwrap()
+        .unwrap()?;
+
+    match local.task_create(create).await.unwrap_err() {
+        Error::FailedPrecondition(_) => {}
+        e => return Err(e),
+    }
+
+    Ok(())
+}
+
 // Use a multi threaded runtime because LocalWithDestructor needs
 // it to run its async drop.
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
@@ -494,6 +615,244 @@ async fn test_task_lifecycle() -> Result<()> {
     Ok(())
 }
 
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_task_update() -> Result<()> {
+    let dir = tempdir()?;
+    let id = "test-task-update";
+    create_bundle(dir.path(), None)?;
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    local
+        .task_create(CreateTaskRequest {
+            id: id.to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let update = |resources: Option<&str>| UpdateTaskRequest {
+        id: id.to_string(),
+        resources: resources
+            .map(|json| Any {
+                type_url: "types.containerd.io/opencontainers/runtime-spec/1/LinuxResources"
+                    .to_string(),
+                value: json.as_bytes().to_vec(),
+                ..Default::default()
+            })
+            .into(),
+        ..Default::default()
+    };
+
+    let res = local.task_update(update(None)).await;
+    assert!(matches!(res, Err(Error::InvalidArgument(_))), "{res:?}");
+
+    let res = local.task_update(update(Some("not json"))).await;
+    assert!(matches!(res, Err(Error::InvalidArgument(_))), "{res:?}");
+
+    // the stub doesn't implement the update hook
+    let resources = r#"{"memory":{"limit":268435456},"cpu":{"quota":50000,"period":100000}}"#;
+    let res = local.task_update(update(Some(resources))).await;
+    assert!(matches!(res, Err(Error::Unimplemented(_))), "{res:?}");
+
+    local
+        .task_start(StartRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await?;
+    local
+        .task_kill(KillRequest {
+            id: id.to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+    local
+        .task_wait(WaitRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    // an exited task can't be updated
+    let res = local.task_update(update(Some(resources))).await;
+    assert!(matches!(res, Err(Error::FailedPrecondition(_))), "{res:?}");
+
+    Ok(())
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_task_pids_and_close_io() -> Result<()> {
+    let dir = tempdir()?;
+    let id = "test-task-pids";
+    create_bundle(dir.path(), None)?;
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    local
+        .task_create(CreateTaskRequest {
+            id: id.to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let pids = PidsRequest {
+        id: id.to_string(),
+        ..Default::default()
+    };
+    let res = local.task_pids(pids.clone()).await;
+    assert!(matches!(res, Err(Error::FailedPrecondition(_))), "{res:?}");
+
+    local
+        .task_start(StartRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    // the stub runs in the shim process, so it's the only process reported
+    let processes = local.task_pids(pids).await?.processes;
+    let processes: Vec<_> = processes.iter().map(|p| p.pid).collect();
+    assert_eq!(processes, [std::process::id()]);
+
+    local
+        .task_close_io(CloseIORequest {
+            id: id.to_string(),
+            stdin: true,
+            ..Default::default()
+        })
+        .await?;
+
+    let res = local
+        .task_close_io(CloseIORequest {
+            id: "missing".to_string(),
+            stdin: true,
+            ..Default::default()
+        })
+        .await;
+    assert!(matches!(res, Err(Error::NotFound(_))), "{res:?}");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_task_resize_pty() -> Result<()> {
+    let dir = tempdir()?;
+    create_bundle(dir.path(), None)?;
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "with-terminal".to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
+            terminal: true,
+            ..Default::default()
+        })
+        .await?;
+    local
+        .task_resize_pty(ResizePtyRequest {
+            id: "with-terminal".to_string(),
+            width: 80,
+            height: 24,
+            ..Default::default()
+        })
+        .await?;
+
+    let dir = tempdir()?;
+    create_bundle(dir.path(), None)?;
+    local
+        .task_create(CreateTaskRequest {
+            id: "without-terminal".to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+    let res = local
+        .task_resize_pty(ResizePtyRequest {
+            id: "without-terminal".to_string(),
+            width: 80,
+            height: 24,
+            ..Default::default()
+        })
+        .await;
+    assert!(matches!(res, Err(Error::FailedPrecondition(_))), "{res:?}");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_task_create_hook_failure() -> Result<()> {
+    use oci_spec::runtime::{HookBuilder, HooksBuilder, SpecBuilder};
+
+    let dir = tempdir()?;
+    let id = "test-hook-failure";
+    let hook = HookBuilder::default()
+        .path("/bin/sh")
+        .args(["sh".to_string(), "-c".to_string(), "exit 1".to_string()])
+        .build()?;
+    let hooks = HooksBuilder::default().create_runtime([hook]).build()?;
+    let spec = SpecBuilder::default().hooks(hooks).build()?;
+    create_bundle(dir.path(), Some(spec))?;
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let res = local
+        .task_create(CreateTaskRequest {
+            id: id.to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await;
+    assert!(res.is_err(), "{res:?}");
+
+    let res = local
+        .task_state(StateRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await;
+    assert!(matches!(res, Err(Error::NotFound(_))), "{res:?}");
+
+    Ok(())
+}
+
 #[test]
 fn test_default_runtime_options() -> Result<()> {
     let options: Option<&Any> = None;
@@ -525,6 +884,163 @@ fn test_custom_runtime_options() -> Result<()> {
     let config = Config::get_from_options(req.options.as_ref()).unwrap();
 
     assert!(config.systemd_cgroup);
+    assert_eq!(config.image_policy, ImagePolicy::default());
+    assert_eq!(config.containerd_retry, RetryPolicy::default());
+    assert_eq!(config.isolation, Isolation::Container);
 
     Ok(())
 }
+
+#[test]
+fn test_image_policy_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: r#"
+            SystemdCgroup = false
+            [ImagePolicy]
+            RequireDigest = true
+            RequireSignature = true
+            TrustedKeys = ["/etc/runwasi/cosign.pub"]
+        "#
+        .to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+
+    assert_eq!(
+        config.image_policy,
+        ImagePolicy {
+            require_digest: true,
+            require_signature: true,
+            trusted_keys: vec!["/etc/runwasi/cosign.pub".into()],
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_blob_cache_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: r#"
+            [BlobCache]
+            Dir = "/var/lib/runwasi/blobs"
+        "#
+        .to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+
+    assert_eq!(
+        config.blob_cache,
+        BlobCacheConfig {
+            dir: Some("/var/lib/runwasi/blobs".into()),
+            ..Default::default()
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_containerd_retry_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: r#"
+            SystemdCgroup = false
+            [ContainerdRetry]
+            MaxRetries = 5
+            RequestTimeoutMs = 1000
+        "#
+        .to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+
+    assert_eq!(
+        config.containerd_retry,
+        RetryPolicy {
+            max_retries: 5,
+            request_timeout_ms: 1000,
+            ..Default::default()
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_isolation_runtime_options() -> Result<()> {
+    for (body, isolation) in [
+        ("Isolation = \"in-process\"\n", Isolation::InProcess),
+        ("Isolation = \"InProcess\"\n", Isolation::InProcess),
+        ("isolation = \"container\"\n", Isolation::Container),
+    ] {
+        let options = Options {
+            type_url: "runtimeoptions.v1.Options".to_string(),
+            config_path: "".to_string(),
+            config_body: body.to_string(),
+        };
+        let options = Any {
+            type_url: options.type_url.clone(),
+            value: options.encode_to_vec(),
+            special_fields: SpecialFields::default(),
+        };
+
+        let config = Config::get_from_options(Some(&options)).unwrap();
+        assert_eq!(config.isolation, isolation, "{body}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_replay_unpublished_exits() -> Result<()> {
+    let root = tempdir()?;
+    let store = TaskStore::new(root.path());
+    let record = |exit_published| TaskRecord {
+        config: InstanceConfig::default(),
+        pid: Some(42),
+        exit: Some((137, Utc::now())),
+        exit_published,
+    };
+    store.save("unpublished", &record(false))?;
+    store.save("published", &record(true))?;
+
+    let (tx, mut rx) = channel();
+    let mut local =
+        Local::<InstanceStub, _>::new(tx, WaitableCell::new(), "test_namespace", "/test/address");
+    local.store = store.clone();
+    local.replay_exits();
+
+    let (topic, event) = rx.try_recv().unwrap();
+    assert_eq!(topic, "/tasks/exit");
+    let event = event.downcast_ref::<TaskExit>().unwrap();
+    assert_eq!(event.container_id, "unpublished");
+    assert_eq!(event.exit_status, 137);
+    assert_eq!(event.pid, 42);
+    assert!(rx.try_recv().is_err());
+
+    // the exit isn't replayed again once it's published
+    assert!(store.load("unpublished")?.unwrap().exit_published);
+    Ok(())
+}
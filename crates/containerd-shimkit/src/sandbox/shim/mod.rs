@@ -1,13 +1,16 @@
 //! The shim exposes the [Config] struct to configure the shim and [OtlpConfig] module to enable tracing if the `opentelemetry` feature is enabled.
 
-pub use local::Config;
+pub use local::{BlobCacheConfig, Config, ImagePolicy, Isolation, RetryPolicy};
 
 mod events;
 mod instance_data;
 mod local;
+#[cfg_attr(windows, allow(dead_code))]
+mod pod;
 #[allow(clippy::module_inception)]
 mod shim;
 mod task_state;
+mod task_store;
 pub(crate) use shim::Shim;
 
 #[cfg(feature = "opentelemetry")]
@@ -0,0 +1,522 @@
+//! The sandbox service of the shim, which lets containerd use the shim as the controller of
+//! the pods whose containers it runs, with the `shim` sandboxer of the CRI plugin.
+//!
+//! The pod is owned by the shim process: the shim is started for the pod, before any of its
+//! containers, and its pid is the pid of the pod, so there's no need for a pause container.
+//! The network namespace of the pod is created by containerd, and its path is only recorded
+//! so it's reported in the status of the pod: the shim doesn't join it, nor does it create a
+//! cgroup for the pod. Each container is isolated as set in its own spec by the CRI plugin.
+//!
+//! The tasks created with the id of a pod in their `io.kubernetes.cri.sandbox-id` annotation
+//! belong to the pod. Stopping the pod kills its tasks, and the pod exits once they have exited.
+//! A task that is still running [`KILL_TIMEOUT`] after it's sent `SIGKILL`, e.g., a guest running
+//! in the shim process that doesn't yield, doesn't keep the pod from exiting.
+//! The shim exits once all its pods are shut down.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use containerd_shim::{TtrpcContext, TtrpcResult};
+use containerd_shim_protos::sandbox::sandbox::{
+    CreateSandboxRequest, CreateSandboxResponse, PingRequest, PingResponse, PlatformRequest,
+    PlatformResponse, SandboxStatusRequest, SandboxStatusResponse, ShutdownSandboxRequest,
+    ShutdownSandboxResponse, StartSandboxRequest, StartSandboxResponse, StopSandboxRequest,
+    StopSandboxResponse, WaitSandboxRequest, WaitSandboxResponse,
+};
+use containerd_shim_protos::sandbox::sandbox_ttrpc::Sandbox;
+use containerd_shim_protos::types::platform::Platform;
+use futures::FutureExt as _;
+use futures::future::BoxFuture;
+use log::debug;
+
+use crate::sandbox::Instance;
+use crate::sandbox::async_utils::AmbientRuntime as _;
+use crate::sandbox::shim::events::ToTimestamp;
+use crate::sandbox::shim::instance_data::InstanceData;
+use crate::sandbox::sync::WaitableCell;
+use crate::sandbox::{Error, Result};
+
+/// The state reported for a pod that is running.
+const STATE_READY: &str = "SANDBOX_READY";
+/// The state reported for a pod that is created, or stopped.
+const STATE_NOT_READY: &str = "SANDBOX_NOTREADY";
+
+/// The signal sent to the tasks of a pod when it's stopped.
+const SIGTERM: u32 = 15;
+/// The signal sent to the tasks of a pod that are still running once the stop timeout expires.
+const SIGKILL: u32 = 9;
+/// How long the tasks of a pod are waited for after they are sent `SIGKILL`.
+const KILL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A task that belongs to a pod, which the pod can kill when it's stopped.
+pub(super) trait PodTask: Send + Sync {
+    /// Sends the `signal` to the task.
+    fn kill(&self, signal: u32) -> BoxFuture<'_, Result<()>>;
+
+    /// Waits for the task to exit, if it was started.
+    fn wait(&self) -> BoxFuture<'_, ()>;
+}
+
+impl<T: Instance + Send + Sync> PodTask for InstanceData<T> {
+    fn kill(&self, signal: u32) -> BoxFuture<'_, Result<()>> {
+        InstanceData::kill(self, signal).boxed()
+    }
+
+    fn wait(&self) -> BoxFuture<'_, ()> {
+        async move {
+            if self.pid().is_some() {
+                InstanceData::wait(self).await;
+            }
+        }
+        .boxed()
+    }
+}
+
+/// A pod managed by the shim.
+struct Pod {
+    bundle: String,
+    netns_path: String,
+    created_at: DateTime<Utc>,
+    started: WaitableCell<()>,
+    exit: WaitableCell<(u32, DateTime<Utc>)>,
+    tasks: Mutex<HashMap<String, Arc<dyn PodTask>>>,
+}
+
+impl Pod {
+    /// Kills the tasks of the pod, with `SIGTERM` and then with `SIGKILL` once the `timeout`
+    /// expires, and sets the exit of the pod once they have all exited, or [`KILL_TIMEOUT`]
+    /// after they are sent `SIGKILL`.
+    async fn stop(&self, timeout: Duration) {
+        self.kill_tasks(SIGTERM).await;
+        if self
+            .exit_once_exited()
+            .with_timeout(timeout)
+            .await
+            .is_some()
+        {
+            return;
+        }
+        self.kill_tasks(SIGKILL).await;
+        if self
+            .exit_once_exited()
+            .with_timeout(KILL_TIMEOUT)
+            .await
+            .is_none()
+        {
+            log::warn!(
+                "the tasks of the pod are still running {}s after SIGKILL",
+                KILL_TIMEOUT.as_secs()
+            );
+            let _ = self.exit.set((0, Utc::now()));
+        }
+    }
+
+    async fn kill_tasks(&self, signal: u32) {
+        let tasks: Vec<_> = self.tasks.lock().unwrap().clone().into_iter().collect();
+        for (id, task) in tasks {
+            // the task may have exited already, or not be started yet
+            if let Err(err) = task.kill(signal).await {
+                debug!("failed to kill task {id} with signal {signal}: {err}");
+            }
+        }
+    }
+
+    /// Waits for all the tasks of the pod to exit, and sets the exit of the pod.
+    async fn exit_once_exited(&self) {
+        loop {
+            let tasks = self.tasks.lock().unwrap().clone();
+            futures::future::join_all(tasks.values().map(|task| task.wait())).await;
+            if self.exit_if_no_new_tasks(&tasks) {
+                return;
+            }
+        }
+    }
+
+    /// Sets the exit of the pod if no task was added to it since `tasks`, with the tasks locked
+    /// so that no task is added to the pod once it's stopped.
+    fn exit_if_no_new_tasks(&self, tasks: &HashMap<String, Arc<dyn PodTask>>) -> bool {
+        let current = self.tasks.lock().unwrap();
+        if current.keys().any(|id| !tasks.contains_key(id)) {
+            return false;
+        }
+        let _ = self.exit.set((0, Utc::now()));
+        true
+    }
+}
+
+/// The pods managed by the shim, shared by the sandbox and task services.
+#[derive(Default)]
+pub(super) struct Pods(Mutex<HashMap<String, Arc<Pod>>>);
+
+impl Pods {
+    pub(super) fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    fn get(&self, id: &str) -> Result<Arc<Pod>> {
+        let pod = self.0.lock().unwrap().get(id).cloned();
+        pod.ok_or_else(|| Error::NotFound(id.to_string()))
+    }
+
+    /// Adds the task `id` to the pod `pod_id`, if the pod is managed by the shim.
+    /// Tasks can't be added to a pod that is stopped.
+    pub(super) fn add_task(&self, pod_id: &str, id: &str, task: Arc<dyn PodTask>) -> Result<()> {
+        let Ok(pod) = self.get(pod_id) else {
+            return Ok(());
+        };
+        let mut tasks = pod.tasks.lock().unwrap();
+        if pod.exit.wait().now_or_never().is_some() {
+            return Err(Error::FailedPrecondition(format!(
+                "sandbox {pod_id} is stopped"
+            )));
+        }
+        tasks.insert(id.to_string(), task);
+        Ok(())
+    }
+
+    /// Removes the deleted task `id` from the pod it belongs to, if any.
+    pub(super) fn remove_task(&self, id: &str) {
+        let pods: Vec<_> = self.0.lock().unwrap().values().cloned().collect();
+        for pod in pods {
+            pod.tasks.lock().unwrap().remove(id);
+        }
+    }
+}
+
+/// PodSandbox implements the Sandbox service for a containerd shim.
+pub struct PodSandbox {
+    pods: Arc<Pods>,
+    exit: WaitableCell<()>,
+}
+
+impl PodSandbox {
+    pub(super) fn new(pods: Arc<Pods>, exit: WaitableCell<()>) -> Self {
+        Self { pods, exit }
+    }
+
+    pub(super) fn create(&self, req: CreateSandboxRequest) -> Result<()> {
+        let mut pods = self.pods.0.lock().unwrap();
+        if pods.contains_key(&req.sandbox_id) {
+            return Err(Error::AlreadyExists(req.sandbox_id));
+        }
+        let pod = Pod {
+            bundle: req.bundle_path,
+            netns_path: req.netns_path,
+            created_at: Utc::now(),
+            started: WaitableCell::new(),
+            exit: WaitableCell::new(),
+            tasks: Mutex::default(),
+        };
+        pods.insert(req.sandbox_id, Arc::new(pod));
+        Ok(())
+    }
+
+    fn start(&self, req: StartSandboxRequest) -> Result<StartSandboxResponse> {
+        let pod = self.pods.get(&req.sandbox_id)?;
+        if pod.started.set(()).is_err() {
+            return Err(Error::FailedPrecondition(format!(
+                "sandbox {} already started",
+                req.sandbox_id
+            )));
+        }
+        Ok(StartSandboxResponse {
+            pid: std::process::id(),
+            created_at: Some(pod.created_at.to_timestamp()).into(),
+            ..Default::default()
+        })
+    }
+
+    pub(super) async fn stop(&self, req: StopSandboxRequest) -> Result<()> {
+        let pod = self.pods.get(&req.sandbox_id)?;
+        pod.stop(Duration::from_secs(req.timeout_secs.into())).await;
+        Ok(())
+    }
+
+    fn wait(&self, req: WaitSandboxRequest) -> Result<WaitSandboxResponse> {
+        let pod = self.pods.get(&req.sandbox_id)?;
+        // the pod may run for a long time, so its exit is awaited on the runtime, and the
+        // thread of the ttrpc server only waits for the result, without driving the runtime
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        async move {
+            let _ = tx.send(*pod.exit.wait().await);
+        }
+        .spawn();
+        let (exit_status, exited_at) = rx
+            .recv()
+            .map_err(|_| Error::Others(format!("wait for sandbox {} failed", req.sandbox_id)))?;
+        Ok(WaitSandboxResponse {
+            exit_status,
+            exited_at: Some(exited_at.to_timestamp()).into(),
+            ..Default::default()
+        })
+    }
+
+    fn status(&self, req: SandboxStatusRequest) -> Result<SandboxStatusResponse> {
+        let pod = self.pods.get(&req.sandbox_id)?;
+        let exited_at = pod.exit.wait().now_or_never().map(|(_, at)| *at);
+        let state = match (pod.started.wait().now_or_never(), exited_at) {
+            (Some(_), None) => STATE_READY,
+            _ => STATE_NOT_READY,
+        };
+        let info = HashMap::from([
+            ("bundle".to_string(), pod.bundle.clone()),
+            ("netns_path".to_string(), pod.netns_path.clone()),
+        ]);
+        Ok(SandboxStatusResponse {
+            sandbox_id: req.sandbox_id,
+            pid: std::process::id(),
+            state: state.to_string(),
+            info,
+            created_at: Some(pod.created_at.to_timestamp()).into(),
+            exited_at: exited_at.map(ToTimestamp::to_timestamp).into(),
+            ..Default::default()
+        })
+    }
+
+    async fn shutdown(&self, req: ShutdownSandboxRequest) -> Result<()> {
+        let pod = self.pods.get(&req.sandbox_id)?;
+        // the pod is usually stopped already, otherwise its tasks are killed right away,
+        // and the wait for them to exit is bounded
+        pod.stop(Duration::ZERO).await;
+        self.pods.0.lock().unwrap().remove(&req.sandbox_id);
+        // the tasks of the remaining pods keep the shim alive
+        if self.pods.is_empty() {
+            let _ = self.exit.set(());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the platform of the shim, with the names used by OCI images.
+fn platform() -> Platform {
+    let architecture = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    };
+    Platform {
+        os: std::env::consts::OS.to_string(),
+        architecture: architecture.to_string(),
+        ..Default::default()
+    }
+}
+
+impl Sandbox for PodSandbox {
+    fn create_sandbox(
+        &self,
+        _ctx: &TtrpcContext,
+        req: CreateSandboxRequest,
+    ) -> TtrpcResult<CreateSandboxResponse> {
+        debug!("create sandbox: {} {}", req.sandbox_id, req.netns_path);
+        self.create(req)?;
+        Ok(CreateSandboxResponse::new())
+    }
+
+    fn start_sandbox(
+        &self,
+        _ctx: &TtrpcContext,
+        req: StartSandboxRequest,
+    ) -> TtrpcResult<StartSandboxResponse> {
+        debug!("start sandbox: {}", req.sandbox_id);
+        Ok(self.start(req)?)
+    }
+
+    fn platform(&self, _ctx: &TtrpcContext, req: PlatformRequest) -> TtrpcResult<PlatformResponse> {
+        debug!("platform: {}", req.sandbox_id);
+        self.pods.get(&req.sandbox_id)?;
+        Ok(PlatformResponse {
+            platform: Some(platform()).into(),
+            ..Default::default()
+        })
+    }
+
+    fn stop_sandbox(
+        &self,
+        _ctx: &TtrpcContext,
+        req: StopSandboxRequest,
+    ) -> TtrpcResult<StopSandboxResponse> {
+        debug!("stop sandbox: {}", req.sandbox_id);
+        self.stop(req).block_on()?;
+        Ok(StopSandboxResponse::new())
+    }
+
+    fn wait_sandbox(
+        &self,
+        _ctx: &TtrpcContext,
+        req: WaitSandboxRequest,
+    ) -> TtrpcResult<WaitSandboxResponse> {
+        debug!("wait sandbox: {}", req.sandbox_id);
+        Ok(self.wait(req)?)
+    }
+
+    fn sandbox_status(
+        &self,
+        _ctx: &TtrpcContext,
+        req: SandboxStatusRequest,
+    ) -> TtrpcResult<SandboxStatusResponse> {
+        debug!("sandbox status: {}", req.sandbox_id);
+        Ok(self.status(req)?)
+    }
+
+    fn ping_sandbox(&self, _ctx: &TtrpcContext, req: PingRequest) -> TtrpcResult<PingResponse> {
+        self.pods.get(&req.sandbox_id)?;
+        Ok(PingResponse::new())
+    }
+
+    fn shutdown_sandbox(
+        &self,
+        _ctx: &TtrpcContext,
+        req: ShutdownSandboxRequest,
+    ) -> TtrpcResult<ShutdownSandboxResponse> {
+        debug!("shutdown sandbox: {}", req.sandbox_id);
+        self.shutdown(req).block_on()?;
+        Ok(ShutdownSandboxResponse::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A task that records the signals it's sent, and exits when it's killed with `SIGKILL`.
+    #[derive(Default)]
+    struct FakeTask {
+        signals: Mutex<Vec<u32>>,
+        killed: WaitableCell<()>,
+    }
+
+    impl PodTask for FakeTask {
+        fn kill(&self, signal: u32) -> BoxFuture<'_, Result<()>> {
+            self.signals.lock().unwrap().push(signal);
+            if signal == SIGKILL {
+                let _ = self.killed.set(());
+            }
+            async { Ok(()) }.boxed()
+        }
+
+        fn wait(&self) -> BoxFuture<'_, ()> {
+            self.killed.wait().map(|_| ()).boxed()
+        }
+    }
+
+    fn create_request(id: &str) -> CreateSandboxRequest {
+        CreateSandboxRequest {
+            sandbox_id: id.to_string(),
+            bundle_path: format!("/run/sandboxes/{id}"),
+            netns_path: format!("/var/run/netns/{id}"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pod_lifecycle() -> anyhow::Result<()> {
+        let exit = WaitableCell::new();
+        let service = PodSandbox::new(Arc::default(), exit.clone());
+
+        service.create(create_request("pod"))?;
+        assert!(matches!(
+            service.create(create_request("pod")),
+            Err(Error::AlreadyExists(_))
+        ));
+
+        let status = |service: &PodSandbox| {
+            service.status(SandboxStatusRequest {
+                sandbox_id: "pod".to_string(),
+                ..Default::default()
+            })
+        };
+        assert_eq!(status(&service)?.state, STATE_NOT_READY);
+        assert_eq!(status(&service)?.info["netns_path"], "/var/run/netns/pod");
+
+        let started = service.start(StartSandboxRequest {
+            sandbox_id: "pod".to_string(),
+            ..Default::default()
+        })?;
+        assert_eq!(started.pid, std::process::id());
+        assert_eq!(status(&service)?.state, STATE_READY);
+
+        assert!(status(&service)?.exited_at.is_none());
+
+        service
+            .stop(StopSandboxRequest {
+                sandbox_id: "pod".to_string(),
+                ..Default::default()
+            })
+            .block_on()?;
+        let wait = WaitSandboxRequest {
+            sandbox_id: "pod".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(service.wait(wait)?.exit_status, 0);
+        let stopped = status(&service)?;
+        assert_eq!(stopped.state, STATE_NOT_READY);
+        assert!(stopped.exited_at.is_some());
+
+        service
+            .shutdown(ShutdownSandboxRequest {
+                sandbox_id: "pod".to_string(),
+                ..Default::default()
+            })
+            .block_on()?;
+        assert!(matches!(status(&service), Err(Error::NotFound(_))));
+        assert!(exit.wait().now_or_never().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shutdown_waits_for_all_pods() -> anyhow::Result<()> {
+        let exit = WaitableCell::new();
+        let service = PodSandbox::new(Arc::default(), exit.clone());
+        service.create(create_request("a"))?;
+        service.create(create_request("b"))?;
+
+        service
+            .shutdown(ShutdownSandboxRequest {
+                sandbox_id: "a".to_string(),
+                ..Default::default()
+            })
+            .block_on()?;
+        assert!(exit.wait().now_or_never().is_none());
+
+        service
+            .shutdown(ShutdownSandboxRequest {
+                sandbox_id: "b".to_string(),
+                ..Default::default()
+            })
+            .block_on()?;
+        assert!(exit.wait().now_or_never().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_waits_for_the_tasks_to_exit() -> anyhow::Result<()> {
+        let service = PodSandbox::new(Arc::default(), WaitableCell::new());
+        service.create(create_request("pod"))?;
+
+        let task = Arc::new(FakeTask::default());
+        service.pods.add_task("pod", "task", task.clone())?;
+        // the tasks of pods that aren't managed by the shim are ignored
+        service.pods.add_task("other", "task", task.clone())?;
+
+        // the task ignores SIGTERM, so it's killed once the timeout expires
+        let pod = service.pods.get("pod")?;
+        pod.stop(Duration::ZERO).block_on();
+        assert_eq!(*task.signals.lock().unwrap(), [SIGTERM, SIGKILL]);
+
+        // the pod exits even though its task isn't deleted yet
+        let status = SandboxStatusRequest {
+            sandbox_id: "pod".to_string(),
+            ..Default::default()
+        };
+        assert!(service.status(status)?.exited_at.is_some());
+
+        assert!(matches!(
+            service.pods.add_task("pod", "other-task", task),
+            Err(Error::FailedPrecondition(_))
+        ));
+        Ok(())
+    }
+}
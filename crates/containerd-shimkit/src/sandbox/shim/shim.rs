@@ -1,6 +1,9 @@
 use std::env::current_dir;
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 use chrono::Utc;
 use containerd_shim::error::Error as ShimError;
@@ -14,15 +17,27 @@ use crate::sandbox::async_utils::AmbientRuntime as _;
 use crate::sandbox::instance::Instance;
 use crate::sandbox::shim::events::{RemoteEventSender, ToTimestamp};
 use crate::sandbox::shim::local::Local;
+use crate::sandbox::shim::pod::{PodSandbox, Pods};
+use crate::sandbox::shim::task_store::TaskStore;
 use crate::sandbox::sync::WaitableCell;
+use crate::sandbox::{Error, InstanceConfig};
+use crate::sys::DEFAULT_CONTAINER_ROOT_DIR;
+
+/// Maximum time the shim waits for the events of its tasks to be published before it exits.
+const EVENTS_FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// Shim implements the [containerd_shim::Shim] trait using `Local<T>` as the task service.
 ///
 /// It can be used as [`containerd_shim::synchronous::run<Shim<I>>()`] to start the shim.
+/// It also provides [`PodSandbox`] as the sandbox service, for the pods the shim is started for.
 pub struct Shim<I: Instance + Sync + Send> {
     namespace: String,
     containerd_address: String,
     exit: WaitableCell<()>,
+    pods: Arc<Pods>,
+    /// The events of the task service, flushed before the shim exits.
+    events: OnceLock<RemoteEventSender>,
+    runtime_id: String,
     _id: String,
     _phantom: PhantomData<I>,
 }
@@ -47,11 +62,14 @@ where
     type T = Local<I>;
 
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
-    fn new(_runtime_id: &str, args: &Flags, _config: &mut shim::Config) -> Self {
+    fn new(runtime_id: &str, args: &Flags, _config: &mut shim::Config) -> Self {
         Shim {
             namespace: args.namespace.to_string(),
             containerd_address: args.address.clone(),
             exit: WaitableCell::new(),
+            pods: Arc::default(),
+            events: OnceLock::new(),
+            runtime_id: runtime_id.to_string(),
             _id: args.id.to_string(),
             _phantom: PhantomData,
         }
@@ -81,6 +99,12 @@ where
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
     fn wait(&mut self) {
         self.exit.wait().block_on();
+        // the exit and delete events of the tasks must reach containerd before the shim exits
+        if let Some(events) = self.events.get() {
+            if !events.flush(EVENTS_FLUSH_TIMEOUT) {
+                log::warn!("exiting before all the task events are published");
+            }
+        }
     }
 
     #[cfg_attr(
@@ -89,8 +113,13 @@ where
     )]
     fn create_task_service(&self, publisher: RemotePublisher) -> Self::T {
         let events = RemoteEventSender::new(&self.namespace, publisher);
+        let _ = self.events.set(events.clone());
         let exit = self.exit.clone();
-        Local::<I>::new(events, exit, &self.namespace, &self.containerd_address)
+        let mut local = Local::<I>::new(events, exit, &self.namespace, &self.containerd_address);
+        local.pods = self.pods.clone();
+        local.store = TaskStore::new(self.task_store_root());
+        local.replay_exits();
+        local
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
@@ -102,3 +131,38 @@ where
         })
     }
 }
+
+impl<I> Shim<I>
+where
+    I: Instance + Sync + Send,
+{
+    /// Returns the root dir of the runtime the tasks are persisted under.
+    ///
+    /// The shim runs in the bundle of the task it's started for, so a restarted shim finds the
+    /// root dir set in the options of the bundle, as its tasks were created with.
+    fn task_store_root(&self) -> PathBuf {
+        let root = current_dir().map_err(Error::from).and_then(|bundle| {
+            let config = InstanceConfig {
+                bundle,
+                namespace: self.namespace.clone(),
+                ..Default::default()
+            };
+            config.determine_rootdir(&self.runtime_id)
+        });
+        match root {
+            Ok(root) => root,
+            Err(err) => {
+                log::warn!("failed to determine the root dir of the runtime: {err}");
+                DEFAULT_CONTAINER_ROOT_DIR
+                    .join(&self.runtime_id)
+                    .join(&self.namespace)
+            }
+        }
+    }
+
+    /// Creates the sandbox service, sharing its pods with the task service.
+    #[cfg(unix)]
+    pub(crate) fn create_sandbox_service(&self) -> PodSandbox {
+        PodSandbox::new(self.pods.clone(), self.exit.clone())
+    }
+}
@@ -29,6 +29,14 @@ impl TaskState {
         Ok(())
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    pub fn update(&self) -> Result<()> {
+        match self {
+            Self::Created | Self::Started => Ok(()),
+            _ => state_transition_error(*self, "Updating"),
+        }
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     pub fn delete(&mut self) -> Result<()> {
         *self = match self {
@@ -0,0 +1,231 @@
+//! The state of the tasks of the shim, persisted under the root dir of the runtime.
+//!
+//! The tasks of a shim that exits unexpectedly keep running in their containers. When the shim
+//! is restarted, it finds their state in the store, and recovers them with [`Instance::recover`]
+//! the first time containerd refers to them, e.g., with the `connect` or `state` RPCs.
+//!
+//! [`Instance::recover`]: crate::sandbox::Instance::recover
+
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::sandbox::{Error, InstanceConfig, Result};
+
+/// The directory of the store, under the root dir of the runtime.
+const TASK_STORE_DIR: &str = ".tasks";
+
+/// The persisted state of a task.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(super) struct TaskRecord {
+    /// The config the instance was created with.
+    pub config: InstanceConfig,
+    /// The pid of the task, once it's started.
+    pub pid: Option<u32>,
+    /// The exit status of the task, once it has exited.
+    pub exit: Option<(u32, DateTime<Utc>)>,
+    /// Whether the `TaskExit` event was published, so the exit is replayed by a restarted
+    /// shim otherwise.
+    #[serde(default)]
+    pub exit_published: bool,
+}
+
+/// Store of the state of the tasks, with a JSON file per task.
+///
+/// The default store doesn't persist anything.
+#[derive(Clone, Default, Debug)]
+pub(super) struct TaskStore {
+    dir: Option<PathBuf>,
+    /// Serializes the changes to the store, so a task that is removed isn't saved again.
+    lock: Arc<Mutex<()>>,
+}
+
+impl TaskStore {
+    /// Creates a store under the `root` dir of the runtime.
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        let dir = root.as_ref().join(TASK_STORE_DIR);
+        Self {
+            dir: Some(dir),
+            lock: Arc::default(),
+        }
+    }
+
+    fn path(&self, id: &str) -> Result<Option<PathBuf>> {
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+        if id.is_empty() || id.contains(['/', '\\']) || id.starts_with('.') {
+            return Err(Error::InvalidArgument(format!("invalid task id {id:?}")));
+        }
+        Ok(Some(dir.join(format!("{id}.json"))))
+    }
+
+    /// Saves the state of the task `id`, replacing its previous state atomically.
+    pub fn save(&self, id: &str, record: &TaskRecord) -> Result<()> {
+        let Some(path) = self.path(id)? else {
+            return Ok(());
+        };
+        let _lock = self.lock.lock().unwrap();
+        write(&path, record)
+    }
+
+    /// Saves the state of the task `id`, logging the failures, so they don't fail the request
+    /// that changed the state of the task.
+    pub fn persist(&self, id: &str, record: &TaskRecord) {
+        if let Err(err) = self.save(id, record) {
+            log::warn!("failed to persist the state of task {id}: {err}");
+        }
+    }
+
+    /// Updates the state of the task `id` with `f`, unless the task was removed, e.g., when
+    /// it's deleted while its exit is published.
+    pub fn update(&self, id: &str, f: impl FnOnce(&mut TaskRecord)) -> Result<()> {
+        let Some(path) = self.path(id)? else {
+            return Ok(());
+        };
+        let _lock = self.lock.lock().unwrap();
+        let Some(mut record) = self.load(id)? else {
+            return Ok(());
+        };
+        f(&mut record);
+        write(&path, &record)
+    }
+
+    /// Returns the tasks in the store, with their state.
+    pub fn list(&self) -> Result<Vec<(String, TaskRecord)>> {
+        let Some(dir) = &self.dir else {
+            return Ok(vec![]);
+        };
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut tasks = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|id| id.to_str()) else {
+                continue;
+            };
+            match self.load(id) {
+                Ok(Some(record)) => tasks.push((id.to_string(), record)),
+                Ok(None) => {}
+                Err(err) => log::warn!("failed to load the state of task {id}: {err}"),
+            }
+        }
+        Ok(tasks)
+    }
+
+    /// Loads the state of the task `id`, if it's in the store.
+    pub fn load(&self, id: &str) -> Result<Option<TaskRecord>> {
+        let Some(path) = self.path(id)? else {
+            return Ok(None);
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some(serde_json::from_reader(file)?))
+    }
+
+    /// Removes the state of the task `id` from the store.
+    pub fn remove(&self, id: &str) -> Result<()> {
+        let Some(path) = self.path(id)? else {
+            return Ok(());
+        };
+        let _lock = self.lock.lock().unwrap();
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes the `record` to `path`, replacing the previous state atomically.
+///
+/// The state is written to a temporary file that is synced before it's renamed, so the store
+/// holds either the previous or the new state, even if the host crashes.
+fn write(path: &Path, record: &TaskRecord) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    let tmp = path.with_extension("json.tmp");
+    let mut file = File::create(&tmp)?;
+    serde_json::to_writer(&mut file, record)?;
+    file.sync_all()?;
+    std::fs::rename(tmp, path)?;
+    // the rename is only durable once the directory is synced
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_task_store() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let store = TaskStore::new(dir.path());
+        assert!(store.load("task")?.is_none());
+
+        let mut record = TaskRecord {
+            config: InstanceConfig {
+                bundle: "/run/bundle".into(),
+                ..Default::default()
+            },
+            pid: None,
+            exit: None,
+            exit_published: false,
+        };
+        store.save("task", &record)?;
+        record.pid = Some(42);
+        store.save("task", &record)?;
+
+        let loaded = store.load("task")?.unwrap();
+        assert_eq!(loaded.pid, Some(42));
+        assert_eq!(loaded.config.bundle, PathBuf::from("/run/bundle"));
+
+        assert!(store.load("../task").is_err());
+
+        store.update("task", |record| record.exit_published = true)?;
+        let tasks = store.list()?;
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].0, "task");
+        assert!(tasks[0].1.exit_published);
+
+        store.remove("task")?;
+        store.remove("task")?;
+        assert!(store.load("task")?.is_none());
+
+        // a removed task isn't saved again
+        store.update("task", |record| record.exit_published = true)?;
+        assert!(store.load("task")?.is_none());
+        assert!(store.list()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_task_store() -> anyhow::Result<()> {
+        let store = TaskStore::default();
+        let record = TaskRecord {
+            config: InstanceConfig::default(),
+            pid: Some(42),
+            exit: None,
+            exit_published: false,
+        };
+        store.save("task", &record)?;
+        assert!(store.load("task")?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind, IoSlice, IoSliceMut, Result};
+use std::os::fd::{AsRawFd as _, BorrowedFd, FromRawFd as _};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+
+use nix::pty::Winsize;
+use nix::sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg};
+use tempfile::TempDir;
+
+nix::ioctl_write_ptr_bad!(set_winsize, nix::libc::TIOCSWINSZ, Winsize);
+
+/// The unix socket an instance sends the master of its terminal to, as a `SCM_RIGHTS` message,
+/// like the `--console-socket` of runc.
+///
+/// The socket is created in a private temporary directory, removed when it's dropped, so no
+/// other user can connect to it, or create it before the shim does.
+pub struct ConsoleSocket {
+    path: PathBuf,
+    listener: UnixListener,
+    _dir: TempDir,
+}
+
+impl ConsoleSocket {
+    pub fn new() -> Result<Self> {
+        // the bundle path can be longer than the maximum length of a socket path
+        let dir = tempfile::Builder::new()
+            .prefix("runwasi-console-")
+            .tempdir()?;
+        let path = dir.path().join("console.sock");
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            path,
+            listener,
+            _dir: dir,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Receives the master of the terminal, which the instance sends while it's created.
+    pub fn receive(&self) -> Result<File> {
+        let (stream, _) = self.listener.accept().map_err(|err| match err.kind() {
+            ErrorKind::WouldBlock => IoError::new(
+                ErrorKind::NotConnected,
+                "the instance didn't send the master of its terminal",
+            ),
+            _ => err,
+        })?;
+        stream.set_nonblocking(false)?;
+
+        let mut buf = [0u8; 4096];
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let mut cmsg = nix::cmsg_space!([std::os::fd::RawFd; 1]);
+        let msg = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        for cmsg in msg.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                if let Some(&fd) = fds.first() {
+                    // SAFETY: the fd was just received, so it's owned by this process
+                    return Ok(unsafe { File::from_raw_fd(fd) });
+                }
+            }
+        }
+        Err(IoError::new(
+            ErrorKind::InvalidData,
+            "no file descriptor received on the console socket",
+        ))
+    }
+}
+
+/// Sends the `master` of a terminal to the console socket at `path`.
+pub fn send_console(path: impl AsRef<Path>, master: BorrowedFd<'_>) -> Result<()> {
+    let stream = UnixStream::connect(path)?;
+    let fds = [master.as_raw_fd()];
+    sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(b"/dev/ptmx")],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    Ok(())
+}
+
+/// Sets the size of the terminal of `master`.
+pub fn resize(master: &File, width: u32, height: u32) -> Result<()> {
+    let size = Winsize {
+        ws_row: u16::try_from(height).unwrap_or(u16::MAX),
+        ws_col: u16::try_from(width).unwrap_or(u16::MAX),
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: `size` is a valid `Winsize` for the duration of the call
+    unsafe { set_winsize(master.as_raw_fd(), &size) }?;
+    Ok(())
+}
+
+/// Copies `stdin` to the terminal of `master`, and the terminal to `stdout`, on threads
+/// that run until the streams are closed.
+///
+/// Returns the thread copying the output of the terminal, which finishes once the guest
+/// closes the terminal, so it can be joined before its exit is reported. The thread copying
+/// `stdin` isn't returned: it's blocked reading `stdin` until containerd closes it.
+pub fn copy_io(
+    master: &File,
+    stdin: Option<File>,
+    stdout: Option<File>,
+) -> Result<Option<JoinHandle<()>>> {
+    if let Some(mut stdin) = stdin {
+        let mut master = master.try_clone()?;
+        std::thread::spawn(move || {
+            if let Err(err) = std::io::copy(&mut stdin, &mut master) {
+                log::debug!("terminal stdin closed: {err}");
+            }
+        });
+    }
+    let Some(mut stdout) = stdout else {
+        return Ok(None);
+    };
+    let mut master = master.try_clone()?;
+    let output = std::thread::spawn(move || {
+        // reading the master fails with EIO once the guest closes the terminal
+        if let Err(err) = std::io::copy(&mut master, &mut stdout) {
+            log::debug!("terminal stdout closed: {err}");
+        }
+    });
+    Ok(Some(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read as _, Write as _};
+    use std::os::fd::AsFd as _;
+
+    use nix::pty::openpty;
+
+    use super::*;
+
+    #[test]
+    fn test_console_socket() -> anyhow::Result<()> {
+        let socket = ConsoleSocket::new()?;
+        assert!(socket.receive().is_err());
+
+        let pty = openpty(None, None)?;
+        send_console(socket.path(), pty.master.as_fd())?;
+        let mut master = socket.receive()?;
+        resize(&master, 80, 24)?;
+
+        let mut slave = File::from(pty.slave);
+        slave.write_all(b"hello\n")?;
+        let mut buf = [0u8; 5];
+        master.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"hello");
+
+        let path = socket.path().to_path_buf();
+        drop(socket);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_io_finishes_once_the_terminal_is_closed() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let pty = openpty(None, None)?;
+        let master = File::from(pty.master);
+        let stdout = File::create(dir.path().join("stdout"))?;
+
+        let output = copy_io(&master, None, Some(stdout))?.expect("stdout is copied");
+        let mut slave = File::from(pty.slave);
+        slave.write_all(b"hello\n")?;
+        drop(slave);
+
+        output.join().unwrap();
+        let stdout = std::fs::read_to_string(dir.path().join("stdout"))?;
+        assert_eq!(stdout, "hello\r\n");
+        Ok(())
+    }
+}
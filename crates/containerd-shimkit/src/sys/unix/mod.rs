@@ -1,7 +1,9 @@
 use std::path::PathBuf;
 use std::sync::LazyLock;
 
+pub mod console;
 pub mod metrics;
+pub mod pids;
 pub mod stdio;
 
 pub static DEFAULT_CONTAINER_ROOT_DIR: LazyLock<PathBuf> =
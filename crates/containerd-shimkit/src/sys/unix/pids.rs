@@ -0,0 +1,85 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Returns the pids of the threads in the cgroup of the process `pid`, which include the
+/// threads of the guest and of its children.
+/// Only `pid` is returned for a task of the shim process, whose cgroup isn't its own.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+pub fn get_pids(pid: u32) -> Result<Vec<u32>> {
+    if pid == std::process::id() {
+        return Ok(vec![pid]);
+    }
+
+    let cgroups = std::fs::read_to_string(format!("/proc/{pid}/cgroup"))
+        .with_context(|| format!("failed to read the cgroup of {pid}"))?;
+    let tasks = tasks_path(&cgroups, Path::new(CGROUP_ROOT))
+        .with_context(|| format!("no cgroup found for {pid}"))?;
+    let tasks =
+        std::fs::read_to_string(&tasks).with_context(|| format!("failed to read {tasks:?}"))?;
+
+    let mut pids: Vec<u32> = tasks.lines().filter_map(|tid| tid.parse().ok()).collect();
+    pids.sort_unstable();
+    Ok(pids)
+}
+
+/// Returns the path of the file listing the threads of a cgroup, from the content of
+/// `/proc/<pid>/cgroup`.
+fn tasks_path(cgroups: &str, root: &Path) -> Option<PathBuf> {
+    let hierarchies: Vec<_> = cgroups
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let _id = fields.next()?;
+            Some((fields.next()?, fields.next()?))
+        })
+        .collect();
+
+    // on hybrid hosts, the v1 pids hierarchy is used rather than the unified one
+    if let Some((controllers, path)) = hierarchies
+        .iter()
+        .find(|(controllers, _)| controllers.split(',').any(|c| c == "pids"))
+    {
+        return Some(
+            root.join(controllers)
+                .join(path.trim_start_matches('/'))
+                .join("tasks"),
+        );
+    }
+
+    let (_, path) = hierarchies
+        .iter()
+        .find(|(controllers, _)| controllers.is_empty())?;
+    Some(
+        root.join(path.trim_start_matches('/'))
+            .join("cgroup.threads"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tasks_path() {
+        let root = Path::new("/sys/fs/cgroup");
+
+        let v2 = "0::/kubepods/pod1/abc\n";
+        assert_eq!(
+            tasks_path(v2, root),
+            Some(PathBuf::from(
+                "/sys/fs/cgroup/kubepods/pod1/abc/cgroup.threads"
+            ))
+        );
+
+        let v1 = "12:memory:/kubepods/abc\n5:pids:/kubepods/abc\n0::/kubepods/abc\n";
+        assert_eq!(
+            tasks_path(v1, root),
+            Some(PathBuf::from("/sys/fs/cgroup/pids/kubepods/abc/tasks"))
+        );
+
+        assert_eq!(tasks_path("12:memory:/abc\n", root), None);
+    }
+}
@@ -1,7 +1,70 @@
 use std::fs::{File, OpenOptions};
 use std::io::Result;
+use std::os::fd::AsRawFd as _;
+use std::os::unix::fs::{FileTypeExt as _, OpenOptionsExt as _};
 use std::path::Path;
 
+use nix::fcntl::{FcntlArg, OFlag, fcntl};
+
 pub fn open(path: impl AsRef<Path>) -> Result<File> {
     OpenOptions::new().read(true).write(true).open(path)
 }
+
+/// Opens the stdin of a guest for reading only, so that the guest reads EOF once all
+/// the writers of the fifo are closed.
+pub fn open_stdin(path: impl AsRef<Path>) -> Result<File> {
+    // don't block until a writer opens the fifo
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(path)?;
+    let flags = OFlag::from_bits_truncate(fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)?);
+    fcntl(
+        file.as_raw_fd(),
+        FcntlArg::F_SETFL(flags.difference(OFlag::O_NONBLOCK)),
+    )?;
+    Ok(file)
+}
+
+/// Opens a writer of the stdin fifo, which keeps the guest from reading EOF until
+/// it's closed, when containerd closes the IO of the task.
+/// Returns `None` when stdin isn't a fifo.
+pub fn hold_stdin(path: impl AsRef<Path>) -> Result<Option<File>> {
+    if !std::fs::metadata(&path)?.file_type().is_fifo() {
+        return Ok(None);
+    }
+    open(path).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read as _, Write as _};
+
+    use nix::sys::stat::Mode;
+    use nix::unistd::mkfifo;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_stdin_reads_eof_once_released() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("stdin");
+        mkfifo(&path, Mode::S_IRWXU)?;
+
+        let mut writer = hold_stdin(&path)?.expect("stdin is a fifo");
+        let mut stdin = open_stdin(&path)?;
+
+        writer.write_all(b"hello")?;
+        drop(writer);
+
+        let mut input = String::new();
+        stdin.read_to_string(&mut input)?;
+        assert_eq!(input, "hello");
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "")?;
+        assert!(hold_stdin(&file)?.is_none());
+        Ok(())
+    }
+}
@@ -14,3 +14,12 @@ pub fn open(path: impl AsRef<Path>) -> Result<File> {
     }
     options.open(path)
 }
+
+pub fn open_stdin(path: impl AsRef<Path>) -> Result<File> {
+    open(path)
+}
+
+/// Containerd closes the named pipe of stdin itself, so there's no writer to hold.
+pub fn hold_stdin(_path: impl AsRef<Path>) -> Result<Option<File>> {
+    Ok(None)
+}